@@ -0,0 +1,22 @@
+//! Checks that the CLIP merges `src/clip.rs` embeds are in `assets/`, so a checkout without
+//! them fails to build instead of producing a binary that can't count tokens.
+
+use std::path::Path;
+
+const VOCAB_PATH: &str = "assets/bpe_simple_vocab_16e6.txt.gz";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", VOCAB_PATH);
+    let bytes = std::fs::read(Path::new(VOCAB_PATH)).unwrap_or_else(|e| {
+        panic!(
+            "cannot read the CLIP merges {}: {} (see assets/README.md)",
+            VOCAB_PATH, e
+        )
+    });
+    if !bytes.starts_with(&[0x1f, 0x8b]) {
+        panic!(
+            "{} is not gzip-compressed (see assets/README.md)",
+            VOCAB_PATH
+        );
+    }
+}
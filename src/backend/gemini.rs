@@ -0,0 +1,260 @@
+use async_trait::async_trait;
+use gemini_rs::types::{self, Status};
+use serde::Deserialize;
+
+use super::{BackendError, Message, OnText, PromptBackend, Role, read_lines, sse_data};
+
+/// Default model name for the Gemini API
+pub const DEFAULT_MODEL: &str = "gemini-2.0-flash";
+
+/// Gemini API server, used directly for streaming since `gemini_rs` can't stream
+const BASE_URL: &str = "https://generativelanguage.googleapis.com";
+
+/// Backend that talks to Google's Gemini API through `gemini_rs`
+pub struct GeminiBackend {
+    client: gemini_rs::Client,
+    http: reqwest::Client,
+    base_url: String,
+    key: String,
+    model: String,
+    temperature: Option<f32>,
+}
+
+impl GeminiBackend {
+    /// Create a Gemini backend using `key`, with `model` falling back to [`DEFAULT_MODEL`]
+    pub fn new(key: String, model: Option<String>, temperature: Option<f32>) -> Self {
+        Self {
+            client: gemini_rs::Client::new(key.clone()),
+            http: reqwest::Client::new(),
+            base_url: BASE_URL.to_string(),
+            key,
+            model: model.unwrap_or_else(|| DEFAULT_MODEL.to_string()),
+            temperature,
+        }
+    }
+}
+
+#[async_trait]
+impl PromptBackend for GeminiBackend {
+    fn name(&self) -> &'static str {
+        "gemini"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn chat(
+        &self,
+        system_instruction: &str,
+        messages: &[Message],
+    ) -> Result<String, BackendError> {
+        let mut chat = self
+            .client
+            .chat(&self.model)
+            .system_instruction(system_instruction); // Pass system instructions and history
+        chat.config_mut().temperature = self.temperature;
+        // The whole conversation is resent, so refinements build on the earlier replies
+        *chat.history_mut() = messages.iter().map(content).collect();
+        let res = chat.generate_content().await.map_err(request_error)?;
+        Ok(res.to_string().trim().to_string())
+    }
+
+    async fn chat_stream(
+        &self,
+        system_instruction: &str,
+        messages: &[Message],
+        on_text: &mut OnText<'_>,
+    ) -> Result<String, BackendError> {
+        let body = types::GenerateContent {
+            contents: messages.iter().map(content).collect(),
+            generation_config: Some(types::GenerationConfig {
+                temperature: self.temperature,
+                ..Default::default()
+            }),
+            system_instruction: Some(types::SystemInstructionContent {
+                parts: vec![types::SystemInstructionPart {
+                    text: Some(system_instruction.to_string()),
+                }],
+            }),
+            ..Default::default()
+        };
+        let response = self
+            .http
+            .post(format!(
+                "{}/v1beta/models/{}:streamGenerateContent?alt=sse",
+                self.base_url, self.model
+            ))
+            .header("x-goog-api-key", &self.key)
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| BackendError::Request(e.to_string()))?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(match serde_json::from_str::<types::ApiError>(&text) {
+                Ok(api_error) => request_error(gemini_rs::Error::Gemini(api_error.error)),
+                Err(_) => BackendError::from_status(status, &text),
+            });
+        }
+
+        let mut text = String::new();
+        read_lines(response, |line| {
+            let Some(data) = sse_data(line) else {
+                return Ok(());
+            };
+            let chunk: StreamChunk = serde_json::from_str(data)
+                .map_err(|e| BackendError::Request(format!("invalid stream event: {}", e)))?;
+            let parts = chunk
+                .candidates
+                .into_iter()
+                .take(1)
+                .flat_map(|c| c.content.parts);
+            for piece in parts.filter_map(|part| part.text) {
+                on_text(&piece);
+                text.push_str(&piece);
+            }
+            Ok(())
+        })
+        .await?;
+        Ok(text.trim().to_string())
+    }
+}
+
+/// One server-sent event of a streamed reply; only the text of the first candidate is used
+#[derive(Deserialize)]
+struct StreamChunk {
+    #[serde(default)]
+    candidates: Vec<StreamCandidate>,
+}
+
+#[derive(Deserialize)]
+struct StreamCandidate {
+    #[serde(default)]
+    content: StreamContent,
+}
+
+#[derive(Default, Deserialize)]
+struct StreamContent {
+    #[serde(default)]
+    parts: Vec<types::Part>,
+}
+
+fn content(message: &Message) -> types::Content {
+    types::Content {
+        role: match message.role {
+            Role::User => types::Role::User,
+            Role::Model => types::Role::Model,
+        },
+        parts: vec![types::Part::text(&message.text)],
+    }
+}
+
+/// Map a `gemini_rs` error, reporting an invalid or unauthorized key as [`BackendError::Auth`]
+fn request_error(error: gemini_rs::Error) -> BackendError {
+    if let gemini_rs::Error::Gemini(detail) = &error {
+        let key_invalid = detail
+            .details
+            .iter()
+            .any(|info| info.reason.as_deref() == Some("API_KEY_INVALID"));
+        if key_invalid || matches!(detail.status, Status::PermissionDenied) {
+            return BackendError::Auth(detail.message.clone());
+        }
+    }
+    BackendError::Request(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::stub::StubServer;
+
+    fn api_error(json: &str) -> gemini_rs::Error {
+        gemini_rs::Error::Gemini(serde_json::from_str(json).unwrap())
+    }
+
+    #[test]
+    fn invalid_key_is_an_auth_error() {
+        let err = request_error(api_error(
+            r#"{"code": 400, "message": "API key not valid. Please pass a valid API key.",
+                "status": "INVALID_ARGUMENT",
+                "details": [{"@type": "type.googleapis.com/google.rpc.ErrorInfo",
+                             "reason": "API_KEY_INVALID", "domain": "googleapis.com"}]}"#,
+        ));
+        assert!(matches!(err, BackendError::Auth(msg) if msg.starts_with("API key not valid")));
+
+        let err = request_error(api_error(
+            r#"{"code": 403, "message": "Permission denied", "status": "PERMISSION_DENIED"}"#,
+        ));
+        assert!(matches!(err, BackendError::Auth(_)));
+    }
+
+    fn backend(server: &StubServer) -> GeminiBackend {
+        GeminiBackend {
+            base_url: server.url(),
+            ..GeminiBackend::new("test-key".to_string(), None, Some(0.5))
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_server_sent_events() {
+        let server = StubServer::start(
+            200,
+            "data: {\"candidates\": [{\"content\": {\"parts\": [{\"text\": \"1girl, \"}], \"role\": \"model\"}}]}\r\n\r\n\
+             data: {\"candidates\": [{\"content\": {\"parts\": [{\"text\": \"moon\\n\"}], \"role\": \"model\"}, \"finishReason\": \"STOP\"}]}\r\n\r\n",
+        )
+        .await;
+        let mut pieces = Vec::new();
+        let text = backend(&server)
+            .chat_stream("SYSTEM", &[Message::user("witch")], &mut |piece| {
+                pieces.push(piece.to_string())
+            })
+            .await
+            .unwrap();
+        // The reply is trimmed; the pieces are passed on as they arrive
+        assert_eq!(text, "1girl, moon");
+        assert_eq!(pieces, ["1girl, ", "moon\n"]);
+
+        let request = server.request().await;
+        assert!(request.head.starts_with(&format!(
+            "POST /v1beta/models/{}:streamGenerateContent?alt=sse ",
+            DEFAULT_MODEL
+        )));
+        assert!(
+            request
+                .head
+                .to_lowercase()
+                .contains("x-goog-api-key: test-key")
+        );
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["system_instruction"]["parts"][0]["text"], "SYSTEM");
+        assert_eq!(body["contents"][0]["role"], "user");
+        assert_eq!(body["contents"][0]["parts"][0]["text"], "witch");
+        assert_eq!(body["generationConfig"]["temperature"], 0.5);
+    }
+
+    #[tokio::test]
+    async fn streaming_reports_rejected_keys() {
+        let server = StubServer::start(
+            400,
+            r#"{"error": {"code": 400, "message": "API key not valid.", "status": "INVALID_ARGUMENT",
+                "details": [{"@type": "type.googleapis.com/google.rpc.ErrorInfo",
+                             "reason": "API_KEY_INVALID"}]}}"#,
+        )
+        .await;
+        let err = backend(&server)
+            .chat_stream("SYSTEM", &[Message::user("witch")], &mut |_| {})
+            .await
+            .unwrap_err();
+        assert!(matches!(err, BackendError::Auth(_)), "{}", err);
+    }
+
+    #[test]
+    fn other_errors_are_request_errors() {
+        let err = request_error(api_error(
+            r#"{"code": 429, "message": "Quota exceeded", "status": "RESOURCE_EXHAUSTED"}"#,
+        ));
+        assert!(matches!(err, BackendError::Request(_)));
+    }
+}
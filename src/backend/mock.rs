@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+
+use super::{BackendError, Message, OnText, PromptBackend};
+
+/// A request received by [`MockBackend`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockCall {
+    pub system_instruction: String,
+    /// The last message, which the response is looked up by
+    pub keyword: String,
+    /// Messages of the conversation before `keyword`
+    pub earlier: Vec<Message>,
+}
+
+/// Deterministic offline backend that replays canned responses keyed by the input keyword,
+/// or by the last message of a conversation.
+///
+/// Keywords without a canned response get a fixed prompt derived from the keyword itself,
+/// so the same input always produces the same output.
+#[derive(Default)]
+pub struct MockBackend {
+    responses: HashMap<String, String>,
+    calls: Mutex<Vec<MockCall>>,
+}
+
+impl MockBackend {
+    /// Create a mock replaying `responses` (keyword -> generated prompt)
+    pub fn new(responses: HashMap<String, String>) -> Self {
+        Self {
+            responses,
+            calls: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Load canned responses from a JSON object mapping keywords to generated prompts
+    pub fn from_file(path: &Path) -> Result<Self, BackendError> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            BackendError::Config(format!("cannot read mock responses {:?}: {}", path, e))
+        })?;
+        let responses = serde_json::from_str(&contents).map_err(|e| {
+            BackendError::Config(format!("invalid mock responses {:?}: {}", path, e))
+        })?;
+        Ok(Self::new(responses))
+    }
+
+    /// Requests received so far, oldest first
+    #[cfg(test)]
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Response used for keywords without a canned entry
+    pub fn fallback_response(keyword: &str) -> String {
+        format!(
+            "masterpiece, best quality, anime screenshot, ({}:1.2), cel shading, vibrant colors, soft lighting",
+            keyword
+        )
+    }
+}
+
+#[async_trait]
+impl PromptBackend for MockBackend {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn model(&self) -> &str {
+        "replay"
+    }
+
+    async fn chat(
+        &self,
+        system_instruction: &str,
+        messages: &[Message],
+    ) -> Result<String, BackendError> {
+        let (last, earlier) = messages
+            .split_last()
+            .ok_or_else(|| BackendError::Request("no message to reply to".to_string()))?;
+        let keyword = last.text.as_str();
+        self.calls.lock().unwrap().push(MockCall {
+            system_instruction: system_instruction.to_string(),
+            keyword: keyword.to_string(),
+            earlier: earlier.to_vec(),
+        });
+        Ok(self
+            .responses
+            .get(keyword)
+            .cloned()
+            .unwrap_or_else(|| Self::fallback_response(keyword)))
+    }
+
+    /// Streams the response one comma-separated token at a time
+    async fn chat_stream(
+        &self,
+        system_instruction: &str,
+        messages: &[Message],
+        on_text: &mut OnText<'_>,
+    ) -> Result<String, BackendError> {
+        let text = self.chat(system_instruction, messages).await?;
+        for piece in text.split_inclusive(", ") {
+            on_text(piece);
+        }
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replays_canned_responses_and_records_calls() {
+        let backend = MockBackend::new(HashMap::from([(
+            "knight".to_string(),
+            "1boy, armor".to_string(),
+        )]));
+
+        assert_eq!(
+            backend.generate("SYS", "knight").await.unwrap(),
+            "1boy, armor"
+        );
+        assert_eq!(
+            backend.generate("SYS", "dragon").await.unwrap(),
+            MockBackend::fallback_response("dragon")
+        );
+        let calls = backend.calls();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[1].keyword, "dragon");
+        assert_eq!(calls[1].system_instruction, "SYS");
+        assert!(calls[1].earlier.is_empty());
+
+        let conversation = [
+            Message::user("dragon"),
+            Message::model("red dragon"),
+            Message::user("knight"),
+        ];
+        assert_eq!(
+            backend.chat("SYS", &conversation).await.unwrap(),
+            "1boy, armor"
+        );
+        assert_eq!(backend.calls()[2].earlier, conversation[..2]);
+    }
+}
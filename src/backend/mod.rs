@@ -0,0 +1,308 @@
+//! LLM backends used to turn a keyword into a generated image prompt.
+//!
+//! Every provider implements [`PromptBackend`], so the rest of PromptFlow only deals with
+//! "system instruction and messages in, prompt text out" and never with a specific client
+//! library. Providers that stream their replies (Gemini and OpenAI as server-sent events,
+//! Ollama as JSON lines) also implement [`PromptBackend::chat_stream`].
+
+mod gemini;
+mod mock;
+mod ollama;
+mod openai;
+#[cfg(test)]
+mod stub;
+
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use async_trait::async_trait;
+
+pub use gemini::GeminiBackend;
+pub use mock::MockBackend;
+pub use ollama::{OllamaApi, OllamaBackend, OllamaOptions};
+pub use openai::OpenAiBackend;
+
+/// Error returned by a backend when construction or generation fails
+#[derive(Debug)]
+pub enum BackendError {
+    /// The backend is missing a setting it needs (key, base URL, ...)
+    Config(String),
+    /// The request failed in transit or was rejected by the provider
+    Request(String),
+    /// The provider rejected the API key
+    Auth(String),
+}
+
+impl BackendError {
+    /// Turn an unsuccessful HTTP response into an error, telling rejected credentials apart
+    fn from_status(status: reqwest::StatusCode, body: &str) -> Self {
+        let message = format!("{}: {}", status, body.trim());
+        match status {
+            reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => {
+                BackendError::Auth(message)
+            }
+            _ => BackendError::Request(message),
+        }
+    }
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::Config(msg) => write!(f, "backend configuration error: {}", msg),
+            BackendError::Request(msg) => write!(f, "backend request failed: {}", msg),
+            BackendError::Auth(msg) => write!(f, "API key rejected: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+/// Who wrote a message of a conversation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    User,
+    Model,
+}
+
+/// One turn of a conversation with the model
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub role: Role,
+    pub text: String,
+}
+
+impl Message {
+    pub fn user(text: impl Into<String>) -> Self {
+        Self {
+            role: Role::User,
+            text: text.into(),
+        }
+    }
+
+    pub fn model(text: impl Into<String>) -> Self {
+        Self {
+            role: Role::Model,
+            text: text.into(),
+        }
+    }
+}
+
+/// Receiver of the pieces of a streamed reply, in order
+pub type OnText<'a> = dyn for<'t> FnMut(&'t str) + Send + 'a;
+
+/// A provider capable of generating a prompt from a system instruction and a user keyword
+#[async_trait]
+pub trait PromptBackend: Send + Sync {
+    /// Short identifier of the backend, as accepted by `--backend`
+    fn name(&self) -> &'static str;
+
+    /// Model the backend generates with
+    fn model(&self) -> &str;
+
+    /// Generate the model's reply to `messages`, guided by `system_instruction`. The messages
+    /// alternate between the user and the model and end with a user message.
+    async fn chat(
+        &self,
+        system_instruction: &str,
+        messages: &[Message],
+    ) -> Result<String, BackendError>;
+
+    /// Generate a prompt for `keyword`, guided by `system_instruction`
+    async fn generate(
+        &self,
+        system_instruction: &str,
+        keyword: &str,
+    ) -> Result<String, BackendError> {
+        self.chat(system_instruction, &[Message::user(keyword)])
+            .await
+    }
+
+    /// Like [`chat`](Self::chat), passing each piece of the reply to `on_text` as it arrives.
+    /// Backends that can't stream pass the whole reply at once.
+    async fn chat_stream(
+        &self,
+        system_instruction: &str,
+        messages: &[Message],
+        on_text: &mut OnText<'_>,
+    ) -> Result<String, BackendError> {
+        let text = self.chat(system_instruction, messages).await?;
+        on_text(&text);
+        Ok(text)
+    }
+}
+
+/// Pass each line of the body of `response` to `on_line` as it arrives, without its line ending
+async fn read_lines(
+    mut response: reqwest::Response,
+    mut on_line: impl FnMut(&str) -> Result<(), BackendError> + Send,
+) -> Result<(), BackendError> {
+    let mut buffer = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| BackendError::Request(e.to_string()))?
+    {
+        buffer.extend_from_slice(&chunk);
+        // Lines are split on bytes, so a character cut between two chunks is put back together
+        while let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            on_line(String::from_utf8_lossy(&line).trim_end())?;
+        }
+    }
+    if !buffer.is_empty() {
+        on_line(String::from_utf8_lossy(&buffer).trim_end())?;
+    }
+    Ok(())
+}
+
+/// Payload of a server-sent event `data:` line, `None` for other lines
+fn sse_data(line: &str) -> Option<&str> {
+    line.strip_prefix("data:").map(str::trim_start)
+}
+
+/// Backends selectable with `--backend`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BackendKind {
+    #[default]
+    Gemini,
+    OpenAi,
+    Ollama,
+    /// Offline replay backend for tests and demos
+    Mock,
+}
+
+impl BackendKind {
+    /// All known backends, in the order they are listed in help and error messages
+    pub const ALL: &'static [BackendKind] = &[
+        BackendKind::Gemini,
+        BackendKind::OpenAi,
+        BackendKind::Ollama,
+        BackendKind::Mock,
+    ];
+
+    /// Identifier used on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            BackendKind::Gemini => "gemini",
+            BackendKind::OpenAi => "openai",
+            BackendKind::Ollama => "ollama",
+            BackendKind::Mock => "mock",
+        }
+    }
+
+    /// Whether the backend cannot work without an API key. Keyless backends skip the
+    /// stored/environment key lookup and only receive a key passed explicitly with `--key`.
+    pub fn requires_key(self) -> bool {
+        match self {
+            BackendKind::Gemini => true,
+            BackendKind::OpenAi | BackendKind::Ollama | BackendKind::Mock => false,
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        BackendKind::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| {
+                let known: Vec<&str> = BackendKind::ALL.iter().map(|k| k.name()).collect();
+                format!("Unknown backend {:?} (available: {})", s, known.join(", "))
+            })
+    }
+}
+
+/// Settings used when constructing a backend; each backend ignores the ones it has no use for
+#[derive(Debug, Clone, Default)]
+pub struct BackendOptions {
+    /// API key, for backends that need one
+    pub key: Option<String>,
+    /// Model override; each backend falls back to its own default
+    pub model: Option<String>,
+    /// Server URL override for self-hostable backends
+    pub base_url: Option<String>,
+    /// Sampling temperature; the provider default is used when unset
+    pub temperature: Option<f32>,
+    /// Context window size (Ollama only)
+    pub num_ctx: Option<u32>,
+    /// Endpoint to generate with (Ollama only)
+    pub ollama_api: OllamaApi,
+    /// JSON file of canned responses (mock only)
+    pub mock_responses: Option<PathBuf>,
+}
+
+/// Construct the backend selected by `kind`
+pub fn create(
+    kind: BackendKind,
+    options: BackendOptions,
+) -> Result<Box<dyn PromptBackend>, BackendError> {
+    match kind {
+        BackendKind::Gemini => {
+            let key = options.key.ok_or_else(|| {
+                BackendError::Config("the gemini backend requires an API key".to_string())
+            })?;
+            Ok(Box::new(GeminiBackend::new(
+                key,
+                options.model,
+                options.temperature,
+            )))
+        }
+        BackendKind::OpenAi => Ok(Box::new(OpenAiBackend::new(
+            options.base_url,
+            options.model,
+            options.key,
+            options.temperature,
+        ))),
+        BackendKind::Ollama => Ok(Box::new(OllamaBackend::new(
+            options.base_url,
+            options.model,
+            options.ollama_api,
+            OllamaOptions {
+                temperature: options.temperature,
+                num_ctx: options.num_ctx,
+            },
+        ))),
+        BackendKind::Mock => match options.mock_responses {
+            Some(path) => Ok(Box::new(MockBackend::from_file(&path)?)),
+            None => Ok(Box::new(MockBackend::default())),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_backend_names_case_insensitively() {
+        assert_eq!("gemini".parse::<BackendKind>(), Ok(BackendKind::Gemini));
+        assert_eq!(" Ollama ".parse::<BackendKind>(), Ok(BackendKind::Ollama));
+        assert_eq!("MOCK".parse::<BackendKind>(), Ok(BackendKind::Mock));
+    }
+
+    #[test]
+    fn unknown_backend_lists_available_ones() {
+        let err = "claude".parse::<BackendKind>().unwrap_err();
+        assert!(err.contains("gemini, openai, ollama, mock"), "{}", err);
+    }
+
+    #[test]
+    fn gemini_requires_a_key() {
+        let err = create(BackendKind::Gemini, BackendOptions::default())
+            .err()
+            .unwrap();
+        assert!(matches!(err, BackendError::Config(_)));
+        assert!(create(BackendKind::Mock, BackendOptions::default()).is_ok());
+    }
+}
@@ -0,0 +1,406 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use super::{BackendError, Message, OnText, PromptBackend, Role, read_lines};
+
+/// Server URL used when neither `--base-url` nor `OLLAMA_HOST` is set
+pub const DEFAULT_BASE_URL: &str = "http://localhost:11434";
+
+/// Model used when none is configured
+pub const DEFAULT_MODEL: &str = "llama3.1";
+
+/// Which Ollama endpoint to generate with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OllamaApi {
+    /// `/api/chat`, sending the instruction as a system message
+    #[default]
+    Chat,
+    /// `/api/generate`, sending the instruction in the `system` field
+    Generate,
+}
+
+impl std::fmt::Display for OllamaApi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            OllamaApi::Chat => "chat",
+            OllamaApi::Generate => "generate",
+        })
+    }
+}
+
+impl std::str::FromStr for OllamaApi {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "chat" => Ok(OllamaApi::Chat),
+            "generate" => Ok(OllamaApi::Generate),
+            _ => Err(format!(
+                "Unknown Ollama API {:?} (available: chat, generate)",
+                s
+            )),
+        }
+    }
+}
+
+/// Model parameters passed through Ollama's `options` object
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OllamaOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_ctx: Option<u32>,
+}
+
+impl OllamaOptions {
+    fn is_empty(&self) -> bool {
+        self.temperature.is_none() && self.num_ctx.is_none()
+    }
+}
+
+/// Backend for a local Ollama server, usable without any API key or internet access
+pub struct OllamaBackend {
+    http: reqwest::Client,
+    base_url: String,
+    model: String,
+    api: OllamaApi,
+    options: OllamaOptions,
+}
+
+impl OllamaBackend {
+    /// Create a backend for `base_url`, falling back to `OLLAMA_HOST` and then [`DEFAULT_BASE_URL`]
+    pub fn new(
+        base_url: Option<String>,
+        model: Option<String>,
+        api: OllamaApi,
+        options: OllamaOptions,
+    ) -> Self {
+        let base_url = base_url
+            .or_else(|| std::env::var("OLLAMA_HOST").ok())
+            .unwrap_or_else(|| DEFAULT_BASE_URL.to_string());
+        let base_url = if base_url.contains("://") {
+            base_url
+        } else {
+            // OLLAMA_HOST is commonly given as a bare host:port
+            format!("http://{}", base_url)
+        };
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model: model.unwrap_or_else(|| DEFAULT_MODEL.to_string()),
+            api,
+            options,
+        }
+    }
+
+    async fn send(
+        &self,
+        path: &str,
+        body: &impl Serialize,
+    ) -> Result<reqwest::Response, BackendError> {
+        let response = self
+            .http
+            .post(format!("{}{}", self.base_url, path))
+            .json(body)
+            .send()
+            .await
+            .map_err(|e| BackendError::Request(e.to_string()))?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(BackendError::from_status(status, &text));
+        }
+        Ok(response)
+    }
+
+    fn options(&self) -> Option<&OllamaOptions> {
+        (!self.options.is_empty()).then_some(&self.options)
+    }
+
+    /// Send the conversation to the configured endpoint, streaming the reply if `stream` is set
+    async fn request(
+        &self,
+        system_instruction: &str,
+        messages: &[Message],
+        stream: bool,
+    ) -> Result<reqwest::Response, BackendError> {
+        match self.api {
+            OllamaApi::Chat => {
+                let system = ChatMessage {
+                    role: "system",
+                    content: system_instruction,
+                };
+                let body = ChatRequest {
+                    model: &self.model,
+                    messages: std::iter::once(system)
+                        .chain(messages.iter().map(|message| ChatMessage {
+                            role: match message.role {
+                                Role::User => "user",
+                                Role::Model => "assistant",
+                            },
+                            content: &message.text,
+                        }))
+                        .collect(),
+                    stream,
+                    options: self.options(),
+                };
+                self.send("/api/chat", &body).await
+            }
+            OllamaApi::Generate => {
+                let prompt = transcript(messages);
+                let body = GenerateRequest {
+                    model: &self.model,
+                    system: system_instruction,
+                    prompt: &prompt,
+                    stream,
+                    options: self.options(),
+                };
+                self.send("/api/generate", &body).await
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: Vec<ChatMessage<'a>>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<&'a OllamaOptions>,
+}
+
+#[derive(Serialize)]
+struct ChatMessage<'a> {
+    role: &'a str,
+    content: &'a str,
+}
+
+#[derive(Deserialize)]
+struct ResponseMessage {
+    content: String,
+}
+
+#[derive(Serialize)]
+struct GenerateRequest<'a> {
+    model: &'a str,
+    system: &'a str,
+    prompt: &'a str,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<&'a OllamaOptions>,
+}
+
+/// Reply of either endpoint, or one line of it when streamed
+#[derive(Deserialize)]
+struct Reply {
+    /// Set by `/api/chat`
+    #[serde(default)]
+    message: Option<ResponseMessage>,
+    /// Set by `/api/generate`
+    #[serde(default)]
+    response: Option<String>,
+    /// Sent in place of a reply when generation fails after streaming started
+    #[serde(default)]
+    error: Option<String>,
+}
+
+impl Reply {
+    fn text(self) -> Result<String, BackendError> {
+        if let Some(error) = self.error {
+            return Err(BackendError::Request(error));
+        }
+        Ok(self
+            .message
+            .map(|message| message.content)
+            .or(self.response)
+            .unwrap_or_default())
+    }
+}
+
+#[async_trait]
+impl PromptBackend for OllamaBackend {
+    fn name(&self) -> &'static str {
+        "ollama"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn chat(
+        &self,
+        system_instruction: &str,
+        messages: &[Message],
+    ) -> Result<String, BackendError> {
+        let reply: Reply = self
+            .request(system_instruction, messages, false)
+            .await?
+            .json()
+            .await
+            .map_err(|e| BackendError::Request(e.to_string()))?;
+        Ok(reply.text()?.trim().to_string())
+    }
+
+    async fn chat_stream(
+        &self,
+        system_instruction: &str,
+        messages: &[Message],
+        on_text: &mut OnText<'_>,
+    ) -> Result<String, BackendError> {
+        let response = self.request(system_instruction, messages, true).await?;
+        let mut text = String::new();
+        read_lines(response, |line| {
+            if line.is_empty() {
+                return Ok(());
+            }
+            let reply: Reply = serde_json::from_str(line)
+                .map_err(|e| BackendError::Request(format!("invalid stream line: {}", e)))?;
+            let piece = reply.text()?;
+            on_text(&piece);
+            text.push_str(&piece);
+            Ok(())
+        })
+        .await?;
+        Ok(text.trim().to_string())
+    }
+}
+
+/// Single prompt for `/api/generate`, which has no messages: a lone user message as is, a
+/// longer conversation as a transcript
+fn transcript(messages: &[Message]) -> String {
+    match messages {
+        [message] => message.text.clone(),
+        _ => messages
+            .iter()
+            .map(|message| match message.role {
+                Role::User => format!("User: {}", message.text),
+                Role::Model => format!("Assistant: {}", message.text),
+            })
+            .chain(std::iter::once("Assistant:".to_string()))
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::stub::StubServer;
+
+    #[tokio::test]
+    async fn chat_sends_messages_and_options() {
+        let server = StubServer::start(
+            200,
+            r#"{"model":"qwen","message":{"role":"assistant","content":"1girl, solo"},"done":true}"#,
+        )
+        .await;
+        let backend = OllamaBackend::new(
+            Some(server.url()),
+            Some("qwen".to_string()),
+            OllamaApi::Chat,
+            OllamaOptions {
+                temperature: Some(0.5),
+                num_ctx: Some(8192),
+            },
+        );
+
+        assert_eq!(
+            backend.generate("SYSTEM", "knight").await.unwrap(),
+            "1girl, solo"
+        );
+
+        let request = server.request().await;
+        assert!(request.head.starts_with("POST /api/chat "));
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["model"], "qwen");
+        assert_eq!(body["stream"], false);
+        assert_eq!(body["messages"][0]["content"], "SYSTEM");
+        assert_eq!(body["messages"][1]["content"], "knight");
+        assert_eq!(body["options"]["temperature"], 0.5);
+        assert_eq!(body["options"]["num_ctx"], 8192);
+    }
+
+    #[tokio::test]
+    async fn generate_uses_system_field_and_omits_empty_options() {
+        let server =
+            StubServer::start(200, r#"{"response":"masterpiece, 1boy\n","done":true}"#).await;
+        let backend = OllamaBackend::new(
+            Some(server.url()),
+            None,
+            OllamaApi::Generate,
+            OllamaOptions::default(),
+        );
+
+        assert_eq!(
+            backend.generate("SYSTEM", "knight").await.unwrap(),
+            "masterpiece, 1boy"
+        );
+
+        let request = server.request().await;
+        assert!(request.head.starts_with("POST /api/generate "));
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["model"], DEFAULT_MODEL);
+        assert_eq!(body["system"], "SYSTEM");
+        assert_eq!(body["prompt"], "knight");
+        assert!(body.get("options").is_none());
+    }
+
+    #[tokio::test]
+    async fn streams_json_lines() {
+        let server = StubServer::start(
+            200,
+            "{\"message\":{\"role\":\"assistant\",\"content\":\"1girl\"},\"done\":false}\n\
+             {\"message\":{\"role\":\"assistant\",\"content\":\", moon\"},\"done\":false}\n\
+             {\"message\":{\"role\":\"assistant\",\"content\":\"\"},\"done\":true}\n",
+        )
+        .await;
+        let backend = OllamaBackend::new(
+            Some(server.url()),
+            None,
+            OllamaApi::Chat,
+            OllamaOptions::default(),
+        );
+
+        let mut pieces = Vec::new();
+        let text = backend
+            .chat_stream("SYSTEM", &[Message::user("witch")], &mut |piece| {
+                pieces.push(piece.to_string())
+            })
+            .await
+            .unwrap();
+        assert_eq!(text, "1girl, moon");
+        assert_eq!(pieces.concat(), "1girl, moon");
+        let body: serde_json::Value = serde_json::from_str(&server.request().await.body).unwrap();
+        assert_eq!(body["stream"], true);
+
+        let server = StubServer::start(
+            200,
+            "{\"response\":\"1girl\",\"done\":false}\n{\"error\":\"model crashed\"}\n",
+        )
+        .await;
+        let backend = OllamaBackend::new(
+            Some(server.url()),
+            None,
+            OllamaApi::Generate,
+            OllamaOptions::default(),
+        );
+        let err = backend
+            .chat_stream("SYSTEM", &[Message::user("witch")], &mut |_| {})
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("model crashed"), "{}", err);
+    }
+
+    #[test]
+    fn generate_api_gets_conversations_as_a_transcript() {
+        assert_eq!(
+            transcript(&[
+                Message::user("knight"),
+                Message::model("1boy"),
+                Message::user("more lighting"),
+            ]),
+            "User: knight\n\nAssistant: 1boy\n\nUser: more lighting\n\nAssistant:"
+        );
+    }
+}
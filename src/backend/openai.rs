@@ -0,0 +1,321 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use super::{BackendError, Message, OnText, PromptBackend, Role, read_lines, sse_data};
+
+/// Base URL used when none is configured
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
+/// Model used when none is configured
+pub const DEFAULT_MODEL: &str = "gpt-4o-mini";
+
+/// Backend for any server implementing OpenAI's `/v1/chat/completions` endpoint
+/// (OpenAI itself, llama.cpp server, vLLM, LM Studio, ...)
+pub struct OpenAiBackend {
+    http: reqwest::Client,
+    base_url: String,
+    model: String,
+    key: Option<String>,
+    temperature: Option<f32>,
+}
+
+impl OpenAiBackend {
+    /// Create a backend for `base_url` (e.g. `http://localhost:8080/v1`).
+    /// The key is sent as a bearer token when present; local servers usually don't need one.
+    pub fn new(
+        base_url: Option<String>,
+        model: Option<String>,
+        key: Option<String>,
+        temperature: Option<f32>,
+    ) -> Self {
+        let base_url = base_url.unwrap_or_else(|| DEFAULT_BASE_URL.to_string());
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model: model.unwrap_or_else(|| DEFAULT_MODEL.to_string()),
+            key,
+            temperature,
+        }
+    }
+
+    fn endpoint(&self) -> String {
+        format!("{}/chat/completions", self.base_url)
+    }
+
+    /// Send the conversation, asking for server-sent events if `stream` is set
+    async fn send(
+        &self,
+        system_instruction: &str,
+        messages: &[Message],
+        stream: bool,
+    ) -> Result<reqwest::Response, BackendError> {
+        let system = ChatMessage {
+            role: "system",
+            content: system_instruction,
+        };
+        let body = ChatRequest {
+            model: &self.model,
+            messages: std::iter::once(system)
+                .chain(messages.iter().map(|message| ChatMessage {
+                    role: match message.role {
+                        Role::User => "user",
+                        Role::Model => "assistant",
+                    },
+                    content: &message.text,
+                }))
+                .collect(),
+            temperature: self.temperature,
+            stream,
+        };
+
+        let mut request = self.http.post(self.endpoint()).json(&body);
+        if let Some(key) = &self.key {
+            request = request.bearer_auth(key);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| BackendError::Request(e.to_string()))?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(BackendError::from_status(status, &text));
+        }
+        Ok(response)
+    }
+}
+
+#[derive(Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: Vec<ChatMessage<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+}
+
+#[derive(Serialize)]
+struct ChatMessage<'a> {
+    role: &'a str,
+    content: &'a str,
+}
+
+#[derive(Deserialize)]
+struct ChatResponse {
+    choices: Vec<Choice>,
+}
+
+#[derive(Deserialize)]
+struct Choice {
+    message: ResponseMessage,
+}
+
+#[derive(Deserialize)]
+struct ResponseMessage {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+/// One server-sent event of a streamed reply
+#[derive(Deserialize)]
+struct StreamChunk {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+}
+
+#[derive(Deserialize)]
+struct StreamChoice {
+    delta: ResponseMessage,
+}
+
+#[async_trait]
+impl PromptBackend for OpenAiBackend {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn chat(
+        &self,
+        system_instruction: &str,
+        messages: &[Message],
+    ) -> Result<String, BackendError> {
+        let response = self.send(system_instruction, messages, false).await?;
+        let parsed: ChatResponse = response
+            .json()
+            .await
+            .map_err(|e| BackendError::Request(e.to_string()))?;
+        reply(parsed)
+    }
+
+    async fn chat_stream(
+        &self,
+        system_instruction: &str,
+        messages: &[Message],
+        on_text: &mut OnText<'_>,
+    ) -> Result<String, BackendError> {
+        let response = self.send(system_instruction, messages, true).await?;
+        let mut text = String::new();
+        // Servers that ignore `stream` answer with a whole reply instead of events
+        let mut plain = String::new();
+        read_lines(response, |line| {
+            let Some(data) = sse_data(line) else {
+                // Lines starting with a colon are event stream comments, sent as keep-alives
+                if !line.starts_with(':') {
+                    plain.push_str(line);
+                }
+                return Ok(());
+            };
+            if data == "[DONE]" {
+                return Ok(());
+            }
+            let chunk: StreamChunk = serde_json::from_str(data)
+                .map_err(|e| BackendError::Request(format!("invalid stream event: {}", e)))?;
+            for piece in chunk.choices.into_iter().filter_map(|c| c.delta.content) {
+                on_text(&piece);
+                text.push_str(&piece);
+            }
+            Ok(())
+        })
+        .await?;
+        if text.is_empty() && !plain.trim().is_empty() {
+            let parsed: ChatResponse =
+                serde_json::from_str(&plain).map_err(|e| BackendError::Request(e.to_string()))?;
+            text = reply(parsed)?;
+            on_text(&text);
+        }
+        Ok(text.trim().to_string())
+    }
+}
+
+/// Text of the first choice of a whole reply
+fn reply(parsed: ChatResponse) -> Result<String, BackendError> {
+    parsed
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.message.content)
+        .map(|content| content.trim().to_string())
+        .ok_or_else(|| BackendError::Request("response contained no choices".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::stub::StubServer;
+
+    #[tokio::test]
+    async fn sends_system_and_user_messages() {
+        let server = StubServer::start(
+            200,
+            r#"{"choices":[{"message":{"role":"assistant","content":" (1girl:1.2), solo \n"}}]}"#,
+        )
+        .await;
+        let backend = OpenAiBackend::new(
+            Some(format!("{}/v1/", server.url())),
+            Some("local-model".to_string()),
+            Some("sk-test".to_string()),
+            Some(0.25),
+        );
+
+        let text = backend.generate("SYSTEM", "knight").await.unwrap();
+        assert_eq!(text, "(1girl:1.2), solo");
+
+        let request = server.request().await;
+        assert!(request.head.starts_with("POST /v1/chat/completions "));
+        assert!(
+            request
+                .head
+                .to_lowercase()
+                .contains("authorization: bearer sk-test")
+        );
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["model"], "local-model");
+        assert_eq!(body["temperature"], 0.25);
+        assert_eq!(body["messages"][0]["role"], "system");
+        assert_eq!(body["messages"][0]["content"], "SYSTEM");
+        assert_eq!(body["messages"][1]["role"], "user");
+        assert_eq!(body["messages"][1]["content"], "knight");
+    }
+
+    #[tokio::test]
+    async fn sends_the_whole_conversation() {
+        let server = StubServer::start(200, r#"{"choices":[{"message":{"content":"ok"}}]}"#).await;
+        let backend = OpenAiBackend::new(Some(server.url()), None, None, None);
+
+        let messages = [
+            Message::user("knight"),
+            Message::model("1boy, armor"),
+            Message::user("more lighting"),
+        ];
+        backend.chat("SYSTEM", &messages).await.unwrap();
+        let body: serde_json::Value = serde_json::from_str(&server.request().await.body).unwrap();
+        let roles: Vec<&str> = body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["role"].as_str().unwrap())
+            .collect();
+        assert_eq!(roles, ["system", "user", "assistant", "user"]);
+        assert_eq!(body["messages"][2]["content"], "1boy, armor");
+    }
+
+    #[tokio::test]
+    async fn streams_server_sent_events() {
+        let server = StubServer::start(
+            200,
+            "data: {\"choices\":[{\"delta\":{\"role\":\"assistant\"}}]}\n\n\
+             data: {\"choices\":[{\"delta\":{\"content\":\"1girl, \"}}]}\n\n\
+             : keep-alive\n\n\
+             data: {\"choices\":[{\"delta\":{\"content\":\"moon\\n\"}}]}\n\n\
+             data: [DONE]\n\n",
+        )
+        .await;
+        let backend = OpenAiBackend::new(Some(server.url()), None, None, None);
+
+        let mut pieces = Vec::new();
+        let text = backend
+            .chat_stream("SYSTEM", &[Message::user("witch")], &mut |piece| {
+                pieces.push(piece.to_string())
+            })
+            .await
+            .unwrap();
+        assert_eq!(text, "1girl, moon");
+        assert_eq!(pieces, ["1girl, ", "moon\n"]);
+        let body: serde_json::Value = serde_json::from_str(&server.request().await.body).unwrap();
+        assert_eq!(body["stream"], true);
+    }
+
+    #[tokio::test]
+    async fn omits_authorization_without_key() {
+        let server = StubServer::start(200, r#"{"choices":[{"message":{"content":"ok"}}]}"#).await;
+        let backend = OpenAiBackend::new(Some(server.url()), None, None, None);
+
+        backend.generate("SYSTEM", "knight").await.unwrap();
+        let request = server.request().await;
+        assert!(!request.head.to_lowercase().contains("authorization:"));
+    }
+
+    #[tokio::test]
+    async fn reports_http_errors() {
+        let server = StubServer::start(500, r#"{"error":"model not loaded"}"#).await;
+        let backend = OpenAiBackend::new(Some(server.url()), None, None, None);
+
+        let err = backend.generate("SYSTEM", "knight").await.unwrap_err();
+        assert!(err.to_string().contains("model not loaded"));
+    }
+
+    #[tokio::test]
+    async fn reports_rejected_keys() {
+        let server = StubServer::start(401, r#"{"error":{"code":"invalid_api_key"}}"#).await;
+        let backend = OpenAiBackend::new(Some(server.url()), None, Some("sk-old".into()), None);
+
+        let err = backend.generate("SYSTEM", "knight").await.unwrap_err();
+        assert!(matches!(err, BackendError::Auth(msg) if msg.contains("invalid_api_key")));
+    }
+}
@@ -0,0 +1,97 @@
+//! Minimal single-request HTTP server used to test HTTP backends without the network.
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+/// A request captured by [`StubServer`]
+pub struct CapturedRequest {
+    /// Request line and headers
+    pub head: String,
+    pub body: String,
+}
+
+/// Serves one canned response on a random local port and records the request it received
+pub struct StubServer {
+    addr: std::net::SocketAddr,
+    request: oneshot::Receiver<CapturedRequest>,
+}
+
+impl StubServer {
+    pub async fn start(status: u16, body: &str) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = oneshot::channel();
+        let body = body.to_string();
+
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let captured = read_request(&mut socket).await;
+            let response = format!(
+                "HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            socket.shutdown().await.ok();
+            let _ = tx.send(captured);
+        });
+
+        Self { addr, request: rx }
+    }
+
+    /// Base URL of the server, without a trailing slash
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// The request the server received
+    pub async fn request(self) -> CapturedRequest {
+        self.request.await.unwrap()
+    }
+}
+
+async fn read_request(socket: &mut tokio::net::TcpStream) -> CapturedRequest {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = socket.read(&mut chunk).await.unwrap();
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(end) = find_header_end(&buf) {
+            let head = String::from_utf8_lossy(&buf[..end]).to_string();
+            let length = content_length(&head);
+            while buf.len() < end + 4 + length {
+                let n = socket.read(&mut chunk).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            let body = String::from_utf8_lossy(&buf[end + 4..]).to_string();
+            return CapturedRequest { head, body };
+        }
+    }
+    CapturedRequest {
+        head: String::from_utf8_lossy(&buf).to_string(),
+        body: String::new(),
+    }
+}
+
+fn find_header_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n")
+}
+
+fn content_length(head: &str) -> usize {
+    head.lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("content-length")
+                .then(|| value.trim().parse().ok())?
+        })
+        .unwrap_or(0)
+}
@@ -0,0 +1,730 @@
+//! `PromptFlow batch`: prompts for every keyword in a file.
+//!
+//! Keywords are read from plain text (one per line, `#` comments allowed), CSV (the `keyword`
+//! column, or the first one without such a header) or JSON Lines (strings, or objects with a
+//! `keyword` field). Rows are generated concurrently, optionally rate limited, and each result
+//! is appended to a JSON Lines or CSV file as soon as it is ready. A failed row is recorded with
+//! its error instead of stopping the batch.
+//!
+//! Running the same batch again resumes it: rows the output already has a prompt for are
+//! skipped, and failed rows are dropped from the output and generated again.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::backend::{BackendError, Message, OnText, PromptBackend};
+use crate::clip::ClipTokenizer;
+use crate::generate::{self, GenerationSettings};
+use crate::history::{History, Retention};
+use crate::output::csv_field;
+
+/// Rows generated at the same time when `--concurrency` isn't given
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+/// Most rows generated at the same time
+pub const MAX_CONCURRENCY: usize = 32;
+
+/// Header row of CSV results
+const CSV_HEADER: [&str; 6] = [
+    "row",
+    "keyword",
+    "prompt",
+    "negative_prompt",
+    "tokens",
+    "error",
+];
+
+/// Failure to read the keywords or to read or write the results
+#[derive(Debug)]
+pub enum BatchError {
+    Io(PathBuf, io::Error),
+    /// A malformed line or record, numbered from 1
+    Parse(PathBuf, usize, String),
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BatchError::Io(path, e) => write!(f, "could not access {}: {}", path.display(), e),
+            BatchError::Parse(path, line, message) => {
+                write!(f, "{}:{}: {}", path.display(), line, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BatchError {}
+
+/// A keyword to generate for, with its line or record number in the input file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Row {
+    pub number: usize,
+    pub keyword: String,
+}
+
+/// Outcome of one row, as written to the results
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RowResult {
+    pub row: usize,
+    pub keyword: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub negative_prompt: Option<String>,
+    /// CLIP tokens of the prompt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl RowResult {
+    fn completes(&self) -> bool {
+        self.error.is_none() && self.prompt.is_some()
+    }
+
+    fn csv_fields(&self) -> [String; 6] {
+        [
+            self.row.to_string(),
+            self.keyword.clone(),
+            self.prompt.clone().unwrap_or_default(),
+            self.negative_prompt.clone().unwrap_or_default(),
+            self.tokens
+                .map(|tokens| tokens.to_string())
+                .unwrap_or_default(),
+            self.error.clone().unwrap_or_default(),
+        ]
+    }
+
+    fn from_csv(record: &[String]) -> Result<Self, String> {
+        let [row, keyword, prompt, negative_prompt, tokens, error] = record else {
+            return Err(format!(
+                "expected {} fields, found {}",
+                CSV_HEADER.len(),
+                record.len()
+            ));
+        };
+        let some = |field: &String| Some(field.clone()).filter(|field| !field.is_empty());
+        Ok(Self {
+            row: row.parse().map_err(|_| format!("invalid row {:?}", row))?,
+            keyword: keyword.clone(),
+            prompt: some(prompt),
+            negative_prompt: some(negative_prompt),
+            tokens: some(tokens)
+                .map(|tokens| tokens.parse())
+                .transpose()
+                .map_err(|_| format!("invalid token count {:?}", tokens))?,
+            error: some(error),
+        })
+    }
+}
+
+/// Whether a file holds CSV, JSON Lines or (for keywords only) plain text, by its extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    Text,
+    Csv,
+    Jsonl,
+}
+
+impl FileFormat {
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => FileFormat::Csv,
+            Some(ext)
+                if ext.eq_ignore_ascii_case("jsonl") || ext.eq_ignore_ascii_case("ndjson") =>
+            {
+                FileFormat::Jsonl
+            }
+            _ => FileFormat::Text,
+        }
+    }
+}
+
+/// Read the keywords of `path`
+pub fn read_keywords(path: &Path) -> Result<Vec<Row>, BatchError> {
+    let text = std::fs::read_to_string(path).map_err(|e| BatchError::Io(path.into(), e))?;
+    parse_keywords(&text, FileFormat::of(path))
+        .map_err(|(line, message)| BatchError::Parse(path.into(), line, message))
+}
+
+/// The keywords of `text` in `format`; a failure gives the line or record number and the reason
+pub fn parse_keywords(text: &str, format: FileFormat) -> Result<Vec<Row>, (usize, String)> {
+    let mut rows = Vec::new();
+    match format {
+        FileFormat::Text => {
+            for (i, line) in text.lines().enumerate() {
+                let keyword = line.trim();
+                if !keyword.is_empty() && !keyword.starts_with('#') {
+                    rows.push(Row {
+                        number: i + 1,
+                        keyword: keyword.to_string(),
+                    });
+                }
+            }
+        }
+        FileFormat::Csv => {
+            let records = csv_records(text)?;
+            let header = records.first().and_then(|header| {
+                header
+                    .iter()
+                    .position(|field| field.trim().eq_ignore_ascii_case("keyword"))
+            });
+            let column = header.unwrap_or(0);
+            for (i, record) in records.iter().enumerate() {
+                if i == 0 && header.is_some() {
+                    continue;
+                }
+                let keyword = record.get(column).map_or("", |field| field.trim());
+                if !keyword.is_empty() {
+                    rows.push(Row {
+                        number: i + 1,
+                        keyword: keyword.to_string(),
+                    });
+                }
+            }
+        }
+        FileFormat::Jsonl => {
+            for (i, line) in text.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let keyword = match serde_json::from_str(line) {
+                    Ok(Value::String(keyword)) => keyword,
+                    Ok(Value::Object(mut object)) => match object.remove("keyword") {
+                        Some(Value::String(keyword)) => keyword,
+                        _ => return Err((i + 1, "expected a \"keyword\" string".to_string())),
+                    },
+                    Ok(_) => return Err((i + 1, "expected a string or an object".to_string())),
+                    Err(e) => return Err((i + 1, e.to_string())),
+                };
+                if !keyword.trim().is_empty() {
+                    rows.push(Row {
+                        number: i + 1,
+                        keyword: keyword.trim().to_string(),
+                    });
+                }
+            }
+        }
+    }
+    Ok(rows)
+}
+
+/// The records of CSV `text`, with quoted fields unquoted; a failure gives the record number
+fn csv_records(text: &str) -> Result<Vec<Vec<String>>, (usize, String)> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err((records.len() + 1, "unterminated quoted field".to_string()));
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// The results file, open for appending
+pub struct Results {
+    path: PathBuf,
+    file: File,
+    format: FileFormat,
+}
+
+impl Results {
+    /// Open the results at `path`, JSON Lines unless it ends in `.csv`. The completed rows of a
+    /// previous run are kept, unless `restart`, and returned; failed rows are dropped.
+    pub fn open(path: &Path, restart: bool) -> Result<(Self, Vec<RowResult>), BatchError> {
+        let io_error = |e| BatchError::Io(path.into(), e);
+        let format = match FileFormat::of(path) {
+            FileFormat::Csv => FileFormat::Csv,
+            _ => FileFormat::Jsonl,
+        };
+        let previous = match std::fs::read_to_string(path) {
+            Ok(text) if !restart => parse_results(&text, format)
+                .map_err(|(line, message)| BatchError::Parse(path.into(), line, message))?,
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(io_error(e)),
+            _ => Vec::new(),
+        };
+        let completed: Vec<RowResult> = previous.into_iter().filter(RowResult::completes).collect();
+
+        // Rewrite through a temporary file, so an interruption never loses completed rows
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).map_err(io_error)?;
+        }
+        let tmp = path.with_extension("tmp");
+        let mut text = Vec::new();
+        if format == FileFormat::Csv {
+            writeln!(text, "{}", CSV_HEADER.join(",")).map_err(io_error)?;
+        }
+        for result in &completed {
+            write_result(&mut text, result, format).map_err(io_error)?;
+        }
+        std::fs::write(&tmp, text).map_err(io_error)?;
+        std::fs::rename(&tmp, path).map_err(io_error)?;
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(path)
+            .map_err(io_error)?;
+        let results = Self {
+            path: path.into(),
+            file,
+            format,
+        };
+        Ok((results, completed))
+    }
+
+    /// Append `result`
+    pub fn write(&mut self, result: &RowResult) -> Result<(), BatchError> {
+        write_result(&mut self.file, result, self.format)
+            .and_then(|()| self.file.flush())
+            .map_err(|e| BatchError::Io(self.path.clone(), e))
+    }
+}
+
+fn write_result(out: &mut impl Write, result: &RowResult, format: FileFormat) -> io::Result<()> {
+    match format {
+        FileFormat::Csv => {
+            let fields = result.csv_fields();
+            let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+            writeln!(out, "{}", fields.join(","))
+        }
+        _ => writeln!(out, "{}", serde_json::to_string(result)?),
+    }
+}
+
+/// The results in `text`, skipping a last line cut short by an interruption
+fn parse_results(text: &str, format: FileFormat) -> Result<Vec<RowResult>, (usize, String)> {
+    match format {
+        FileFormat::Csv => {
+            let records = csv_records(text)?;
+            match records.first() {
+                None => return Ok(Vec::new()),
+                Some(header) if header.iter().eq(CSV_HEADER.iter()) => {}
+                Some(_) => return Err((1, "not a batch results file".to_string())),
+            }
+            let last = records.len() - 1;
+            let mut results = Vec::new();
+            for (i, record) in records.iter().enumerate().skip(1) {
+                match RowResult::from_csv(record) {
+                    Ok(result) => results.push(result),
+                    Err(_) if i == last => {}
+                    Err(message) => return Err((i + 1, message)),
+                }
+            }
+            Ok(results)
+        }
+        _ => {
+            let lines: Vec<&str> = text.lines().collect();
+            let mut results = Vec::new();
+            for (i, line) in lines.iter().enumerate() {
+                match serde_json::from_str(line) {
+                    Ok(result) => results.push(result),
+                    Err(_) if i + 1 == lines.len() || line.trim().is_empty() => {}
+                    Err(e) => return Err((i + 1, e.to_string())),
+                }
+            }
+            Ok(results)
+        }
+    }
+}
+
+/// Spaces out requests to at most a number per minute
+struct RateLimiter {
+    interval: Duration,
+    next: tokio::sync::Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn per_minute(requests: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(60) / requests.max(1),
+            next: tokio::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait for the next free slot
+    async fn wait(&self) {
+        let slot = {
+            let mut next = self.next.lock().await;
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        tokio::time::sleep_until(slot.into()).await;
+    }
+}
+
+/// A backend whose every request, including validation retries and follow-ups, waits for a
+/// slot from `limiter`
+struct RateLimited<'a> {
+    backend: &'a dyn PromptBackend,
+    limiter: RateLimiter,
+}
+
+#[async_trait]
+impl PromptBackend for RateLimited<'_> {
+    fn name(&self) -> &'static str {
+        self.backend.name()
+    }
+
+    fn model(&self) -> &str {
+        self.backend.model()
+    }
+
+    async fn chat(
+        &self,
+        system_instruction: &str,
+        messages: &[Message],
+    ) -> Result<String, BackendError> {
+        self.limiter.wait().await;
+        self.backend.chat(system_instruction, messages).await
+    }
+
+    async fn chat_stream(
+        &self,
+        system_instruction: &str,
+        messages: &[Message],
+        on_text: &mut OnText<'_>,
+    ) -> Result<String, BackendError> {
+        self.limiter.wait().await;
+        self.backend
+            .chat_stream(system_instruction, messages, on_text)
+            .await
+    }
+}
+
+/// Everything needed to run a batch
+pub struct Batch {
+    pub backend: Box<dyn PromptBackend>,
+    /// Previous prompts sent as context, as they were when the batch started
+    pub context: History,
+    /// History every generated prompt is recorded in
+    pub history: History,
+    pub retention: Retention,
+    pub settings: GenerationSettings,
+    pub tokenizer: ClipTokenizer,
+    pub max_tokens: Option<usize>,
+    pub concurrency: usize,
+    /// Most backend requests sent per minute, counting validation retries and follow-ups
+    pub rate_limit: Option<u32>,
+}
+
+/// Rows generated and failed by [`Batch::run`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Summary {
+    pub generated: usize,
+    pub failed: usize,
+}
+
+impl Batch {
+    /// Generate every row of `rows`, appending each outcome to `results` as soon as it is known
+    pub async fn run(
+        &mut self,
+        rows: Vec<Row>,
+        results: &mut Results,
+    ) -> Result<Summary, BatchError> {
+        let Batch {
+            backend,
+            context,
+            history,
+            retention,
+            settings,
+            tokenizer,
+            max_tokens,
+            concurrency,
+            rate_limit,
+        } = self;
+        let limited = rate_limit.map(|requests| RateLimited {
+            backend: backend.as_ref(),
+            limiter: RateLimiter::per_minute(requests),
+        });
+        let backend: &dyn PromptBackend = match &limited {
+            Some(limited) => limited,
+            None => backend.as_ref(),
+        };
+        let (context, settings) = (&*context, &*settings);
+        let total = rows.len();
+
+        let mut pending = stream::iter(rows)
+            .map(|row| async move {
+                let started = Instant::now();
+                let generated = generate::generate(backend, context, settings, &row.keyword).await;
+                (row, generated, started.elapsed())
+            })
+            .buffer_unordered((*concurrency).clamp(1, MAX_CONCURRENCY));
+
+        let mut summary = Summary::default();
+        while let Some((row, generated, latency)) = pending.next().await {
+            let done = summary.generated + summary.failed + 1;
+            let result = match generated {
+                Ok(generated) => {
+                    let fitted =
+                        generate::fit_token_budget(&generated.text, tokenizer, *max_tokens);
+                    let entry = generate::history_entry(
+                        backend,
+                        settings,
+                        &row.keyword,
+                        &fitted,
+                        &generated.negative_prompt,
+                        latency,
+                    );
+                    if let Err(e) = history.record(entry, retention) {
+                        eprintln!("Warning: could not save the prompt history: {}", e);
+                    }
+                    summary.generated += 1;
+                    eprintln!("[{}/{}] {}", done, total, row.keyword);
+                    RowResult {
+                        row: row.number,
+                        keyword: row.keyword,
+                        tokens: Some(fitted.tokens.total()),
+                        prompt: Some(settings.dialect.format(&fitted.text)),
+                        negative_prompt: Some(
+                            settings.dialect.format_negative(&generated.negative_prompt),
+                        ),
+                        error: None,
+                    }
+                }
+                Err(e) => {
+                    summary.failed += 1;
+                    eprintln!("[{}/{}] {} failed: {}", done, total, row.keyword, e);
+                    RowResult {
+                        row: row.number,
+                        keyword: row.keyword,
+                        prompt: None,
+                        negative_prompt: None,
+                        tokens: None,
+                        error: Some(e.to_string()),
+                    }
+                }
+            };
+            results.write(&result)?;
+        }
+        Ok(summary)
+    }
+}
+
+/// The rows of `rows` that have no completed result in `completed`
+pub fn remaining(rows: Vec<Row>, completed: &[RowResult]) -> Vec<Row> {
+    let done: HashSet<(usize, &str)> = completed
+        .iter()
+        .map(|result| (result.row, result.keyword.as_str()))
+        .collect();
+    rows.into_iter()
+        .filter(|row| !done.contains(&(row.number, row.keyword.as_str())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MockBackend;
+    use std::collections::HashMap;
+    use std::env;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("promptflow-batch-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn reads_keywords_in_every_format() {
+        let keywords = |text, format| {
+            parse_keywords(text, format)
+                .unwrap()
+                .into_iter()
+                .map(|row| (row.number, row.keyword))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            keywords("knight\n\n# ideas\n  sea witch  \n", FileFormat::Text),
+            vec![(1, "knight".to_string()), (4, "sea witch".to_string())]
+        );
+        assert_eq!(
+            keywords(
+                "id,keyword\r\n1,\"castle, at night\"\r\n2,\"say \"\"hi\"\"\"\n3,\n",
+                FileFormat::Csv
+            ),
+            vec![
+                (2, "castle, at night".to_string()),
+                (3, "say \"hi\"".to_string())
+            ]
+        );
+        assert_eq!(
+            keywords("knight,ignored\nwitch", FileFormat::Csv),
+            vec![(1, "knight".to_string()), (2, "witch".to_string())]
+        );
+        assert_eq!(
+            keywords(
+                "\"knight\"\n{\"keyword\": \"witch\", \"n\": 2}\n",
+                FileFormat::Jsonl
+            ),
+            vec![(1, "knight".to_string()), (2, "witch".to_string())]
+        );
+        assert_eq!(
+            parse_keywords("\"knight\"\n{\"n\": 2}\n", FileFormat::Jsonl),
+            Err((2, "expected a \"keyword\" string".to_string()))
+        );
+        assert!(parse_keywords("a,\"b\n", FileFormat::Csv).is_err());
+    }
+
+    #[test]
+    fn resumes_from_completed_rows() {
+        for name in ["results.jsonl", "results.csv"] {
+            let path = scratch_dir("resume").join(name);
+            let ok = RowResult {
+                row: 1,
+                keyword: "knight".to_string(),
+                prompt: Some("1boy, (armor:1.2)".to_string()),
+                negative_prompt: Some("lowres, \"bad\"".to_string()),
+                tokens: Some(9),
+                error: None,
+            };
+            let failed = RowResult {
+                row: 2,
+                keyword: "witch".to_string(),
+                prompt: None,
+                negative_prompt: None,
+                tokens: None,
+                error: Some("quota exceeded,\nretry later".to_string()),
+            };
+            let (mut results, completed) = Results::open(&path, false).unwrap();
+            assert!(completed.is_empty());
+            results.write(&ok).unwrap();
+            results.write(&failed).unwrap();
+            drop(results);
+            // An interrupted write leaves a partial last line
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            write!(file, "{{\"row\": 3, \"keyw").unwrap();
+            drop(file);
+
+            let (_, completed) = Results::open(&path, false).unwrap();
+            assert_eq!(completed, vec![ok.clone()], "{}", name);
+            let rows = vec![
+                Row {
+                    number: 1,
+                    keyword: "knight".to_string(),
+                },
+                Row {
+                    number: 2,
+                    keyword: "witch".to_string(),
+                },
+            ];
+            assert_eq!(remaining(rows.clone(), &completed), rows[1..].to_vec());
+            let (_, completed) = Results::open(&path, true).unwrap();
+            assert!(completed.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limit_spaces_every_request() {
+        let backend = MockBackend::new(HashMap::new());
+        let limited = RateLimited {
+            backend: &backend,
+            limiter: RateLimiter::per_minute(1200),
+        };
+        let started = Instant::now();
+        limited.generate("", "knight").await.unwrap();
+        limited.chat("", &[Message::user("witch")]).await.unwrap();
+        limited
+            .chat_stream("", &[Message::user("shrine")], &mut |_| {})
+            .await
+            .unwrap();
+        // Slots are 50 ms apart and the first is immediate
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(backend.calls().len(), 3);
+        assert_eq!((limited.name(), limited.model()), ("mock", backend.model()));
+    }
+
+    #[tokio::test]
+    async fn records_failed_rows_and_keeps_going() {
+        struct Failing(MockBackend);
+
+        #[async_trait::async_trait]
+        impl PromptBackend for Failing {
+            fn name(&self) -> &'static str {
+                self.0.name()
+            }
+
+            fn model(&self) -> &str {
+                self.0.model()
+            }
+
+            async fn chat(
+                &self,
+                system_instruction: &str,
+                messages: &[crate::backend::Message],
+            ) -> Result<String, crate::backend::BackendError> {
+                if messages[0].text == "fail" {
+                    return Err(crate::backend::BackendError::Request(
+                        "quota exceeded".into(),
+                    ));
+                }
+                self.0.chat(system_instruction, messages).await
+            }
+        }
+
+        let dir = scratch_dir("run");
+        let history = || History::load(dir.join("history.jsonl"));
+        let mut batch = Batch {
+            backend: Box::new(Failing(MockBackend::new(HashMap::new()))),
+            context: history(),
+            history: history(),
+            retention: Retention::default(),
+            settings: GenerationSettings::default(),
+            tokenizer: ClipTokenizer::estimating(),
+            max_tokens: None,
+            concurrency: 2,
+            rate_limit: Some(6000),
+        };
+        let rows = parse_keywords("knight\nfail\nwitch\n", FileFormat::Text).unwrap();
+        let path = dir.join("out.jsonl");
+        let (mut results, _) = Results::open(&path, false).unwrap();
+
+        let summary = batch.run(rows, &mut results).await.unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                generated: 2,
+                failed: 1
+            }
+        );
+        let (_, completed) = Results::open(&path, false).unwrap();
+        let mut keywords: Vec<&str> = completed.iter().map(|r| r.keyword.as_str()).collect();
+        keywords.sort();
+        assert_eq!(keywords, ["knight", "witch"]);
+        assert_eq!(history().entries().len(), 2);
+    }
+}
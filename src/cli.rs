@@ -0,0 +1,951 @@
+//! Command-line argument parsing.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::error::ErrorKind;
+use clap::{Args as ClapArgs, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+
+use crate::backend::{BackendKind, OllamaApi};
+use crate::batch;
+use crate::config::Layer;
+use crate::context::ContextStrategy;
+use crate::negative::NegativeMode;
+use crate::output::{ExportFormat, OutputFormat};
+use crate::preset;
+use crate::target::{self, Target};
+use crate::validate::{self, ValidationMode};
+use crate::variants;
+
+/// Address `serve` listens on unless `--listen` is given
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:8787";
+
+/// Generate detailed anime-style image prompts from simple keywords.
+///
+/// `PromptFlow "keyword"` is shorthand for `PromptFlow generate "keyword"`.
+#[derive(Debug, Parser)]
+#[command(
+    name = "PromptFlow",
+    version,
+    args_conflicts_with_subcommands = true,
+    arg_required_else_help = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    generate: GenerateArgs,
+}
+
+/// What the command line asks for
+#[derive(Debug, Clone, PartialEq, Subcommand)]
+pub enum Command {
+    /// Generate a prompt for a keyword
+    Generate(GenerateArgs),
+    /// List, search, export, rerun, pin or delete the recorded generations
+    History {
+        /// Work on this session's history instead of the active one
+        #[arg(long, value_name = "NAME")]
+        session: Option<String>,
+        #[command(subcommand)]
+        command: HistoryCommand,
+    },
+    /// Manage named sessions, each with its own history and defaults
+    Session {
+        #[command(subcommand)]
+        command: SessionCommand,
+    },
+    /// Inspect the layered configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Manage stored API keys
+    Key {
+        #[command(subcommand)]
+        command: KeyCommand,
+    },
+    /// Manage the style presets the system instruction and defaults are taken from
+    Presets {
+        #[command(subcommand)]
+        command: PresetsCommand,
+    },
+    /// Generate prompts for every keyword in a text, CSV or JSON Lines file
+    Batch(BatchArgs),
+    /// Generate and refine prompts interactively
+    Repl(ReplArgs),
+    /// Browse, edit and compare prompts in a full-screen terminal interface
+    Tui(TuiArgs),
+    /// Serve prompt generation as a JSON API over HTTP
+    Serve(ServeArgs),
+    /// Print a shell completion script
+    Completions {
+        /// Shell to generate completions for
+        shell: Shell,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Subcommand)]
+pub enum HistoryCommand {
+    /// Print recorded generations, oldest first
+    List {
+        /// Only print the most recent N entries
+        #[arg(long, short = 'n', value_name = "N")]
+        limit: Option<usize>,
+    },
+    /// Print the entries whose keyword or prompt contains every word of QUERY
+    Search {
+        #[arg(required = true, value_name = "QUERY")]
+        words: Vec<String>,
+    },
+    /// Print every field of an entry
+    Show {
+        /// Entry id, as printed by `history list`
+        id: u64,
+    },
+    /// Generate a fresh prompt for an entry's keyword with the same backend, model, preset and
+    /// target
+    Rerun(Box<RerunArgs>),
+    /// Pin entries, so the `pinned` context strategy sends them to the model
+    Pin {
+        #[arg(required = true, value_name = "ID")]
+        ids: Vec<u64>,
+    },
+    /// Unpin entries
+    Unpin {
+        #[arg(required = true, value_name = "ID")]
+        ids: Vec<u64>,
+    },
+    /// Delete entries
+    Rm {
+        /// Entry ids, as printed by `history list`
+        #[arg(required = true, value_name = "ID")]
+        ids: Vec<u64>,
+    },
+    /// Delete the history
+    Clear,
+    /// Write the whole history to standard output
+    Export {
+        /// Output format
+        #[arg(
+            long,
+            short,
+            value_name = "FORMAT",
+            ignore_case = true,
+            default_value = "json",
+            value_parser = export_format_parser()
+        )]
+        format: ExportFormat,
+    },
+}
+
+/// Options of `history rerun`
+#[derive(Debug, Clone, PartialEq, ClapArgs)]
+pub struct RerunArgs {
+    /// Entry id, as printed by `history list`
+    pub id: u64,
+    /// Print the per-category coverage and per-segment CLIP token counts
+    #[arg(long)]
+    pub explain: bool,
+    /// How the result is printed: text, json, yaml, env or raw
+    #[arg(
+        long,
+        short,
+        value_name = "FORMAT",
+        ignore_case = true,
+        default_value = "text",
+        value_parser = output_format_parser()
+    )]
+    pub format: OutputFormat,
+    /// Wait for the whole response instead of printing it as it streams in
+    #[arg(long)]
+    pub no_stream: bool,
+    /// Overrides applied on top of the entry's backend and model
+    #[command(flatten)]
+    pub args: Args,
+}
+
+impl RerunArgs {
+    /// Generation options for `keyword`, keeping the explicit overrides
+    pub fn into_generate(self, keyword: String) -> GenerateArgs {
+        GenerateArgs {
+            keyword,
+            explain: self.explain,
+            format: self.format,
+            count: 1,
+            no_stream: self.no_stream,
+            args: self.args,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Subcommand)]
+pub enum SessionCommand {
+    /// Create a session
+    New {
+        /// Session name: letters, digits, '-', '_' and '.'
+        name: String,
+        /// Built-in preset the session's system instruction is taken from
+        #[arg(long, value_name = "NAME")]
+        preset: Option<String>,
+        /// Negative prompt used for the session's generations
+        #[arg(long, value_name = "TEXT")]
+        negative_prompt: Option<String>,
+        /// Character description to keep consistent across prompts; can be repeated
+        #[arg(long = "descriptor", short, value_name = "TEXT")]
+        descriptors: Vec<String>,
+        /// Make the new session the active one
+        #[arg(long)]
+        switch: bool,
+    },
+    /// List sessions; the active one is marked with '*'
+    List {
+        /// Include archived sessions
+        #[arg(long)]
+        all: bool,
+    },
+    /// Make a session the active one; without a name, go back to the global history
+    Switch {
+        /// Session name
+        name: Option<String>,
+    },
+    /// Archive a session: it is hidden from `session list` and can't be switched to
+    Archive {
+        /// Session name
+        name: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Subcommand)]
+pub enum ConfigCommand {
+    /// Print every effective value and the layer it came from; flags are applied on top
+    Show(Args),
+}
+
+#[derive(Debug, Clone, PartialEq, Subcommand)]
+pub enum KeyCommand {
+    /// Store a key, read from the terminal without echo or from standard input
+    Set {
+        #[command(flatten)]
+        entry: KeyEntry,
+        /// Encrypt the key store with a passphrase
+        #[arg(long)]
+        encrypt: bool,
+    },
+    /// Print stored keys, masked; without a backend every entry is listed
+    Show {
+        #[command(flatten)]
+        entry: KeyEntry,
+        /// Print the full key instead of a masked one
+        #[arg(long, requires = "backend")]
+        reveal: bool,
+    },
+    /// Delete a stored key
+    Rm {
+        #[command(flatten)]
+        entry: KeyEntry,
+    },
+    /// Replace a stored key with a new one
+    Rotate {
+        #[command(flatten)]
+        entry: KeyEntry,
+    },
+}
+
+/// Which key store entry a `key` command works on
+#[derive(Debug, Clone, Default, PartialEq, ClapArgs)]
+pub struct KeyEntry {
+    /// Backend the key is for; defaults to the configured backend
+    #[arg(value_name = "BACKEND", ignore_case = true, value_parser = backend_parser())]
+    pub backend: Option<BackendKind>,
+    /// Key store profile; defaults to the configured profile
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+}
+
+impl KeyEntry {
+    /// The options that override configuration values
+    pub fn config_layer(&self) -> Layer {
+        Layer {
+            profile: self.profile.clone(),
+            backend: self.backend,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Subcommand)]
+pub enum PresetsCommand {
+    /// List the built-in and user presets
+    List,
+    /// Print a preset's full system instruction and negative prompt
+    Show {
+        /// Preset name
+        name: String,
+    },
+    /// Create a user preset from a copy of another one and print its path
+    New {
+        /// Preset name: letters, digits, '-', '_' and '.'
+        name: String,
+        /// Preset to copy
+        #[arg(long, value_name = "PRESET", default_value = preset::DEFAULT_PRESET)]
+        from: String,
+        /// Write a Markdown file with TOML front matter instead of a TOML file
+        #[arg(long)]
+        markdown: bool,
+    },
+}
+
+/// Options of the `generate` command
+#[derive(Debug, Clone, Default, PartialEq, ClapArgs)]
+pub struct GenerateArgs {
+    /// Keyword to generate a prompt for; several words are joined with spaces
+    #[arg(value_name = "KEYWORD")]
+    words: Vec<String>,
+    /// Keyword to generate a prompt for, as an alternative to the positional form
+    #[arg(long, short, value_name = "PROMPT", conflicts_with = "words")]
+    prompt: Option<String>,
+    /// Keyword to generate a prompt for, already trimmed and non-empty
+    #[arg(skip)]
+    pub keyword: String,
+    /// Print the per-category coverage and per-segment CLIP token counts
+    #[arg(long)]
+    pub explain: bool,
+    /// How the result is printed: text, json, yaml, env or raw
+    #[arg(
+        long,
+        short,
+        value_name = "FORMAT",
+        ignore_case = true,
+        default_value = "text",
+        value_parser = output_format_parser()
+    )]
+    pub format: OutputFormat,
+    /// Number of distinct variants to generate
+    #[arg(
+        long,
+        short = 'n',
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..=i64::from(variants::MAX_COUNT))
+    )]
+    pub count: u32,
+    /// How different the variants are, from 0 to 1; also scales the temperature
+    #[arg(long, value_name = "D", value_parser = variants::parse_diversity)]
+    pub diversity: Option<f32>,
+    /// Wait for the whole response instead of printing it as it streams in
+    #[arg(long)]
+    pub no_stream: bool,
+    #[command(flatten)]
+    pub args: Args,
+}
+
+/// Options of the `batch` command
+#[derive(Debug, Clone, PartialEq, ClapArgs)]
+pub struct BatchArgs {
+    /// Keywords, one per line (.txt), in a `keyword` column (.csv) or as JSON Lines (.jsonl)
+    #[arg(value_name = "FILE")]
+    pub input: PathBuf,
+    /// Results file, CSV if it ends in .csv and JSON Lines otherwise; completed rows already in
+    /// it are skipped
+    #[arg(long, short, value_name = "FILE")]
+    pub output: PathBuf,
+    /// Keywords generated at the same time
+    #[arg(
+        long,
+        short = 'j',
+        value_name = "N",
+        default_value_t = batch::DEFAULT_CONCURRENCY,
+        value_parser = clap::value_parser!(u64)
+            .range(1..=batch::MAX_CONCURRENCY as u64)
+            .map(|n| n as usize)
+    )]
+    pub concurrency: usize,
+    /// Most backend requests sent per minute, counting validation retries and follow-ups
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub rate_limit: Option<u32>,
+    /// Generate every row again instead of resuming from the results file
+    #[arg(long)]
+    pub restart: bool,
+    #[command(flatten)]
+    pub args: Args,
+}
+
+/// Options of the `repl` command
+#[derive(Debug, Clone, PartialEq, ClapArgs)]
+pub struct ReplArgs {
+    #[command(flatten)]
+    pub args: Args,
+}
+
+/// Options of the `tui` command
+#[derive(Debug, Clone, PartialEq, ClapArgs)]
+pub struct TuiArgs {
+    #[command(flatten)]
+    pub args: Args,
+}
+
+/// Options of the `serve` command
+#[derive(Debug, Clone, PartialEq, ClapArgs)]
+pub struct ServeArgs {
+    /// Address to listen on
+    #[arg(long, value_name = "ADDR", default_value = DEFAULT_LISTEN)]
+    pub listen: SocketAddr,
+    #[command(flatten)]
+    pub args: Args,
+}
+
+/// Options that override configuration values, shared by every command that generates
+#[derive(Debug, Clone, Default, PartialEq, ClapArgs)]
+pub struct Args {
+    /// API key for backends that need one
+    #[arg(long, short, value_name = "KEY")]
+    pub key: Option<String>,
+    /// Key store profile API keys are looked up in
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+    /// Session whose history, preset, descriptors and negative prompt are used
+    #[arg(long, value_name = "NAME")]
+    pub session: Option<String>,
+    /// Style preset the system instruction, negative prompt and banned terms come from
+    #[arg(long, value_name = "NAME")]
+    pub preset: Option<String>,
+    /// Image model the prompt is written for
+    #[arg(long, value_name = "MODEL", ignore_case = true, value_parser = target_parser())]
+    pub target: Option<Target>,
+    /// Aspect ratio appended as --ar (Midjourney only)
+    #[arg(long, visible_alias = "ar", value_name = "W:H", value_parser = target::parse_aspect_ratio)]
+    pub aspect_ratio: Option<(u32, u32)>,
+    /// Stylize value appended as --stylize, 0 to 1000 (Midjourney only)
+    #[arg(long, value_name = "N", value_parser = target::parse_stylize)]
+    pub stylize: Option<u32>,
+    /// LLM backend used for generation
+    #[arg(long, short, value_name = "BACKEND", ignore_case = true, value_parser = backend_parser())]
+    pub backend: Option<BackendKind>,
+    /// Model override; each backend has its own default
+    #[arg(long, short, value_name = "MODEL")]
+    pub model: Option<String>,
+    /// Server URL for self-hosted backends, e.g. http://localhost:8080/v1
+    #[arg(long, value_name = "URL")]
+    pub base_url: Option<String>,
+    /// Sampling temperature passed to the backend
+    #[arg(long, value_name = "T")]
+    pub temperature: Option<f32>,
+    /// Context window size (Ollama only)
+    #[arg(long, value_name = "N")]
+    pub num_ctx: Option<u32>,
+    /// Ollama endpoint to generate with
+    #[arg(long, value_name = "API", ignore_case = true, value_parser = ollama_api_parser())]
+    pub ollama_api: Option<OllamaApi>,
+    /// What to do with a prompt that breaks the rules
+    #[arg(
+        long = "validate",
+        value_name = "MODE",
+        ignore_case = true,
+        value_parser = validation_parser()
+    )]
+    pub validation: Option<ValidationMode>,
+    /// Number of correction requests in retry mode
+    #[arg(long, value_name = "N")]
+    pub max_retries: Option<u32>,
+    /// Allowed range for (keyword:factor) weights
+    #[arg(long, value_name = "MIN:MAX", value_parser = validate::parse_weight_range)]
+    pub weight_range: Option<(f32, f32)>,
+    /// Ask the model to fill in missing component categories
+    #[arg(long)]
+    pub fill_missing: bool,
+    /// How the negative prompt is composed: fixed, rules or llm
+    #[arg(
+        long,
+        value_name = "MODE",
+        ignore_case = true,
+        value_parser = negative_mode_parser()
+    )]
+    pub negative_mode: Option<NegativeMode>,
+    /// CLIP token budget; lowest-weighted tokens are trimmed to fit
+    #[arg(long, value_name = "N")]
+    pub max_tokens: Option<usize>,
+    /// Previous prompts sent as context: recent[:N], similar[:N], pinned[:N] or none
+    #[arg(long, value_name = "STRATEGY", value_parser = ContextStrategy::from_str)]
+    pub context: Option<ContextStrategy>,
+}
+
+fn backend_parser() -> impl TypedValueParser<Value = BackendKind> {
+    PossibleValuesParser::new(BackendKind::ALL.iter().map(|kind| kind.name())).map(|name| {
+        name.parse::<BackendKind>()
+            .expect("possible values are backend names")
+    })
+}
+
+fn target_parser() -> impl TypedValueParser<Value = Target> {
+    PossibleValuesParser::new(Target::ALL.map(Target::name)).map(|name| {
+        name.parse::<Target>()
+            .expect("possible values are target names")
+    })
+}
+
+fn ollama_api_parser() -> impl TypedValueParser<Value = OllamaApi> {
+    PossibleValuesParser::new(["chat", "generate"]).map(|name| {
+        name.parse::<OllamaApi>()
+            .expect("possible values are Ollama APIs")
+    })
+}
+
+fn export_format_parser() -> impl TypedValueParser<Value = ExportFormat> {
+    PossibleValuesParser::new(ExportFormat::ALL.map(ExportFormat::name)).map(|name| {
+        name.parse::<ExportFormat>()
+            .expect("possible values are export formats")
+    })
+}
+
+fn output_format_parser() -> impl TypedValueParser<Value = OutputFormat> {
+    PossibleValuesParser::new(OutputFormat::ALL.map(OutputFormat::name)).map(|name| {
+        name.parse::<OutputFormat>()
+            .expect("possible values are output formats")
+    })
+}
+
+fn validation_parser() -> impl TypedValueParser<Value = ValidationMode> {
+    PossibleValuesParser::new(["off", "fix", "retry"]).map(|name| {
+        name.parse::<ValidationMode>()
+            .expect("possible values are validation modes")
+    })
+}
+
+fn negative_mode_parser() -> impl TypedValueParser<Value = NegativeMode> {
+    PossibleValuesParser::new(["fixed", "rules", "llm"]).map(|name| {
+        name.parse::<NegativeMode>()
+            .expect("possible values are negative prompt modes")
+    })
+}
+
+impl Args {
+    /// The options that override configuration values
+    pub fn config_layer(&self) -> Layer {
+        Layer {
+            profile: self.profile.clone(),
+            session: self.session.clone(),
+            preset: self.preset.clone(),
+            target: self.target,
+            aspect_ratio: self.aspect_ratio,
+            stylize: self.stylize,
+            backend: self.backend,
+            model: self.model.clone(),
+            base_url: self.base_url.clone(),
+            temperature: self.temperature,
+            num_ctx: self.num_ctx,
+            ollama_api: self.ollama_api,
+            validate: self.validation,
+            max_retries: self.max_retries,
+            weight_range: self.weight_range,
+            fill_missing: self.fill_missing.then_some(true),
+            max_tokens: self.max_tokens,
+            context: self.context,
+            negative_mode: self.negative_mode,
+            ..Default::default()
+        }
+    }
+}
+
+/// Parse `args` (including the program name at index 0).
+///
+/// Errors carry clap's formatted message; `--help` and `--version` are returned as errors too,
+/// so callers should finish with [`clap::Error::exit`].
+pub fn parse_args(args: &[String]) -> Result<Command, clap::Error> {
+    let cli = Cli::try_parse_from(args)?;
+    let mut command = cli.command.unwrap_or(Command::Generate(cli.generate));
+    if let Command::Generate(generate) = &mut command {
+        let keyword = match &generate.prompt {
+            Some(prompt) => prompt.trim().to_string(),
+            None => generate.words.join(" ").trim().to_string(),
+        };
+        if keyword.is_empty() {
+            return Err(Cli::command().error(
+                ErrorKind::MissingRequiredArgument,
+                "Please provide a non-empty keyword, e.g. PromptFlow \"anime knight\"",
+            ));
+        }
+        generate.keyword = keyword;
+    }
+    Ok(command)
+}
+
+/// Write the completion script for `shell` to `out`
+pub fn write_completions(shell: Shell, out: &mut impl std::io::Write) {
+    clap_complete::generate(shell, &mut Cli::command(), "PromptFlow", out);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> Result<Command, clap::Error> {
+        let args: Vec<String> = std::iter::once("PromptFlow")
+            .chain(args.iter().copied())
+            .map(String::from)
+            .collect();
+        parse_args(&args)
+    }
+
+    fn parse(args: &[&str]) -> Result<GenerateArgs, clap::Error> {
+        match command(args)? {
+            Command::Generate(args) => Ok(args),
+            other => panic!("expected a generate command, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cli_definition_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn positional_keyword() {
+        let args = parse(&["  anime knight  "]).unwrap();
+        assert_eq!(args.keyword, "anime knight");
+        assert_eq!(args.args.backend, None);
+        assert_eq!(args.args.key, None);
+        assert_eq!(args.args.config_layer(), Layer::default());
+    }
+
+    #[test]
+    fn shorthand_matches_generate_subcommand() {
+        let shorthand = parse(&["-b", "mock", "anime", "knight"]).unwrap();
+        assert_eq!(shorthand.keyword, "anime knight");
+        assert_eq!(
+            parse(&["generate", "-b", "mock", "anime knight"])
+                .unwrap()
+                .keyword,
+            shorthand.keyword
+        );
+        assert_eq!(
+            parse(&["generate", "-p", "history"]).unwrap().keyword,
+            "history"
+        );
+    }
+
+    #[test]
+    fn named_flags() {
+        let args = parse(&[
+            "-k",
+            "secret",
+            "--backend",
+            "Ollama",
+            "-m",
+            "qwen",
+            "--base-url",
+            "http://box:11434",
+            "--temperature",
+            "0.7",
+            "--num-ctx",
+            "4096",
+            "--ollama-api",
+            "generate",
+            "--validate",
+            "retry",
+            "--max-retries",
+            "3",
+            "--weight-range",
+            "0.5:1.5",
+            "--explain",
+            "-f",
+            "yaml",
+            "--fill-missing",
+            "--max-tokens",
+            "150",
+            "--context",
+            "similar:3",
+            "--negative-mode",
+            "LLM",
+            "--target",
+            "MidJourney",
+            "--ar",
+            "16:9",
+            "--stylize",
+            "250",
+            "-p",
+            "cyberpunk samurai",
+        ])
+        .unwrap();
+        let options = &args.args;
+        assert_eq!(options.key.as_deref(), Some("secret"));
+        assert_eq!(options.backend, Some(BackendKind::Ollama));
+        assert_eq!(options.model.as_deref(), Some("qwen"));
+        assert_eq!(options.base_url.as_deref(), Some("http://box:11434"));
+        assert_eq!(options.temperature, Some(0.7));
+        assert_eq!(options.num_ctx, Some(4096));
+        assert_eq!(options.ollama_api, Some(OllamaApi::Generate));
+        assert_eq!(options.validation, Some(ValidationMode::Retry));
+        assert_eq!(options.max_retries, Some(3));
+        assert_eq!(options.weight_range, Some((0.5, 1.5)));
+        assert!(args.explain);
+        assert_eq!(args.format, OutputFormat::Yaml);
+        assert!(options.fill_missing);
+        assert_eq!(options.max_tokens, Some(150));
+        assert_eq!(options.negative_mode, Some(NegativeMode::Llm));
+        assert_eq!(options.target, Some(Target::Midjourney));
+        assert_eq!(options.aspect_ratio, Some((16, 9)));
+        assert_eq!(options.stylize, Some(250));
+        assert_eq!(args.keyword, "cyberpunk samurai");
+
+        let layer = options.config_layer();
+        assert_eq!(layer.backend, Some(BackendKind::Ollama));
+        assert_eq!(layer.fill_missing, Some(true));
+        assert_eq!(layer.context, Some("similar:3".parse().unwrap()));
+        assert_eq!(layer.history_depth, None);
+    }
+
+    #[test]
+    fn subcommands() {
+        let Command::Config {
+            command: ConfigCommand::Show(args),
+        } = command(&["config", "show", "-m", "qwen"]).unwrap()
+        else {
+            panic!("expected config show");
+        };
+        assert_eq!(args.model.as_deref(), Some("qwen"));
+        assert_eq!(
+            command(&["history", "list", "-n", "3"]).unwrap(),
+            Command::History {
+                session: None,
+                command: HistoryCommand::List { limit: Some(3) }
+            }
+        );
+        assert_eq!(
+            command(&["history", "export", "--format", "CSV"]).unwrap(),
+            Command::History {
+                session: None,
+                command: HistoryCommand::Export {
+                    format: ExportFormat::Csv
+                }
+            }
+        );
+        assert!(command(&["history", "export", "-f", "xml"]).is_err());
+        assert!(command(&["history", "rm"]).is_err());
+        let Command::History {
+            command: HistoryCommand::Rerun(rerun),
+            ..
+        } = command(&["history", "rerun", "4", "-m", "qwen2.5"]).unwrap()
+        else {
+            panic!("expected history rerun");
+        };
+        assert_eq!(rerun.id, 4);
+        let generate = rerun.into_generate("knight".to_string());
+        assert_eq!(generate.keyword, "knight");
+        assert_eq!(generate.args.model.as_deref(), Some("qwen2.5"));
+        let Command::Key {
+            command: KeyCommand::Show { entry, reveal },
+        } = command(&["key", "show", "OpenAI", "--profile", "work", "--reveal"]).unwrap()
+        else {
+            panic!("expected key show");
+        };
+        assert_eq!(entry.backend, Some(BackendKind::OpenAi));
+        assert_eq!(entry.profile.as_deref(), Some("work"));
+        assert!(reveal);
+        assert!(command(&["key", "show", "--reveal"]).is_err());
+
+        assert_eq!(
+            command(&["history", "--session", "azure", "clear"]).unwrap(),
+            Command::History {
+                session: Some("azure".to_string()),
+                command: HistoryCommand::Clear
+            }
+        );
+        assert_eq!(
+            command(&[
+                "session",
+                "new",
+                "azure",
+                "--preset",
+                "anime",
+                "-d",
+                "Azure: blue hair",
+                "-d",
+                "Rook: black cat",
+                "--switch",
+            ])
+            .unwrap(),
+            Command::Session {
+                command: SessionCommand::New {
+                    name: "azure".to_string(),
+                    preset: Some("anime".to_string()),
+                    negative_prompt: None,
+                    descriptors: vec![
+                        "Azure: blue hair".to_string(),
+                        "Rook: black cat".to_string()
+                    ],
+                    switch: true,
+                }
+            }
+        );
+        assert_eq!(
+            command(&["session", "switch"]).unwrap(),
+            Command::Session {
+                command: SessionCommand::Switch { name: None }
+            }
+        );
+        assert_eq!(
+            parse(&["--session", "azure", "knight"])
+                .unwrap()
+                .args
+                .config_layer()
+                .session
+                .as_deref(),
+            Some("azure")
+        );
+
+        assert_eq!(
+            command(&["presets", "new", "ink", "--markdown"]).unwrap(),
+            Command::Presets {
+                command: PresetsCommand::New {
+                    name: "ink".to_string(),
+                    from: preset::DEFAULT_PRESET.to_string(),
+                    markdown: true,
+                }
+            }
+        );
+        assert_eq!(
+            parse(&["--preset", "chibi", "cat"])
+                .unwrap()
+                .args
+                .config_layer()
+                .preset
+                .as_deref(),
+            Some("chibi")
+        );
+
+        let Command::Serve(serve) = command(&["serve", "-b", "mock"]).unwrap() else {
+            panic!("expected serve");
+        };
+        assert_eq!(serve.listen, DEFAULT_LISTEN.parse().unwrap());
+        assert_eq!(serve.args.backend, Some(BackendKind::Mock));
+    }
+
+    #[test]
+    fn missing_arguments_show_help() {
+        let err = command(&[]).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            ErrorKind::DisplayHelpOnMissingArgumentOrSubcommand
+        );
+        assert!(err.to_string().contains("Usage: PromptFlow"));
+    }
+
+    #[test]
+    fn flag_without_value() {
+        let err = command(&["knight", "--key"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidValue);
+        assert!(err.to_string().contains("--key <KEY>"), "{}", err);
+    }
+
+    #[test]
+    fn unknown_flags_are_rejected() {
+        let err = command(&["--modle", "qwen", "knight"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnknownArgument);
+        assert!(err.to_string().contains("--model"), "{}", err);
+    }
+
+    #[test]
+    fn invalid_values() {
+        let err = command(&["x", "--backend", "nope"]).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("[possible values: gemini, openai, ollama, mock]")
+        );
+        assert_eq!(
+            command(&["x", "--temperature", "hot"]).unwrap_err().kind(),
+            ErrorKind::ValueValidation
+        );
+        assert_eq!(
+            command(&["x", "--num-ctx", "-1"]).unwrap_err().kind(),
+            ErrorKind::UnknownArgument
+        );
+        assert!(
+            command(&["x", "--weight-range", "2:1"])
+                .unwrap_err()
+                .to_string()
+                .contains("MIN:MAX")
+        );
+        assert!(
+            command(&["x", "--target", "sd3"])
+                .unwrap_err()
+                .to_string()
+                .contains(
+                    "[possible values: sd15, sdxl, pony, illustrious, flux, midjourney, dalle]"
+                )
+        );
+        assert!(
+            command(&["x", "--stylize", "5000"])
+                .unwrap_err()
+                .to_string()
+                .contains("expected 0 to 1000")
+        );
+        assert_eq!(
+            command(&["x", "--count", "0"]).unwrap_err().kind(),
+            ErrorKind::ValueValidation
+        );
+        assert!(
+            command(&["x", "--diversity", "1.5"])
+                .unwrap_err()
+                .to_string()
+                .contains("from 0 to 1")
+        );
+    }
+
+    #[test]
+    fn batch_options() {
+        let Command::Batch(batch) = command(&[
+            "batch",
+            "ideas.txt",
+            "-o",
+            "out.jsonl",
+            "-j",
+            "8",
+            "--backend",
+            "mock",
+        ])
+        .unwrap() else {
+            panic!("expected a batch command");
+        };
+        assert_eq!(batch.input, PathBuf::from("ideas.txt"));
+        assert_eq!(batch.concurrency, 8);
+        assert_eq!(batch.rate_limit, None);
+        assert_eq!(batch.args.backend, Some(BackendKind::Mock));
+        assert!(command(&["batch", "ideas.txt"]).is_err());
+        assert_eq!(
+            command(&["batch", "ideas.txt", "-o", "out.csv", "-j", "0"])
+                .unwrap_err()
+                .kind(),
+            ErrorKind::ValueValidation
+        );
+    }
+
+    #[test]
+    fn variant_options() {
+        let args = parse(&["x", "-n", "4", "--diversity", "0.8"]).unwrap();
+        assert_eq!((args.count, args.diversity), (4, Some(0.8)));
+        let args = parse(&["x"]).unwrap();
+        assert_eq!((args.count, args.diversity), (1, None));
+        assert!(!args.no_stream);
+        assert!(parse(&["--no-stream", "x"]).unwrap().no_stream);
+    }
+
+    #[test]
+    fn blank_prompt_is_rejected() {
+        let err = command(&["-k", "secret", "   "]).unwrap_err();
+        assert!(err.to_string().contains("non-empty keyword"));
+    }
+
+    #[test]
+    fn completions_name_the_binary() {
+        let mut out = Vec::new();
+        write_completions(Shell::Bash, &mut out);
+        let script = String::from_utf8(out).unwrap();
+        assert!(script.contains("_PromptFlow()"));
+        assert!(script.contains("--max-tokens"));
+    }
+}
@@ -0,0 +1,385 @@
+//! CLIP token counting and 75-token chunk analysis.
+//!
+//! Stable Diffusion front-ends tokenize prompts with CLIP's byte-level BPE and feed them to the
+//! text encoder in chunks of [`CHUNK_SIZE`] tokens, padding every `BREAK` segment to a chunk
+//! boundary. A concept whose tokens straddle a boundary is encoded in two halves and loses
+//! coherence.
+//!
+//! The tokenizer uses OpenAI's `bpe_simple_vocab_16e6.txt.gz` merges, which ship in `assets/`
+//! and are embedded in the binary.
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use crate::parser::{Prompt, Token};
+
+/// Tokens per CLIP chunk, excluding the start/end markers
+pub const CHUNK_SIZE: usize = 75;
+
+/// OpenAI's CLIP merges, gzip-compressed; `build.rs` checks they are there
+const VOCAB: &[u8] = include_bytes!("../assets/bpe_simple_vocab_16e6.txt.gz");
+
+/// Number of merges CLIP actually uses from the file (49152 - 256 - 2)
+const MERGE_COUNT: usize = 48894;
+
+/// CLIP tokenizer, exact with the BPE merges and estimating without them
+pub struct ClipTokenizer {
+    ranks: Option<HashMap<(String, String), usize>>,
+    byte_encoder: Vec<char>,
+}
+
+impl ClipTokenizer {
+    /// Tokenizer with the merges embedded in the binary
+    pub fn load() -> Self {
+        Self::from_gzip(VOCAB).expect("the embedded CLIP merges are valid")
+    }
+
+    fn from_gzip(bytes: &[u8]) -> std::io::Result<Self> {
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(bytes).read_to_string(&mut text)?;
+        Ok(Self::from_merges(&text))
+    }
+
+    /// Build a tokenizer from the contents of a merges file (a version header line, then one
+    /// space-separated pair per line in rank order)
+    pub fn from_merges(text: &str) -> Self {
+        let ranks = text
+            .lines()
+            .skip(1)
+            .take(MERGE_COUNT)
+            .filter_map(|line| {
+                let (a, b) = line.split_once(' ')?;
+                Some((a.to_string(), b.to_string()))
+            })
+            .enumerate()
+            .map(|(rank, pair)| (pair, rank))
+            .collect();
+        Self {
+            ranks: Some(ranks),
+            byte_encoder: bytes_to_unicode(),
+        }
+    }
+
+    /// Tokenizer that estimates counts without merges, for tests that don't depend on them
+    #[cfg(test)]
+    pub fn estimating() -> Self {
+        Self {
+            ranks: None,
+            byte_encoder: bytes_to_unicode(),
+        }
+    }
+
+    /// Whether counts come from the real BPE merges rather than an estimate
+    pub fn is_exact(&self) -> bool {
+        self.ranks.is_some()
+    }
+
+    /// Number of CLIP tokens in `text`, excluding start/end markers
+    pub fn count(&self, text: &str) -> usize {
+        let text = text.to_lowercase();
+        pre_tokenize(&text)
+            .into_iter()
+            .map(|word| match &self.ranks {
+                Some(ranks) => {
+                    let encoded: String = word
+                        .bytes()
+                        .map(|b| self.byte_encoder[b as usize])
+                        .collect();
+                    bpe_len(&encoded, ranks)
+                }
+                None => estimate_len(word),
+            })
+            .sum()
+    }
+}
+
+/// CLIP's reversible byte-to-printable-character table
+fn bytes_to_unicode() -> Vec<char> {
+    let printable = |b: u32| {
+        (u32::from('!')..=u32::from('~')).contains(&b)
+            || (u32::from('¡')..=u32::from('¬')).contains(&b)
+            || (u32::from('®')..=u32::from('ÿ')).contains(&b)
+    };
+    let mut next = 256;
+    (0..256u32)
+        .map(|b| {
+            if printable(b) {
+                char::from_u32(b).unwrap()
+            } else {
+                let c = char::from_u32(next).unwrap();
+                next += 1;
+                c
+            }
+        })
+        .collect()
+}
+
+/// Split lowercased text like CLIP's regex: contractions, letter runs, single digits, and
+/// runs of other non-space characters
+fn pre_tokenize(text: &str) -> Vec<&str> {
+    const CONTRACTIONS: [&str; 7] = ["'s", "'t", "'re", "'ve", "'m", "'ll", "'d"];
+    let mut words = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+            continue;
+        } else if let Some(contraction) = CONTRACTIONS.iter().find(|p| rest.starts_with(*p)) {
+            contraction.len()
+        } else if c.is_alphabetic() {
+            run_len(rest, char::is_alphabetic)
+        } else if c.is_numeric() {
+            c.len_utf8()
+        } else {
+            run_len(rest, |c| {
+                !c.is_whitespace() && !c.is_alphabetic() && !c.is_numeric()
+            })
+        };
+        words.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+    words
+}
+
+fn run_len(text: &str, pred: impl Fn(char) -> bool) -> usize {
+    text.char_indices()
+        .find(|&(_, c)| !pred(c))
+        .map_or(text.len(), |(i, _)| i)
+}
+
+/// Number of BPE symbols `word` (already byte-encoded) merges down to
+fn bpe_len(word: &str, ranks: &HashMap<(String, String), usize>) -> usize {
+    let mut symbols: Vec<String> = word.chars().map(String::from).collect();
+    if let Some(last) = symbols.last_mut() {
+        last.push_str("</w>");
+    }
+    while symbols.len() > 1 {
+        let best = symbols
+            .windows(2)
+            .filter_map(|pair| ranks.get(&(pair[0].clone(), pair[1].clone())))
+            .min();
+        let Some(&best) = best else { break };
+
+        let mut merged = Vec::with_capacity(symbols.len());
+        let mut i = 0;
+        while i < symbols.len() {
+            if i + 1 < symbols.len()
+                && ranks.get(&(symbols[i].clone(), symbols[i + 1].clone())) == Some(&best)
+            {
+                merged.push(format!("{}{}", symbols[i], symbols[i + 1]));
+                i += 2;
+            } else {
+                merged.push(symbols[i].clone());
+                i += 1;
+            }
+        }
+        symbols = merged;
+    }
+    symbols.len()
+}
+
+/// Rough token count for a pre-tokenized word when the merges aren't available: common words
+/// are a single token, long words split every few letters, punctuation is a token per character
+fn estimate_len(word: &str) -> usize {
+    let chars = word.chars().count();
+    match word.chars().next() {
+        Some(c) if c.is_alphabetic() && chars <= 7 => 1,
+        Some(c) if c.is_alphabetic() => chars.div_ceil(5),
+        _ => chars,
+    }
+}
+
+/// A comma-separated concept whose tokens fall into two chunks
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkSplit {
+    /// Index of the BREAK segment the concept is in
+    pub segment: usize,
+    pub text: String,
+    /// Chunk (within the segment) the concept starts in; it ends in the next one
+    pub chunk: usize,
+}
+
+/// Token counts of a prompt as CLIP sees it
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenReport {
+    /// Token count of each BREAK segment
+    pub segments: Vec<usize>,
+    /// Concepts cut in half by a chunk boundary
+    pub splits: Vec<ChunkSplit>,
+    /// Whether counts are exact rather than estimated
+    pub exact: bool,
+}
+
+impl TokenReport {
+    pub fn total(&self) -> usize {
+        self.segments.iter().sum()
+    }
+
+    /// Chunks the prompt is encoded in, with every segment padded to a chunk boundary
+    pub fn chunks(&self) -> usize {
+        self.segments
+            .iter()
+            .map(|&n| n.div_ceil(CHUNK_SIZE).max(1))
+            .sum()
+    }
+}
+
+/// Count tokens per segment of `prompt` and find concepts split across chunks
+pub fn analyze(prompt: &Prompt, tokenizer: &ClipTokenizer) -> TokenReport {
+    let mut segments = Vec::new();
+    let mut splits = Vec::new();
+    for (index, segment) in prompt.segments.iter().enumerate() {
+        let mut position = 0;
+        for (i, token) in segment.tokens.iter().enumerate() {
+            let text = token.plain_text();
+            let count = tokenizer.count(&text);
+            if count > 0 {
+                let first_chunk = position / CHUNK_SIZE;
+                let last_chunk = (position + count - 1) / CHUNK_SIZE;
+                if first_chunk != last_chunk {
+                    splits.push(ChunkSplit {
+                        segment: index,
+                        text,
+                        chunk: first_chunk,
+                    });
+                }
+            }
+            position += count;
+            // The separating comma is a token of its own
+            if i + 1 < segment.tokens.len() {
+                position += 1;
+            }
+        }
+        segments.push(position);
+    }
+    TokenReport {
+        segments,
+        splits,
+        exact: tokenizer.is_exact(),
+    }
+}
+
+/// Remove tokens from `prompt` until it fits in `max_tokens`, dropping the lowest-weighted
+/// tokens first (and later ones first among equal weights). Returns the removed tokens.
+pub fn trim_to_budget(
+    prompt: &mut Prompt,
+    tokenizer: &ClipTokenizer,
+    max_tokens: usize,
+) -> Vec<String> {
+    let mut removed = Vec::new();
+    while analyze(prompt, tokenizer).total() > max_tokens {
+        let mut lowest: Option<(usize, usize, f32)> = None;
+        for (s, segment) in prompt.segments.iter().enumerate() {
+            for (t, token) in segment.tokens.iter().enumerate() {
+                let weight = token.weight();
+                if lowest.is_none_or(|(_, _, w)| weight <= w) {
+                    lowest = Some((s, t, weight));
+                }
+            }
+        }
+        let Some((s, t, _)) = lowest else { break };
+        let token: Token = prompt.segments[s].tokens.remove(t);
+        removed.push(token.to_string());
+        if prompt.segments[s].tokens.is_empty() {
+            prompt.segments.remove(s);
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    /// Tiny merges table: "cat", "dog" and "blue" become single tokens, "sky" does not merge
+    const MERGES: &str = "#version: 0.2\nc a\nca t</w>\nd o\ndo g</w>\nb l\nbl u\nblu e</w>\n";
+
+    #[test]
+    fn byte_encoder_matches_clip() {
+        let encoder = bytes_to_unicode();
+        assert_eq!(encoder[b'a' as usize], 'a');
+        assert_eq!(encoder[b' ' as usize], 'Ġ');
+        assert_eq!(encoder[0], 'Ā');
+        assert_eq!(encoder.len(), 256);
+    }
+
+    #[test]
+    fn pre_tokenizes_like_clip() {
+        assert_eq!(
+            pre_tokenize("rem's 8k, (blue:1.2)!!"),
+            vec![
+                "rem", "'s", "8", "k", ",", "(", "blue", ":", "1", ".", "2", ")!!"
+            ]
+        );
+    }
+
+    #[test]
+    fn bpe_counts_with_merges() {
+        let tokenizer = ClipTokenizer::from_merges(MERGES);
+        assert!(tokenizer.is_exact());
+        assert_eq!(tokenizer.count("cat"), 1);
+        assert_eq!(tokenizer.count("Blue dog"), 2);
+        assert_eq!(tokenizer.count("sky"), 3);
+        assert_eq!(tokenizer.count("cat, dog"), 3);
+        assert_eq!(tokenizer.count("cats"), 3);
+    }
+
+    #[test]
+    fn estimates_without_merges() {
+        let tokenizer = ClipTokenizer::estimating();
+        assert!(!tokenizer.is_exact());
+        assert_eq!(tokenizer.count("blue hair"), 2);
+        assert_eq!(tokenizer.count("chiaroscuro, 8k"), 6);
+    }
+
+    #[test]
+    fn reads_gzipped_merges() {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(MERGES.as_bytes()).unwrap();
+        let tokenizer = ClipTokenizer::from_gzip(&encoder.finish().unwrap()).unwrap();
+        assert_eq!(tokenizer.count("blue cat"), 2);
+    }
+
+    #[test]
+    fn embeds_every_merge() {
+        let tokenizer = ClipTokenizer::load();
+        assert!(tokenizer.is_exact());
+        assert_eq!(tokenizer.ranks.map(|ranks| ranks.len()), Some(MERGE_COUNT));
+    }
+
+    #[test]
+    fn counts_segments_and_finds_chunk_splits() {
+        let tokenizer = ClipTokenizer::from_merges(MERGES);
+        // 37 "cat" tokens + 36 commas = 73 tokens, then "sky" (3 tokens) crosses token 75
+        let first = vec!["cat"; 37].join(", ");
+        let text = format!("{}, sky, dog, BREAK, blue, cat", first);
+        let (prompt, _) = parser::parse(&text);
+
+        let report = analyze(&prompt, &tokenizer);
+        assert_eq!(report.segments, vec![73 + 1 + 3 + 1 + 1, 3]);
+        assert_eq!(report.total(), 82);
+        assert_eq!(report.chunks(), 3);
+        assert_eq!(
+            report.splits,
+            vec![ChunkSplit {
+                segment: 0,
+                text: "sky".to_string(),
+                chunk: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn trims_lowest_weights_first() {
+        let tokenizer = ClipTokenizer::from_merges(MERGES);
+        let (mut prompt, _) = parser::parse("(cat:1.3), [dog], blue, BREAK, (sky:0.5), dog");
+        assert_eq!(analyze(&prompt, &tokenizer).total(), 5 + 5);
+
+        let removed = trim_to_budget(&mut prompt, &tokenizer, 5);
+        assert_eq!(removed, vec!["(sky:0.5)", "[dog]"]);
+        assert_eq!(prompt.to_string(), "(cat:1.3), blue, BREAK, dog");
+        assert_eq!(analyze(&prompt, &tokenizer).total(), 4);
+    }
+}
@@ -0,0 +1,1089 @@
+//! Layered configuration.
+//!
+//! Every setting starts from a built-in default and is overridden, in order, by the global
+//! config file, the project config file, the session, `PROMPTFLOW_*` environment variables and
+//! finally command-line flags. Each effective value remembers which layer it came from, so
+//! `PromptFlow config show` can explain it.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::backend::{BackendKind, OllamaApi};
+use crate::context::ContextStrategy;
+use crate::history::{self, HISTORY_DEPTH, Retention};
+use crate::key::DEFAULT_PROFILE;
+use crate::negative::NegativeMode;
+use crate::parser::format_weight;
+use crate::preset::{self, DEFAULT_PRESET, Preset, PresetError};
+use crate::session::{self, Session, SessionError};
+use crate::target::{self, Dialect, Target};
+use crate::validate::{self, ValidationMode, ValidationRules};
+
+/// File name of the global config inside [`config_dir`]
+pub const CONFIG_FILE: &str = "config.toml";
+
+/// File name of the per-project config, looked up from the working directory upwards
+pub const PROJECT_FILE: &str = ".promptflow.toml";
+
+/// Keys a project config may not set: any checked-out repository can hold one, and these pick
+/// where stored API keys are sent and which of them are used
+const PROJECT_FORBIDDEN_KEYS: [&str; 2] = ["base_url", "profile"];
+
+/// Prefix of the environment variables overriding config keys, e.g. `PROMPTFLOW_MODEL`
+pub const ENV_PREFIX: &str = "PROMPTFLOW_";
+
+/// `$XDG_CONFIG_HOME/promptflow`, falling back to `~/.config/promptflow`
+pub fn config_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("promptflow"))
+}
+
+/// `$XDG_DATA_HOME/promptflow`, falling back to `~/.local/share/promptflow`
+pub fn data_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+        .map(|dir| dir.join("promptflow"))
+}
+
+/// Location of the global config file
+pub fn global_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(CONFIG_FILE))
+}
+
+/// Nearest [`PROJECT_FILE`] in `start` or one of its ancestors
+pub fn find_project_file(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join(PROJECT_FILE))
+        .find(|path| path.is_file())
+}
+
+/// Environment variable overriding config `key`
+pub fn env_var(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.to_ascii_uppercase())
+}
+
+/// Layer an effective value was taken from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Default,
+    Global(PathBuf),
+    Project(PathBuf),
+    Session(String),
+    Preset(String),
+    Env(String),
+    Cli(String),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => f.write_str("default"),
+            Source::Global(path) => write!(f, "global config {}", path.display()),
+            Source::Project(path) => write!(f, "project config {}", path.display()),
+            Source::Session(name) => write!(f, "session {:?}", name),
+            Source::Preset(name) => write!(f, "preset {:?}", name),
+            Source::Env(var) => write!(f, "environment {}", var),
+            Source::Cli(flag) => write!(f, "command line {}", flag),
+        }
+    }
+}
+
+/// Failure to read or interpret one of the config layers
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Session(SessionError),
+    Preset(PresetError),
+    Invalid {
+        source: Source,
+        key: &'static str,
+        message: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config {}: {}", path.display(), e),
+            ConfigError::Session(e) => e.fmt(f),
+            ConfigError::Preset(e) => e.fmt(f),
+            ConfigError::Invalid {
+                source,
+                key,
+                message,
+            } => write!(f, "invalid {} from {}: {}", key, source, message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// An effective config value and the layer it came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Setting<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+impl<T> Setting<T> {
+    fn new(value: T) -> Self {
+        Self {
+            value,
+            source: Source::Default,
+        }
+    }
+
+    /// A value taken from the preset called `name`
+    fn from_preset(value: T, name: &str) -> Self {
+        Self {
+            value,
+            source: Source::Preset(name.to_string()),
+        }
+    }
+
+    fn update(&mut self, value: Option<T>, source: Source) {
+        if let Some(value) = value {
+            self.value = value;
+            self.source = source;
+        }
+    }
+}
+
+/// Values set by a single layer; `None` leaves the lower layers' value in place
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Layer {
+    pub profile: Option<String>,
+    pub session: Option<String>,
+    pub preset: Option<String>,
+    pub target: Option<Target>,
+    pub aspect_ratio: Option<(u32, u32)>,
+    pub stylize: Option<u32>,
+    pub backend: Option<BackendKind>,
+    pub model: Option<String>,
+    pub base_url: Option<String>,
+    pub temperature: Option<f32>,
+    pub num_ctx: Option<u32>,
+    pub ollama_api: Option<OllamaApi>,
+    pub validate: Option<ValidationMode>,
+    pub max_retries: Option<u32>,
+    pub weight_range: Option<(f32, f32)>,
+    pub banned_terms: Option<Vec<String>>,
+    pub fill_missing: Option<bool>,
+    pub max_tokens: Option<usize>,
+    pub history_depth: Option<usize>,
+    pub context: Option<ContextStrategy>,
+    pub history_max_entries: Option<usize>,
+    pub history_max_age_days: Option<u32>,
+    pub system_instruction: Option<String>,
+    pub descriptors: Option<Vec<String>>,
+    pub negative_prompt: Option<String>,
+    pub negative_mode: Option<NegativeMode>,
+}
+
+/// On-disk form of a config file; enum-like values are parsed the same way as their flags
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    profile: Option<String>,
+    session: Option<String>,
+    preset: Option<String>,
+    target: Option<String>,
+    aspect_ratio: Option<String>,
+    stylize: Option<u32>,
+    backend: Option<String>,
+    model: Option<String>,
+    base_url: Option<String>,
+    temperature: Option<f32>,
+    num_ctx: Option<u32>,
+    ollama_api: Option<String>,
+    validate: Option<String>,
+    max_retries: Option<u32>,
+    weight_range: Option<String>,
+    banned_terms: Option<Vec<String>>,
+    fill_missing: Option<bool>,
+    max_tokens: Option<usize>,
+    history_depth: Option<usize>,
+    context: Option<String>,
+    history_max_entries: Option<usize>,
+    history_max_age_days: Option<u32>,
+    system_instruction: Option<String>,
+    descriptors: Option<Vec<String>>,
+    negative_prompt: Option<String>,
+    negative_mode: Option<String>,
+}
+
+/// Parse `raw` with `parse`, attributing a failure to `key` from `source`
+fn parse_with<T, E: fmt::Display>(
+    key: &'static str,
+    raw: Option<String>,
+    source: &dyn Fn(&'static str) -> Source,
+    parse: impl Fn(&str) -> Result<T, E>,
+) -> Result<Option<T>, ConfigError> {
+    raw.map(|raw| {
+        parse(&raw).map_err(|e| ConfigError::Invalid {
+            source: source(key),
+            key,
+            message: e.to_string(),
+        })
+    })
+    .transpose()
+}
+
+impl Layer {
+    /// Read the config file at `path`, attributing its values to `source`
+    pub fn from_file(path: &Path, source: Source) -> Result<Self, ConfigError> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        let file: ConfigFile =
+            toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+        let source = move |_| source.clone();
+        Ok(Self {
+            profile: file.profile,
+            session: file.session,
+            preset: file.preset,
+            target: parse_with("target", file.target, &source, str::parse)?,
+            aspect_ratio: parse_with(
+                "aspect_ratio",
+                file.aspect_ratio,
+                &source,
+                target::parse_aspect_ratio,
+            )?,
+            stylize: parse_with(
+                "stylize",
+                file.stylize.map(|value| value.to_string()),
+                &source,
+                target::parse_stylize,
+            )?,
+            backend: parse_with("backend", file.backend, &source, str::parse)?,
+            model: file.model,
+            base_url: file.base_url,
+            temperature: file.temperature,
+            num_ctx: file.num_ctx,
+            ollama_api: parse_with("ollama_api", file.ollama_api, &source, str::parse)?,
+            validate: parse_with("validate", file.validate, &source, str::parse)?,
+            max_retries: file.max_retries,
+            weight_range: parse_with(
+                "weight_range",
+                file.weight_range,
+                &source,
+                validate::parse_weight_range,
+            )?,
+            banned_terms: file.banned_terms,
+            fill_missing: file.fill_missing,
+            max_tokens: file.max_tokens,
+            history_depth: file.history_depth,
+            context: parse_with("context", file.context, &source, str::parse)?,
+            history_max_entries: file.history_max_entries,
+            history_max_age_days: file.history_max_age_days,
+            system_instruction: file.system_instruction,
+            descriptors: file.descriptors,
+            negative_prompt: file.negative_prompt,
+            negative_mode: parse_with("negative_mode", file.negative_mode, &source, str::parse)?,
+        })
+    }
+
+    /// Collect the `PROMPTFLOW_*` variables returned by `lookup`
+    pub fn from_env(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let get = |key: &str| lookup(&env_var(key));
+        let source = |key: &'static str| Source::Env(env_var(key));
+        Ok(Self {
+            profile: get("profile"),
+            session: get("session"),
+            preset: get("preset"),
+            target: parse_with("target", get("target"), &source, str::parse)?,
+            aspect_ratio: parse_with(
+                "aspect_ratio",
+                get("aspect_ratio"),
+                &source,
+                target::parse_aspect_ratio,
+            )?,
+            stylize: parse_with("stylize", get("stylize"), &source, target::parse_stylize)?,
+            backend: parse_with("backend", get("backend"), &source, str::parse)?,
+            model: get("model"),
+            base_url: get("base_url"),
+            temperature: parse_with("temperature", get("temperature"), &source, str::parse)?,
+            num_ctx: parse_with("num_ctx", get("num_ctx"), &source, str::parse)?,
+            ollama_api: parse_with("ollama_api", get("ollama_api"), &source, str::parse)?,
+            validate: parse_with("validate", get("validate"), &source, str::parse)?,
+            max_retries: parse_with("max_retries", get("max_retries"), &source, str::parse)?,
+            weight_range: parse_with(
+                "weight_range",
+                get("weight_range"),
+                &source,
+                validate::parse_weight_range,
+            )?,
+            // Comma-separated, since environment variables can't hold a list
+            banned_terms: get("banned_terms").map(|raw| {
+                raw.split(',')
+                    .map(str::trim)
+                    .filter(|term| !term.is_empty())
+                    .map(String::from)
+                    .collect()
+            }),
+            fill_missing: parse_with("fill_missing", get("fill_missing"), &source, str::parse)?,
+            max_tokens: parse_with("max_tokens", get("max_tokens"), &source, str::parse)?,
+            history_depth: parse_with("history_depth", get("history_depth"), &source, str::parse)?,
+            context: parse_with("context", get("context"), &source, str::parse)?,
+            history_max_entries: parse_with(
+                "history_max_entries",
+                get("history_max_entries"),
+                &source,
+                str::parse,
+            )?,
+            history_max_age_days: parse_with(
+                "history_max_age_days",
+                get("history_max_age_days"),
+                &source,
+                str::parse,
+            )?,
+            system_instruction: get("system_instruction"),
+            // Semicolon-separated, since descriptors usually contain commas
+            descriptors: get("descriptors").map(|raw| {
+                raw.split(';')
+                    .map(str::trim)
+                    .filter(|descriptor| !descriptor.is_empty())
+                    .map(String::from)
+                    .collect()
+            }),
+            negative_prompt: get("negative_prompt"),
+            negative_mode: parse_with("negative_mode", get("negative_mode"), &source, str::parse)?,
+        })
+    }
+}
+
+impl Layer {
+    /// The values set by `session`
+    pub fn from_session(session: &Session) -> Self {
+        Self {
+            preset: session.preset.clone(),
+            descriptors: (!session.descriptors.is_empty()).then(|| session.descriptors.clone()),
+            negative_prompt: session.negative_prompt.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Effective configuration after merging every layer
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Key store profile API keys are looked up in
+    pub profile: Setting<String>,
+    /// Session whose history and defaults are used, if any
+    pub session: Setting<Option<String>>,
+    /// Style preset the system instruction, negative prompt and banned terms default to
+    pub preset: Setting<String>,
+    /// Image model the prompts are written for
+    pub target: Setting<Target>,
+    /// Midjourney `--ar`
+    pub aspect_ratio: Setting<Option<(u32, u32)>>,
+    /// Midjourney `--stylize`
+    pub stylize: Setting<Option<u32>>,
+    pub backend: Setting<BackendKind>,
+    /// Model override; each backend falls back to its own default when unset
+    pub model: Setting<Option<String>>,
+    pub base_url: Setting<Option<String>>,
+    pub temperature: Setting<Option<f32>>,
+    pub num_ctx: Setting<Option<u32>>,
+    pub ollama_api: Setting<OllamaApi>,
+    pub validate: Setting<ValidationMode>,
+    pub max_retries: Setting<u32>,
+    pub weight_range: Setting<(f32, f32)>,
+    pub banned_terms: Setting<Vec<String>>,
+    pub fill_missing: Setting<bool>,
+    pub max_tokens: Setting<Option<usize>>,
+    /// Number of previous prompts included in the system instruction, unless the context
+    /// strategy gives its own
+    pub history_depth: Setting<usize>,
+    /// How the history entries sent as context are chosen
+    pub context: Setting<ContextStrategy>,
+    /// Most history entries kept, 0 for no limit
+    pub history_max_entries: Setting<usize>,
+    /// Age in days after which history entries are dropped
+    pub history_max_age_days: Setting<Option<u32>>,
+    pub system_instruction: Setting<String>,
+    /// Character descriptions added to the system instruction
+    pub descriptors: Setting<Vec<String>>,
+    pub negative_prompt: Setting<String>,
+    /// How the negative prompt is composed from `negative_prompt`
+    pub negative_mode: Setting<NegativeMode>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let rules = ValidationRules::default();
+        let preset = preset::default();
+        Self {
+            profile: Setting::new(DEFAULT_PROFILE.to_string()),
+            session: Setting::new(None),
+            preset: Setting::new(DEFAULT_PRESET.to_string()),
+            target: Setting::new(Target::default()),
+            aspect_ratio: Setting::new(None),
+            stylize: Setting::new(None),
+            backend: Setting::new(BackendKind::default()),
+            model: Setting::new(None),
+            base_url: Setting::new(None),
+            temperature: Setting::new(None),
+            num_ctx: Setting::new(None),
+            ollama_api: Setting::new(OllamaApi::default()),
+            validate: Setting::new(ValidationMode::default()),
+            max_retries: Setting::new(validate::DEFAULT_MAX_RETRIES),
+            weight_range: Setting::new(rules.weight_range),
+            banned_terms: Setting::from_preset(preset.banned_terms.clone(), &preset.name),
+            fill_missing: Setting::new(false),
+            max_tokens: Setting::new(None),
+            history_depth: Setting::new(HISTORY_DEPTH),
+            context: Setting::new(ContextStrategy::default()),
+            history_max_entries: Setting::new(history::MAX_ENTRIES),
+            history_max_age_days: Setting::new(None),
+            system_instruction: Setting::from_preset(preset.system_instruction(), &preset.name),
+            descriptors: Setting::new(Vec::new()),
+            negative_prompt: Setting::from_preset(preset.negative_prompt.clone(), &preset.name),
+            negative_mode: Setting::new(NegativeMode::default()),
+        }
+    }
+}
+
+impl Config {
+    /// Merge the global and project config files, the session, the environment and the
+    /// command line. The session is the one named by the other layers, or else the one chosen
+    /// with `session switch`.
+    pub fn load(cli: Layer) -> Result<Self, ConfigError> {
+        let project = std::env::current_dir()
+            .ok()
+            .and_then(|dir| find_project_file(&dir));
+        let presets = preset::load().map_err(ConfigError::Preset)?;
+        let env = |var: &str| std::env::var(var).ok();
+        let config = Self::resolve(
+            global_path().as_deref(),
+            project.as_deref(),
+            None,
+            &presets,
+            env,
+            cli.clone(),
+        )?;
+        let (name, source) = match config.session.value {
+            Some(name) => (name, config.session.source),
+            None => match session::active() {
+                Some(name) => (name.clone(), Source::Session(name)),
+                None => return Ok(config),
+            },
+        };
+        let session = Session::open(&name).map_err(ConfigError::Session)?;
+        let mut config = Self::resolve(
+            global_path().as_deref(),
+            project.as_deref(),
+            Some(&session),
+            &presets,
+            env,
+            cli,
+        )?;
+        config.session = Setting {
+            value: Some(name),
+            source,
+        };
+        Ok(config)
+    }
+
+    /// Merge the layers in precedence order; missing config files are skipped. Values no layer
+    /// sets are then taken from the chosen preset, looked up in `presets`.
+    pub fn resolve(
+        global: Option<&Path>,
+        project: Option<&Path>,
+        session: Option<&Session>,
+        presets: &[Preset],
+        env: impl Fn(&str) -> Option<String>,
+        cli: Layer,
+    ) -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+        if let Some(path) = global.filter(|path| path.is_file()) {
+            let source = Source::Global(path.to_path_buf());
+            config.apply(Layer::from_file(path, source.clone())?, |_| source.clone());
+        }
+        if let Some(path) = project.filter(|path| path.is_file()) {
+            let source = Source::Project(path.to_path_buf());
+            let layer = Layer::from_file(path, source.clone())?;
+            let forbidden = [layer.base_url.is_some(), layer.profile.is_some()];
+            if let Some((key, _)) = PROJECT_FORBIDDEN_KEYS
+                .iter()
+                .zip(forbidden)
+                .find(|(_, set)| *set)
+            {
+                return Err(ConfigError::Invalid {
+                    source,
+                    key,
+                    message: "not allowed in a project config, which could send your API keys \
+                              elsewhere; set it in the global config, the environment or on the \
+                              command line"
+                        .to_string(),
+                });
+            }
+            config.apply(layer, |_| source.clone());
+        }
+        if let Some(session) = session {
+            config.apply(Layer::from_session(session), |_| {
+                Source::Session(session.name.clone())
+            });
+        }
+        config.apply(Layer::from_env(env)?, |key| Source::Env(env_var(key)));
+        config.apply(cli, |key| {
+            Source::Cli(format!("--{}", key.replace('_', "-")))
+        });
+        config.apply_preset(presets)?;
+        Ok(config)
+    }
+
+    /// Take the values no layer set from the configured preset
+    fn apply_preset(&mut self, presets: &[Preset]) -> Result<(), ConfigError> {
+        let preset =
+            preset::find(presets, &self.preset.value).ok_or_else(|| ConfigError::Invalid {
+                source: self.preset.source.clone(),
+                key: "preset",
+                message: format!(
+                    "unknown preset {:?} (available: {})",
+                    self.preset.value,
+                    preset::names(presets)
+                ),
+            })?;
+        self.preset.value = preset.name.clone();
+        if let Source::Preset(_) = self.system_instruction.source {
+            self.system_instruction =
+                Setting::from_preset(preset.system_instruction(), &preset.name);
+        }
+        if let Source::Preset(_) = self.negative_prompt.source {
+            self.negative_prompt =
+                Setting::from_preset(preset.negative_prompt.clone(), &preset.name);
+        }
+        if let Source::Preset(_) = self.banned_terms.source {
+            self.banned_terms = Setting::from_preset(preset.banned_terms.clone(), &preset.name);
+        }
+        Ok(())
+    }
+
+    /// Override every value `layer` sets, attributing it to `source(key)`
+    fn apply(&mut self, layer: Layer, source: impl Fn(&'static str) -> Source) {
+        self.profile.update(layer.profile, source("profile"));
+        self.preset.update(layer.preset, source("preset"));
+        self.target.update(layer.target, source("target"));
+        self.aspect_ratio
+            .update(layer.aspect_ratio.map(Some), source("aspect_ratio"));
+        self.stylize
+            .update(layer.stylize.map(Some), source("stylize"));
+        self.session
+            .update(layer.session.map(Some), source("session"));
+        self.backend.update(layer.backend, source("backend"));
+        self.model.update(layer.model.map(Some), source("model"));
+        self.base_url
+            .update(layer.base_url.map(Some), source("base_url"));
+        self.temperature
+            .update(layer.temperature.map(Some), source("temperature"));
+        self.num_ctx
+            .update(layer.num_ctx.map(Some), source("num_ctx"));
+        self.ollama_api
+            .update(layer.ollama_api, source("ollama_api"));
+        self.validate.update(layer.validate, source("validate"));
+        self.max_retries
+            .update(layer.max_retries, source("max_retries"));
+        self.weight_range
+            .update(layer.weight_range, source("weight_range"));
+        self.banned_terms
+            .update(layer.banned_terms, source("banned_terms"));
+        self.fill_missing
+            .update(layer.fill_missing, source("fill_missing"));
+        self.max_tokens
+            .update(layer.max_tokens.map(Some), source("max_tokens"));
+        self.history_depth
+            .update(layer.history_depth, source("history_depth"));
+        self.context.update(layer.context, source("context"));
+        self.history_max_entries
+            .update(layer.history_max_entries, source("history_max_entries"));
+        self.history_max_age_days.update(
+            layer.history_max_age_days.map(Some),
+            source("history_max_age_days"),
+        );
+        self.system_instruction
+            .update(layer.system_instruction, source("system_instruction"));
+        self.descriptors
+            .update(layer.descriptors, source("descriptors"));
+        self.negative_prompt
+            .update(layer.negative_prompt, source("negative_prompt"));
+        self.negative_mode
+            .update(layer.negative_mode, source("negative_mode"));
+    }
+
+    /// Validation rules built from the configured weight range and banned terms
+    pub fn validation_rules(&self) -> ValidationRules {
+        ValidationRules {
+            weight_range: self.weight_range.value,
+            banned_terms: self.banned_terms.value.clone(),
+        }
+    }
+
+    /// Target model and the parameters its formatter appends
+    pub fn dialect(&self) -> Dialect {
+        Dialect {
+            target: self.target.value,
+            aspect_ratio: self.aspect_ratio.value,
+            stylize: self.stylize.value,
+        }
+    }
+
+    /// History retention policy built from the configured limits
+    pub fn history_retention(&self) -> Retention {
+        Retention {
+            max_entries: Some(self.history_max_entries.value).filter(|&max| max > 0),
+            max_age: self
+                .history_max_age_days
+                .value
+                .map(|days| std::time::Duration::from_secs(u64::from(days) * 24 * 60 * 60)),
+        }
+    }
+
+    /// Every key with its displayed value and source, in config file order
+    pub fn entries(&self) -> Vec<(&'static str, String, &Source)> {
+        fn optional<T: fmt::Display>(value: &Option<T>) -> String {
+            value
+                .as_ref()
+                .map_or_else(|| "(unset)".to_string(), T::to_string)
+        }
+        vec![
+            ("profile", self.profile.value.clone(), &self.profile.source),
+            (
+                "session",
+                optional(&self.session.value),
+                &self.session.source,
+            ),
+            ("preset", self.preset.value.clone(), &self.preset.source),
+            ("target", self.target.value.to_string(), &self.target.source),
+            (
+                "aspect_ratio",
+                optional(
+                    &self
+                        .aspect_ratio
+                        .value
+                        .map(|(width, height)| format!("{}:{}", width, height)),
+                ),
+                &self.aspect_ratio.source,
+            ),
+            (
+                "stylize",
+                optional(&self.stylize.value),
+                &self.stylize.source,
+            ),
+            (
+                "backend",
+                self.backend.value.to_string(),
+                &self.backend.source,
+            ),
+            ("model", optional(&self.model.value), &self.model.source),
+            (
+                "base_url",
+                optional(&self.base_url.value),
+                &self.base_url.source,
+            ),
+            (
+                "temperature",
+                optional(&self.temperature.value),
+                &self.temperature.source,
+            ),
+            (
+                "num_ctx",
+                optional(&self.num_ctx.value),
+                &self.num_ctx.source,
+            ),
+            (
+                "ollama_api",
+                self.ollama_api.value.to_string(),
+                &self.ollama_api.source,
+            ),
+            (
+                "validate",
+                self.validate.value.to_string(),
+                &self.validate.source,
+            ),
+            (
+                "max_retries",
+                self.max_retries.value.to_string(),
+                &self.max_retries.source,
+            ),
+            (
+                "weight_range",
+                format!(
+                    "{}:{}",
+                    format_weight(self.weight_range.value.0),
+                    format_weight(self.weight_range.value.1)
+                ),
+                &self.weight_range.source,
+            ),
+            (
+                "banned_terms",
+                self.banned_terms.value.join(", "),
+                &self.banned_terms.source,
+            ),
+            (
+                "fill_missing",
+                self.fill_missing.value.to_string(),
+                &self.fill_missing.source,
+            ),
+            (
+                "max_tokens",
+                optional(&self.max_tokens.value),
+                &self.max_tokens.source,
+            ),
+            (
+                "history_depth",
+                self.history_depth.value.to_string(),
+                &self.history_depth.source,
+            ),
+            (
+                "context",
+                self.context.value.to_string(),
+                &self.context.source,
+            ),
+            (
+                "history_max_entries",
+                self.history_max_entries.value.to_string(),
+                &self.history_max_entries.source,
+            ),
+            (
+                "history_max_age_days",
+                optional(&self.history_max_age_days.value),
+                &self.history_max_age_days.source,
+            ),
+            (
+                "system_instruction",
+                summarize(&self.system_instruction.value),
+                &self.system_instruction.source,
+            ),
+            (
+                "descriptors",
+                summarize(&self.descriptors.value.join("; ")),
+                &self.descriptors.source,
+            ),
+            (
+                "negative_prompt",
+                summarize(&self.negative_prompt.value),
+                &self.negative_prompt.source,
+            ),
+            (
+                "negative_mode",
+                self.negative_mode.value.to_string(),
+                &self.negative_mode.source,
+            ),
+        ]
+    }
+}
+
+/// First line of a long text value and its length, to keep `config show` readable
+fn summarize(text: &str) -> String {
+    const MAX_CHARS: usize = 48;
+    let first_line = text.trim().lines().next().unwrap_or_default();
+    if first_line.len() == text.len() && text.chars().count() <= MAX_CHARS {
+        return text.to_string();
+    }
+    let head: String = first_line.chars().take(MAX_CHARS).collect();
+    format!("{}... ({} chars)", head, text.chars().count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("promptflow-config-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn defaults_without_any_layer() {
+        let config = Config::resolve(
+            None,
+            None,
+            None,
+            &preset::builtin(),
+            no_env,
+            Layer::default(),
+        )
+        .unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.history_depth.value, HISTORY_DEPTH);
+        assert_eq!(
+            config.negative_prompt.value,
+            preset::default().negative_prompt
+        );
+        assert!(config.entries().iter().all(|(key, _, source)| match *key {
+            "system_instruction" | "negative_prompt" | "banned_terms" => {
+                **source == Source::Preset(DEFAULT_PRESET.to_string())
+            }
+            _ => **source == Source::Default,
+        }));
+    }
+
+    #[test]
+    fn layers_override_in_precedence_order() {
+        let dir = scratch_dir("layers");
+        let global = dir.join("config.toml");
+        std::fs::write(
+            &global,
+            "backend = \"ollama\"\nmodel = \"llama3.1\"\nhistory_depth = 3\nnegative_prompt = \"lowres\"\ntemperature = 0.2\n",
+        )
+        .unwrap();
+        let project = dir.join(PROJECT_FILE);
+        std::fs::write(
+            &project,
+            "model = \"qwen2.5\"\nweight_range = \"0.5:1.5\"\nbanned_terms = [\"photo\"]\ntemperature = 0.4\n",
+        )
+        .unwrap();
+        let env = HashMap::from([
+            ("PROMPTFLOW_TEMPERATURE".to_string(), "0.6".to_string()),
+            ("PROMPTFLOW_VALIDATE".to_string(), "retry".to_string()),
+        ]);
+        let cli = Layer {
+            validate: Some(ValidationMode::Off),
+            ..Default::default()
+        };
+
+        let config = Config::resolve(
+            Some(&global),
+            Some(&project),
+            None,
+            &preset::builtin(),
+            |var| env.get(var).cloned(),
+            cli,
+        )
+        .unwrap();
+        assert_eq!(config.backend.value, BackendKind::Ollama);
+        assert_eq!(config.backend.source, Source::Global(global.clone()));
+        assert_eq!(config.history_depth.value, 3);
+        assert_eq!(config.negative_prompt.value, "lowres");
+        assert_eq!(config.model.value.as_deref(), Some("qwen2.5"));
+        assert_eq!(config.model.source, Source::Project(project.clone()));
+        assert_eq!(config.validation_rules().weight_range, (0.5, 1.5));
+        assert_eq!(config.validation_rules().banned_terms, vec!["photo"]);
+        assert_eq!(config.temperature.value, Some(0.6));
+        assert_eq!(
+            config.temperature.source,
+            Source::Env("PROMPTFLOW_TEMPERATURE".to_string())
+        );
+        assert_eq!(config.validate.value, ValidationMode::Off);
+        assert_eq!(
+            config.validate.source,
+            Source::Cli("--validate".to_string())
+        );
+        assert_eq!(config.max_retries.source, Source::Default);
+    }
+
+    #[test]
+    fn project_config_cannot_redirect_keys() {
+        let dir = scratch_dir("project-keys");
+        let project = dir.join(PROJECT_FILE);
+        for contents in [
+            "backend = \"openai\"\nbase_url = \"https://example.com/v1\"\n",
+            "profile = \"work\"\n",
+        ] {
+            std::fs::write(&project, contents).unwrap();
+            let err = Config::resolve(
+                None,
+                Some(&project),
+                None,
+                &preset::builtin(),
+                no_env,
+                Layer::default(),
+            )
+            .unwrap_err()
+            .to_string();
+            assert!(err.contains("not allowed in a project config"), "{}", err);
+        }
+
+        std::fs::write(&project, "backend = \"openai\"\n").unwrap();
+        let cli = Layer {
+            base_url: Some("http://localhost:8080/v1".to_string()),
+            ..Default::default()
+        };
+        let config =
+            Config::resolve(None, Some(&project), None, &preset::builtin(), no_env, cli).unwrap();
+        assert_eq!(config.backend.value, BackendKind::OpenAi);
+        assert_eq!(
+            config.base_url.value.as_deref(),
+            Some("http://localhost:8080/v1")
+        );
+    }
+
+    #[test]
+    fn session_layer_sits_between_files_and_env() {
+        let dir = scratch_dir("session");
+        let global = dir.join("config.toml");
+        std::fs::write(&global, "negative_prompt = \"blurry\"\nmodel = \"qwen\"\n").unwrap();
+        let mut session = Session {
+            name: "azure".to_string(),
+            preset: Some("Anime".to_string()),
+            negative_prompt: Some("lowres".to_string()),
+            descriptors: vec!["Azure: blue hair".to_string()],
+            ..Default::default()
+        };
+        let env =
+            |var: &str| (var == "PROMPTFLOW_DESCRIPTORS").then(|| "Rook; ; Azure".to_string());
+
+        let config = Config::resolve(
+            Some(&global),
+            None,
+            Some(&session),
+            &preset::builtin(),
+            no_env,
+            Layer::default(),
+        )
+        .unwrap();
+        assert_eq!(config.negative_prompt.value, "lowres");
+        assert_eq!(
+            config.negative_prompt.source,
+            Source::Session("azure".to_string())
+        );
+        assert_eq!(config.model.source, Source::Global(global.clone()));
+        assert_eq!(
+            config.system_instruction.source,
+            Source::Preset("anime".to_string())
+        );
+        assert_eq!(config.descriptors.value, vec!["Azure: blue hair"]);
+
+        let config = Config::resolve(
+            Some(&global),
+            None,
+            Some(&session),
+            &preset::builtin(),
+            env,
+            Layer::default(),
+        )
+        .unwrap();
+        assert_eq!(config.descriptors.value, vec!["Rook", "Azure"]);
+
+        session.preset = Some("noir".to_string());
+        let err = Config::resolve(
+            None,
+            None,
+            Some(&session),
+            &preset::builtin(),
+            no_env,
+            Layer::default(),
+        )
+        .unwrap_err()
+        .to_string();
+        assert_eq!(
+            err,
+            format!(
+                "invalid preset from session \"azure\": unknown preset \"noir\" (available: {})",
+                preset::names(&preset::builtin())
+            )
+        );
+    }
+
+    #[test]
+    fn history_retention_from_config() {
+        assert_eq!(Config::default().history_retention(), Retention::default());
+
+        let dir = scratch_dir("retention");
+        let global = dir.join("config.toml");
+        std::fs::write(&global, "history_max_age_days = 30\n").unwrap();
+        let env = HashMap::from([(
+            "PROMPTFLOW_HISTORY_MAX_ENTRIES".to_string(),
+            "0".to_string(),
+        )]);
+        let config = Config::resolve(
+            Some(&global),
+            None,
+            None,
+            &preset::builtin(),
+            |var| env.get(var).cloned(),
+            Layer::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            config.history_retention(),
+            Retention {
+                max_entries: None,
+                max_age: Some(std::time::Duration::from_secs(30 * 24 * 60 * 60)),
+            }
+        );
+    }
+
+    #[test]
+    fn finds_project_file_in_ancestors() {
+        let dir = scratch_dir("ancestors");
+        let nested = dir.join("a/b");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::write(dir.join(PROJECT_FILE), "").unwrap();
+        assert_eq!(find_project_file(&nested), Some(dir.join(PROJECT_FILE)));
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_bad_values() {
+        let dir = scratch_dir("invalid");
+        let path = dir.join("config.toml");
+        std::fs::write(&path, "modle = \"qwen\"\n").unwrap();
+        let err = Config::resolve(
+            Some(&path),
+            None,
+            None,
+            &preset::builtin(),
+            no_env,
+            Layer::default(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("unknown field `modle`"), "{}", err);
+
+        std::fs::write(&path, "backend = \"nope\"\n").unwrap();
+        let err = Config::resolve(
+            Some(&path),
+            None,
+            None,
+            &preset::builtin(),
+            no_env,
+            Layer::default(),
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("invalid backend from global config"),
+            "{}",
+            err
+        );
+
+        let err = Config::resolve(
+            None,
+            None,
+            None,
+            &preset::builtin(),
+            |var| (var == "PROMPTFLOW_MAX_TOKENS").then(|| "lots".to_string()),
+            Layer::default(),
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("invalid max_tokens from environment PROMPTFLOW_MAX_TOKENS")
+        );
+    }
+
+    #[test]
+    fn summarizes_long_values() {
+        assert_eq!(summarize("lowres, blurry"), "lowres, blurry");
+        let instruction = preset::default().system_instruction();
+        let summary = summarize(&instruction);
+        assert!(summary.starts_with("You are an assistant"));
+        assert!(summary.ends_with(&format!("({} chars)", instruction.chars().count())));
+    }
+}
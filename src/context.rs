@@ -0,0 +1,278 @@
+//! Choosing which history entries are sent to the model as context.
+//!
+//! - `recent[:N]`: the N most recent prompts
+//! - `similar[:N]`: the N entries whose keyword and prompt best match the keyword, ranked with
+//!   BM25
+//! - `pinned[:N]`: entries pinned with `history pin`, all of them unless N is given
+//! - `none`: no context
+//!
+//! Without `:N`, `recent` and `similar` use the configured `history_depth`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::history::Entry;
+
+/// BM25 term frequency saturation
+const K1: f64 = 1.2;
+/// BM25 document length normalization
+const B: f64 = 0.75;
+
+/// Which history entries a strategy picks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContextKind {
+    /// The most recent entries
+    #[default]
+    Recent,
+    /// The entries most similar to the keyword
+    Similar,
+    /// Pinned entries only
+    Pinned,
+    /// No context
+    None,
+}
+
+/// A context strategy and the number of entries it selects, if given
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ContextStrategy {
+    pub kind: ContextKind,
+    pub count: Option<usize>,
+}
+
+impl fmt::Display for ContextStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self.kind {
+            ContextKind::Recent => "recent",
+            ContextKind::Similar => "similar",
+            ContextKind::Pinned => "pinned",
+            ContextKind::None => "none",
+        };
+        match self.count {
+            Some(count) => write!(f, "{}:{}", name, count),
+            None => f.write_str(name),
+        }
+    }
+}
+
+impl FromStr for ContextStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        let (name, count) = match s.split_once(':') {
+            Some((name, count)) => {
+                let count = count
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("invalid entry count {:?}", count))?;
+                (name.trim(), Some(count))
+            }
+            None => (s.as_str(), None),
+        };
+        let kind = match name {
+            "recent" => ContextKind::Recent,
+            "similar" => ContextKind::Similar,
+            "pinned" => ContextKind::Pinned,
+            "none" if count.is_none() => ContextKind::None,
+            "none" => return Err("`none` takes no entry count".to_string()),
+            _ => {
+                return Err(format!(
+                    "unknown context strategy {:?} (expected recent[:N], similar[:N], pinned[:N] or none)",
+                    name
+                ));
+            }
+        };
+        Ok(Self { kind, count })
+    }
+}
+
+impl ContextStrategy {
+    /// Entries of `entries` (oldest first) to send as context for `keyword`, oldest first.
+    /// `default_count` applies when no count was given.
+    pub fn select<'a>(
+        &self,
+        entries: &'a [Entry],
+        keyword: &str,
+        default_count: usize,
+    ) -> Vec<&'a Entry> {
+        let count = self.count.unwrap_or(default_count);
+        match self.kind {
+            ContextKind::Recent => entries[entries.len().saturating_sub(count)..]
+                .iter()
+                .collect(),
+            ContextKind::Similar => similar(entries, keyword, count),
+            ContextKind::Pinned => {
+                let pinned: Vec<&Entry> = entries.iter().filter(|entry| entry.pinned).collect();
+                // Without an explicit count, every pinned entry is wanted
+                let count = self.count.unwrap_or(pinned.len());
+                pinned[pinned.len().saturating_sub(count)..].to_vec()
+            }
+            ContextKind::None => Vec::new(),
+        }
+    }
+}
+
+/// Lowercased words of `text`, leaving out numbers such as weights
+fn terms(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty() && !term.chars().all(|c| c.is_ascii_digit()))
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// The `count` entries scoring highest for `keyword` with BM25 over their keyword and prompt,
+/// in chronological order; entries sharing no term with the keyword are never selected
+fn similar<'a>(entries: &'a [Entry], keyword: &str, count: usize) -> Vec<&'a Entry> {
+    let query = terms(keyword);
+    let documents: Vec<Vec<String>> = entries
+        .iter()
+        .map(|entry| terms(&format!("{} {}", entry.keyword, entry.prompt)))
+        .collect();
+    if documents.is_empty() || count == 0 {
+        return Vec::new();
+    }
+    let average_length =
+        documents.iter().map(Vec::len).sum::<usize>() as f64 / documents.len() as f64;
+
+    let mut document_frequency: HashMap<&str, usize> = HashMap::new();
+    for document in &documents {
+        let mut seen: Vec<&str> = document.iter().map(String::as_str).collect();
+        seen.sort_unstable();
+        seen.dedup();
+        for term in seen {
+            *document_frequency.entry(term).or_default() += 1;
+        }
+    }
+
+    let n = documents.len() as f64;
+    let mut scored: Vec<(usize, f64)> = documents
+        .iter()
+        .enumerate()
+        .map(|(index, document)| {
+            let length_norm = 1.0 - B + B * document.len() as f64 / average_length.max(1.0);
+            let score = query
+                .iter()
+                .map(|term| {
+                    let tf = document.iter().filter(|t| *t == term).count() as f64;
+                    let df = document_frequency.get(term.as_str()).copied().unwrap_or(0) as f64;
+                    let idf = ((n - df + 0.5) / (df + 0.5) + 1.0).ln();
+                    idf * tf * (K1 + 1.0) / (tf + K1 * length_norm)
+                })
+                .sum::<f64>();
+            (index, score)
+        })
+        .filter(|&(_, score)| score > 0.0)
+        .collect();
+    // Highest score first; among equal scores the more recent entry wins
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1).then(b.0.cmp(&a.0)));
+    scored.truncate(count);
+    scored.sort_by_key(|&(index, _)| index);
+    scored
+        .into_iter()
+        .map(|(index, _)| &entries[index])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target::Target;
+
+    fn entry(keyword: &str, prompt: &str, pinned: bool) -> Entry {
+        Entry {
+            id: 0,
+            timestamp: 0,
+            keyword: keyword.to_string(),
+            prompt: prompt.to_string(),
+            negative_prompt: String::new(),
+            backend: "mock".to_string(),
+            model: "replay".to_string(),
+            preset: None,
+            target: Target::Sd15,
+            aspect_ratio: None,
+            stylize: None,
+            tokens: Vec::new(),
+            latency_ms: 0,
+            pinned,
+        }
+    }
+
+    fn keywords(entries: Vec<&Entry>) -> Vec<&str> {
+        entries.iter().map(|e| e.keyword.as_str()).collect()
+    }
+
+    fn history() -> Vec<Entry> {
+        vec![
+            entry(
+                "azure knight",
+                "1girl, (azure armor:1.3), knight, castle",
+                true,
+            ),
+            entry("sea witch", "1girl, witch, ocean waves, night", false),
+            entry("cyberpunk street", "neon lights, rain, city street", false),
+            entry(
+                "knight on horseback",
+                "1boy, knight, horse, (plate armor:1.2)",
+                false,
+            ),
+            entry("forest shrine", "shrine, forest, moss, sunbeams", true),
+        ]
+    }
+
+    #[test]
+    fn parses_and_displays_strategies() {
+        for text in ["recent", "recent:3", "similar:5", "pinned", "none"] {
+            assert_eq!(text.parse::<ContextStrategy>().unwrap().to_string(), text);
+        }
+        assert_eq!(
+            " Similar : 2".parse(),
+            Ok(ContextStrategy {
+                kind: ContextKind::Similar,
+                count: Some(2)
+            })
+        );
+        assert!("none:2".parse::<ContextStrategy>().is_err());
+        assert!("recent:x".parse::<ContextStrategy>().is_err());
+        assert!("random".parse::<ContextStrategy>().is_err());
+    }
+
+    #[test]
+    fn selects_recent_and_pinned_entries() {
+        let history = history();
+        let select = |text: &str| {
+            keywords(
+                text.parse::<ContextStrategy>()
+                    .unwrap()
+                    .select(&history, "knight", 2),
+            )
+        };
+        assert_eq!(
+            select("recent"),
+            vec!["knight on horseback", "forest shrine"]
+        );
+        assert_eq!(select("recent:1"), vec!["forest shrine"]);
+        assert_eq!(select("pinned"), vec!["azure knight", "forest shrine"]);
+        assert_eq!(select("pinned:1"), vec!["forest shrine"]);
+        assert!(select("none").is_empty());
+    }
+
+    #[test]
+    fn similar_ranks_related_entries() {
+        let history = history();
+        let similar = ContextStrategy {
+            kind: ContextKind::Similar,
+            count: None,
+        };
+        assert_eq!(
+            keywords(similar.select(&history, "Knight in armor", 2)),
+            vec!["azure knight", "knight on horseback"]
+        );
+        assert_eq!(
+            keywords(similar.select(&history, "rainy street", 5)),
+            vec!["cyberpunk street"]
+        );
+        assert!(similar.select(&history, "dragon", 5).is_empty());
+        assert!(similar.select(&[], "knight", 5).is_empty());
+    }
+}
@@ -0,0 +1,255 @@
+//! Coverage of the eight mandatory prompt component categories.
+//!
+//! Tokens are classified with a local keyword lexicon, so no model call is needed to find out
+//! which categories a generated prompt is missing.
+
+use std::fmt;
+
+use crate::parser;
+use crate::validate::contains_term;
+
+/// Component categories the system instruction requires every prompt to cover
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Category {
+    Subject,
+    Medium,
+    Style,
+    Platform,
+    Quality,
+    Details,
+    Color,
+    Lighting,
+}
+
+impl Category {
+    /// All categories, in the order the system instruction lists them
+    pub const ALL: [Category; 8] = [
+        Category::Subject,
+        Category::Medium,
+        Category::Style,
+        Category::Platform,
+        Category::Quality,
+        Category::Details,
+        Category::Color,
+        Category::Lighting,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Subject => "Subject",
+            Category::Medium => "Medium",
+            Category::Style => "Style",
+            Category::Platform => "Platform",
+            Category::Quality => "Quality",
+            Category::Details => "Details",
+            Category::Color => "Color",
+            Category::Lighting => "Lighting",
+        }
+    }
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Keyword lexicon, checked in order: the first category with a matching term wins.
+/// More specific categories come first, so "volumetric lighting" is Lighting rather than
+/// Details and "blue eyes" is Color rather than Details.
+#[rustfmt::skip]
+const LEXICON: &[(Category, &[&str])] = &[
+    (
+        Category::Quality,
+        &[
+            "masterpiece", "best quality", "high quality", "amazing quality", "normal quality",
+            "absurdres", "superabsurd res", "highres", "high resolution", "4k", "8k", "hdr",
+            "ultra-detailed", "ultra detailed", "highly detailed", "sharp focus",
+            "detailed linework", "very aesthetic", "newest", "wallpaper", "uhd", "high contrast",
+        ],
+    ),
+    (
+        Category::Platform,
+        &[
+            "pixiv", "artstation", "danbooru", "gelbooru", "safebooru", "booru", "deviantart",
+            "trending on", "featured on",
+        ],
+    ),
+    (
+        Category::Lighting,
+        &[
+            "lighting", "light", "lights", "lightrays", "light rays", "god rays", "glow",
+            "glowing", "backlighting", "backlit", "rim light", "volumetric", "lens flare",
+            "sunlight", "moonlight", "chiaroscuro", "bloom", "illuminated", "shadow", "shadows",
+            "golden hour", "twilight", "neon", "dappled",
+        ],
+    ),
+    (
+        Category::Medium,
+        &[
+            "anime screenshot", "screenshot", "digital painting", "illustration", "manga page",
+            "light novel", "cel", "cel shading", "2d", "animation", "key visual", "lineart",
+            "line art", "sketch", "watercolor", "painting", "official art", "artwork",
+            "drawing", "anime coloring", "pixel art", "concept art",
+        ],
+    ),
+    (
+        Category::Style,
+        &[
+            "anime style", "modern anime", "90s anime", "retro anime", "anime aesthetic",
+            "shojo", "shoujo", "shonen", "shounen", "seinen", "ghibli", "shinkai", "chibi",
+            "style", "aesthetic", "kyoto animation", "ufotable", "trigger", "moe", "inspired",
+        ],
+    ),
+    (
+        Category::Color,
+        &[
+            "color", "colors", "colour", "colours", "colorful", "palette", "vibrant", "pastel",
+            "monochrome", "saturated", "red", "blue", "green", "yellow", "purple", "violet",
+            "pink", "black", "white", "silver", "gold", "golden", "orange", "teal", "cyan",
+            "azure", "crimson", "brown", "grey", "gray",
+        ],
+    ),
+    (
+        Category::Subject,
+        &[
+            "1girl", "1boy", "2girls", "2boys", "multiple girls", "multiple boys", "solo",
+            "girl", "boy", "woman", "man", "character", "protagonist", "knight", "samurai",
+            "ninja", "mecha", "robot", "creature", "dragon", "princess", "prince", "witch",
+            "wizard", "warrior", "idol", "maid", "elf", "angel", "demon", "cat", "fox", "wolf",
+            "animal", "couple", "child",
+        ],
+    ),
+    (
+        Category::Details,
+        &[
+            "background", "scenery", "landscape", "outdoors", "indoors", "dress", "uniform",
+            "armor", "clothing", "kimono", "jewelry", "earrings", "gloves", "hat", "holding",
+            "sword", "weapon", "looking at viewer", "smile", "expression", "pose", "dynamic",
+            "angle", "upper body", "full body", "cowboy shot", "from side", "from above",
+            "from below", "profile", "sparkles", "speed lines", "particles", "fire", "wind",
+            "petals", "rain", "snow", "city", "forest", "sky", "clouds", "hair", "eyes",
+            "depth of field", "detailed", "intricate", "epic",
+        ],
+    ),
+];
+
+/// Category of a token's text according to the lexicon, if any term matches
+pub fn classify(text: &str) -> Option<Category> {
+    LEXICON
+        .iter()
+        .find(|(_, terms)| terms.iter().any(|term| contains_term(text, term)))
+        .map(|(category, _)| *category)
+}
+
+/// Per-token classification of a prompt
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Coverage {
+    /// Each token's plain text and its category, in prompt order
+    pub tokens: Vec<(String, Option<Category>)>,
+}
+
+impl Coverage {
+    /// Tokens classified as `category`
+    pub fn tokens_in(&self, category: Category) -> Vec<&str> {
+        self.tokens
+            .iter()
+            .filter(|(_, c)| *c == Some(category))
+            .map(|(text, _)| text.as_str())
+            .collect()
+    }
+
+    /// Tokens no lexicon term matched
+    pub fn unclassified(&self) -> Vec<&str> {
+        self.tokens
+            .iter()
+            .filter(|(_, c)| c.is_none())
+            .map(|(text, _)| text.as_str())
+            .collect()
+    }
+
+    /// Categories without a single token, in [`Category::ALL`] order
+    pub fn missing(&self) -> Vec<Category> {
+        Category::ALL
+            .into_iter()
+            .filter(|&category| !self.tokens.iter().any(|(_, c)| *c == Some(category)))
+            .collect()
+    }
+}
+
+/// Classify every token of the generated prompt `text`
+pub fn analyze(text: &str) -> Coverage {
+    let (prompt, _) = parser::parse(text);
+    let tokens = prompt
+        .segments
+        .iter()
+        .flat_map(|segment| &segment.tokens)
+        .map(|token| {
+            let plain = token.plain_text();
+            let category = classify(&plain);
+            (plain, category)
+        })
+        .collect();
+    Coverage { tokens }
+}
+
+/// Follow-up message asking the model for keywords covering only the `missing` categories
+pub fn fill_request(keyword: &str, text: &str, missing: &[Category]) -> String {
+    let names: Vec<&str> = missing.iter().map(|c| c.name()).collect();
+    format!(
+        "{}\n\nYour prompt for this keyword was:\n{}\n\nIt has no keywords for these mandatory categories: {}.\nReply with ONLY additional comma-separated keywords covering those categories, without repeating the prompt.",
+        keyword,
+        text,
+        names.join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_common_tokens() {
+        assert_eq!(classify("1girl"), Some(Category::Subject));
+        assert_eq!(classify("anime screenshot"), Some(Category::Medium));
+        assert_eq!(classify("Makoto Shinkai style"), Some(Category::Style));
+        assert_eq!(classify("Pixiv"), Some(Category::Platform));
+        assert_eq!(classify("best quality"), Some(Category::Quality));
+        assert_eq!(classify("school uniform"), Some(Category::Details));
+        assert_eq!(classify("blue eyes"), Some(Category::Color));
+        assert_eq!(classify("volumetric lighting"), Some(Category::Lighting));
+        assert_eq!(classify("horikoshi kouhei"), None);
+    }
+
+    #[test]
+    fn reports_missing_categories() {
+        let coverage = analyze("1girl, (blue hair:1.2), cel shading, masterpiece, BREAK, castle");
+        assert_eq!(coverage.tokens_in(Category::Subject), vec!["1girl"]);
+        assert_eq!(coverage.tokens_in(Category::Color), vec!["blue hair"]);
+        assert_eq!(coverage.unclassified(), vec!["castle"]);
+        assert_eq!(
+            coverage.missing(),
+            vec![
+                Category::Style,
+                Category::Platform,
+                Category::Details,
+                Category::Lighting
+            ]
+        );
+    }
+
+    #[test]
+    fn full_prompt_covers_everything() {
+        let coverage = analyze(
+            "1girl, anime screenshot, modern anime style, pixiv, masterpiece, cherry blossom petals, pastel palette, rim lighting",
+        );
+        assert!(coverage.missing().is_empty());
+    }
+
+    #[test]
+    fn fill_request_names_only_missing_categories() {
+        let message = fill_request("knight", "1boy", &[Category::Platform, Category::Lighting]);
+        assert!(message.starts_with("knight\n"));
+        assert!(message.contains("mandatory categories: Platform, Lighting."));
+    }
+}
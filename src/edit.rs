@@ -0,0 +1,130 @@
+//! Local edits of a generated prompt, made without asking the model again.
+//!
+//! Tokens are matched by their plain text: a term matches a token that contains it as a whole
+//! word or word sequence, ignoring case and weights.
+
+use crate::parser::{self, Group, GroupKind, Node, Token};
+use crate::validate::contains_term;
+
+/// `token` with its grouping replaced by the single explicit `weight`; a weight of `1.0` leaves
+/// the plain text
+pub fn reweight(token: &Token, weight: f32) -> Token {
+    let plain = Token {
+        nodes: vec![Node::Text(token.plain_text().trim().to_string())],
+    };
+    if parser::format_weight(weight) == "1.0" {
+        return plain;
+    }
+    Token {
+        nodes: vec![Node::Group(Group {
+            kind: GroupKind::Paren,
+            weight: Some(weight),
+            tokens: vec![plain],
+        })],
+    }
+}
+
+fn matches(token: &Token, term: &str) -> bool {
+    contains_term(&token.plain_text(), term.trim())
+}
+
+/// `text` with every token matching `term` set to `weight`, or `None` if none matches
+pub fn set_weight(text: &str, term: &str, weight: f32) -> Option<String> {
+    let (mut prompt, _) = parser::parse(text);
+    let mut found = false;
+    for token in prompt.segments.iter_mut().flat_map(|s| &mut s.tokens) {
+        if matches(token, term) {
+            *token = reweight(token, weight);
+            found = true;
+        }
+    }
+    found.then(|| prompt.to_string())
+}
+
+/// `text` without the tokens matching `term`, or `None` if none matches
+pub fn drop_term(text: &str, term: &str) -> Option<String> {
+    let (mut prompt, _) = parser::parse(text);
+    let before: usize = prompt.segments.iter().map(|s| s.tokens.len()).sum();
+    for segment in &mut prompt.segments {
+        segment.tokens.retain(|token| !matches(token, term));
+    }
+    prompt.segments.retain(|segment| !segment.tokens.is_empty());
+    let after: usize = prompt.segments.iter().map(|s| s.tokens.len()).sum();
+    (after < before).then(|| prompt.to_string())
+}
+
+/// Number of tokens in `text`, across all segments
+pub fn token_count(text: &str) -> usize {
+    let (prompt, _) = parser::parse(text);
+    prompt.segments.iter().map(|s| s.tokens.len()).sum()
+}
+
+/// `text` with the weight of token `index`, counted across segments, changed by `delta` and
+/// kept within `range`, or `None` if there is no such token
+pub fn adjust_weight(text: &str, index: usize, delta: f32, range: (f32, f32)) -> Option<String> {
+    let (mut prompt, _) = parser::parse(text);
+    let token = prompt
+        .segments
+        .iter_mut()
+        .flat_map(|s| &mut s.tokens)
+        .nth(index)?;
+    let weight = ((token.weight() + delta) * 100.0).round() / 100.0;
+    *token = reweight(token, weight.clamp(range.0, range.1));
+    Some(prompt.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROMPT: &str = "1girl, ((sparkles)), night sky, BREAK, (photorealism:1.2), [realism]";
+
+    #[test]
+    fn sets_the_weight_of_matching_tokens() {
+        assert_eq!(
+            set_weight(PROMPT, "Sparkles", 0.6).unwrap(),
+            "1girl, (sparkles:0.6), night sky, BREAK, (photorealism:1.2), [realism]"
+        );
+        assert_eq!(
+            set_weight(PROMPT, "realism", 1.0).unwrap(),
+            "1girl, ((sparkles)), night sky, BREAK, (photorealism:1.2), realism"
+        );
+        assert_eq!(
+            set_weight(PROMPT, "sky", 1.25)
+                .unwrap()
+                .matches(":1.25")
+                .count(),
+            1
+        );
+        assert_eq!(set_weight(PROMPT, "spark", 0.6), None);
+    }
+
+    #[test]
+    fn drops_matching_tokens_and_empty_segments() {
+        assert_eq!(
+            drop_term(PROMPT, "realism").unwrap(),
+            "1girl, ((sparkles)), night sky, BREAK, (photorealism:1.2)"
+        );
+        assert_eq!(drop_term("1girl, BREAK, moon", "moon").unwrap(), "1girl");
+        assert_eq!(drop_term(PROMPT, "sun"), None);
+    }
+
+    #[test]
+    fn adjusts_weights_by_index() {
+        let range = (0.1, 2.0);
+        assert_eq!(token_count(PROMPT), 5);
+        assert_eq!(
+            adjust_weight(PROMPT, 1, 0.05, range).unwrap(),
+            "1girl, (sparkles:1.26), night sky, BREAK, (photorealism:1.2), [realism]"
+        );
+        assert_eq!(
+            adjust_weight(PROMPT, 3, 1.0, range).unwrap(),
+            "1girl, ((sparkles)), night sky, BREAK, (photorealism:2.0), [realism]"
+        );
+        assert_eq!(
+            adjust_weight("1girl, (moon:1.05)", 1, -0.05, range).unwrap(),
+            "1girl, moon"
+        );
+        assert_eq!(adjust_weight(PROMPT, 5, 0.1, range), None);
+    }
+}
@@ -0,0 +1,623 @@
+//! Prompt generation: one model call plus the completion and validation passes, or several
+//! parallel calls for distinct variants.
+
+use std::time::Duration;
+
+use futures::future::join_all;
+
+use crate::backend::{Message, OnText, PromptBackend};
+use crate::clip::{self, ClipTokenizer, TokenReport};
+use crate::config::{Config, Source};
+use crate::context::ContextStrategy;
+use crate::history::{self, Entry, History};
+use crate::negative::{self, NegativeMode};
+use crate::target::Dialect;
+use crate::validate::{self, ValidationMode, Validator};
+use crate::{coverage, instruction, parser, variants};
+
+/// Instruction, context and post-processing applied to every generated prompt
+#[derive(Debug, Clone)]
+pub struct GenerationSettings {
+    /// System instruction the history context is appended to
+    pub system_instruction: String,
+    /// Preset the system instruction comes from, if any
+    pub preset: Option<String>,
+    /// Character descriptions the prompts must stay consistent with
+    pub descriptors: Vec<String>,
+    /// Image model the prompt is written for
+    pub dialect: Dialect,
+    /// Negative prompt the composed one starts from
+    pub negative_prompt: String,
+    /// How the negative prompt is composed
+    pub negative_mode: NegativeMode,
+    /// Asks for one of several distinct variants, see [`variants::note`]
+    pub variation: Option<String>,
+    /// Which previous prompts are sent as context
+    pub context: ContextStrategy,
+    /// Number of previous prompts sent as context when `context` doesn't say
+    pub history_depth: usize,
+    pub validator: Validator,
+    /// Ask the model for keywords covering component categories the prompt is missing
+    pub fill_missing: bool,
+}
+
+impl GenerationSettings {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            system_instruction: config.system_instruction.value.clone(),
+            preset: match &config.system_instruction.source {
+                Source::Preset(name) => Some(name.clone()),
+                _ => None,
+            },
+            descriptors: config.descriptors.value.clone(),
+            dialect: config.dialect(),
+            negative_prompt: config.negative_prompt.value.clone(),
+            negative_mode: config.negative_mode.value,
+            variation: None,
+            context: config.context.value,
+            history_depth: config.history_depth.value,
+            validator: Validator {
+                mode: config.validate.value,
+                max_retries: config.max_retries.value,
+                rules: config.validation_rules(),
+            },
+            fill_missing: config.fill_missing.value,
+        }
+    }
+}
+
+impl Default for GenerationSettings {
+    fn default() -> Self {
+        Self::from_config(&Config::default())
+    }
+}
+
+/// A generated prompt and the negative prompt composed for it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Generated {
+    pub text: String,
+    pub negative_prompt: String,
+}
+
+/// Ask `backend` for a prompt for `keyword`, giving it previously generated prompts chosen by the
+/// context strategy, then complete and check the result and compose its negative prompt
+/// according to `settings`
+pub async fn generate(
+    backend: &dyn PromptBackend,
+    history: &History,
+    settings: &GenerationSettings,
+    keyword: &str,
+) -> Result<Generated, Box<dyn std::error::Error>> {
+    let system_instruction = system_instruction(history, settings, keyword);
+    let response = backend.generate(&system_instruction, keyword).await?;
+    complete(backend, settings, &system_instruction, keyword, &response).await
+}
+
+/// Like [`generate`], passing the model's reply to `on_text` as it streams in. Only the first
+/// reply is streamed; the result is completed and checked once it is whole.
+pub async fn generate_streaming(
+    backend: &dyn PromptBackend,
+    history: &History,
+    settings: &GenerationSettings,
+    keyword: &str,
+    on_text: &mut OnText<'_>,
+) -> Result<Generated, Box<dyn std::error::Error>> {
+    let system_instruction = system_instruction(history, settings, keyword);
+    let response = backend
+        .chat_stream(&system_instruction, &[Message::user(keyword)], on_text)
+        .await?;
+    complete(backend, settings, &system_instruction, keyword, &response).await
+}
+
+/// System instruction for `keyword`: the configured one with the target's and the negative
+/// prompt's notes, the descriptors and the previous prompts chosen by the context strategy
+pub fn system_instruction(
+    history: &History,
+    settings: &GenerationSettings,
+    keyword: &str,
+) -> String {
+    let context: Vec<&str> = settings
+        .context
+        .select(history.entries(), keyword, settings.history_depth)
+        .into_iter()
+        .map(|entry| entry.prompt.as_str())
+        .collect();
+    let mut notes: Vec<&str> = settings.dialect.target.instruction().into_iter().collect();
+    if settings.negative_mode == NegativeMode::Llm {
+        notes.push(negative::LLM_INSTRUCTION);
+    }
+    if let Some(variation) = &settings.variation {
+        notes.push(variation);
+    }
+    instruction::build_system_instruction(
+        &settings.system_instruction,
+        &notes,
+        &settings.descriptors,
+        &context,
+    )
+}
+
+/// Turn `response`, the model's answer for `keyword` under `system_instruction`, into the final
+/// prompt: fill in missing categories, validate and repair it, and compose its negative prompt
+pub async fn complete(
+    backend: &dyn PromptBackend,
+    settings: &GenerationSettings,
+    system_instruction: &str,
+    keyword: &str,
+    response: &str,
+) -> Result<Generated, Box<dyn std::error::Error>> {
+    let validator = &settings.validator;
+    let (mut text, mut suggested) = negative::split_response(response);
+
+    // === CATEGORY COVERAGE ===
+    if settings.fill_missing {
+        let missing = coverage::analyze(&text).missing();
+        if !missing.is_empty() {
+            let names: Vec<&str> = missing.iter().map(|c| c.name()).collect();
+            eprintln!("Asking the model to fill in: {}", names.join(", "));
+            let request = coverage::fill_request(keyword, &text, &missing);
+            let (addition, _) =
+                negative::split_response(&backend.generate(system_instruction, &request).await?);
+            let addition = addition.trim().trim_matches(',').trim();
+            if !addition.is_empty() {
+                text = format!("{}, {}", text.trim().trim_end_matches(','), addition);
+            }
+        }
+    }
+
+    // === VALIDATION ===
+    let mut problems = validate::validate(&text, &validator.rules);
+    if validator.mode == ValidationMode::Retry {
+        let mut attempt = 0;
+        while !problems.is_empty() && attempt < validator.max_retries {
+            attempt += 1;
+            eprintln!(
+                "Generated prompt has {} problem(s), asking the model to fix them (attempt {}/{})",
+                problems.len(),
+                attempt,
+                validator.max_retries
+            );
+            let message = validate::retry_message(keyword, &text, &problems);
+            let (fixed, negative) =
+                negative::split_response(&backend.generate(system_instruction, &message).await?);
+            text = fixed;
+            suggested = negative.or(suggested);
+            problems = validate::validate(&text, &validator.rules);
+        }
+    }
+
+    if !problems.is_empty() {
+        for problem in &problems {
+            eprintln!("Warning: {}", problem);
+        }
+        if validator.mode != ValidationMode::Off {
+            eprintln!("Repairing the generated prompt");
+            text = validate::repair(&text, &validator.rules);
+        }
+    }
+
+    // === NEGATIVE PROMPT ===
+    let negative_prompt = negative::compose(
+        settings.negative_mode,
+        &settings.negative_prompt,
+        settings.dialect.target,
+        keyword,
+        &text,
+        suggested.as_deref(),
+    );
+    Ok(Generated {
+        text,
+        negative_prompt,
+    })
+}
+
+/// Generate `count` variants for `keyword` with parallel calls, each asked to vary the prompt
+/// according to `diversity`, and drop the near duplicates. Failed calls are reported and
+/// skipped; only when every call fails is the first error returned.
+pub async fn generate_variants(
+    backend: &dyn PromptBackend,
+    history: &History,
+    settings: &GenerationSettings,
+    keyword: &str,
+    count: usize,
+    diversity: f32,
+) -> Result<Vec<Generated>, Box<dyn std::error::Error>> {
+    if count <= 1 {
+        return Ok(vec![generate(backend, history, settings, keyword).await?]);
+    }
+    let settings: Vec<GenerationSettings> = (0..count)
+        .map(|i| GenerationSettings {
+            variation: Some(variants::note(i, count, diversity)),
+            ..settings.clone()
+        })
+        .collect();
+    let results = join_all(
+        settings
+            .iter()
+            .map(|settings| generate(backend, history, settings, keyword)),
+    )
+    .await;
+
+    let mut generated = Vec::new();
+    let mut first_error = None;
+    for (i, result) in results.into_iter().enumerate() {
+        match result {
+            Ok(variant) => generated.push(variant),
+            Err(e) => {
+                eprintln!("Warning: variant {} failed: {}", i + 1, e);
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    if let (true, Some(e)) = (generated.is_empty(), first_error) {
+        return Err(e);
+    }
+    let texts: Vec<&str> = generated.iter().map(|g| g.text.as_str()).collect();
+    let kept = variants::distinct(&texts);
+    if kept.len() < generated.len() {
+        eprintln!(
+            "Dropped {} near-duplicate variant(s)",
+            generated.len() - kept.len()
+        );
+    }
+    Ok(kept.into_iter().map(|i| generated[i].clone()).collect())
+}
+
+/// History entry for the prompt `fitted`, generated by `backend` for `keyword` in `latency`
+pub fn history_entry(
+    backend: &dyn PromptBackend,
+    settings: &GenerationSettings,
+    keyword: &str,
+    fitted: &Fitted,
+    negative_prompt: &str,
+    latency: Duration,
+) -> Entry {
+    Entry {
+        id: 0,
+        timestamp: history::now(),
+        keyword: keyword.to_string(),
+        prompt: fitted.text.clone(),
+        negative_prompt: negative_prompt.to_string(),
+        backend: backend.name().to_string(),
+        model: backend.model().to_string(),
+        preset: settings.preset.clone(),
+        target: settings.dialect.target,
+        aspect_ratio: settings.dialect.aspect_ratio,
+        stylize: settings.dialect.stylize,
+        tokens: fitted.tokens.segments.clone(),
+        latency_ms: latency.as_millis().try_into().unwrap_or(u64::MAX),
+        pinned: false,
+    }
+}
+
+/// A prompt fitted to the CLIP token budget
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fitted {
+    pub text: String,
+    /// Plain text of the tokens trimmed to fit, in removal order
+    pub removed: Vec<String>,
+    pub tokens: TokenReport,
+}
+
+/// Trim `text` to `max_tokens` CLIP tokens, if set, and count the tokens of the result.
+/// The text is only reserialized when something was removed.
+pub fn fit_token_budget(
+    text: &str,
+    tokenizer: &ClipTokenizer,
+    max_tokens: Option<usize>,
+) -> Fitted {
+    let (mut prompt, _) = parser::parse(text);
+    let removed = match max_tokens {
+        Some(max_tokens) => clip::trim_to_budget(&mut prompt, tokenizer, max_tokens),
+        None => Vec::new(),
+    };
+    let text = if removed.is_empty() {
+        text.to_string()
+    } else {
+        prompt.to_string()
+    };
+    Fitted {
+        text,
+        removed,
+        tokens: clip::analyze(&prompt, tokenizer),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MockBackend;
+    use crate::validate::ValidationRules;
+    use std::collections::HashMap;
+    use std::env;
+
+    fn scratch_history(name: &str) -> History {
+        let dir = env::temp_dir().join(format!("promptflow-main-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        History::load(dir.join(history::HISTORY_FILE))
+    }
+
+    #[tokio::test]
+    async fn sends_recent_prompts_as_context() {
+        let backend = MockBackend::new(HashMap::from([
+            ("old".to_string(), "1girl, old".to_string()),
+            ("knight".to_string(), "1boy, (armor:1.2)".to_string()),
+        ]));
+        let mut history = scratch_history("context");
+        let settings = GenerationSettings::default();
+        let tokenizer = ClipTokenizer::estimating();
+
+        let text = generate(&backend, &history, &settings, "old")
+            .await
+            .unwrap()
+            .text;
+        let fitted = fit_token_budget(&text, &tokenizer, None);
+        let entry = history_entry(
+            &backend,
+            &settings,
+            "old",
+            &fitted,
+            "lowres",
+            Duration::from_millis(42),
+        );
+        assert_eq!(entry.prompt, "1girl, old");
+        assert_eq!(entry.backend, "mock");
+        assert_eq!(entry.preset.as_deref(), Some("anime"));
+        assert_eq!(entry.tokens, fitted.tokens.segments);
+        assert_eq!(entry.latency_ms, 42);
+        history.record(entry, &Default::default()).unwrap();
+
+        let text = generate(&backend, &history, &settings, "knight")
+            .await
+            .unwrap()
+            .text;
+        assert_eq!(text, "1boy, (armor:1.2)");
+        let calls = backend.calls();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[1].keyword, "knight");
+        assert!(
+            calls[1]
+                .system_instruction
+                .ends_with("**Previous Generated Prompts:**\n1girl, old")
+        );
+    }
+
+    #[tokio::test]
+    async fn context_strategy_picks_the_prompts() {
+        let backend = MockBackend::new(HashMap::new());
+        let mut history = scratch_history("strategy");
+        let tokenizer = ClipTokenizer::estimating();
+        for keyword in ["azure knight", "sea witch", "forest shrine"] {
+            let text = format!("1girl, {}", keyword);
+            let fitted = fit_token_budget(&text, &tokenizer, None);
+            let entry = history_entry(
+                &backend,
+                &GenerationSettings::default(),
+                keyword,
+                &fitted,
+                "",
+                Duration::ZERO,
+            );
+            history.record(entry, &Default::default()).unwrap();
+        }
+
+        for (context, expected) in [
+            ("similar:1", "\n1girl, azure knight"),
+            ("recent:2", "\n1girl, sea witch\n1girl, forest shrine"),
+            ("none", "**Previous Generated Prompts:**\n"),
+        ] {
+            let settings = GenerationSettings {
+                context: context.parse().unwrap(),
+                validator: Validator {
+                    mode: ValidationMode::Off,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            generate(&backend, &history, &settings, "knight")
+                .await
+                .unwrap();
+            let calls = backend.calls();
+            let instruction = &calls.last().unwrap().system_instruction;
+            assert!(
+                instruction.ends_with(expected),
+                "{}: {}",
+                context,
+                instruction
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn fix_mode_repairs_locally() {
+        let backend = MockBackend::new(HashMap::from([(
+            "knight".to_string(),
+            "1boy, (armor:5), photorealistic".to_string(),
+        )]));
+        let history = scratch_history("fix");
+
+        let text = generate(&backend, &history, &GenerationSettings::default(), "knight")
+            .await
+            .unwrap()
+            .text;
+        assert_eq!(text, "1boy, (armor:2.0)");
+        assert_eq!(backend.calls().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn off_mode_leaves_prompt_untouched() {
+        let backend = MockBackend::new(HashMap::from([(
+            "knight".to_string(),
+            "1boy, (armor:5)".to_string(),
+        )]));
+        let history = scratch_history("off");
+        let settings = GenerationSettings {
+            validator: Validator {
+                mode: ValidationMode::Off,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let text = generate(&backend, &history, &settings, "knight")
+            .await
+            .unwrap()
+            .text;
+        assert_eq!(text, "1boy, (armor:5)");
+    }
+
+    #[tokio::test]
+    async fn retry_mode_re_asks_with_problems() {
+        let rules = ValidationRules::default();
+        let retry = validate::retry_message(
+            "knight",
+            "1boy, (armor:5)",
+            &validate::validate("1boy, (armor:5)", &rules),
+        );
+        let backend = MockBackend::new(HashMap::from([
+            ("knight".to_string(), "1boy, (armor:5)".to_string()),
+            (retry, "1boy, (armor:1.4)".to_string()),
+        ]));
+        let history = scratch_history("retry");
+        let settings = GenerationSettings {
+            validator: Validator {
+                mode: ValidationMode::Retry,
+                max_retries: 2,
+                rules,
+            },
+            ..Default::default()
+        };
+
+        let text = generate(&backend, &history, &settings, "knight")
+            .await
+            .unwrap()
+            .text;
+        assert_eq!(text, "1boy, (armor:1.4)");
+        let calls = backend.calls();
+        assert_eq!(calls.len(), 2);
+        assert!(calls[1].keyword.starts_with("knight\n"));
+        assert!(
+            calls[1]
+                .keyword
+                .contains("weight 5.0 on (armor) is outside the allowed range")
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_mode_falls_back_to_repair() {
+        let backend = MockBackend::new(HashMap::from([(
+            "knight".to_string(),
+            "1boy, (armor:5)".to_string(),
+        )]));
+        let history = scratch_history("retry-repair");
+        let settings = GenerationSettings {
+            validator: Validator {
+                mode: ValidationMode::Retry,
+                max_retries: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let text = generate(&backend, &history, &settings, "knight")
+            .await
+            .unwrap()
+            .text;
+        assert_eq!(text, "1boy, (armor:2.0)");
+        assert_eq!(backend.calls().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn fill_missing_appends_requested_categories() {
+        let prompt =
+            "1girl, anime screenshot, anime style, masterpiece, school uniform, pastel palette";
+        let request = coverage::fill_request(
+            "schoolgirl",
+            prompt,
+            &[coverage::Category::Platform, coverage::Category::Lighting],
+        );
+        let backend = MockBackend::new(HashMap::from([
+            ("schoolgirl".to_string(), prompt.to_string()),
+            (request, "pixiv, soft rim lighting,".to_string()),
+        ]));
+        let history = scratch_history("fill");
+        let settings = GenerationSettings {
+            fill_missing: true,
+            ..Default::default()
+        };
+
+        let text = generate(&backend, &history, &settings, "schoolgirl")
+            .await
+            .unwrap()
+            .text;
+        assert_eq!(text, format!("{}, pixiv, soft rim lighting", prompt));
+        assert!(coverage::analyze(&text).missing().is_empty());
+        assert_eq!(backend.calls().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn llm_mode_takes_the_models_negative_prompt() {
+        let backend = MockBackend::new(HashMap::from([(
+            "knight".to_string(),
+            "1boy, (armor:1.2)\nNegative prompt: rusty armor, lowres".to_string(),
+        )]));
+        let history = scratch_history("negative");
+        let settings = GenerationSettings {
+            negative_prompt: "lowres, blurry".to_string(),
+            negative_mode: NegativeMode::Llm,
+            ..Default::default()
+        };
+
+        let generated = generate(&backend, &history, &settings, "knight")
+            .await
+            .unwrap();
+        assert_eq!(generated.text, "1boy, (armor:1.2)");
+        assert_eq!(generated.negative_prompt, "lowres, blurry, rusty armor");
+        assert!(
+            backend.calls()[0]
+                .system_instruction
+                .contains(negative::LLM_INSTRUCTION)
+        );
+    }
+
+    #[tokio::test]
+    async fn variants_are_told_apart_and_deduplicated() {
+        let backend = MockBackend::new(HashMap::from([(
+            "knight".to_string(),
+            "1boy, (armor:1.2), castle".to_string(),
+        )]));
+        let history = scratch_history("variants");
+        let settings = GenerationSettings::default();
+
+        let generated = generate_variants(&backend, &history, &settings, "knight", 3, 0.9)
+            .await
+            .unwrap();
+        // The mock answers every call alike, so only the first variant survives
+        assert_eq!(generated.len(), 1);
+        let calls = backend.calls();
+        assert_eq!(calls.len(), 3);
+        assert!(
+            calls[2]
+                .system_instruction
+                .contains("**Variation 3 of 3:**")
+        );
+        assert!(
+            calls[2]
+                .system_instruction
+                .contains("above all the color palette")
+        );
+    }
+
+    #[test]
+    fn token_budget_only_rewrites_when_trimming() {
+        let tokenizer = ClipTokenizer::estimating();
+        let text = "1girl,  (armor:1.2), castle";
+        let fitted = fit_token_budget(text, &tokenizer, None);
+        assert_eq!(fitted.text, text);
+        assert!(fitted.removed.is_empty());
+
+        let fitted = fit_token_budget(text, &tokenizer, Some(fitted.tokens.total() - 1));
+        assert_eq!(fitted.removed, vec!["castle"]);
+        assert_eq!(fitted.text, "1girl, (armor:1.2)");
+    }
+}
@@ -0,0 +1,439 @@
+//! Prompt history: every generation with its result and metadata, kept as JSON Lines.
+//!
+//! The most recent generated prompts are given to the model as context. Old entries are dropped
+//! according to a [`Retention`] policy whenever a new one is recorded.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::target::{Dialect, Target};
+
+/// Number of previous prompts included in the system instruction
+pub const HISTORY_DEPTH: usize = 5;
+
+/// Number of entries kept by default
+pub const MAX_ENTRIES: usize = 1000;
+
+/// File name of the history inside the data directory
+pub const HISTORY_FILE: &str = "history.jsonl";
+
+/// Default location of the history file, `None` if no data directory can be determined
+pub fn history_path() -> Option<PathBuf> {
+    config::data_dir().map(|dir| dir.join(HISTORY_FILE))
+}
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// `secs` since the Unix epoch as UTC `(year, month, day, hour, minute, second)`
+fn civil(secs: u64) -> (i64, u32, u32, u32, u32, u32) {
+    // Days to civil date, from Howard Hinnant's `civil_from_days`
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let time = secs % 86_400;
+    (
+        year,
+        month,
+        day,
+        (time / 3600) as u32,
+        (time / 60 % 60) as u32,
+        (time % 60) as u32,
+    )
+}
+
+/// `secs` since the Unix epoch as `YYYY-MM-DD HH:MM` in UTC
+pub fn format_time(secs: u64) -> String {
+    let (year, month, day, hour, minute, _) = civil(secs);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year, month, day, hour, minute
+    )
+}
+
+/// `secs` since the Unix epoch as an RFC 3339 UTC timestamp
+pub fn format_rfc3339(secs: u64) -> String {
+    let (year, month, day, hour, minute, second) = civil(secs);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, hour, minute, second
+    )
+}
+
+/// One generation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Number identifying the entry in `history` commands, assigned when it is recorded
+    pub id: u64,
+    /// When the prompt was generated, in seconds since the Unix epoch
+    pub timestamp: u64,
+    pub keyword: String,
+    /// Prompt in Stable Diffusion syntax; [`Entry::dialect`] formats it for `target`
+    pub prompt: String,
+    pub negative_prompt: String,
+    pub backend: String,
+    pub model: String,
+    /// Built-in preset the system instruction came from, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    /// Image model the prompt was written for
+    #[serde(default)]
+    pub target: Target,
+    /// Midjourney `--ar W:H`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aspect_ratio: Option<(u32, u32)>,
+    /// Midjourney `--stylize N`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stylize: Option<u32>,
+    /// CLIP token count of each BREAK segment of the prompt
+    pub tokens: Vec<usize>,
+    /// Time the backend took, including retries, in milliseconds
+    pub latency_ms: u64,
+    /// Favorite kept as context by the `pinned` strategy
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+}
+
+impl Entry {
+    /// Target and parameters the prompt is formatted with on output
+    pub fn dialect(&self) -> Dialect {
+        Dialect {
+            target: self.target,
+            aspect_ratio: self.aspect_ratio,
+            stylize: self.stylize,
+        }
+    }
+}
+
+/// Which entries are dropped when the history grows
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Retention {
+    /// Most entries kept, `None` for no limit
+    pub max_entries: Option<usize>,
+    /// Entries older than this are dropped, `None` to keep them regardless of age
+    pub max_age: Option<Duration>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_entries: Some(MAX_ENTRIES),
+            max_age: None,
+        }
+    }
+}
+
+impl Retention {
+    /// Index of the first entry of `entries` (oldest first) to keep at time `now`
+    fn first_kept(&self, entries: &[Entry], now: u64) -> usize {
+        let by_count = self
+            .max_entries
+            .map_or(0, |max| entries.len().saturating_sub(max));
+        let by_age = self.max_age.map_or(0, |age| {
+            let cutoff = now.saturating_sub(age.as_secs());
+            entries.partition_point(|entry| entry.timestamp < cutoff)
+        });
+        by_count.max(by_age)
+    }
+}
+
+/// File next to the history at `path` holding the highest id issued
+fn last_id_path(path: &Path) -> PathBuf {
+    path.with_extension("last-id")
+}
+
+/// History file with one JSON entry per line, oldest first
+pub struct History {
+    path: PathBuf,
+    entries: Vec<Entry>,
+    /// Highest id ever issued, so ids of removed entries are never reused
+    last_id: u64,
+}
+
+impl History {
+    /// Load the history at `path`, starting empty if it doesn't exist or can't be read; lines
+    /// that aren't valid entries are skipped
+    pub fn load(path: PathBuf) -> Self {
+        let entries = std::fs::read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Entry>(line).ok())
+            .collect::<Vec<_>>();
+        let counted = std::fs::read_to_string(last_id_path(&path))
+            .ok()
+            .and_then(|text| text.trim().parse().ok())
+            .unwrap_or(0);
+        let last_id = entries.iter().map(|e| e.id).fold(counted, u64::max);
+        Self {
+            path,
+            entries,
+            last_id,
+        }
+    }
+
+    /// Every entry, oldest first
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// The entry with `id`
+    pub fn get(&self, id: u64) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+
+    /// Entries whose keyword or prompt contains every word of `query`, ignoring case
+    pub fn search(&self, query: &str) -> Vec<&Entry> {
+        let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+        self.entries
+            .iter()
+            .filter(|entry| {
+                let text = format!("{}\n{}", entry.keyword, entry.prompt).to_lowercase();
+                terms.iter().all(|term| text.contains(term.as_str()))
+            })
+            .collect()
+    }
+
+    /// Append `entry` under a new id, dropping the entries `retention` no longer keeps
+    pub fn record(&mut self, mut entry: Entry, retention: &Retention) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        entry.id = self.last_id + 1;
+        self.last_id = entry.id;
+        self.save_last_id()?;
+        self.entries.push(entry);
+        let first_kept = retention.first_kept(&self.entries, now());
+        if first_kept == 0 {
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            let line = serde_json::to_string(self.entries.last().unwrap())?;
+            return writeln!(file, "{}", line);
+        }
+        self.entries.drain(..first_kept);
+        self.save()
+    }
+
+    /// Remember the highest id issued, which the entries alone don't tell once the newest is
+    /// removed
+    fn save_last_id(&self) -> std::io::Result<()> {
+        std::fs::write(last_id_path(&self.path), format!("{}\n", self.last_id))
+    }
+
+    /// Rewrite the whole file through a temporary file, so it is never left half written
+    fn save(&self) -> std::io::Result<()> {
+        let mut text = String::new();
+        for entry in &self.entries {
+            text.push_str(&serde_json::to_string(entry)?);
+            text.push('\n');
+        }
+        let tmp = self.path.with_extension("jsonl.tmp");
+        std::fs::write(&tmp, text)?;
+        std::fs::rename(&tmp, &self.path)
+    }
+
+    /// Delete the entries with the given `ids`, returning how many there were
+    pub fn remove(&mut self, ids: &[u64]) -> std::io::Result<usize> {
+        let before = self.entries.len();
+        self.entries.retain(|entry| !ids.contains(&entry.id));
+        let removed = before - self.entries.len();
+        if removed > 0 {
+            self.save_last_id()?;
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    /// Pin or unpin the entries with the given `ids`, returning how many there were
+    pub fn set_pinned(&mut self, ids: &[u64], pinned: bool) -> std::io::Result<usize> {
+        let mut changed = 0;
+        for entry in &mut self.entries {
+            if ids.contains(&entry.id) {
+                entry.pinned = pinned;
+                changed += 1;
+            }
+        }
+        if changed > 0 {
+            self.save()?;
+        }
+        Ok(changed)
+    }
+
+    /// Delete every entry, removing the file
+    pub fn clear(&mut self) -> std::io::Result<()> {
+        if self.last_id > 0 {
+            self.save_last_id()?;
+        }
+        self.entries.clear();
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// The `n` most recent entries in chronological order
+    pub fn recent(&self, n: usize) -> &[Entry] {
+        &self.entries[self.entries.len().saturating_sub(n)..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "promptflow-history-{}-{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::remove_dir_all(&dir).ok();
+        dir.join("data").join(HISTORY_FILE)
+    }
+
+    fn entry(keyword: &str, timestamp: u64) -> Entry {
+        Entry {
+            id: 0,
+            timestamp,
+            keyword: keyword.to_string(),
+            prompt: format!("1girl, {}", keyword),
+            negative_prompt: "lowres".to_string(),
+            backend: "mock".to_string(),
+            model: "replay".to_string(),
+            preset: Some("anime".to_string()),
+            target: Target::Sd15,
+            aspect_ratio: None,
+            stylize: None,
+            tokens: vec![4],
+            latency_ms: 12,
+            pinned: false,
+        }
+    }
+
+    #[test]
+    fn records_and_returns_recent_entries() {
+        let path = temp_path("recent");
+        let mut history = History::load(path.clone());
+        assert!(history.recent(HISTORY_DEPTH).is_empty());
+        for keyword in ["a", "b", "c", "d", "e", "f"] {
+            history
+                .record(entry(keyword, now()), &Retention::default())
+                .unwrap();
+        }
+        let keywords: Vec<&str> = history
+            .recent(HISTORY_DEPTH)
+            .iter()
+            .map(|e| e.keyword.as_str())
+            .collect();
+        assert_eq!(keywords, vec!["b", "c", "d", "e", "f"]);
+
+        let mut reloaded = History::load(path.clone());
+        assert_eq!(reloaded.entries(), history.entries());
+        assert_eq!(reloaded.recent(1)[0].prompt, "1girl, f");
+        assert_eq!(reloaded.recent(1)[0].id, 6);
+
+        reloaded.clear().unwrap();
+        assert!(reloaded.recent(HISTORY_DEPTH).is_empty());
+        assert!(!path.exists());
+        reloaded.clear().unwrap();
+
+        // Ids of cleared entries aren't given out again
+        let mut reloaded = History::load(path);
+        reloaded
+            .record(entry("g", now()), &Retention::default())
+            .unwrap();
+        assert_eq!(reloaded.entries()[0].id, 7);
+    }
+
+    #[test]
+    fn skips_unreadable_lines() {
+        let path = temp_path("corrupt");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let line = serde_json::to_string(&entry("knight", 1)).unwrap();
+        std::fs::write(&path, format!("old keyword\n{}\n{{\"trunc", line)).unwrap();
+        let history = History::load(path);
+        assert_eq!(history.entries(), &[entry("knight", 1)]);
+    }
+
+    #[test]
+    fn searches_and_removes_by_id() {
+        let path = temp_path("search");
+        let mut history = History::load(path.clone());
+        for keyword in ["Azure Knight", "sea witch", "night market"] {
+            history
+                .record(entry(keyword, now()), &Retention::default())
+                .unwrap();
+        }
+        let ids = |entries: Vec<&Entry>| entries.iter().map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(ids(history.search("knight")), vec![1]);
+        assert_eq!(ids(history.search("1GIRL ni")), vec![1, 3]);
+        assert_eq!(ids(history.search("")), vec![1, 2, 3]);
+        assert_eq!(history.get(2).unwrap().keyword, "sea witch");
+
+        assert_eq!(history.set_pinned(&[3], true).unwrap(), 1);
+        assert!(History::load(path.clone()).get(3).unwrap().pinned);
+        assert_eq!(history.remove(&[2, 7]).unwrap(), 1);
+        assert_eq!(history.remove(&[2]).unwrap(), 0);
+        let mut reloaded = History::load(path);
+        assert_eq!(ids(reloaded.search("")), vec![1, 3]);
+        reloaded
+            .record(entry("dragon", now()), &Retention::default())
+            .unwrap();
+        assert_eq!(reloaded.get(4).unwrap().keyword, "dragon");
+
+        // Removing the newest entry doesn't free its id
+        assert_eq!(reloaded.remove(&[4]).unwrap(), 1);
+        let mut reloaded = History::load(reloaded.path.clone());
+        reloaded
+            .record(entry("wyvern", now()), &Retention::default())
+            .unwrap();
+        assert!(reloaded.get(4).is_none());
+        assert_eq!(reloaded.get(5).unwrap().keyword, "wyvern");
+    }
+
+    #[test]
+    fn formats_timestamps_in_utc() {
+        assert_eq!(format_time(0), "1970-01-01 00:00");
+        assert_eq!(format_rfc3339(951_827_696), "2000-02-29T12:34:56Z");
+        assert_eq!(format_time(1_792_195_200), "2026-10-17 00:00");
+    }
+
+    #[test]
+    fn drops_entries_beyond_the_retention_limits() {
+        let path = temp_path("retention");
+        let retention = Retention {
+            max_entries: Some(3),
+            max_age: Some(Duration::from_secs(3600)),
+        };
+        let mut history = History::load(path.clone());
+        history
+            .record(entry("stale", now() - 7200), &retention)
+            .unwrap();
+        history.record(entry("a", now()), &retention).unwrap();
+        assert_eq!(history.entries().len(), 1);
+        for keyword in ["b", "c", "d"] {
+            history.record(entry(keyword, now()), &retention).unwrap();
+        }
+        let keywords: Vec<String> = History::load(path)
+            .entries()
+            .iter()
+            .map(|e| e.keyword.clone())
+            .collect();
+        assert_eq!(keywords, vec!["b", "c", "d"]);
+    }
+}
@@ -0,0 +1,106 @@
+//! Instructions sent to the model.
+
+/// Techniques shared by every preset, appended after the preset's own instruction, categories
+/// and examples
+pub const TECHNIQUES: &str = r#"**Advanced Techniques Explained:**
+
+**1. Keyword Weighting:**
+*   Adjust the importance of a keyword using the syntax: `(keyword: factor)`
+*   `factor < 1`: Less important (e.g., `(background details: 0.7)`)
+*   `factor > 1`: More important (e.g., `(dynamic pose: 1.4)`)
+*   *Use this to fine-tune specific elements of the style.*
+
+**2. Character Consistency:**
+*   For consistent depictions, use known character names when appropriate.
+*   Example: Prompting for 'Rem' (from Re:Zero) helps generate her specific appearance.
+
+**3. Prompt Segmentation (`BREAK`):**
+*   Prevent the AI from mixing distinct concepts (e.g., applying character's hair color to the background). Separate them with the `BREAK` keyword.
+*   Example:
+    girl with pink hair, wearing school uniform
+    BREAK
+    detailed classroom background, sunny day
+
+--------------------
+**Underlying Principle (Think like Stable Diffusion):**
+
+*   Stable Diffusion is an image sampler. Your prompt guides it towards the part of its potential outputs that matches the requested style.
+*   **Detailed and specific prompts using techniques like weighting and segmentation are effective** because they narrow the sampling space, guiding diffusion towards the desired, complex aesthetic. Your role is to use *as many* of these tools as possible to create the best guidance.
+*  **Avoid** vague or overly simplistic prompts. Instead, aim for complexity and detail to achieve the best results.
+* You *must only* return a single prompt string, formatted as a single line with no line breaks or newlines. Do not include any additional text or explanations in your response."#;
+
+/// Combine the system instructions `base` (normally a preset's) with extra notes such as the
+/// target model's, the character descriptors to keep consistent, if any, and the recent prompt
+/// history
+pub fn build_system_instruction(
+    base: &str,
+    notes: &[&str],
+    descriptors: &[String],
+    recent_prompts: &[&str],
+) -> String {
+    let mut instruction = base.to_string();
+    for note in notes {
+        instruction.push_str("\n\n--------------------\n");
+        instruction.push_str(note);
+    }
+    if !descriptors.is_empty() {
+        instruction.push_str(
+            "\n\n--------------------\n**Character Descriptors:**\n\
+             Keep these characters consistent whenever they appear:\n",
+        );
+        for descriptor in descriptors {
+            instruction.push_str(&format!("- {}\n", descriptor));
+        }
+        instruction.pop();
+    }
+    format!(
+        "{}\n\n--------------------\n**Previous Generated Prompts:**\n{}",
+        instruction,
+        recent_prompts.join("\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "You are an assistant.";
+
+    #[test]
+    fn appends_history_block() {
+        let instruction = build_system_instruction(BASE, &[], &[], &["knight", "dragon"]);
+        assert!(instruction.starts_with(BASE));
+        assert!(instruction.ends_with("**Previous Generated Prompts:**\nknight\ndragon"));
+    }
+
+    #[test]
+    fn empty_history_keeps_header() {
+        let instruction = build_system_instruction(BASE, &[], &[], &[]);
+        assert!(instruction.ends_with("**Previous Generated Prompts:**\n"));
+    }
+
+    #[test]
+    fn lists_descriptors_before_history() {
+        let descriptors = vec!["Azure: long blue hair, silver armor".to_string()];
+        let instruction = build_system_instruction(BASE, &[], &descriptors, &["knight"]);
+        assert!(instruction.contains(
+            "**Character Descriptors:**\nKeep these characters consistent whenever they appear:\n\
+             - Azure: long blue hair, silver armor\n\n--------------------\n\
+             **Previous Generated Prompts:**\nknight"
+        ));
+    }
+
+    #[test]
+    fn adds_notes_after_base() {
+        let instruction = build_system_instruction(
+            BASE,
+            &["**Target Model: Flux**", "**Negative Prompt:**"],
+            &[],
+            &[],
+        );
+        assert!(instruction.starts_with(
+            "You are an assistant.\n\n--------------------\n**Target Model: Flux**\n\n\
+             --------------------\n**Negative Prompt:**\n\n"
+        ));
+    }
+}
@@ -0,0 +1,816 @@
+//! API key storage and lookup.
+//!
+//! Keys live in [`KEYS_FILE`] under the config directory, readable only by the owner, with one
+//! entry per profile and backend. The store can be encrypted with a passphrase: the cipher key
+//! is derived with Argon2id and every entry is sealed with ChaCha20-Poly1305 under its own
+//! nonce.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use argon2::Argon2;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use serde::{Deserialize, Serialize};
+
+/// Environment variable holding the Gemini API key
+pub const KEY_ENV_VAR: &str = "GENAI_API_KEY";
+
+/// Environment variable holding the passphrase of an encrypted store
+pub const PASSPHRASE_ENV_VAR: &str = "PROMPTFLOW_KEY_PASSPHRASE";
+
+/// File name of the key store inside the config directory
+pub const KEYS_FILE: &str = "keys.toml";
+
+/// Profile used when none is configured
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Plaintext sealed with the passphrase so a wrong one is detected before anything is written
+const CHECK_VALUE: &[u8] = b"promptflow";
+
+/// Location of the key store
+pub fn store_path() -> Result<PathBuf, KeyError> {
+    crate::config::config_dir()
+        .map(|dir| dir.join(KEYS_FILE))
+        .ok_or(KeyError::NoConfigDir)
+}
+
+/// File older versions cached the Gemini key in, in plaintext
+pub fn legacy_key_path() -> PathBuf {
+    std::env::temp_dir().join("key")
+}
+
+/// Failure to read, decrypt or update the key store
+#[derive(Debug)]
+pub enum KeyError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+    /// Neither `XDG_CONFIG_HOME` nor `HOME` is set
+    NoConfigDir,
+    /// Reading a key or passphrase from the terminal failed
+    Terminal(std::io::Error),
+    /// The store is encrypted and no passphrase was available
+    PassphraseRequired,
+    PassphraseMismatch,
+    WrongPassphrase,
+    /// No key for the backend in the given profile
+    NotFound {
+        profile: String,
+        backend: String,
+    },
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Io(path, e) => write!(f, "could not access {}: {}", path.display(), e),
+            KeyError::Parse(path, e) => write!(f, "invalid key store {}: {}", path.display(), e),
+            KeyError::PassphraseRequired => write!(
+                f,
+                "the key store is encrypted; enter the passphrase on a terminal or set {}",
+                PASSPHRASE_ENV_VAR
+            ),
+            KeyError::NoConfigDir => f.write_str(
+                "cannot locate the config directory for the key store; set XDG_CONFIG_HOME or HOME",
+            ),
+            KeyError::Terminal(e) => write!(f, "could not read from the terminal: {}", e),
+            KeyError::PassphraseMismatch => f.write_str("the passphrases don't match"),
+            KeyError::WrongPassphrase => f.write_str("wrong passphrase for the key store"),
+            KeyError::NotFound { profile, backend } => {
+                write!(f, "no {} API key stored in profile {:?}", backend, profile)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// On-disk form of the store
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StoreFile {
+    /// Present when the store is encrypted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encryption: Option<Encryption>,
+    /// Profile name to backend name to key
+    #[serde(default)]
+    profiles: BTreeMap<String, BTreeMap<String, StoredKey>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Encryption {
+    /// Argon2id salt, base64
+    salt: String,
+    /// [`CHECK_VALUE`] sealed with the derived key
+    check: Sealed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Sealed {
+    nonce: String,
+    ciphertext: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredKey {
+    Plain(String),
+    Encrypted(Sealed),
+}
+
+/// Cipher derived from the passphrase and the store's salt
+struct Cipher(ChaCha20Poly1305);
+
+impl Cipher {
+    fn derive(passphrase: &str, salt: &[u8]) -> Self {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .expect("salt and output lengths are within Argon2's limits");
+        Self(ChaCha20Poly1305::new(&key.into()))
+    }
+
+    fn seal(&self, plaintext: &[u8]) -> Sealed {
+        let nonce = random_bytes::<12>();
+        let ciphertext = self
+            .0
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .expect("encrypting into a Vec cannot fail");
+        Sealed {
+            nonce: BASE64.encode(nonce),
+            ciphertext: BASE64.encode(ciphertext),
+        }
+    }
+
+    fn open(&self, sealed: &Sealed) -> Result<Vec<u8>, KeyError> {
+        let nonce = BASE64
+            .decode(&sealed.nonce)
+            .ok()
+            .filter(|nonce| nonce.len() == 12)
+            .ok_or(KeyError::WrongPassphrase)?;
+        let ciphertext = BASE64
+            .decode(&sealed.ciphertext)
+            .map_err(|_| KeyError::WrongPassphrase)?;
+        self.0
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| KeyError::WrongPassphrase)
+    }
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    getrandom::fill(&mut bytes).expect("the operating system provides randomness");
+    bytes
+}
+
+/// Source of the passphrase for an encrypted store, only consulted when one is needed
+pub type Passphrase<'a> = &'a dyn Fn() -> Result<String, KeyError>;
+
+/// API keys per profile and backend, stored at a fixed path
+pub struct KeyStore {
+    path: PathBuf,
+    file: StoreFile,
+}
+
+impl KeyStore {
+    /// Open the store at `path`, starting empty if it doesn't exist yet
+    pub fn open(path: PathBuf) -> Result<Self, KeyError> {
+        let file = match std::fs::read_to_string(&path) {
+            Ok(text) => {
+                toml::from_str(&text).map_err(|e| KeyError::Parse(path.clone(), e.to_string()))?
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => StoreFile::default(),
+            Err(e) => return Err(KeyError::Io(path, e)),
+        };
+        Ok(Self { path, file })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.file.encryption.is_some()
+    }
+
+    /// `(profile, backend)` of every stored key, sorted
+    pub fn entries(&self) -> Vec<(&str, &str)> {
+        self.file
+            .profiles
+            .iter()
+            .flat_map(|(profile, keys)| {
+                keys.keys()
+                    .map(move |backend| (profile.as_str(), backend.as_str()))
+            })
+            .collect()
+    }
+
+    pub fn contains(&self, profile: &str, backend: &str) -> bool {
+        self.file
+            .profiles
+            .get(profile)
+            .is_some_and(|keys| keys.contains_key(backend))
+    }
+
+    /// Cipher of an encrypted store, after checking the passphrase; `None` for a plaintext one
+    fn cipher(&self, passphrase: Passphrase) -> Result<Option<Cipher>, KeyError> {
+        let Some(encryption) = &self.file.encryption else {
+            return Ok(None);
+        };
+        let salt = BASE64
+            .decode(&encryption.salt)
+            .map_err(|e| KeyError::Parse(self.path.clone(), e.to_string()))?;
+        let cipher = Cipher::derive(&passphrase()?, &salt);
+        if cipher.open(&encryption.check)? != CHECK_VALUE {
+            return Err(KeyError::WrongPassphrase);
+        }
+        Ok(Some(cipher))
+    }
+
+    /// Key for `backend` in `profile`, if stored
+    pub fn get(
+        &self,
+        profile: &str,
+        backend: &str,
+        passphrase: Passphrase,
+    ) -> Result<Option<String>, KeyError> {
+        let Some(stored) = self
+            .file
+            .profiles
+            .get(profile)
+            .and_then(|keys| keys.get(backend))
+        else {
+            return Ok(None);
+        };
+        match stored {
+            StoredKey::Plain(key) => Ok(Some(key.clone())),
+            StoredKey::Encrypted(sealed) => {
+                let cipher = self
+                    .cipher(passphrase)?
+                    .ok_or(KeyError::PassphraseRequired)?;
+                let key = String::from_utf8(cipher.open(sealed)?)
+                    .map_err(|_| KeyError::WrongPassphrase)?;
+                Ok(Some(key))
+            }
+        }
+    }
+
+    /// Store `key` for `backend` in `profile`, encrypted if the store is
+    pub fn set(
+        &mut self,
+        profile: &str,
+        backend: &str,
+        key: &str,
+        passphrase: Passphrase,
+    ) -> Result<(), KeyError> {
+        let stored = match self.cipher(passphrase)? {
+            Some(cipher) => StoredKey::Encrypted(cipher.seal(key.as_bytes())),
+            None => StoredKey::Plain(key.to_string()),
+        };
+        self.file
+            .profiles
+            .entry(profile.to_string())
+            .or_default()
+            .insert(backend.to_string(), stored);
+        Ok(())
+    }
+
+    /// Remove the key for `backend` in `profile`, returning whether there was one
+    pub fn remove(&mut self, profile: &str, backend: &str) -> bool {
+        let Some(keys) = self.file.profiles.get_mut(profile) else {
+            return false;
+        };
+        let removed = keys.remove(backend).is_some();
+        if keys.is_empty() {
+            self.file.profiles.remove(profile);
+        }
+        removed
+    }
+
+    /// Encrypt every plaintext entry with `passphrase`; the store stays encrypted from now on
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<(), KeyError> {
+        if self.is_encrypted() {
+            return Ok(());
+        }
+        let salt = random_bytes::<16>();
+        let cipher = Cipher::derive(passphrase, &salt);
+        for keys in self.file.profiles.values_mut() {
+            for stored in keys.values_mut() {
+                if let StoredKey::Plain(key) = stored {
+                    *stored = StoredKey::Encrypted(cipher.seal(key.as_bytes()));
+                }
+            }
+        }
+        self.file.encryption = Some(Encryption {
+            salt: BASE64.encode(salt),
+            check: cipher.seal(CHECK_VALUE),
+        });
+        Ok(())
+    }
+
+    /// Write the store back, readable and writable only by the owner
+    pub fn save(&self) -> Result<(), KeyError> {
+        let io_error = |e| KeyError::Io(self.path.clone(), e);
+        let text = toml::to_string(&self.file)
+            .map_err(|e| KeyError::Parse(self.path.clone(), e.to_string()))?;
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).map_err(io_error)?;
+        }
+        // Write a private temporary file and rename it over the store, so the key is never
+        // readable by others and a failed write can't truncate the store
+        let temp = self.path.with_extension("toml.tmp");
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&temp).map_err(io_error)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))
+                .map_err(io_error)?;
+        }
+        file.write_all(format!("# API keys managed by `PromptFlow key`\n{}", text).as_bytes())
+            .map_err(io_error)?;
+        file.sync_all().map_err(io_error)?;
+        std::fs::rename(&temp, &self.path).map_err(io_error)
+    }
+
+    /// Move a key cached at `legacy` by older versions into `profile`/`backend`, unless a key
+    /// is stored there already, and delete the old file. The file sits in a shared temporary
+    /// directory, so it is left alone unless it is a regular file owned by the current user.
+    pub fn migrate_legacy(
+        &mut self,
+        legacy: &Path,
+        profile: &str,
+        backend: &str,
+        passphrase: Passphrase,
+    ) -> Result<Migration, KeyError> {
+        let mut migration = Migration::default();
+        if !is_own_file(legacy) {
+            return Ok(migration);
+        }
+        let Ok(contents) = std::fs::read_to_string(legacy) else {
+            return Ok(migration);
+        };
+        let key = contents.trim();
+        migration.moved = !key.is_empty() && !self.contains(profile, backend);
+        if migration.moved {
+            self.set(profile, backend, key, passphrase)?;
+            self.save()?;
+        }
+        migration.remove_error = std::fs::remove_file(legacy).err();
+        Ok(migration)
+    }
+}
+
+/// Outcome of [`KeyStore::migrate_legacy`]
+#[derive(Debug, Default)]
+pub struct Migration {
+    /// Whether a key was moved into the store
+    pub moved: bool,
+    /// Why the old file could not be deleted, if it couldn't
+    pub remove_error: Option<std::io::Error>,
+}
+
+/// Whether `path` is a regular file, not a symlink, owned by the current user
+fn is_own_file(path: &Path) -> bool {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return false;
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        // SAFETY: geteuid has no preconditions and cannot fail
+        let uid = unsafe { libc::geteuid() };
+        if metadata.uid() != uid {
+            return false;
+        }
+    }
+    metadata.file_type().is_file()
+}
+
+/// `key` with everything but its last four characters hidden, for display
+pub fn mask(key: &str) -> String {
+    let chars: Vec<char> = key.chars().collect();
+    let shown = if chars.len() > 8 { 4 } else { 0 };
+    let hidden = "*".repeat(chars.len() - shown);
+    hidden + &chars[chars.len() - shown..].iter().collect::<String>()
+}
+
+/// Where a resolved API key came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeySource {
+    /// The `--key` argument
+    Flag,
+    /// The [`KEY_ENV_VAR`] environment variable
+    Env,
+    /// The key store
+    Store,
+}
+
+impl fmt::Display for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeySource::Flag => f.write_str("--key"),
+            KeySource::Env => f.write_str(KEY_ENV_VAR),
+            KeySource::Store => f.write_str("the key store"),
+        }
+    }
+}
+
+/// An API key together with where it was found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedKey {
+    pub key: String,
+    pub source: KeySource,
+    /// The key was passed in and the store has none for the backend yet: save it with
+    /// [`save_new_key`] once the backend has accepted it
+    pub save: bool,
+}
+
+/// Resolve the key for `backend` in `profile` from the `--key` argument (`explicit`), the
+/// environment (`env_key`) or the store, in that order. Nothing is written to the store.
+pub fn resolve_key(
+    store: &KeyStore,
+    profile: &str,
+    backend: &str,
+    explicit: Option<String>,
+    env_key: Option<String>,
+    passphrase: Passphrase,
+) -> Result<ResolvedKey, Box<dyn std::error::Error>> {
+    let passed = [(explicit, KeySource::Flag), (env_key, KeySource::Env)]
+        .into_iter()
+        .filter_map(|(key, source)| Some((key?.trim().to_string(), source)))
+        .find(|(key, _)| !key.is_empty());
+
+    if let Some((key, source)) = passed {
+        return Ok(ResolvedKey {
+            key,
+            source,
+            save: !store.contains(profile, backend),
+        });
+    }
+
+    match store.get(profile, backend, passphrase)? {
+        Some(key) if !key.trim().is_empty() => Ok(ResolvedKey {
+            key: key.trim().to_string(),
+            source: KeySource::Store,
+            save: false,
+        }),
+        _ => Err(format!(
+            "API key not found. Provide it with --key, set {} environment variable or run `PromptFlow key set`",
+            KEY_ENV_VAR
+        )
+        .into()),
+    }
+}
+
+/// Save `key`, which the backend accepted, for `backend` in `profile` so later runs can do
+/// without passing it, and return whether it was saved. An existing entry is never overwritten,
+/// and an encrypted store is left alone when the passphrase isn't available.
+pub fn save_new_key(
+    store: &mut KeyStore,
+    profile: &str,
+    backend: &str,
+    key: &str,
+    passphrase: Passphrase,
+) -> Result<bool, KeyError> {
+    if store.contains(profile, backend) {
+        return Ok(false);
+    }
+    match store.set(profile, backend, key, passphrase) {
+        Ok(()) => store.save().map(|()| true),
+        Err(KeyError::PassphraseRequired) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("promptflow-key-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn store(name: &str) -> KeyStore {
+        KeyStore::open(temp_dir(name).join(KEYS_FILE)).unwrap()
+    }
+
+    fn no_passphrase() -> Result<String, KeyError> {
+        Err(KeyError::PassphraseRequired)
+    }
+
+    fn secret() -> Result<String, KeyError> {
+        Ok("correct horse".to_string())
+    }
+
+    #[test]
+    fn argument_is_stored_once_accepted() {
+        let mut store = store("arg");
+        let key = resolve_key(
+            &store,
+            DEFAULT_PROFILE,
+            "gemini",
+            Some("from-arg".into()),
+            Some("from-env".into()),
+            &no_passphrase,
+        )
+        .unwrap();
+        assert_eq!(key.key, "from-arg");
+        assert_eq!(key.source, KeySource::Flag);
+        assert!(key.save);
+        // Until the backend accepts it, a mistyped key leaves no trace
+        assert!(!store.path().exists());
+
+        assert!(
+            save_new_key(
+                &mut store,
+                DEFAULT_PROFILE,
+                "gemini",
+                &key.key,
+                &no_passphrase
+            )
+            .unwrap()
+        );
+        let reopened = KeyStore::open(store.path().to_path_buf()).unwrap();
+        assert_eq!(
+            reopened
+                .get(DEFAULT_PROFILE, "gemini", &no_passphrase)
+                .unwrap()
+                .as_deref(),
+            Some("from-arg")
+        );
+    }
+
+    #[test]
+    fn environment_is_used_without_argument() {
+        let store = store("env");
+        let key = resolve_key(
+            &store,
+            DEFAULT_PROFILE,
+            "gemini",
+            None,
+            Some("from-env".into()),
+            &no_passphrase,
+        )
+        .unwrap();
+        assert_eq!(key.key, "from-env");
+        assert_eq!(key.source, KeySource::Env);
+        assert!(key.save);
+        assert!(!store.contains(DEFAULT_PROFILE, "gemini"));
+    }
+
+    #[test]
+    fn stored_key_is_reused() {
+        let mut store = store("stored");
+        store
+            .set(DEFAULT_PROFILE, "gemini", "stored", &no_passphrase)
+            .unwrap();
+        assert_eq!(
+            resolve_key(
+                &store,
+                DEFAULT_PROFILE,
+                "gemini",
+                None,
+                None,
+                &no_passphrase
+            )
+            .unwrap(),
+            ResolvedKey {
+                key: "stored".into(),
+                source: KeySource::Store,
+                save: false
+            }
+        );
+    }
+
+    #[test]
+    fn passed_keys_override_the_stored_one() {
+        let mut store = store("override");
+        store
+            .set(DEFAULT_PROFILE, "gemini", "stored", &no_passphrase)
+            .unwrap();
+        for (explicit, env_key, expected) in [
+            (Some("from-arg"), Some("from-env"), "from-arg"),
+            (None, Some("from-env"), "from-env"),
+            (Some("  "), Some("from-env"), "from-env"),
+        ] {
+            let key = resolve_key(
+                &store,
+                DEFAULT_PROFILE,
+                "gemini",
+                explicit.map(Into::into),
+                env_key.map(Into::into),
+                &no_passphrase,
+            )
+            .unwrap();
+            assert_eq!(key.key, expected);
+        }
+        // The stored key is kept for runs without an override
+        assert_eq!(
+            store
+                .get(DEFAULT_PROFILE, "gemini", &no_passphrase)
+                .unwrap()
+                .as_deref(),
+            Some("stored")
+        );
+    }
+
+    #[test]
+    fn missing_key_is_an_error() {
+        let store = store("missing");
+        let err = resolve_key(
+            &store,
+            DEFAULT_PROFILE,
+            "gemini",
+            None,
+            None,
+            &no_passphrase,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("--key"));
+    }
+
+    #[test]
+    fn entries_are_per_profile_and_backend() {
+        let mut store = store("profiles");
+        store
+            .set("default", "gemini", "g1", &no_passphrase)
+            .unwrap();
+        store.set("work", "gemini", "g2", &no_passphrase).unwrap();
+        store.set("work", "openai", "o2", &no_passphrase).unwrap();
+        assert_eq!(
+            store.entries(),
+            vec![
+                ("default", "gemini"),
+                ("work", "gemini"),
+                ("work", "openai")
+            ]
+        );
+        assert_eq!(
+            store
+                .get("work", "gemini", &no_passphrase)
+                .unwrap()
+                .as_deref(),
+            Some("g2")
+        );
+        assert!(store.remove("default", "gemini"));
+        assert!(!store.remove("default", "gemini"));
+        assert_eq!(
+            store.entries(),
+            vec![("work", "gemini"), ("work", "openai")]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn store_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let mut store = store("private");
+        store
+            .set(DEFAULT_PROFILE, "gemini", "secret", &no_passphrase)
+            .unwrap();
+        store.save().unwrap();
+        let mode = std::fs::metadata(store.path())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn encrypted_store_needs_the_passphrase() {
+        let mut store = store("encrypted");
+        store
+            .set(DEFAULT_PROFILE, "gemini", "AIzaSecret", &no_passphrase)
+            .unwrap();
+        store.encrypt("correct horse").unwrap();
+        store.save().unwrap();
+
+        let text = std::fs::read_to_string(store.path()).unwrap();
+        assert!(!text.contains("AIzaSecret"));
+
+        let mut store = KeyStore::open(store.path().to_path_buf()).unwrap();
+        assert!(store.is_encrypted());
+        assert_eq!(
+            store
+                .get(DEFAULT_PROFILE, "gemini", &secret)
+                .unwrap()
+                .as_deref(),
+            Some("AIzaSecret")
+        );
+        assert!(matches!(
+            store.get(DEFAULT_PROFILE, "gemini", &no_passphrase),
+            Err(KeyError::PassphraseRequired)
+        ));
+        let wrong = || Ok("wrong".to_string());
+        assert!(matches!(
+            store.set(DEFAULT_PROFILE, "openai", "sk-1", &wrong),
+            Err(KeyError::WrongPassphrase)
+        ));
+
+        store
+            .set(DEFAULT_PROFILE, "openai", "sk-1", &secret)
+            .unwrap();
+        assert_eq!(
+            store
+                .get(DEFAULT_PROFILE, "openai", &secret)
+                .unwrap()
+                .as_deref(),
+            Some("sk-1")
+        );
+    }
+
+    #[test]
+    fn passed_key_works_without_the_passphrase() {
+        let mut store = store("locked");
+        store
+            .set(DEFAULT_PROFILE, "gemini", "stored", &no_passphrase)
+            .unwrap();
+        store.encrypt("correct horse").unwrap();
+        let key = resolve_key(
+            &store,
+            DEFAULT_PROFILE,
+            "gemini",
+            Some("passed".into()),
+            None,
+            &no_passphrase,
+        )
+        .unwrap();
+        assert_eq!(key.key, "passed");
+        assert_eq!(
+            store
+                .get(DEFAULT_PROFILE, "gemini", &secret)
+                .unwrap()
+                .as_deref(),
+            Some("stored")
+        );
+    }
+
+    #[test]
+    fn migrates_legacy_file() {
+        let dir = temp_dir("legacy");
+        let legacy = dir.join("key");
+        std::fs::write(&legacy, "  old-key\n").unwrap();
+        let mut store = KeyStore::open(dir.join(KEYS_FILE)).unwrap();
+
+        let migration = store
+            .migrate_legacy(&legacy, DEFAULT_PROFILE, "gemini", &no_passphrase)
+            .unwrap();
+        assert!(migration.moved);
+        assert!(migration.remove_error.is_none());
+        assert!(!legacy.exists());
+        let reopened = KeyStore::open(dir.join(KEYS_FILE)).unwrap();
+        assert_eq!(
+            reopened
+                .get(DEFAULT_PROFILE, "gemini", &no_passphrase)
+                .unwrap()
+                .as_deref(),
+            Some("old-key")
+        );
+
+        // An existing entry wins, but the old file is still removed
+        std::fs::write(&legacy, "older-key").unwrap();
+        assert!(
+            !store
+                .migrate_legacy(&legacy, DEFAULT_PROFILE, "gemini", &no_passphrase)
+                .unwrap()
+                .moved
+        );
+        assert!(!legacy.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn legacy_symlinks_are_left_alone() {
+        let dir = temp_dir("legacy-symlink");
+        let target = dir.join("someone-elses-key");
+        std::fs::write(&target, "their-key").unwrap();
+        let legacy = dir.join("key");
+        std::os::unix::fs::symlink(&target, &legacy).unwrap();
+        let mut store = KeyStore::open(dir.join(KEYS_FILE)).unwrap();
+
+        let migration = store
+            .migrate_legacy(&legacy, DEFAULT_PROFILE, "gemini", &no_passphrase)
+            .unwrap();
+        assert!(!migration.moved);
+        assert!(!store.contains(DEFAULT_PROFILE, "gemini"));
+        assert!(legacy.exists());
+        assert!(target.exists());
+    }
+
+    #[test]
+    fn masks_all_but_the_tail() {
+        assert_eq!(mask("AIzaSyExample1234"), "*************1234");
+        assert_eq!(mask("short"), "*****");
+    }
+}
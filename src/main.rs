@@ -1,246 +1,922 @@
-use gemini_rs;
+mod backend;
+mod batch;
+mod cli;
+mod clip;
+mod config;
+mod context;
+mod coverage;
+mod edit;
+mod generate;
+mod history;
+mod instruction;
+mod key;
+mod negative;
+mod output;
+mod parser;
+mod preset;
+mod repl;
+mod serve;
+mod session;
+mod target;
+mod tty;
+mod tui;
+mod validate;
+mod variants;
+
+use backend::{BackendKind, PromptBackend};
+use cli::{
+    BatchArgs, Command, ConfigCommand, GenerateArgs, HistoryCommand, KeyCommand, PresetsCommand,
+    ReplArgs, SessionCommand, TuiArgs,
+};
+use config::Config;
+use generate::GenerationSettings;
+use history::History;
+use key::{KeyError, KeySource, KeyStore, ResolvedKey};
+use output::OutputFormat;
+use preset::{Preset, PresetError};
+use session::{Session, SessionError};
 use std::env;
-use std::path::PathBuf;
-
-/// System instructions for the Gemini AI model that define how to generate anime-style prompts
-/// This multi-paragraph text guides the AI to create detailed anime-specific prompts with:
-/// - Required components (subject, medium, style, etc.)
-/// - Keyword weighting techniques
-/// - Character consistency guidelines
-/// - Prompt segmentation using BREAK
-/// - Examples of properly formatted prompts
-const SYSTEM_INSTRUCTION: &str = r#"
-You are an assistant specialized in generating prompts **exclusively for anime-style** AI image generation from a given keyword.
-
-**Core Task:**
-Generate detailed AI image prompts based on a user's keyword, ensuring the final image aesthetic is distinctly **anime or manga style**.
-**Crucially, you MUST actively utilize ALL the following techniques where appropriate to achieve high-quality anime results:**
-*   Incorporate detailed keywords covering the 8 mandatory component categories, tailoring them for anime.
-*   Employ keyword weighting `(keyword: factor)` to emphasize or de-emphasize specific anime elements (e.g., `(cel shading:1.3)`, `(sparkles:0.8)`).
-*   Use known anime/manga character names for consistency when relevant to the keyword (e.g., 'Asuka Langley Soryu', 'Naruto Uzumaki').
-*   Utilize the `BREAK` keyword for segmentation to prevent concept mixing in complex anime scenes.
-*   Adhere to the principle of being highly detailed and specific to effectively guide the image generation process towards the desired anime look.
-
-**Constraint:**
-**Your primary focus is the anime aesthetic. Do NOT generate prompts aiming for realism, photorealism, or photographic styles. Avoid keywords like 'photo', 'photorealistic', 'hyperrealistic', 'realistic' unless used carefully as a minor modifier for specific background elements *while maintaining an overall anime style*.**
-
-**Mandatory Prompt Components (Anime Focused):**
-The prompts you generate MUST contain keywords covering the following categories, interpreted through an anime lens:
-1.  **Subject:** (e.g., anime girl, shonen protagonist, mecha, fantasy creature in anime style)
-2.  **Medium:** (e.g., anime screenshot, digital painting (anime style), manga page, light novel illustration, 2D animation cel, cel shading)
-3.  **Style:** (e.g., modern anime, 90s anime aesthetic, shojo manga style, studio ghibli inspired, Makoto Shinkai style, chibi)
-4.  **Art-sharing website/Platform:** (e.g., Pixiv, ArtStation (with anime tags), Danbooru aesthetic - *use platforms known for anime art*)
-5.  **Resolution/Quality:** (e.g., high quality illustration, sharp focus, detailed linework, 4k anime wallpaper)
-6.  **Additional details:** (background, clothing specific to anime tropes, actions, specific visual elements like speed lines, sparkles, dramatic expressions)
-7.  **Color:** (e.g., vibrant anime colors, pastel palette, specific character hair/eye colors, cel shaded colors)
-8.  **Lighting:** (e.g., dramatic anime lighting, volumetric light, rim lighting, soft anime glow, lens flare)
-
---------------------
-**Example (Illustrating Anime Techniques):**
-
-*   **Input Keyword:** 'anime girl with blue hair in a fantasy setting'
-*   **Generated Prompt:** 'HDR, 8K, high contrast, masterpiece, best quality, amazing quality, very aesthetic, superabsurd res, high resolution, ultra-detailed, absurdres, newest, scenery, (horikoshi kouhei:0.3), (quasarcake:0.3), (wlop:0.3), lightrays, chiaroscuro, dynamic angle, 1girl, solo, long hair, breasts, looking at viewer, blue eyes, black hair, gloves, dress, jewelry, upper body, ponytail, earrings, parted lips, hand up, nail polish, white dress, from side, fingernails, profile, facial mark, fire, index finger raised, blue nails, blue fire, epic fire aura, epic, 748cmstyle, backlighting, partially illuminated, Intricately designed, Mysterious Shadows, BREAK, photorealistic, beautiful detailed eyes, detailed skin, detailed hair, volumetric lighting, dappled light, light particles, dramatic shadows, cinematic lighting, photo background, depth of field'
-    *   *Note:* This example uses anime-specific terms (anime knight, cel shading, Pixiv, fantasy anime aesthetic), weighting, the `BREAK` keyword, and covers all 8 component categories within the anime context.
-
---------------------
-**Advanced Techniques Explained:**
-
-**1. Keyword Weighting:**
-*   Adjust the importance of a keyword using the syntax: `(keyword: factor)`
-*   `factor < 1`: Less important (e.g., `(background details: 0.7)`)
-*   `factor > 1`: More important (e.g., `(dynamic pose: 1.4)`)
-*   *Use this to fine-tune specific anime elements.*
-
-**2. Character Consistency:**
-*   For consistent depictions, use known anime/manga character names when appropriate.
-*   Example: Prompting for 'Rem' (from Re:Zero) helps generate her specific appearance.
-
-**3. Prompt Segmentation (`BREAK`):**
-*   Prevent the AI from mixing distinct concepts (e.g., applying character's hair color to the background). Separate using `BREAK` on its own line.
-*   Example:
-    anime girl with pink hair, wearing school uniform
-    BREAK
-    detailed classroom background, sunny day
-
---------------------
-**Underlying Principle (Think like Stable Diffusion for Anime):**
-
-*   Stable Diffusion is an image sampler. Your prompt guides it towards the *anime* part of its potential outputs.
-*   **Detailed and specific prompts using techniques like weighting and segmentation are effective** because they narrow the sampling space, guiding diffusion towards the desired, complex **anime aesthetic**. Your role is to use *as many* these tools as possible to create the best guidance for generating anime-style images.
-*  **Avoid** vague or overly simplistic prompts. Instead, aim for complexity and detail to achieve the best results.
-* You *must only* return a single prompt string, formatted as a single line with no line breaks or newlines. Do not include any additional text or explanations in your response.
-"#;
-
-/// Standard negative prompt used for AI image generation
-/// Contains terms to avoid common AI image generation issues like poor anatomy,
-/// watermarks, low quality, etc.
-const NEGATIVE_PROMPT: &str = "ugly, tiling, poorly drawn hands, poorly drawn feet, poorly drawn face, out of frame, extra limbs, disfigured, deformed, body out of frame, bad anatomy, watermark, signature, cut off, low contrast, underexposed, overexposed, bad art, beginner, amateur, distorted face, blurry, lowres, low quality, worst quality, low quality, normal quality, jpeg artifacts, signature, watermark, username, blurry";
-
-// Model name for the Gemini API
-const MODEL: &str = "gemini-2.0-flash";
+use std::error::Error;
+use std::io::{IsTerminal, Write};
+use std::time::Instant;
+
+/// Environment variable pointing the mock backend at a JSON file of canned responses
+const MOCK_RESPONSES_ENV_VAR: &str = "PROMPTFLOW_MOCK_RESPONSES";
+
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Parse command line arguments
+async fn main() -> Result<(), Box<dyn Error>> {
+    // Parse command line arguments; --help, --version and usage errors exit here
     let args: Vec<String> = env::args().collect();
-    let mut i = 1;
-    let mut api_key = None;
-    let mut prompt = None;
+    let command = cli::parse_args(&args).unwrap_or_else(|e| e.exit());
+
+    match command {
+        Command::Generate(generate) => run_generate(generate).await,
+        Command::Config {
+            command: ConfigCommand::Show(args),
+        } => {
+            let config = load_config(args.config_layer())?;
+            output::write_config(&mut std::io::stdout().lock(), &config)?;
+            Ok(())
+        }
+        Command::History { session, command } => run_history(session, command).await,
+        Command::Session { command } => run_session(command),
+        Command::Key { command } => run_key(command),
+        Command::Presets { command } => run_presets(command),
+        Command::Batch(batch) => run_batch(batch).await,
+        Command::Repl(repl) => run_repl(repl).await,
+        Command::Tui(tui) => run_tui(tui).await,
+        Command::Serve(serve) => {
+            let config = load_config(serve.args.config_layer())?;
+            let backend = create_backend(&config, &serve.args)?;
+            let listener = tokio::net::TcpListener::bind(serve.listen).await?;
+            eprintln!(
+                "Listening on http://{} ({} / {})",
+                listener.local_addr()?,
+                backend.name(),
+                backend.model()
+            );
+            let service = serve::Service {
+                backend,
+                history: History::load(
+                    session::history_path(config.session.value.as_deref())
+                        .map_err(session_error)?,
+                ),
+                retention: config.history_retention(),
+                settings: GenerationSettings::from_config(&config),
+                tokenizer: clip::ClipTokenizer::load(),
+                max_tokens: config.max_tokens.value,
+            };
+            serve::run(listener, service).await?;
+            Ok(())
+        }
+        Command::Completions { shell } => {
+            cli::write_completions(shell, &mut std::io::stdout().lock());
+            Ok(())
+        }
+    }
+}
+
+/// Merge the configuration layers with the command-line options in `cli`
+fn load_config(cli: config::Layer) -> Result<Config, Box<dyn Error>> {
+    Config::load(cli).map_err(|e| {
+        eprintln!("Error: {}", e);
+        "Invalid configuration".into()
+    })
+}
+
+fn key_error(e: KeyError) -> Box<dyn Error> {
+    eprintln!("Error: {}", e);
+    "Key store error".into()
+}
+
+fn session_error(e: SessionError) -> Box<dyn Error> {
+    eprintln!("Error: {}", e);
+    "Session error".into()
+}
+
+/// Open the key store, first moving a key cached by older versions into it
+fn open_store(passphrase: &tty::Passphrase) -> Result<KeyStore, KeyError> {
+    let mut store = KeyStore::open(key::store_path()?)?;
+    let legacy = key::legacy_key_path();
+    match store.migrate_legacy(
+        &legacy,
+        key::DEFAULT_PROFILE,
+        BackendKind::Gemini.name(),
+        &|| passphrase.get(),
+    ) {
+        Ok(migration) => {
+            if migration.moved {
+                eprintln!(
+                    "Moved the API key cached in {} to {}",
+                    legacy.display(),
+                    store.path().display()
+                );
+            }
+            if let Some(e) = migration.remove_error {
+                eprintln!("Warning: could not delete {}: {}", legacy.display(), e);
+            }
+        }
+        Err(KeyError::PassphraseRequired) => eprintln!(
+            "Warning: {} was not moved into the encrypted key store: {}",
+            legacy.display(),
+            KeyError::PassphraseRequired
+        ),
+        Err(e) => return Err(e),
+    }
+    Ok(store)
+}
+
+/// Resolve the API key if the configured backend needs one, then construct the backend
+fn create_backend(
+    config: &Config,
+    args: &cli::Args,
+) -> Result<Box<dyn PromptBackend>, Box<dyn Error>> {
+    let passphrase = tty::Passphrase::default();
+    let key = resolve_backend_key(config, args, &passphrase)?;
+    Ok(build_backend(config, key.map(|k| k.key))?)
+}
+
+/// Find the API key for the configured backend: `--key`, then `GENAI_API_KEY`, then the store
+fn resolve_backend_key(
+    config: &Config,
+    args: &cli::Args,
+    passphrase: &tty::Passphrase,
+) -> Result<Option<ResolvedKey>, Box<dyn Error>> {
+    let backend_kind = config.backend.value;
+    let profile = &config.profile.value;
+    if !backend_kind.requires_key() {
+        // Backends that run without a key (e.g. a local Ollama server) only get one passed with
+        // --key or stored for them, and never fail for lack of one. A store that can't be read
+        // still fails, rather than sending no key and getting an opaque rejection.
+        if let Some(key) = args.key.clone() {
+            return Ok(Some(ResolvedKey {
+                key,
+                source: KeySource::Flag,
+                save: false,
+            }));
+        }
+        let store = match open_store(passphrase) {
+            Ok(store) => store,
+            // Without a config directory there is no store to hold a key
+            Err(KeyError::NoConfigDir) => return Ok(None),
+            Err(e) => return Err(key_error(e)),
+        };
+        let key = store
+            .get(profile, backend_kind.name(), &|| passphrase.get())
+            .map_err(key_error)?;
+        return Ok(key.map(|key| ResolvedKey {
+            key,
+            source: KeySource::Store,
+            save: false,
+        }));
+    }
+
+    let store = open_store(passphrase).map_err(key_error)?;
+    match key::resolve_key(
+        &store,
+        profile,
+        backend_kind.name(),
+        args.key.clone(),
+        env::var(key::KEY_ENV_VAR).ok(),
+        &|| passphrase.get(),
+    ) {
+        Ok(k) => Ok(Some(k)),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            Err("Missing API key".into())
+        }
+    }
+}
 
-    // Log if no arguments were provided
-    if args.len() < 2 {
-        eprintln!("Error: No arguments provided. Please specify a prompt.");
+/// Construct the configured backend around `key`
+fn build_backend(
+    config: &Config,
+    key: Option<String>,
+) -> Result<Box<dyn PromptBackend>, backend::BackendError> {
+    backend::create(
+        config.backend.value,
+        backend::BackendOptions {
+            key,
+            model: config.model.value.clone(),
+            base_url: config.base_url.value.clone(),
+            temperature: config.temperature.value,
+            num_ctx: config.num_ctx.value,
+            ollama_api: config.ollama_api.value,
+            mock_responses: env::var_os(MOCK_RESPONSES_ENV_VAR).map(Into::into),
+        },
+    )
+}
+
+/// Whether `e` means the backend rejected the API key
+fn is_auth_error(e: &(dyn Error + 'static)) -> bool {
+    matches!(
+        e.downcast_ref::<backend::BackendError>(),
+        Some(backend::BackendError::Auth(_))
+    )
+}
+
+/// Ask for a key to replace the rejected stored one and save it in the store
+fn replace_stored_key(
+    config: &Config,
+    passphrase: &tty::Passphrase,
+) -> Result<String, Box<dyn Error>> {
+    let profile = config.profile.value.as_str();
+    let backend = config.backend.value.name();
+    let key = tty::read_secret(&format!("New API key for {} ({}): ", backend, profile))
+        .map_err(|e| key_error(KeyError::Terminal(e)))?;
+    let mut store = open_store(passphrase).map_err(key_error)?;
+    store
+        .set(profile, backend, &key, &|| passphrase.get())
+        .map_err(key_error)?;
+    store.save().map_err(key_error)?;
+    eprintln!("Stored the {} API key in profile {:?}", backend, profile);
+    Ok(key)
+}
+
+/// Save `key`, passed in and accepted by the backend, in the store for later runs
+fn save_accepted_key(config: &Config, passphrase: &tty::Passphrase, key: &str) {
+    let saved = open_store(passphrase).and_then(|mut store| {
+        key::save_new_key(
+            &mut store,
+            &config.profile.value,
+            config.backend.value.name(),
+            key,
+            &|| passphrase.get(),
+        )
+    });
+    if let Err(e) = saved {
         eprintln!(
-            "Usage: {} [--key|-k KEY] [--prompt|-p PROMPT] or {} \"your prompt\"",
-            args[0], args[0]
+            "Warning: could not save the API key in the key store: {}",
+            e
         );
-        return Err("Missing arguments".into());
     }
+}
 
-    while i < args.len() {
-        match args[i].as_str() {
-            "--key" | "-k" => {
-                if i + 1 < args.len() {
-                    api_key = Some(args[i + 1].clone());
-                    i += 2;
-                } else {
-                    eprintln!("Error: API key argument requires a value");
-                    eprintln!("Usage: {} --key YOUR_API_KEY", args[0]);
-                    return Err("Missing API key value".into());
-                }
+async fn run_generate(generate: GenerateArgs) -> Result<(), Box<dyn Error>> {
+    let mut config = load_config(generate.args.config_layer())?;
+    let count = generate.count.max(1) as usize;
+    let diversity = generate.diversity.unwrap_or(variants::DEFAULT_DIVERSITY);
+    if count > 1 || generate.diversity.is_some() {
+        config.temperature.value = Some(variants::temperature(config.temperature.value, diversity));
+    }
+    let passphrase = tty::Passphrase::default();
+    let key = resolve_backend_key(&config, &generate.args, &passphrase)?;
+    let key_source = key.as_ref().map(|k| k.source);
+    let unsaved_key = key.as_ref().filter(|k| k.save).map(|k| k.key.clone());
+    let mut backend = build_backend(&config, key.map(|k| k.key))?;
+    let settings = GenerationSettings::from_config(&config);
+
+    // === PROMPT HISTORY MANAGEMENT ===
+    let mut history = History::load(
+        session::history_path(config.session.value.as_deref()).map_err(session_error)?,
+    );
+
+    // === AI PROMPT GENERATION ===
+    let mut started = Instant::now();
+    eprintln!(
+        "Generating {} for: {:?} ({} / {})",
+        if count > 1 {
+            format!("{} prompt variants", count)
+        } else {
+            "prompt".to_string()
+        },
+        generate.keyword,
+        backend.name(),
+        backend.model()
+    );
+    let keyword = &generate.keyword;
+    // Only a single prompt printed as text has a reader to show the reply to as it streams in.
+    // A terminal gets it under the prompt header, anything else on stderr to keep stdout clean.
+    let stream =
+        (!generate.no_stream && count == 1 && generate.format == OutputFormat::Text).then(|| {
+            if std::io::stdout().is_terminal() {
+                Echo::Stdout
+            } else {
+                Echo::Stderr
             }
-            "--prompt" | "-p" => {
-                if i + 1 < args.len() {
-                    prompt = Some(args[i + 1].clone());
-                    i += 2;
-                } else {
-                    eprintln!("Error: Prompt argument requires a value");
-                    eprintln!("Usage: {} --prompt \"your prompt\"", args[0]);
-                    return Err("Missing prompt value".into());
-                }
+        });
+    let (generated, streamed) = match generate_prompts(
+        backend.as_ref(),
+        &history,
+        &settings,
+        keyword,
+        count,
+        diversity,
+        stream,
+    )
+    .await
+    {
+        // A stored key may have expired or been revoked: ask for a new one and try again
+        Err(e) if is_auth_error(e.as_ref()) && key_source == Some(KeySource::Store) => {
+            eprintln!("Error: {}", e);
+            eprintln!("The stored API key was rejected");
+            let key = replace_stored_key(&config, &passphrase)?;
+            backend = build_backend(&config, Some(key))?;
+            started = Instant::now();
+            generate_prompts(
+                backend.as_ref(),
+                &history,
+                &settings,
+                keyword,
+                count,
+                diversity,
+                stream,
+            )
+            .await?
+        }
+        Err(e) if is_auth_error(e.as_ref()) => {
+            eprintln!("Error: {}", e);
+            if let Some(source) = key_source {
+                eprintln!("The API key from {} was rejected", source);
             }
-            _ => {
-                // First non-flag argument is treated as the prompt
-                if prompt.is_none() {
-                    prompt = Some(args[i].clone());
-                }
-                i += 1;
-            }
-        }
-    }
-
-    // === API KEY MANAGEMENT ===
-    // First check if key exists in temp file before requiring it as an argument
-    let temp_path: PathBuf = env::temp_dir().join("key");
-    let key = if temp_path.exists() {
-        match std::fs::read_to_string(&temp_path) {
-            Ok(contents) if !contents.trim().is_empty() => contents.trim().to_string(),
-            _ => {
-                // File exists but is empty or unreadable, try argument or env var
-                match api_key {
-                    Some(k) => k,
-                    None => match env::var("GENAI_API_KEY") {
-                        Ok(k) => {
-                            // Write key to temp file for future use
-                            std::fs::write(&temp_path, &k)?;
-                            k
-                        }
-                        Err(_) => {
-                            eprintln!(
-                                "Error: API key not found. Provide it with --key or set GENAI_API_KEY environment variable"
-                            );
-                            return Err("Missing API key".into());
-                        }
-                    },
-                }
+            return Err("Authentication failed".into());
+        }
+        result => result?,
+    };
+    let latency = started.elapsed();
+    if let Some(key) = unsaved_key {
+        save_accepted_key(&config, &passphrase, &key);
+    }
+
+    let tokenizer = clip::ClipTokenizer::load();
+    let variants = generated.len();
+    let mut reports = Vec::new();
+    let mut analyses = Vec::new();
+    for (i, generated) in generated.into_iter().enumerate() {
+        // Status lines name the variant once there are several
+        let label = if variants > 1 {
+            format!("Variant {}: ", i + 1)
+        } else {
+            String::new()
+        };
+
+        // === CLIP TOKEN BUDGET ===
+        let mut fitted =
+            generate::fit_token_budget(&generated.text, &tokenizer, config.max_tokens.value);
+        if let Some(max_tokens) = config.max_tokens.value
+            && !fitted.removed.is_empty()
+        {
+            eprintln!(
+                "{}Trimmed to {} CLIP tokens by removing: {}",
+                label,
+                max_tokens,
+                fitted.removed.join(", ")
+            );
+        }
+
+        // === TARGET DIALECT ===
+        // Coverage is analyzed on the Stable Diffusion syntax the parser understands
+        let coverage = coverage::analyze(&fitted.text);
+        let segments = output::segment_reports(&fitted.text, &fitted.tokens);
+
+        // === PROMPT HISTORY RECORDING ===
+        // The history keeps the Stable Diffusion syntax; the dialect is applied on output
+        let entry = generate::history_entry(
+            backend.as_ref(),
+            &settings,
+            keyword,
+            &fitted,
+            &generated.negative_prompt,
+            latency,
+        );
+        if let Err(e) = history.record(entry, &config.history_retention()) {
+            eprintln!("Warning: could not save the prompt history: {}", e);
+        }
+        fitted.text = settings.dialect.format(&fitted.text);
+        let negative_prompt = settings.dialect.format_negative(&generated.negative_prompt);
+
+        let tokens = fitted.tokens;
+        if settings.dialect.target.uses_clip() {
+            eprintln!("{}{}", label, output::token_summary(&tokens));
+            for split in &tokens.splits {
+                eprintln!(
+                    "Warning: {:?} in segment {} is split across CLIP chunks {} and {}",
+                    split.text,
+                    split.segment + 1,
+                    split.chunk + 1,
+                    split.chunk + 2
+                );
             }
         }
-    } else {
-        // File doesn't exist, try argument or env var
-        match api_key {
-            Some(k) => {
-                // Write key to temp file for future use
-                std::fs::write(&temp_path, &k)?;
-                k
+
+        reports.push(output::GenerationReport {
+            keyword: keyword.clone(),
+            prompt: fitted.text,
+            negative_prompt,
+            backend: backend.name().to_string(),
+            model: backend.model().to_string(),
+            preset: settings.preset.clone(),
+            target: settings.dialect.target.to_string(),
+            segments,
+            tokens: output::TokenTotals::new(&tokens),
+            trimmed: fitted.removed,
+            missing_categories: coverage.missing().iter().map(|c| c.name()).collect(),
+        });
+        analyses.push((label, coverage, tokens));
+    }
+
+    // === OUTPUT RESULTS ===
+    let mut stdout = std::io::stdout().lock();
+    match (streamed, reports.as_slice()) {
+        (Some(streamed), [report]) => output::write_streamed_result(
+            &mut stdout,
+            &streamed,
+            &report.prompt,
+            &report.negative_prompt,
+        )?,
+        _ => output::write_generations(&mut stdout, &reports, generate.format)?,
+    }
+    for (i, (label, coverage, tokens)) in analyses.iter().enumerate() {
+        if generate.explain {
+            // Only the text format has room for the breakdown; the others keep stdout parseable
+            let mut stderr = std::io::stderr().lock();
+            let mut explain: &mut dyn Write = if generate.format == OutputFormat::Text {
+                &mut stdout
+            } else {
+                &mut stderr
+            };
+            if variants > 1 {
+                writeln!(explain, "\n=== VARIANT {} / {} ===", i + 1, variants)?;
             }
-            None => match env::var("GENAI_API_KEY") {
-                Ok(k) => {
-                    // Write key to temp file for future use
-                    std::fs::write(&temp_path, &k)?;
-                    k
-                }
-                Err(_) => {
-                    eprintln!(
-                        "Error: API key not found. Provide it with --key or set GENAI_API_KEY environment variable"
-                    );
-                    return Err("Missing API key".into());
-                }
-            },
+            output::write_coverage(&mut explain, coverage)?;
+            output::write_tokens(&mut explain, tokens)?;
+        } else if !coverage.missing().is_empty() {
+            let missing: Vec<&str> = coverage.missing().iter().map(|c| c.name()).collect();
+            eprintln!(
+                "Warning: {}prompt has no keywords for: {} (see --explain)",
+                label.to_lowercase(),
+                missing.join(", ")
+            );
         }
+    }
+    Ok(())
+}
+
+/// Where the model's reply is echoed as it streams in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Echo {
+    /// Under the prompt header, on a terminal
+    Stdout,
+    Stderr,
+}
+
+/// Generate `count` prompts for `keyword`, echoing the model's reply as it arrives if `stream`
+/// is set. Also returns the reply if it was echoed on stdout, so it needn't be printed twice.
+async fn generate_prompts(
+    backend: &dyn PromptBackend,
+    history: &History,
+    settings: &GenerationSettings,
+    keyword: &str,
+    count: usize,
+    diversity: f32,
+    stream: Option<Echo>,
+) -> Result<(Vec<generate::Generated>, Option<String>), Box<dyn Error>> {
+    let Some(echo) = stream else {
+        let generated =
+            generate::generate_variants(backend, history, settings, keyword, count, diversity)
+                .await?;
+        return Ok((generated, None));
+    };
+    let mut out: Box<dyn Write + Send> = match echo {
+        Echo::Stdout => Box::new(std::io::stdout()),
+        Echo::Stderr => Box::new(std::io::stderr()),
+    };
+    let mut streamed = String::new();
+    let generated =
+        generate::generate_streaming(backend, history, settings, keyword, &mut |text| {
+            if streamed.is_empty() && echo == Echo::Stdout {
+                let _ = writeln!(out, "\n{}", output::PROMPT_HEADER);
+            }
+            streamed.push_str(text);
+            let _ = write!(out, "{}", text);
+            let _ = out.flush();
+        })
+        .await;
+    if !streamed.is_empty() {
+        // The final prompt, checked and formatted, follows on stdout
+        let _ = writeln!(out);
+    }
+    let streamed = (echo == Echo::Stdout && !streamed.is_empty()).then_some(streamed);
+    Ok((vec![generated?], streamed))
+}
+
+async fn run_batch(args: BatchArgs) -> Result<(), Box<dyn Error>> {
+    let batch_error = |e: batch::BatchError| -> Box<dyn Error> {
+        eprintln!("Error: {}", e);
+        "Batch failed".into()
     };
+    let config = load_config(args.args.config_layer())?;
+    let rows = batch::read_keywords(&args.input).map_err(batch_error)?;
+    let (mut results, completed) =
+        batch::Results::open(&args.output, args.restart).map_err(batch_error)?;
+    let rows = batch::remaining(rows, &completed);
+    if !completed.is_empty() {
+        eprintln!(
+            "Resuming: {} keyword(s) already done in {}",
+            completed.len(),
+            args.output.display()
+        );
+    }
+    if rows.is_empty() {
+        eprintln!("Nothing to generate");
+        return Ok(());
+    }
 
-    // Initialize Gemini API client with the API key
-    let client = gemini_rs::Client::new(key);
+    let backend = create_backend(&config, &args.args)?;
+    eprintln!(
+        "Generating prompts for {} keyword(s) ({} / {})",
+        rows.len(),
+        backend.name(),
+        backend.model()
+    );
+    let history_path =
+        session::history_path(config.session.value.as_deref()).map_err(session_error)?;
+    let mut batch = batch::Batch {
+        backend,
+        context: History::load(history_path.clone()),
+        history: History::load(history_path),
+        retention: config.history_retention(),
+        settings: GenerationSettings::from_config(&config),
+        tokenizer: clip::ClipTokenizer::load(),
+        max_tokens: config.max_tokens.value,
+        concurrency: args.concurrency,
+        rate_limit: args.rate_limit,
+    };
+    let summary = batch.run(rows, &mut results).await.map_err(batch_error)?;
+    eprintln!(
+        "Generated {}, failed {}; results in {}",
+        summary.generated,
+        summary.failed,
+        args.output.display()
+    );
+    if summary.failed > 0 {
+        eprintln!("Run the same command again to retry the failed keywords");
+        return Err(format!("{} keyword(s) failed", summary.failed).into());
+    }
+    Ok(())
+}
 
-    // === USER INPUT HANDLING ===
-    // Get the prompt from earlier parsing
-    let prompt = match prompt {
-        Some(p) if !p.trim().is_empty() => p.trim().to_string(),
-        _ => {
-            eprintln!("Error: Please provide a non-empty prompt");
-            eprintln!("Usage: {} \"your prompt\"", args[0]);
-            return Err("Empty prompt".into());
-        }
+async fn run_repl(args: ReplArgs) -> Result<(), Box<dyn Error>> {
+    let config = load_config(args.args.config_layer())?;
+    let backend = create_backend(&config, &args.args)?;
+    let interactive = std::io::stdin().is_terminal();
+    if interactive {
+        eprintln!(
+            "PromptFlow REPL ({} / {}): enter a keyword, /help for commands, /quit to leave",
+            backend.name(),
+            backend.model()
+        );
+    }
+    let mut repl = repl::Repl {
+        backend,
+        history: History::load(
+            session::history_path(config.session.value.as_deref()).map_err(session_error)?,
+        ),
+        retention: config.history_retention(),
+        settings: GenerationSettings::from_config(&config),
+        tokenizer: clip::ClipTokenizer::load(),
+        max_tokens: config.max_tokens.value,
     };
+    repl.run(
+        std::io::stdin().lock(),
+        &mut std::io::stdout(),
+        interactive.then_some("> "),
+    )
+    .await?;
+    Ok(())
+}
 
-    // === PROMPT HISTORY MANAGEMENT ===
-    // Load or create prompt history file in temp directory
-    let history_path: PathBuf = env::temp_dir().join("prompt_history");
-    let mut history = if history_path.exists() {
-        match std::fs::read_to_string(&history_path) {
-            Ok(contents) => contents,
-            Err(_) => String::new(),
-        }
-    } else {
-        String::new()
+async fn run_tui(args: TuiArgs) -> Result<(), Box<dyn Error>> {
+    let config = load_config(args.args.config_layer())?;
+    let backend = create_backend(&config, &args.args)?;
+    if !std::io::stdout().is_terminal() {
+        return Err("The tui command needs a terminal".into());
+    }
+    let mut tui = tui::Tui {
+        backend,
+        history: History::load(
+            session::history_path(config.session.value.as_deref()).map_err(session_error)?,
+        ),
+        retention: config.history_retention(),
+        settings: GenerationSettings::from_config(&config),
+        tokenizer: clip::ClipTokenizer::load(),
+        max_tokens: config.max_tokens.value,
     };
+    tui.run().await?;
+    Ok(())
+}
 
-    // Append current prompt to history
-    history.push_str(&format!("{}\n", prompt));
-    std::fs::write(&history_path, &history)?;
-
-    // Extract the 5 most recent prompts to provide context to the AI
-    let recent_prompts: String = history
-        .lines()
-        .collect::<Vec<&str>>()
-        .into_iter()
-        .rev() // Reverse to get the most recent first
-        .take(5) // Take only the 5 most recent entries
-        .rev() // Reverse back to chronological order
-        .collect::<Vec<&str>>()
-        .join("\n");
-
-    // Combine system instructions with recent prompt history
-    let system_instruction = format!(
-        "{}\n\n--------------------\n**Previous Generated Prompts:**\n{}",
-        SYSTEM_INSTRUCTION, recent_prompts
+async fn run_history(
+    session: Option<String>,
+    command: HistoryCommand,
+) -> Result<(), Box<dyn Error>> {
+    let config = load_config(config::Layer {
+        session: session.clone(),
+        ..Default::default()
+    })?;
+    let mut history = History::load(
+        session::history_path(config.session.value.as_deref()).map_err(session_error)?,
     );
+    let not_found = |id: u64| -> Box<dyn Error> {
+        eprintln!("Error: no history entry with id {}", id);
+        "Unknown history entry".into()
+    };
+    let mut stdout = std::io::stdout().lock();
+    let pin = matches!(command, HistoryCommand::Pin { .. });
+    match command {
+        HistoryCommand::List { limit } => {
+            let entries: Vec<_> = history.recent(limit.unwrap_or(usize::MAX)).iter().collect();
+            output::write_history_list(&mut stdout, &entries)?;
+        }
+        HistoryCommand::Search { words } => {
+            output::write_history_list(&mut stdout, &history.search(&words.join(" ")))?;
+        }
+        HistoryCommand::Show { id } => {
+            let entry = history.get(id).ok_or_else(|| not_found(id))?;
+            output::write_history_entry(&mut stdout, entry)?;
+        }
+        HistoryCommand::Rerun(rerun) => {
+            let entry = history.get(rerun.id).ok_or_else(|| not_found(rerun.id))?;
+            let entry = entry.clone();
+            let mut generate = rerun.into_generate(entry.keyword.clone());
+            let args = &mut generate.args;
+            args.session = args.session.take().or(session);
+            // Keep the entry's backend and model unless another backend is asked for
+            if args.backend.is_none()
+                && let Ok(backend) = entry.backend.parse()
+            {
+                args.backend = Some(backend);
+                args.model = args.model.take().or(Some(entry.model));
+            }
+            // Likewise the preset and target it was generated with
+            args.preset = args.preset.take().or(entry.preset);
+            args.target = args.target.or(Some(entry.target));
+            args.aspect_ratio = args.aspect_ratio.or(entry.aspect_ratio);
+            args.stylize = args.stylize.or(entry.stylize);
+            drop(stdout);
+            return run_generate(generate).await;
+        }
+        HistoryCommand::Pin { ids } | HistoryCommand::Unpin { ids } => {
+            if let Some(&id) = ids.iter().find(|&&id| history.get(id).is_none()) {
+                return Err(not_found(id));
+            }
+            history.set_pinned(&ids, pin)?;
+        }
+        HistoryCommand::Rm { ids } => {
+            if let Some(&id) = ids.iter().find(|&&id| history.get(id).is_none()) {
+                return Err(not_found(id));
+            }
+            let removed = history.remove(&ids)?;
+            eprintln!(
+                "Removed {} history entr{}",
+                removed,
+                if removed == 1 { "y" } else { "ies" }
+            );
+        }
+        HistoryCommand::Clear => {
+            history.clear()?;
+            eprintln!("History cleared");
+        }
+        HistoryCommand::Export { format } => {
+            output::write_history_export(&mut stdout, history.entries(), format)?;
+        }
+    }
+    Ok(())
+}
 
-    // === AI PROMPT GENERATION ===
-    println!("Generating prompt for: {:?}", prompt);
+fn run_key(command: KeyCommand) -> Result<(), Box<dyn Error>> {
+    let (KeyCommand::Set { entry, .. }
+    | KeyCommand::Show { entry, .. }
+    | KeyCommand::Rm { entry }
+    | KeyCommand::Rotate { entry }) = &command;
+    let config = load_config(entry.config_layer())?;
+    let profile = config.profile.value.as_str();
+    let backend = config.backend.value.name();
+    let not_found = || KeyError::NotFound {
+        profile: profile.to_string(),
+        backend: backend.to_string(),
+    };
 
-    // Call the Gemini API to generate a detailed anime prompt
-    let res = client
-        .chat(MODEL) // Using the flash model for faster response
-        .system_instruction(&system_instruction) // Pass system instructions and history
-        .send_message(&prompt) // Send the user's keyword
-        .await?;
-    let text = res.to_string();
+    let passphrase = tty::Passphrase::default();
+    let mut store = open_store(&passphrase).map_err(key_error)?;
+    match command {
+        KeyCommand::Set { encrypt, .. } => {
+            if encrypt && !store.is_encrypted() {
+                let new_passphrase = passphrase.choose().map_err(key_error)?;
+                store.encrypt(&new_passphrase).map_err(key_error)?;
+            }
+            let key = tty::read_secret(&format!("API key for {} ({}): ", backend, profile))
+                .map_err(|e| key_error(KeyError::Terminal(e)))?;
+            store
+                .set(profile, backend, &key, &|| passphrase.get())
+                .map_err(key_error)?;
+            store.save().map_err(key_error)?;
+            eprintln!("Stored the {} API key in profile {:?}", backend, profile);
+        }
+        KeyCommand::Show { entry, reveal } if entry.backend.is_some() => {
+            let key = store
+                .get(profile, backend, &|| passphrase.get())
+                .map_err(key_error)?
+                .ok_or_else(|| key_error(not_found()))?;
+            println!("{}", if reveal { key } else { key::mask(&key) });
+        }
+        KeyCommand::Show { entry, .. } => {
+            // Every entry, or every entry of the profile given with --profile
+            let entries: Vec<(String, String)> = store
+                .entries()
+                .into_iter()
+                .filter(|(p, _)| entry.profile.is_none() || *p == profile)
+                .map(|(p, b)| (p.to_string(), b.to_string()))
+                .collect();
+            if entries.is_empty() {
+                eprintln!("No API keys stored in {}", store.path().display());
+            }
+            for (p, b) in entries {
+                let key = store
+                    .get(&p, &b, &|| passphrase.get())
+                    .map_err(key_error)?
+                    .unwrap_or_default();
+                println!("{:<12} {:<8} {}", p, b, key::mask(&key));
+            }
+        }
+        KeyCommand::Rm { .. } => {
+            if !store.remove(profile, backend) {
+                return Err(key_error(not_found()));
+            }
+            store.save().map_err(key_error)?;
+            eprintln!("Removed the {} API key from profile {:?}", backend, profile);
+        }
+        KeyCommand::Rotate { .. } => {
+            let old = store
+                .get(profile, backend, &|| passphrase.get())
+                .map_err(key_error)?
+                .ok_or_else(|| key_error(not_found()))?;
+            let key = tty::read_secret(&format!("New API key for {} ({}): ", backend, profile))
+                .map_err(|e| key_error(KeyError::Terminal(e)))?;
+            store
+                .set(profile, backend, &key, &|| passphrase.get())
+                .map_err(key_error)?;
+            store.save().map_err(key_error)?;
+            eprintln!(
+                "Replaced the {} API key {} with {} in profile {:?}",
+                backend,
+                key::mask(&old),
+                key::mask(&key),
+                profile
+            );
+        }
+    }
+    Ok(())
+}
 
-    // === OUTPUT RESULTS ===
-    // Display the generated prompt and standard negative prompt with clear formatting
-    println!("\n=== GENERATED PROMPT ===");
-    println!("{}", text);
-    println!("\n=== NEGATIVE PROMPT ===");
-    println!("{}", NEGATIVE_PROMPT);
+fn run_session(command: SessionCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        SessionCommand::New {
+            name,
+            preset,
+            negative_prompt,
+            descriptors,
+            switch,
+        } => {
+            let preset = match preset {
+                Some(name) => {
+                    let presets = preset::load().map_err(preset_error)?;
+                    Some(find_preset(&presets, &name)?.name.clone())
+                }
+                None => None,
+            };
+            let mut session = Session {
+                name,
+                preset,
+                negative_prompt,
+                descriptors,
+                ..Default::default()
+            };
+            session.create().map_err(session_error)?;
+            eprintln!("Created session {:?}", session.name);
+            if switch {
+                session::set_active(Some(&session.name)).map_err(session_error)?;
+                eprintln!("Switched to session {:?}", session.name);
+            }
+        }
+        SessionCommand::List { all } => {
+            let sessions: Vec<(Session, usize)> = Session::list()
+                .and_then(|sessions| {
+                    sessions
+                        .into_iter()
+                        .filter(|session| all || !session.archived)
+                        .map(|session| {
+                            let prompts = History::load(session.history_path()?).entries().len();
+                            Ok((session, prompts))
+                        })
+                        .collect()
+                })
+                .map_err(session_error)?;
+            if sessions.is_empty() {
+                eprintln!("No sessions; create one with `PromptFlow session new NAME`");
+            }
+            output::write_session_list(
+                &mut std::io::stdout().lock(),
+                &sessions,
+                session::active().as_deref(),
+            )?;
+        }
+        SessionCommand::Switch { name: Some(name) } => {
+            let session = Session::open(&name).map_err(session_error)?;
+            if session.archived {
+                return Err(session_error(SessionError::Archived(name)));
+            }
+            session::set_active(Some(&name)).map_err(session_error)?;
+            eprintln!("Switched to session {:?}", name);
+        }
+        SessionCommand::Switch { name: None } => {
+            session::set_active(None).map_err(session_error)?;
+            eprintln!("Switched to the global history");
+        }
+        SessionCommand::Archive { name } => {
+            let mut session = Session::open(&name).map_err(session_error)?;
+            session.archived = true;
+            session.save().map_err(session_error)?;
+            if session::active().as_deref() == Some(name.as_str()) {
+                session::set_active(None).map_err(session_error)?;
+            }
+            eprintln!("Archived session {:?}", name);
+        }
+    }
+    Ok(())
+}
+
+fn preset_error(e: PresetError) -> Box<dyn Error> {
+    eprintln!("Error: {}", e);
+    "Preset error".into()
+}
+
+/// The preset called `name`, or an error listing the available ones
+fn find_preset<'a>(presets: &'a [Preset], name: &str) -> Result<&'a Preset, Box<dyn Error>> {
+    preset::find(presets, name).ok_or_else(|| {
+        eprintln!(
+            "Error: Unknown preset {:?} (available: {})",
+            name,
+            preset::names(presets)
+        );
+        "Unknown preset".into()
+    })
+}
+
+fn run_presets(command: PresetsCommand) -> Result<(), Box<dyn Error>> {
+    let presets = preset::load().map_err(preset_error)?;
+    match command {
+        PresetsCommand::List => {
+            for preset in &presets {
+                let origin = if preset.path.is_some() {
+                    "user"
+                } else {
+                    "built-in"
+                };
+                println!("{:<14} {:<9} {}", preset.name, origin, preset.description);
+            }
+        }
+        PresetsCommand::Show { name } => {
+            let preset = find_preset(&presets, &name)?;
+            if let Some(path) = &preset.path {
+                eprintln!("Preset {:?} from {}", preset.name, path.display());
+            }
+            println!("{}", preset.system_instruction().trim());
+            println!("\n=== NEGATIVE PROMPT ===\n{}", preset.negative_prompt);
+        }
+        PresetsCommand::New {
+            name,
+            from,
+            markdown,
+        } => {
+            let base = find_preset(&presets, &from)?;
+            let path = preset::create(&name, base, markdown).map_err(preset_error)?;
+            eprintln!(
+                "Created preset {:?} from {:?}; edit it in:",
+                name, base.name
+            );
+            println!("{}", path.display());
+        }
+    }
     Ok(())
 }
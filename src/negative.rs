@@ -0,0 +1,372 @@
+//! Composing the negative prompt.
+//!
+//! Every mode starts from the configured negative prompt (normally the preset's) with duplicate
+//! terms removed:
+//! - `fixed`: just that
+//! - `rules`: plus the target model's own terms, adjusted by local rules matching the keyword
+//!   and the generated prompt, e.g. no anatomy terms for landscapes
+//! - `llm`: plus the target model's terms and the terms the model suggests in the same call,
+//!   falling back to `rules` when it suggests none
+
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::parser::{self, Token};
+use crate::target::Target;
+use crate::validate::contains_term;
+
+/// Asks the model for a negative prompt on a second line, in `llm` mode
+pub const LLM_INSTRUCTION: &str = "**Negative Prompt:**\n\
+     After the prompt, write a second line starting with `Negative prompt:` followed by \
+     comma-separated terms for what must NOT appear in this particular image, e.g. \
+     `Negative prompt: extra limbs, text, blurry`. Only name things that could plausibly go \
+     wrong for this subject and style. This second line is the only exception to the \
+     single-line rule.";
+
+/// Label the model's negative prompt line starts with, matched case-insensitively
+const LLM_LABEL: &str = "negative prompt:";
+
+/// How the negative prompt is put together
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NegativeMode {
+    /// The configured negative prompt, deduplicated
+    Fixed,
+    /// Adjusted by local rule tables
+    #[default]
+    Rules,
+    /// Extended with the model's suggestions
+    Llm,
+}
+
+impl fmt::Display for NegativeMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NegativeMode::Fixed => "fixed",
+            NegativeMode::Rules => "rules",
+            NegativeMode::Llm => "llm",
+        })
+    }
+}
+
+impl FromStr for NegativeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "fixed" => Ok(NegativeMode::Fixed),
+            "rules" => Ok(NegativeMode::Rules),
+            "llm" => Ok(NegativeMode::Llm),
+            _ => Err(format!(
+                "Unknown negative prompt mode {:?} (available: fixed, rules, llm)",
+                s
+            )),
+        }
+    }
+}
+
+/// Terms only needed when the keyword or prompt mentions something, or no longer needed then
+struct Rule {
+    /// Any of these must be mentioned...
+    when: &'static [&'static str],
+    /// ...and none of these
+    unless: &'static [&'static str],
+    add: &'static [&'static str],
+    remove: &'static [&'static str],
+}
+
+#[rustfmt::skip]
+const CHARACTERS: &[&str] = &[
+    "1girl", "1boy", "2girls", "2boys", "multiple girls", "multiple boys", "solo", "girl", "boy",
+    "woman", "man", "person", "people", "character", "portrait", "knight", "samurai", "witch",
+    "warrior", "princess", "prince", "idol", "maid", "elf", "child", "couple",
+];
+
+#[rustfmt::skip]
+const ANATOMY: &[&str] = &[
+    "bad anatomy", "extra limbs", "extra arms", "extra legs", "extra fingers", "missing fingers",
+    "fused fingers", "bad hands", "deformed hands", "mutated hands", "poorly drawn hands",
+    "poorly drawn feet", "poorly drawn face", "distorted face", "disfigured", "malformed limbs",
+    "body out of frame", "uncanny", "plastic skin",
+];
+
+#[rustfmt::skip]
+const PHOTO: &[&str] = &[
+    "photo", "photograph", "photography", "photorealistic", "raw photo", "dslr", "35mm",
+    "film grain", "bokeh",
+];
+
+#[rustfmt::skip]
+const RULES: &[Rule] = &[
+    // Landscapes and still lifes have no anatomy to get wrong
+    Rule {
+        when: &[
+            "landscape", "scenery", "cityscape", "skyline", "architecture", "no humans",
+            "still life", "interior",
+        ],
+        unless: CHARACTERS,
+        add: &[],
+        remove: ANATOMY,
+    },
+    Rule {
+        when: &[
+            "anime", "manga", "cel shading", "chibi", "cartoon", "illustration", "lineart",
+            "line art", "pixel art", "watercolor", "2d",
+        ],
+        unless: PHOTO,
+        add: &["realistic", "3d", "photo"],
+        remove: &[],
+    },
+    Rule {
+        when: PHOTO,
+        unless: &[],
+        add: &["cartoon", "anime", "illustration", "painting", "drawing"],
+        remove: &["realistic", "photo"],
+    },
+    Rule {
+        when: &["solo", "1girl", "1boy"],
+        unless: &[],
+        add: &["multiple views", "duplicate"],
+        remove: &[],
+    },
+    Rule {
+        when: &["holding", "hands", "hand", "peace sign", "waving", "fingers"],
+        unless: &[],
+        add: &["bad hands", "missing fingers", "extra fingers"],
+        remove: &[],
+    },
+    Rule {
+        when: &["portrait", "close-up", "face focus"],
+        unless: &[],
+        add: &["cross-eyed", "asymmetrical eyes"],
+        remove: &[],
+    },
+    // Signs and lettering the prompt asks for shouldn't be negated
+    Rule {
+        when: &["text", "sign", "signboard", "lettering", "typography", "logo", "speech bubble"],
+        unless: &[],
+        add: &[],
+        remove: &["text"],
+    },
+];
+
+/// Terms every negative prompt for `target` should have
+fn target_terms(target: Target) -> &'static [&'static str] {
+    match target {
+        // Illustrious is trained with these aesthetic tags
+        Target::Illustrious => &["bad quality", "displeasing", "very displeasing"],
+        Target::Sd15
+        | Target::Sdxl
+        | Target::Pony
+        | Target::Flux
+        | Target::Midjourney
+        | Target::DallE => &[],
+    }
+}
+
+/// Split a model response into the prompt and the negative prompt it wrote after
+/// `Negative prompt:`, if any
+pub fn split_response(text: &str) -> (String, Option<String>) {
+    // ASCII lowercasing keeps byte offsets valid for `text`
+    match text.to_ascii_lowercase().find(LLM_LABEL) {
+        Some(at) => {
+            let negative = text[at + LLM_LABEL.len()..].trim();
+            let negative = negative.split_whitespace().collect::<Vec<_>>().join(" ");
+            (
+                text[..at].trim().to_string(),
+                Some(negative).filter(|negative| !negative.is_empty()),
+            )
+        }
+        None => (text.to_string(), None),
+    }
+}
+
+/// Negative prompt for `prompt`, generated for `keyword`, built from `base` according to `mode`.
+/// `suggested` is the model's own negative prompt in `llm` mode.
+pub fn compose(
+    mode: NegativeMode,
+    base: &str,
+    target: Target,
+    keyword: &str,
+    prompt: &str,
+    suggested: Option<&str>,
+) -> String {
+    let mut terms = tokens(base);
+    let mut removed: Vec<&str> = Vec::new();
+    let mode = match (mode, suggested) {
+        (NegativeMode::Llm, None) => {
+            eprintln!("Warning: the model wrote no negative prompt, using the rules instead");
+            NegativeMode::Rules
+        }
+        (mode, _) => mode,
+    };
+    if mode != NegativeMode::Fixed {
+        terms.extend(target_terms(target).iter().map(|term| term.to_string()));
+    }
+    match mode {
+        NegativeMode::Fixed => {}
+        NegativeMode::Rules => {
+            let text = format!("{}, {}", keyword, plain_text(prompt));
+            let mentions = |list: &[&str]| list.iter().any(|term| contains_term(&text, term));
+            for rule in RULES {
+                if mentions(rule.when) && !mentions(rule.unless) {
+                    terms.extend(rule.add.iter().map(|term| term.to_string()));
+                    removed.extend(rule.remove);
+                }
+            }
+        }
+        NegativeMode::Llm => terms.extend(suggested.map(tokens).unwrap_or_default()),
+    }
+
+    let mut seen = HashSet::new();
+    terms
+        .into_iter()
+        .filter(|term| {
+            let key = plain_text(term).to_lowercase();
+            !removed.iter().any(|removed| key == *removed) && seen.insert(key)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The comma-separated tokens of `text`, with their weights
+fn tokens(text: &str) -> Vec<String> {
+    let (prompt, _) = parser::parse(text);
+    prompt
+        .segments
+        .iter()
+        .flat_map(|segment| &segment.tokens)
+        .map(Token::to_string)
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+/// `text` without grouping, weights or `BREAK`s
+fn plain_text(text: &str) -> String {
+    let (prompt, _) = parser::parse(text);
+    prompt
+        .segments
+        .iter()
+        .flat_map(|segment| &segment.tokens)
+        .map(|token| token.plain_text().trim().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "ugly, extra limbs, bad anatomy, watermark, (blurry:1.2), text, blurry";
+
+    fn rules(keyword: &str, prompt: &str) -> String {
+        compose(
+            NegativeMode::Rules,
+            BASE,
+            Target::Sd15,
+            keyword,
+            prompt,
+            None,
+        )
+    }
+
+    #[test]
+    fn fixed_mode_only_deduplicates() {
+        assert_eq!(
+            compose(
+                NegativeMode::Fixed,
+                BASE,
+                Target::Illustrious,
+                "anime knight",
+                "1boy",
+                None
+            ),
+            "ugly, extra limbs, bad anatomy, watermark, (blurry:1.2), text"
+        );
+    }
+
+    #[test]
+    fn rules_follow_the_keyword_and_prompt() {
+        assert_eq!(
+            rules("mountain lake", "scenery, lake, anime style"),
+            "ugly, watermark, (blurry:1.2), text, realistic, 3d, photo"
+        );
+        assert_eq!(
+            rules("knight", "1boy, solo, holding sword, raw photo"),
+            "ugly, extra limbs, bad anatomy, watermark, (blurry:1.2), text, cartoon, anime, \
+             illustration, painting, drawing, multiple views, duplicate, bad hands, \
+             missing fingers, extra fingers"
+        );
+        assert_eq!(
+            rules("shop sign", "no humans, street, neon sign"),
+            "ugly, watermark, (blurry:1.2)"
+        );
+    }
+
+    #[test]
+    fn targets_add_their_own_terms() {
+        assert_eq!(
+            compose(
+                NegativeMode::Rules,
+                "lowres",
+                Target::Illustrious,
+                "x",
+                "x",
+                None
+            ),
+            "lowres, bad quality, displeasing, very displeasing"
+        );
+    }
+
+    #[test]
+    fn llm_mode_merges_suggestions() {
+        assert_eq!(
+            compose(
+                NegativeMode::Llm,
+                "lowres, blurry",
+                Target::Sd15,
+                "knight",
+                "1boy",
+                Some("Blurry, broken sword, extra limbs")
+            ),
+            "lowres, blurry, broken sword, extra limbs"
+        );
+        assert_eq!(
+            compose(
+                NegativeMode::Llm,
+                "lowres",
+                Target::Sd15,
+                "lake",
+                "anime, scenery",
+                None
+            ),
+            "lowres, realistic, 3d, photo"
+        );
+    }
+
+    #[test]
+    fn splits_the_negative_prompt_line() {
+        assert_eq!(
+            split_response("1girl, solo\nNegative Prompt: extra limbs,\n text"),
+            (
+                "1girl, solo".to_string(),
+                Some("extra limbs, text".to_string())
+            )
+        );
+        assert_eq!(
+            split_response("1girl, solo"),
+            ("1girl, solo".to_string(), None)
+        );
+        assert_eq!(
+            split_response("1girl negative prompt:"),
+            ("1girl".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn parses_modes() {
+        assert_eq!("LLM".parse(), Ok(NegativeMode::Llm));
+        assert!("auto".parse::<NegativeMode>().is_err());
+    }
+}
@@ -0,0 +1,822 @@
+//! Rendering of generation results and history.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::clip::{CHUNK_SIZE, TokenReport};
+use crate::config::Config;
+use crate::coverage::{Category, Coverage};
+use crate::history::{self, Entry};
+use crate::parser;
+use crate::session::Session;
+
+/// Header of the prompt section of text results
+pub const PROMPT_HEADER: &str = "=== GENERATED PROMPT ===";
+
+/// Display the generated prompt and negative prompt with clear formatting
+pub fn write_result(out: &mut impl Write, prompt: &str, negative: &str) -> io::Result<()> {
+    writeln!(out, "\n{}", PROMPT_HEADER)?;
+    writeln!(out, "{}", prompt)?;
+    writeln!(out, "\n=== NEGATIVE PROMPT ===")?;
+    writeln!(out, "{}", negative)
+}
+
+/// Finish a text result whose reply `streamed` was already printed under [`PROMPT_HEADER`]:
+/// `prompt` again only if checking, repairing, trimming or formatting changed it, then `negative`
+pub fn write_streamed_result(
+    out: &mut impl Write,
+    streamed: &str,
+    prompt: &str,
+    negative: &str,
+) -> io::Result<()> {
+    if streamed.trim() != prompt.trim() {
+        writeln!(out, "\n=== FINAL PROMPT (changed after streaming) ===")?;
+        writeln!(out, "{}", prompt)?;
+    }
+    writeln!(out, "\n=== NEGATIVE PROMPT ===")?;
+    writeln!(out, "{}", negative)
+}
+
+/// How `generate` prints its result on stdout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Decorated prompt and negative prompt blocks
+    #[default]
+    Text,
+    Json,
+    Yaml,
+    /// `NAME='value'` lines for `eval`
+    Env,
+    /// The prompt alone
+    Raw,
+}
+
+impl OutputFormat {
+    pub const ALL: [OutputFormat; 5] = [
+        OutputFormat::Text,
+        OutputFormat::Json,
+        OutputFormat::Yaml,
+        OutputFormat::Env,
+        OutputFormat::Raw,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputFormat::Text => "text",
+            OutputFormat::Json => "json",
+            OutputFormat::Yaml => "yaml",
+            OutputFormat::Env => "env",
+            OutputFormat::Raw => "raw",
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|format| format.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| {
+                format!(
+                    "unknown output format {:?} (expected text, json, yaml, env or raw)",
+                    s
+                )
+            })
+    }
+}
+
+/// Everything known about a generated prompt, for the machine-readable output formats
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GenerationReport {
+    pub keyword: String,
+    /// The prompt in the target's dialect
+    pub prompt: String,
+    pub negative_prompt: String,
+    pub backend: String,
+    pub model: String,
+    pub preset: Option<String>,
+    pub target: String,
+    /// Segments of the prompt in Stable Diffusion syntax, before the target's rewriting
+    pub segments: Vec<SegmentReport>,
+    pub tokens: TokenTotals,
+    /// Tokens removed to fit the CLIP budget
+    pub trimmed: Vec<String>,
+    /// Component categories without a single token
+    pub missing_categories: Vec<&'static str>,
+}
+
+/// One `BREAK` segment with its CLIP token count and the weight of each token
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SegmentReport {
+    pub text: String,
+    pub tokens: usize,
+    pub weights: Vec<TokenWeight>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TokenWeight {
+    pub token: String,
+    pub weight: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TokenTotals {
+    pub total: usize,
+    pub chunks: usize,
+    pub exact: bool,
+}
+
+impl TokenTotals {
+    pub fn new(report: &TokenReport) -> Self {
+        Self {
+            total: report.total(),
+            chunks: report.chunks(),
+            exact: report.exact,
+        }
+    }
+}
+
+/// The segments of `text`, written in Stable Diffusion syntax, with the token counts of `report`
+pub fn segment_reports(text: &str, report: &TokenReport) -> Vec<SegmentReport> {
+    let (prompt, _) = parser::parse(text);
+    prompt
+        .segments
+        .iter()
+        .enumerate()
+        .map(|(i, segment)| SegmentReport {
+            text: segment.to_string(),
+            tokens: report.segments.get(i).copied().unwrap_or_default(),
+            weights: segment
+                .tokens
+                .iter()
+                .map(|token| TokenWeight {
+                    token: token.plain_text().trim().to_string(),
+                    // Rounded so `1.1` doesn't come out as `1.100000023841858`
+                    weight: (f64::from(token.weight()) * 1000.0).round() / 1000.0,
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+/// Print `report` in `format`
+pub fn write_generation(
+    out: &mut impl Write,
+    report: &GenerationReport,
+    format: OutputFormat,
+) -> io::Result<()> {
+    match format {
+        OutputFormat::Text => write_result(out, &report.prompt, &report.negative_prompt),
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, report)?;
+            writeln!(out)
+        }
+        OutputFormat::Yaml => {
+            let value = serde_json::to_value(report).map_err(io::Error::other)?;
+            write_yaml(out, &value, 0)
+        }
+        OutputFormat::Env => write_env(out, report, ""),
+        OutputFormat::Raw => writeln!(out, "{}", report.prompt),
+    }
+}
+
+/// Print the variants in `reports` in `format`: one block per variant for text, an array for
+/// JSON and YAML, variables suffixed with `_1`, `_2`... for env and one line each for raw.
+/// A single variant prints exactly as [`write_generation`].
+pub fn write_generations(
+    out: &mut impl Write,
+    reports: &[GenerationReport],
+    format: OutputFormat,
+) -> io::Result<()> {
+    if let [report] = reports {
+        return write_generation(out, report, format);
+    }
+    match format {
+        OutputFormat::Text => {
+            for (i, report) in reports.iter().enumerate() {
+                writeln!(out, "\n=== VARIANT {} / {} ===", i + 1, reports.len())?;
+                write_result(out, &report.prompt, &report.negative_prompt)?;
+            }
+            Ok(())
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, reports)?;
+            writeln!(out)
+        }
+        OutputFormat::Yaml => {
+            let value = serde_json::to_value(reports).map_err(io::Error::other)?;
+            write_yaml(out, &value, 0)
+        }
+        OutputFormat::Env => {
+            writeln!(out, "COUNT={}", shell_quote(&reports.len().to_string()))?;
+            for (i, report) in reports.iter().enumerate() {
+                write_env(out, report, &format!("_{}", i + 1))?;
+            }
+            Ok(())
+        }
+        OutputFormat::Raw => reports
+            .iter()
+            .try_for_each(|report| writeln!(out, "{}", report.prompt)),
+    }
+}
+
+/// `NAME='value'` lines for `report`, with `suffix` appended to every name
+fn write_env(out: &mut impl Write, report: &GenerationReport, suffix: &str) -> io::Result<()> {
+    let tokens = report.tokens.total.to_string();
+    let preset = report.preset.as_deref().unwrap_or_default();
+    for (name, value) in [
+        ("PROMPT", report.prompt.as_str()),
+        ("NEGATIVE_PROMPT", &report.negative_prompt),
+        ("KEYWORD", &report.keyword),
+        ("BACKEND", &report.backend),
+        ("MODEL", &report.model),
+        ("PRESET", preset),
+        ("TARGET", &report.target),
+        ("TOKENS", &tokens),
+    ] {
+        writeln!(out, "{}{}={}", name, suffix, shell_quote(value))?;
+    }
+    Ok(())
+}
+
+/// `value` single-quoted for POSIX shells
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Block-style YAML for `value`, with every string double-quoted as in JSON
+fn write_yaml(out: &mut impl Write, value: &Value, indent: usize) -> io::Result<()> {
+    let pad = " ".repeat(indent);
+    let children: Vec<(String, &Value)> = match value {
+        Value::Object(map) => map
+            .iter()
+            .map(|(key, value)| (format!("{}{}:", pad, key), value))
+            .collect(),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| (format!("{}-", pad), item))
+            .collect(),
+        scalar => return writeln!(out, "{}{}", pad, scalar),
+    };
+    for (label, child) in children {
+        match child {
+            Value::Object(map) if !map.is_empty() => {
+                writeln!(out, "{}", label)?;
+                write_yaml(out, child, indent + 2)?;
+            }
+            Value::Array(items) if !items.is_empty() => {
+                writeln!(out, "{}", label)?;
+                write_yaml(out, child, indent + 2)?;
+            }
+            Value::Object(_) => writeln!(out, "{} {{}}", label)?,
+            Value::Array(_) => writeln!(out, "{} []", label)?,
+            scalar => writeln!(out, "{} {}", label, scalar)?,
+        }
+    }
+    Ok(())
+}
+
+/// Print the per-category coverage summary shown with `--explain`
+pub fn write_coverage(out: &mut impl Write, coverage: &Coverage) -> io::Result<()> {
+    writeln!(out, "\n=== COVERAGE ===")?;
+    for category in Category::ALL {
+        let tokens = coverage.tokens_in(category);
+        if tokens.is_empty() {
+            writeln!(out, "{:<10} missing", category.name())?;
+        } else {
+            writeln!(out, "{:<10} {}", category.name(), tokens.join(", "))?;
+        }
+    }
+    let unclassified = coverage.unclassified();
+    if !unclassified.is_empty() {
+        writeln!(out, "{:<10} {}", "(other)", unclassified.join(", "))?;
+    }
+    Ok(())
+}
+
+/// One-line token count summary, e.g. `CLIP tokens: 62 + 41 = 103 (3 chunks)`
+pub fn token_summary(report: &TokenReport) -> String {
+    let parts: Vec<String> = report.segments.iter().map(usize::to_string).collect();
+    let counts = if parts.len() > 1 {
+        format!("{} = {}", parts.join(" + "), report.total())
+    } else {
+        report.total().to_string()
+    };
+    format!(
+        "CLIP tokens{}: {} ({} chunk{} of {})",
+        if report.exact { "" } else { " (estimated)" },
+        counts,
+        report.chunks(),
+        if report.chunks() == 1 { "" } else { "s" },
+        CHUNK_SIZE
+    )
+}
+
+/// Print per-segment token counts shown with `--explain`
+pub fn write_tokens(out: &mut impl Write, report: &TokenReport) -> io::Result<()> {
+    writeln!(out, "\n=== TOKENS ===")?;
+    for (i, count) in report.segments.iter().enumerate() {
+        writeln!(
+            out,
+            "Segment {:<3} {} tokens ({} chunk{})",
+            i + 1,
+            count,
+            count.div_ceil(CHUNK_SIZE).max(1),
+            if count.div_ceil(CHUNK_SIZE) > 1 {
+                "s"
+            } else {
+                ""
+            }
+        )?;
+    }
+    writeln!(out, "{}", token_summary(report))
+}
+
+/// Print every effective config value and the layer it came from, for `config show`
+pub fn write_config(out: &mut impl Write, config: &Config) -> io::Result<()> {
+    for (key, value, source) in config.entries() {
+        writeln!(out, "{:<18} = {:<40} # {}", key, value, source)?;
+    }
+    Ok(())
+}
+
+/// One line per history entry: id (starred when pinned), time, backend and model, keyword
+pub fn write_history_list(out: &mut impl Write, entries: &[&Entry]) -> io::Result<()> {
+    for entry in entries {
+        writeln!(
+            out,
+            "{:>4}{} {}  {:<24} {}",
+            entry.id,
+            if entry.pinned { '*' } else { ' ' },
+            history::format_time(entry.timestamp),
+            format!("{}/{}", entry.backend, entry.model),
+            entry.keyword
+        )?;
+    }
+    Ok(())
+}
+
+/// One line per session with its history size: name (starred when active), creation date,
+/// prompt count and preset
+pub fn write_session_list(
+    out: &mut impl Write,
+    sessions: &[(Session, usize)],
+    active: Option<&str>,
+) -> io::Result<()> {
+    for (session, prompts) in sessions {
+        writeln!(
+            out,
+            "{} {:<20} {}  {:>4} prompt{}  {}{}",
+            if active == Some(session.name.as_str()) {
+                '*'
+            } else {
+                ' '
+            },
+            session.name,
+            &history::format_time(session.created)[..10],
+            prompts,
+            if *prompts == 1 { " " } else { "s" },
+            session.preset.as_deref().unwrap_or("-"),
+            if session.archived { " (archived)" } else { "" }
+        )?;
+    }
+    Ok(())
+}
+
+/// Every field of a history entry, for `history show`
+pub fn write_history_entry(out: &mut impl Write, entry: &Entry) -> io::Result<()> {
+    let tokens: Vec<String> = entry.tokens.iter().map(usize::to_string).collect();
+    writeln!(out, "{:<8} {}", "id", entry.id)?;
+    writeln!(
+        out,
+        "{:<8} {} UTC",
+        "time",
+        history::format_time(entry.timestamp)
+    )?;
+    writeln!(out, "{:<8} {}", "keyword", entry.keyword)?;
+    writeln!(out, "{:<8} {}", "backend", entry.backend)?;
+    writeln!(out, "{:<8} {}", "model", entry.model)?;
+    writeln!(
+        out,
+        "{:<8} {}",
+        "preset",
+        entry.preset.as_deref().unwrap_or("(custom)")
+    )?;
+    writeln!(out, "{:<8} {}", "target", entry.target)?;
+    writeln!(
+        out,
+        "{:<8} {} ({})",
+        "tokens",
+        entry.tokens.iter().sum::<usize>(),
+        tokens.join(" + ")
+    )?;
+    writeln!(out, "{:<8} {} ms", "latency", entry.latency_ms)?;
+    if entry.pinned {
+        writeln!(out, "{:<8} yes", "pinned")?;
+    }
+    let dialect = entry.dialect();
+    write_result(
+        out,
+        &dialect.format(&entry.prompt),
+        &dialect.format_negative(&entry.negative_prompt),
+    )
+}
+
+/// File format of `history export`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+    Markdown,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 3] = [
+        ExportFormat::Json,
+        ExportFormat::Csv,
+        ExportFormat::Markdown,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Csv => "csv",
+            ExportFormat::Markdown => "md",
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|format| format.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| format!("unknown export format {:?} (expected json, csv or md)", s))
+    }
+}
+
+/// Write `entries` as a JSON array, CSV with a header row or a Markdown table
+pub fn write_history_export(
+    out: &mut impl Write,
+    entries: &[Entry],
+    format: ExportFormat,
+) -> io::Result<()> {
+    match format {
+        ExportFormat::Json => {
+            let entries: Vec<Entry> = entries.iter().map(in_dialect).collect();
+            serde_json::to_writer_pretty(&mut *out, &entries)?;
+            writeln!(out)
+        }
+        ExportFormat::Csv => {
+            writeln!(
+                out,
+                "id,timestamp,keyword,prompt,negative_prompt,backend,model,preset,target,tokens,latency_ms"
+            )?;
+            for entry in entries {
+                let fields = export_fields(entry);
+                let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+                writeln!(out, "{}", fields.join(","))?;
+            }
+            Ok(())
+        }
+        ExportFormat::Markdown => {
+            writeln!(
+                out,
+                "| ID | Time | Keyword | Prompt | Negative prompt | Backend | Model | Preset | Target | Tokens | Latency (ms) |"
+            )?;
+            writeln!(out, "|---:|---|---|---|---|---|---|---|---|---:|---:|")?;
+            for entry in entries {
+                let fields = export_fields(entry);
+                let fields: Vec<String> = fields.iter().map(|field| markdown_cell(field)).collect();
+                writeln!(out, "| {} |", fields.join(" | "))?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// `entry` with its prompts rewritten for its target, as exported
+fn in_dialect(entry: &Entry) -> Entry {
+    let dialect = entry.dialect();
+    Entry {
+        prompt: dialect.format(&entry.prompt),
+        negative_prompt: dialect.format_negative(&entry.negative_prompt),
+        ..entry.clone()
+    }
+}
+
+/// Columns of the CSV and Markdown exports
+fn export_fields(entry: &Entry) -> [String; 11] {
+    let entry = in_dialect(entry);
+    [
+        entry.id.to_string(),
+        history::format_rfc3339(entry.timestamp),
+        entry.keyword.clone(),
+        entry.prompt.clone(),
+        entry.negative_prompt.clone(),
+        entry.backend.clone(),
+        entry.model.clone(),
+        entry.preset.clone().unwrap_or_default(),
+        entry.target.to_string(),
+        entry.tokens.iter().sum::<usize>().to_string(),
+        entry.latency_ms.to_string(),
+    ]
+}
+
+/// `field` quoted for CSV when it holds a comma, quote or line break
+pub fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// `field` with the characters that would break a Markdown table row escaped
+fn markdown_cell(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('|', "\\|")
+        .replace(['\r', '\n'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coverage;
+    use crate::target::Target;
+
+    fn entry() -> Entry {
+        Entry {
+            id: 7,
+            timestamp: 951_827_696,
+            keyword: "sea witch".to_string(),
+            prompt: "1girl, (sea|foam:1.2), \"witch\"".to_string(),
+            negative_prompt: "lowres".to_string(),
+            backend: "ollama".to_string(),
+            model: "qwen2.5".to_string(),
+            preset: Some("anime".to_string()),
+            target: Target::Sd15,
+            aspect_ratio: None,
+            stylize: None,
+            tokens: vec![12, 3],
+            latency_ms: 850,
+            pinned: false,
+        }
+    }
+
+    #[test]
+    fn renders_history_entries() {
+        let mut out = Vec::new();
+        write_history_list(&mut out, &[&entry()]).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "   7  2000-02-29 12:34  ollama/qwen2.5           sea witch\n"
+        );
+
+        let mut out = Vec::new();
+        write_history_entry(&mut out, &entry()).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("id       7\ntime     2000-02-29 12:34 UTC\n"));
+        assert!(text.contains("tokens   15 (12 + 3)\nlatency  850 ms\n"));
+        assert!(text.ends_with("=== NEGATIVE PROMPT ===\nlowres\n"));
+    }
+
+    #[test]
+    fn renders_machine_readable_results() {
+        let report = GenerationReport {
+            keyword: "it's".to_string(),
+            prompt: "1girl, [crowd]".to_string(),
+            negative_prompt: "lowres".to_string(),
+            backend: "mock".to_string(),
+            model: "replay".to_string(),
+            preset: None,
+            target: "sd15".to_string(),
+            segments: segment_reports(
+                "1girl, [crowd]",
+                &TokenReport {
+                    segments: vec![4],
+                    splits: Vec::new(),
+                    exact: true,
+                },
+            ),
+            tokens: TokenTotals {
+                total: 4,
+                chunks: 1,
+                exact: true,
+            },
+            trimmed: Vec::new(),
+            missing_categories: vec!["Style"],
+        };
+        let render = |format| {
+            let mut out = Vec::new();
+            write_generation(&mut out, &report, format).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(report.segments[0].weights[1].weight, 0.909);
+        assert!(render(OutputFormat::Yaml).ends_with(
+            "preset: null\ntarget: \"sd15\"\nsegments:\n  -\n    text: \"1girl, [crowd]\"\n    \
+             tokens: 4\n    weights:\n      -\n        token: \"1girl\"\n        weight: 1.0\n      \
+             -\n        token: \"crowd\"\n        weight: 0.909\ntokens:\n  total: 4\n  chunks: 1\n  \
+             exact: true\ntrimmed: []\nmissing_categories:\n  - \"Style\"\n"
+        ));
+        let env = render(OutputFormat::Env);
+        assert!(env.contains("\nKEYWORD='it'\\''s'\n"));
+        assert!(env.contains("\nPRESET=''\n"));
+        assert_eq!(render(OutputFormat::Raw), "1girl, [crowd]\n");
+        assert!(render(OutputFormat::Text).starts_with("\n=== GENERATED PROMPT ===\n1girl"));
+        assert_eq!("JSON".parse(), Ok(OutputFormat::Json));
+
+        let mut second = report.clone();
+        second.prompt = "1boy".to_string();
+        let render = |format| {
+            let mut out = Vec::new();
+            write_generations(&mut out, &[report.clone(), second.clone()], format).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert!(render(OutputFormat::Text).contains("\n=== VARIANT 2 / 2 ===\n\n=== GENERATED"));
+        assert!(render(OutputFormat::Json).starts_with("[\n  {\n    \"keyword\""));
+        assert!(render(OutputFormat::Yaml).starts_with("-\n  keyword: \"it's\"\n"));
+        let env = render(OutputFormat::Env);
+        assert!(env.starts_with("COUNT='2'\n"));
+        assert!(env.contains("\nPROMPT_2='1boy'\n"));
+        assert_eq!(render(OutputFormat::Raw), "1girl, [crowd]\n1boy\n");
+    }
+
+    #[test]
+    fn renders_session_list() {
+        let session = |name: &str, preset: Option<&str>, archived| Session {
+            name: name.to_string(),
+            created: 951_827_696,
+            archived,
+            preset: preset.map(String::from),
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        write_session_list(
+            &mut out,
+            &[
+                (session("azure", Some("anime"), false), 1),
+                (session("old", None, true), 12),
+            ],
+            Some("azure"),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "* azure                2000-02-29     1 prompt   anime\n  \
+             old                  2000-02-29    12 prompts  - (archived)\n"
+        );
+    }
+
+    #[test]
+    fn exports_history() {
+        let export = |format| {
+            let mut out = Vec::new();
+            write_history_export(&mut out, &[entry()], format).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let json: Vec<Entry> = serde_json::from_str(&export(ExportFormat::Json)).unwrap();
+        assert_eq!(json, vec![entry()]);
+        assert_eq!(
+            export(ExportFormat::Csv).lines().nth(1).unwrap(),
+            r#"7,2000-02-29T12:34:56Z,sea witch,"1girl, (sea|foam:1.2), ""witch""",lowres,ollama,qwen2.5,anime,sd15,15,850"#
+        );
+        assert_eq!(
+            export(ExportFormat::Markdown).lines().nth(2).unwrap(),
+            r#"| 7 | 2000-02-29T12:34:56Z | sea witch | 1girl, (sea\|foam:1.2), "witch" | lowres | ollama | qwen2.5 | anime | sd15 | 15 | 850 |"#
+        );
+        assert_eq!("MD".parse(), Ok(ExportFormat::Markdown));
+        assert!("xml".parse::<ExportFormat>().is_err());
+    }
+
+    #[test]
+    fn formats_history_for_the_recorded_target() {
+        let entry = Entry {
+            prompt: "1girl, (sea witch:1.2)".to_string(),
+            target: Target::Midjourney,
+            aspect_ratio: Some((16, 9)),
+            ..entry()
+        };
+        let mut out = Vec::new();
+        write_history_entry(&mut out, &entry).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("target   midjourney\n"));
+        assert!(text.contains("=== GENERATED PROMPT ===\n1girl:: sea witch::1.2 --ar 16:9\n"));
+
+        let mut out = Vec::new();
+        write_history_export(&mut out, &[entry], ExportFormat::Json).unwrap();
+        let json: Vec<Entry> = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json[0].prompt, "1girl:: sea witch::1.2 --ar 16:9");
+        assert_eq!(json[0].target, Target::Midjourney);
+    }
+
+    #[test]
+    fn reprints_a_streamed_prompt_only_if_it_changed() {
+        let finish = |streamed| {
+            let mut out = Vec::new();
+            write_streamed_result(&mut out, streamed, "1girl, solo", "lowres").unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            finish("1girl, solo\n"),
+            "\n=== NEGATIVE PROMPT ===\nlowres\n"
+        );
+        assert_eq!(
+            finish("1girl, solo, (solo:9)"),
+            "\n=== FINAL PROMPT (changed after streaming) ===\n1girl, solo\n\n\
+             === NEGATIVE PROMPT ===\nlowres\n"
+        );
+    }
+
+    #[test]
+    fn renders_both_sections() {
+        let mut out = Vec::new();
+        write_result(&mut out, "1girl, solo", "lowres").unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\n=== GENERATED PROMPT ===\n1girl, solo\n\n=== NEGATIVE PROMPT ===\nlowres\n"
+        );
+    }
+
+    #[test]
+    fn renders_coverage_summary() {
+        let mut out = Vec::new();
+        write_coverage(
+            &mut out,
+            &coverage::analyze("1girl, solo, rim lighting, castle"),
+        )
+        .unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("Subject    1girl, solo\n"));
+        assert!(text.contains("Medium     missing\n"));
+        assert!(text.contains("Lighting   rim lighting\n"));
+        assert!(text.ends_with("(other)    castle\n"));
+    }
+
+    #[test]
+    fn summarizes_token_counts() {
+        let report = TokenReport {
+            segments: vec![80, 12],
+            splits: Vec::new(),
+            exact: true,
+        };
+        assert_eq!(
+            token_summary(&report),
+            "CLIP tokens: 80 + 12 = 92 (3 chunks of 75)"
+        );
+
+        let mut out = Vec::new();
+        write_tokens(&mut out, &report).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("Segment 1   80 tokens (2 chunks)\n"));
+        assert!(text.contains("Segment 2   12 tokens (1 chunk)\n"));
+
+        let estimated = TokenReport {
+            segments: vec![10],
+            splits: Vec::new(),
+            exact: false,
+        };
+        assert_eq!(
+            token_summary(&estimated),
+            "CLIP tokens (estimated): 10 (1 chunk of 75)"
+        );
+    }
+
+    #[test]
+    fn lists_config_sources() {
+        let mut out = Vec::new();
+        write_config(&mut out, &Config::default()).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("profile            = default"));
+        assert!(text.contains("\nbackend            = gemini"));
+        assert!(text.contains("model              = (unset)"));
+        assert!(text.contains("\npreset             = anime"));
+        assert!(
+            text.lines()
+                .all(|line| line.ends_with("# default") || line.ends_with("# preset \"anime\""))
+        );
+    }
+}
@@ -0,0 +1,689 @@
+//! Parser for the Stable Diffusion prompt syntax the model is asked to produce.
+//!
+//! A prompt is split into segments by the `BREAK` keyword, and each segment into
+//! comma-separated tokens. Tokens are made of plain text and groups:
+//! - `(text)` emphasizes by [`EMPHASIS_FACTOR`], `((text))` nests the effect
+//! - `(text:1.3)` / `(text: 1.3)` sets an explicit weight
+//! - `[text]` de-emphasizes by [`EMPHASIS_FACTOR`]
+//! - `\(` `\)` `\[` `\]` `\\` are literal characters
+//!
+//! Parsing is lenient: malformed input still yields a [`Prompt`], along with a list of
+//! [`ParseIssue`]s describing what had to be recovered from.
+
+use std::fmt;
+
+/// Weight multiplier applied by a bare `(...)` and divided out by `[...]`
+pub const EMPHASIS_FACTOR: f32 = 1.1;
+
+/// Keyword separating prompt segments
+pub const BREAK: &str = "BREAK";
+
+/// A parsed prompt: `BREAK`-separated segments
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Prompt {
+    pub segments: Vec<Segment>,
+}
+
+/// Comma-separated tokens between two `BREAK`s
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Segment {
+    pub tokens: Vec<Token>,
+}
+
+/// A single comma-separated entry, e.g. `blue hair` or `(cel shading:1.3)`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Token {
+    pub nodes: Vec<Node>,
+}
+
+/// Building block of a token
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    /// Literal text, with escapes already resolved
+    Text(String),
+    /// Parenthesized or bracketed group
+    Group(Group),
+}
+
+/// Kind of bracket a group was written with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupKind {
+    /// `( ... )`: emphasis, or an explicit weight
+    Paren,
+    /// `[ ... ]`: de-emphasis
+    Bracket,
+}
+
+/// An emphasis group and the tokens inside it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Group {
+    pub kind: GroupKind,
+    /// Explicit `:factor`, only ever set on [`GroupKind::Paren`] groups
+    pub weight: Option<f32>,
+    pub tokens: Vec<Token>,
+}
+
+/// Problem found (and recovered from) while parsing
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseIssue {
+    /// A group was never closed; it is treated as closed at the end of the input
+    UnclosedGroup { open: char, offset: usize },
+    /// A closing bracket without an opening one; it is kept as literal text
+    UnmatchedClose { close: char, offset: usize },
+    /// A `(text: factor)` group whose factor isn't a number; it is kept as text
+    MalformedWeight { text: String, offset: usize },
+}
+
+impl fmt::Display for ParseIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseIssue::UnclosedGroup { open, offset } => {
+                write!(f, "unclosed '{}' at offset {}", open, offset)
+            }
+            ParseIssue::UnmatchedClose { close, offset } => {
+                write!(f, "unmatched '{}' at offset {}", close, offset)
+            }
+            ParseIssue::MalformedWeight { text, offset } => {
+                write!(f, "malformed weight in ({}) at offset {}", text, offset)
+            }
+        }
+    }
+}
+
+/// Parse `input`, returning the prompt and any issues that were recovered from
+pub fn parse(input: &str) -> (Prompt, Vec<ParseIssue>) {
+    let mut parser = Parser {
+        chars: input.char_indices().collect(),
+        pos: 0,
+        issues: Vec::new(),
+    };
+    let segments = parser.parse_list(None, 0);
+    let prompt = Prompt {
+        segments: segments
+            .into_iter()
+            .filter(|tokens| !tokens.is_empty())
+            .map(|tokens| Segment { tokens })
+            .collect(),
+    };
+    (prompt, parser.issues)
+}
+
+struct Parser {
+    chars: Vec<(usize, char)>,
+    pos: usize,
+    issues: Vec<ParseIssue>,
+}
+
+impl Parser {
+    /// Parse tokens until `closer` (or the end of input at top level).
+    /// Returns one token list per segment; only the top level (`closer == None`) splits on BREAK.
+    fn parse_list(&mut self, closer: Option<char>, open_offset: usize) -> Vec<Vec<Token>> {
+        let top = closer.is_none();
+        let mut segments: Vec<Vec<Token>> = vec![Vec::new()];
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut text = String::new();
+
+        while let Some(&(offset, c)) = self.chars.get(self.pos) {
+            match c {
+                '\\' if matches!(self.peek(1), Some('(' | ')' | '[' | ']' | '\\')) => {
+                    text.push(self.peek(1).unwrap());
+                    self.pos += 2;
+                }
+                '(' | '[' => {
+                    flush_text(&mut text, &mut nodes);
+                    self.pos += 1;
+                    let kind = if c == '(' {
+                        GroupKind::Paren
+                    } else {
+                        GroupKind::Bracket
+                    };
+                    let close = if c == '(' { ')' } else { ']' };
+                    let inner = self.parse_list(Some(close), offset);
+                    let tokens = inner.into_iter().flatten().collect();
+                    nodes.push(Node::Group(self.finish_group(kind, tokens, offset)));
+                }
+                ')' | ']' if closer == Some(c) => {
+                    self.pos += 1;
+                    finish_token(&mut text, &mut nodes, &mut segments, top);
+                    return segments;
+                }
+                ')' | ']' => {
+                    self.issues
+                        .push(ParseIssue::UnmatchedClose { close: c, offset });
+                    text.push(c);
+                    self.pos += 1;
+                }
+                ',' => {
+                    finish_token(&mut text, &mut nodes, &mut segments, top);
+                    self.pos += 1;
+                }
+                '\n' | '\r' if top => {
+                    finish_token(&mut text, &mut nodes, &mut segments, top);
+                    self.pos += 1;
+                }
+                '\n' | '\r' => {
+                    text.push(' ');
+                    self.pos += 1;
+                }
+                _ => {
+                    text.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+
+        if let Some(closer) = closer {
+            let open = if closer == ')' { '(' } else { '[' };
+            self.issues.push(ParseIssue::UnclosedGroup {
+                open,
+                offset: open_offset,
+            });
+        }
+        finish_token(&mut text, &mut nodes, &mut segments, top);
+        segments
+    }
+
+    fn peek(&self, ahead: usize) -> Option<char> {
+        self.chars.get(self.pos + ahead).map(|&(_, c)| c)
+    }
+}
+
+/// Close the token being built, splitting it on BREAK keywords at top level
+fn finish_token(
+    text: &mut String,
+    nodes: &mut Vec<Node>,
+    segments: &mut Vec<Vec<Token>>,
+    top: bool,
+) {
+    flush_text(text, nodes);
+    let nodes = std::mem::take(nodes);
+    if !top {
+        push_token(segments.last_mut().unwrap(), nodes);
+        return;
+    }
+
+    let mut current = Vec::new();
+    for node in nodes {
+        match node {
+            Node::Text(t) if contains_break(&t) => {
+                let mut pieces = split_break(&t).into_iter();
+                if let Some(first) = pieces.next() {
+                    flush_text(&mut first.to_string(), &mut current);
+                }
+                for piece in pieces {
+                    push_token(segments.last_mut().unwrap(), std::mem::take(&mut current));
+                    segments.push(Vec::new());
+                    flush_text(&mut piece.to_string(), &mut current);
+                }
+            }
+            node => current.push(node),
+        }
+    }
+    push_token(segments.last_mut().unwrap(), current);
+}
+
+impl Parser {
+    /// Extract an explicit `:weight` from the last token of a paren group
+    fn finish_group(&mut self, kind: GroupKind, mut tokens: Vec<Token>, offset: usize) -> Group {
+        let mut group = Group {
+            kind,
+            weight: None,
+            tokens: Vec::new(),
+        };
+        let factor = match tokens.last().and_then(|t| t.nodes.last()) {
+            Some(Node::Text(last)) if kind == GroupKind::Paren => last
+                .rsplit_once(':')
+                .map(|(head, factor)| (head.trim_end().to_string(), factor.trim().parse::<f32>())),
+            _ => None,
+        };
+
+        match factor {
+            Some((head, Ok(w))) if w.is_finite() => {
+                group.weight = Some(w);
+                let token = tokens.last_mut().unwrap();
+                token.nodes.pop();
+                if !head.is_empty() {
+                    token.nodes.push(Node::Text(head));
+                }
+                if token.nodes.is_empty() {
+                    tokens.pop();
+                }
+            }
+            Some(_) => {
+                group.tokens = tokens;
+                self.issues.push(ParseIssue::MalformedWeight {
+                    text: group.tokens_to_string(),
+                    offset,
+                });
+                return group;
+            }
+            None => {}
+        }
+        group.tokens = tokens;
+        group
+    }
+}
+
+/// Move pending text into `nodes`, merging with a preceding text node
+fn flush_text(text: &mut String, nodes: &mut Vec<Node>) {
+    if text.is_empty() {
+        return;
+    }
+    let t = std::mem::take(text);
+    match nodes.last_mut() {
+        Some(Node::Text(prev)) => prev.push_str(&t),
+        _ => nodes.push(Node::Text(t)),
+    }
+}
+
+/// Trim the token's outer whitespace and add it to `tokens` unless it is empty
+fn push_token(tokens: &mut Vec<Token>, mut nodes: Vec<Node>) {
+    if let Some(Node::Text(t)) = nodes.first_mut() {
+        *t = t.trim_start().to_string();
+    }
+    if let Some(Node::Text(t)) = nodes.last_mut() {
+        *t = t.trim_end().to_string();
+    }
+    nodes.retain(|n| !matches!(n, Node::Text(t) if t.is_empty()));
+    if !nodes.is_empty() {
+        tokens.push(Token { nodes });
+    }
+}
+
+fn contains_break(text: &str) -> bool {
+    text.split_whitespace().any(|word| word == BREAK)
+}
+
+/// Split `text` around whitespace-delimited BREAK keywords
+fn split_break(text: &str) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut search = 0;
+    while let Some(found) = text[search..].find(BREAK) {
+        let at = search + found;
+        let end = at + BREAK.len();
+        let before_ok = text[..at].chars().last().is_none_or(char::is_whitespace);
+        let after_ok = text[end..].chars().next().is_none_or(char::is_whitespace);
+        if before_ok && after_ok {
+            pieces.push(&text[start..at]);
+            start = end;
+        }
+        search = end;
+    }
+    pieces.push(&text[start..]);
+    pieces
+}
+
+/// Format a weight with up to two decimals, e.g. `1.3`, `0.75`, `1.0`
+pub fn format_weight(weight: f32) -> String {
+    let s = format!("{:.2}", weight);
+    let s = s.trim_end_matches('0');
+    if s.ends_with('.') {
+        format!("{}0", s)
+    } else {
+        s.to_string()
+    }
+}
+
+fn write_escaped(f: &mut fmt::Formatter<'_>, text: &str) -> fmt::Result {
+    for c in text.chars() {
+        if matches!(c, '(' | ')' | '[' | ']' | '\\') {
+            write!(f, "\\")?;
+        }
+        write!(f, "{}", c)?;
+    }
+    Ok(())
+}
+
+impl Token {
+    /// Effective weight of the token as a whole: the product of the groups wrapping it, e.g.
+    /// `1.21` for `((x))` and `1.3` for `(x:1.3)`. Tokens mixing text and groups weigh `1.0`.
+    pub fn weight(&self) -> f32 {
+        match self.nodes.as_slice() {
+            [Node::Group(group)] => {
+                let inner = match group.tokens.as_slice() {
+                    [token] => token.weight(),
+                    _ => 1.0,
+                };
+                group.factor() * inner
+            }
+            _ => 1.0,
+        }
+    }
+
+    /// The token's text with all grouping and weights stripped, e.g. `blue fire` for `((blue fire:1.2))`
+    pub fn plain_text(&self) -> String {
+        self.nodes
+            .iter()
+            .map(|node| match node {
+                Node::Text(text) => text.clone(),
+                Node::Group(group) => group
+                    .tokens
+                    .iter()
+                    .map(Token::plain_text)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            })
+            .collect()
+    }
+}
+
+impl Group {
+    /// Multiplier this group applies to its contents
+    pub fn factor(&self) -> f32 {
+        match (self.kind, self.weight) {
+            (GroupKind::Paren, Some(weight)) => weight,
+            (GroupKind::Paren, None) => EMPHASIS_FACTOR,
+            (GroupKind::Bracket, _) => 1.0 / EMPHASIS_FACTOR,
+        }
+    }
+
+    fn tokens_to_string(&self) -> String {
+        self.tokens
+            .iter()
+            .map(Token::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Node::Text(text) => write_escaped(f, text),
+            Node::Group(group) => {
+                let (open, close) = match group.kind {
+                    GroupKind::Paren => ('(', ')'),
+                    GroupKind::Bracket => ('[', ']'),
+                };
+                write!(f, "{}{}", open, group.tokens_to_string())?;
+                if let Some(weight) = group.weight {
+                    write!(f, ":{}", format_weight(weight))?;
+                }
+                write!(f, "{}", close)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for node in &self.nodes {
+            write!(f, "{}", node)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, token) in self.tokens.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", token)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Prompt {
+    /// Serialize back to a single line, with segments joined by `, BREAK, `
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, segment) in self.segments.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", {}, ", BREAK)?;
+            }
+            write!(f, "{}", segment)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(t: &str) -> Node {
+        Node::Text(t.to_string())
+    }
+
+    fn token(nodes: Vec<Node>) -> Token {
+        Token { nodes }
+    }
+
+    fn group(kind: GroupKind, weight: Option<f32>, tokens: Vec<Token>) -> Node {
+        Node::Group(Group {
+            kind,
+            weight,
+            tokens,
+        })
+    }
+
+    fn parse_ok(input: &str) -> Prompt {
+        let (prompt, issues) = parse(input);
+        assert!(issues.is_empty(), "unexpected issues: {:?}", issues);
+        prompt
+    }
+
+    #[test]
+    fn splits_tokens_and_segments() {
+        let prompt = parse_ok("1girl, blue hair , BREAK, castle,  night sky");
+        assert_eq!(prompt.segments.len(), 2);
+        assert_eq!(
+            prompt.segments[0].tokens,
+            vec![token(vec![text("1girl")]), token(vec![text("blue hair")])]
+        );
+        assert_eq!(
+            prompt.segments[1].tokens,
+            vec![token(vec![text("castle")]), token(vec![text("night sky")])]
+        );
+    }
+
+    #[test]
+    fn break_on_its_own_line_or_inline() {
+        let prompt = parse_ok("anime girl with pink hair\nBREAK\ndetailed classroom");
+        assert_eq!(
+            prompt.to_string(),
+            "anime girl with pink hair, BREAK, detailed classroom"
+        );
+
+        let prompt = parse_ok("school uniform BREAK sunny day");
+        assert_eq!(prompt.segments.len(), 2);
+        assert_eq!(prompt.to_string(), "school uniform, BREAK, sunny day");
+    }
+
+    #[test]
+    fn break_must_be_a_whole_word() {
+        let prompt = parse_ok("BREAKING dawn, heartbreak");
+        assert_eq!(prompt.segments.len(), 1);
+        assert_eq!(prompt.segments[0].tokens.len(), 2);
+    }
+
+    #[test]
+    fn explicit_weights() {
+        let prompt = parse_ok("(cel shading:1.3), (sparkles: 0.8)");
+        assert_eq!(
+            prompt.segments[0].tokens,
+            vec![
+                token(vec![group(
+                    GroupKind::Paren,
+                    Some(1.3),
+                    vec![token(vec![text("cel shading")])]
+                )]),
+                token(vec![group(
+                    GroupKind::Paren,
+                    Some(0.8),
+                    vec![token(vec![text("sparkles")])]
+                )]),
+            ]
+        );
+        assert_eq!(prompt.to_string(), "(cel shading:1.3), (sparkles:0.8)");
+    }
+
+    #[test]
+    fn nested_emphasis_and_de_emphasis() {
+        let prompt = parse_ok("((masterpiece)), [background], (red, blue:1.2)");
+        let tokens = &prompt.segments[0].tokens;
+        assert_eq!(
+            tokens[0],
+            token(vec![group(
+                GroupKind::Paren,
+                None,
+                vec![token(vec![group(
+                    GroupKind::Paren,
+                    None,
+                    vec![token(vec![text("masterpiece")])]
+                )])]
+            )])
+        );
+        assert_eq!(
+            tokens[1],
+            token(vec![group(
+                GroupKind::Bracket,
+                None,
+                vec![token(vec![text("background")])]
+            )])
+        );
+        assert_eq!(
+            tokens[2],
+            token(vec![group(
+                GroupKind::Paren,
+                Some(1.2),
+                vec![token(vec![text("red")]), token(vec![text("blue")])]
+            )])
+        );
+    }
+
+    #[test]
+    fn escaped_parens_are_literal() {
+        let prompt = parse_ok(r"rem \(re:zero\), (artist \(style\):0.5)");
+        assert_eq!(
+            prompt.segments[0].tokens[0],
+            token(vec![text("rem (re:zero)")])
+        );
+        assert_eq!(
+            prompt.segments[0].tokens[1],
+            token(vec![group(
+                GroupKind::Paren,
+                Some(0.5),
+                vec![token(vec![text("artist (style)")])]
+            )])
+        );
+        assert_eq!(
+            prompt.to_string(),
+            r"rem \(re:zero\), (artist \(style\):0.5)"
+        );
+    }
+
+    #[test]
+    fn mixed_text_and_groups_in_one_token() {
+        let prompt = parse_ok("masterpiece (best:1.2) quality");
+        assert_eq!(
+            prompt.segments[0].tokens[0],
+            token(vec![
+                text("masterpiece "),
+                group(GroupKind::Paren, Some(1.2), vec![token(vec![text("best")])]),
+                text(" quality"),
+            ])
+        );
+        assert_eq!(prompt.to_string(), "masterpiece (best:1.2) quality");
+    }
+
+    #[test]
+    fn break_inside_group_is_text() {
+        let prompt = parse_ok("(a BREAK b)");
+        assert_eq!(prompt.segments.len(), 1);
+    }
+
+    #[test]
+    fn recovers_from_unbalanced_brackets() {
+        let (prompt, issues) = parse("(blue hair, smile");
+        assert_eq!(
+            issues,
+            vec![ParseIssue::UnclosedGroup {
+                open: '(',
+                offset: 0
+            }]
+        );
+        assert_eq!(prompt.to_string(), "(blue hair, smile)");
+
+        let (prompt, issues) = parse("smile), sky");
+        assert_eq!(
+            issues,
+            vec![ParseIssue::UnmatchedClose {
+                close: ')',
+                offset: 5
+            }]
+        );
+        assert_eq!(prompt.to_string(), r"smile\), sky");
+    }
+
+    #[test]
+    fn reports_malformed_weights() {
+        let (prompt, issues) = parse("1girl, (x: abc)");
+        assert_eq!(
+            issues,
+            vec![ParseIssue::MalformedWeight {
+                text: "x: abc".to_string(),
+                offset: 7
+            }]
+        );
+        assert_eq!(prompt.to_string(), "1girl, (x: abc)");
+    }
+
+    #[test]
+    fn empty_tokens_and_segments_are_dropped() {
+        let prompt = parse_ok("BREAK, a,, ,b, BREAK");
+        assert_eq!(prompt.segments.len(), 1);
+        assert_eq!(prompt.to_string(), "a, b");
+    }
+
+    #[test]
+    fn round_trips() {
+        let inputs = [
+            "HDR, 8K, masterpiece, (horikoshi kouhei:0.3), ((blue fire)), [photo background]",
+            "1girl, solo, BREAK, (beautiful detailed eyes:1.25), detailed hair",
+            r"character \(series\), (a, (b:1.5), c:0.9)",
+        ];
+        for input in inputs {
+            let prompt = parse_ok(input);
+            let serialized = prompt.to_string();
+            assert_eq!(serialized, input);
+            assert_eq!(parse_ok(&serialized), prompt);
+        }
+    }
+
+    #[test]
+    fn plain_text_strips_syntax() {
+        let prompt = parse_ok(r"((blue fire:1.2)), [a, b], x \(y\) (z)");
+        let texts: Vec<String> = prompt.segments[0]
+            .tokens
+            .iter()
+            .map(Token::plain_text)
+            .collect();
+        assert_eq!(texts, vec!["blue fire", "a, b", "x (y) z"]);
+    }
+
+    #[test]
+    fn effective_token_weights() {
+        let prompt = parse_ok("plain, (a:1.3), ((b)), [c], ([d]), ((e:0.5)), (f, g:1.4), x (y) z");
+        let weights: Vec<f32> = prompt.segments[0]
+            .tokens
+            .iter()
+            .map(Token::weight)
+            .collect();
+        let expected = [1.0, 1.3, 1.21, 1.0 / 1.1, 1.0, 0.55, 1.4, 1.0];
+        for (weight, expected) in weights.iter().zip(expected) {
+            assert!((weight - expected).abs() < 1e-5, "{:?}", weights);
+        }
+    }
+
+    #[test]
+    fn formats_weights() {
+        assert_eq!(format_weight(1.3), "1.3");
+        assert_eq!(format_weight(1.0), "1.0");
+        assert_eq!(format_weight(0.75), "0.75");
+        assert_eq!(format_weight(1.333), "1.33");
+    }
+}
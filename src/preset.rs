@@ -0,0 +1,442 @@
+//! Style presets: the instruction, examples, component categories and default negative prompt a
+//! generation starts from.
+//!
+//! Built-in presets are compiled in from `presets/`. User presets are read from
+//! `$XDG_CONFIG_HOME/promptflow/presets/`, either as `NAME.toml` with the same fields as the
+//! built-in ones, or as `NAME.md` whose body is the instruction and whose other fields go in TOML
+//! front matter between `+++` lines. A user preset replaces the built-in preset of the same name.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config, instruction};
+
+/// Preset used unless another one is configured
+pub const DEFAULT_PRESET: &str = "anime";
+
+/// Delimiter of the front matter of Markdown presets
+const FRONT_MATTER: &str = "+++";
+
+/// Built-in presets as `(name, TOML)`; the first one is the default
+const BUILTIN: &[(&str, &str)] = &[
+    ("anime", include_str!("../presets/anime.toml")),
+    ("90s-anime", include_str!("../presets/90s-anime.toml")),
+    ("chibi", include_str!("../presets/chibi.toml")),
+    (
+        "manga-lineart",
+        include_str!("../presets/manga-lineart.toml"),
+    ),
+    ("watercolor", include_str!("../presets/watercolor.toml")),
+    ("pixel-art", include_str!("../presets/pixel-art.toml")),
+    ("semi-real", include_str!("../presets/semi-real.toml")),
+    ("photographic", include_str!("../presets/photographic.toml")),
+    ("concept-art", include_str!("../presets/concept-art.toml")),
+];
+
+/// Directory user presets are read from
+pub fn presets_dir() -> Option<PathBuf> {
+    config::config_dir().map(|dir| dir.join("presets"))
+}
+
+/// Failure to read or create a user preset
+#[derive(Debug)]
+pub enum PresetError {
+    Io(PathBuf, std::io::Error),
+    Invalid(PathBuf, String),
+    InvalidName(String),
+    Exists(PathBuf),
+    NoDirectory,
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresetError::Io(path, e) => write!(f, "could not access {}: {}", path.display(), e),
+            PresetError::Invalid(path, message) => {
+                write!(f, "invalid preset {}: {}", path.display(), message)
+            }
+            PresetError::InvalidName(name) => write!(
+                f,
+                "invalid preset name {:?}: use letters, digits, '-', '_' and '.', not starting with '.'",
+                name
+            ),
+            PresetError::Exists(path) => write!(f, "{} already exists", path.display()),
+            PresetError::NoDirectory => {
+                f.write_str("no preset directory: neither XDG_CONFIG_HOME nor HOME is set")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PresetError {}
+
+/// A keyword and the prompt the model should make of it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Example {
+    pub keyword: String,
+    pub prompt: String,
+    /// What the example demonstrates
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+/// Fields of a preset file; in Markdown presets `instruction` is the body instead
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PresetFile {
+    #[serde(default)]
+    description: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    negative_prompt: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    banned_terms: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    instruction: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    categories: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    examples: Vec<Example>,
+}
+
+/// A named style the system instruction and defaults are taken from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preset {
+    pub name: String,
+    /// One-line summary for `presets list`
+    pub description: String,
+    /// What the model is asked to do, before the categories and examples
+    pub instruction: String,
+    /// Component categories every prompt has to cover, as `Name: examples`
+    pub categories: Vec<String>,
+    pub examples: Vec<Example>,
+    /// Negative prompt used unless one is configured
+    pub negative_prompt: String,
+    /// Terms the validator removes unless `banned_terms` is configured
+    pub banned_terms: Vec<String>,
+    /// File the preset was read from; `None` for built-in presets
+    pub path: Option<PathBuf>,
+}
+
+impl Preset {
+    /// Parse a preset file, TOML or, with `markdown`, Markdown with TOML front matter
+    fn parse(name: &str, text: &str, markdown: bool) -> Result<Self, String> {
+        let mut file: PresetFile = if markdown {
+            let (front_matter, body) = split_front_matter(text);
+            let mut file: PresetFile = toml::from_str(front_matter).map_err(|e| e.to_string())?;
+            if file.instruction.is_some() {
+                return Err("the instruction is the body of a Markdown preset".to_string());
+            }
+            file.instruction = Some(body.to_string());
+            file
+        } else {
+            toml::from_str(text).map_err(|e| e.to_string())?
+        };
+        let instruction = file
+            .instruction
+            .take()
+            .filter(|instruction| !instruction.trim().is_empty())
+            .ok_or("missing instruction")?;
+        let negative_prompt = match file.negative_prompt {
+            Some(negative_prompt) => negative_prompt,
+            None if name == DEFAULT_PRESET => return Err("missing negative_prompt".to_string()),
+            None => default().negative_prompt,
+        };
+        Ok(Self {
+            name: name.to_string(),
+            description: file.description,
+            instruction: instruction.trim().to_string(),
+            categories: file.categories,
+            examples: file.examples,
+            negative_prompt,
+            banned_terms: file.banned_terms,
+            path: None,
+        })
+    }
+
+    /// Read the user preset at `path`, named after the file
+    fn read(path: &Path) -> Result<Self, PresetError> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| PresetError::Io(path.to_path_buf(), e))?;
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let markdown = path.extension().is_some_and(|ext| ext == "md");
+        let mut preset = Self::parse(&name, &text, markdown)
+            .map_err(|message| PresetError::Invalid(path.to_path_buf(), message))?;
+        preset.path = Some(path.to_path_buf());
+        Ok(preset)
+    }
+
+    /// Contents of a preset file holding this preset, as TOML or Markdown
+    fn to_file(&self, markdown: bool) -> String {
+        let mut file = PresetFile {
+            description: self.description.clone(),
+            negative_prompt: Some(self.negative_prompt.clone()),
+            banned_terms: self.banned_terms.clone(),
+            instruction: Some(format!("{}\n", self.instruction)),
+            categories: self.categories.clone(),
+            examples: self.examples.clone(),
+        };
+        if !markdown {
+            return toml::to_string_pretty(&file).expect("presets serialize to TOML");
+        }
+        file.instruction = None;
+        format!(
+            "{}\n{}{}\n\n{}\n",
+            FRONT_MATTER,
+            toml::to_string_pretty(&file).expect("presets serialize to TOML"),
+            FRONT_MATTER,
+            self.instruction
+        )
+    }
+
+    /// The full system instruction: the preset's instruction, categories and examples followed
+    /// by [`instruction::TECHNIQUES`]
+    pub fn system_instruction(&self) -> String {
+        let mut text = self.instruction.clone();
+        if !self.categories.is_empty() {
+            text.push_str(
+                "\n\n**Mandatory Prompt Components:**\n\
+                 The prompts you generate MUST contain keywords covering the following categories:",
+            );
+            for (number, category) in self.categories.iter().enumerate() {
+                let category = match category.split_once(':') {
+                    Some((name, examples)) => {
+                        format!("**{}:** (e.g., {})", name.trim(), examples.trim())
+                    }
+                    None => category.trim().to_string(),
+                };
+                text.push_str(&format!("\n{}.  {}", number + 1, category));
+            }
+        }
+        if !self.examples.is_empty() {
+            let heading = if self.examples.len() == 1 {
+                "Example"
+            } else {
+                "Examples"
+            };
+            text.push_str(&format!("\n\n--------------------\n**{}:**", heading));
+            for example in &self.examples {
+                text.push_str(&format!(
+                    "\n\n*   **Input Keyword:** '{}'\n*   **Generated Prompt:** '{}'",
+                    example.keyword, example.prompt
+                ));
+                if let Some(note) = &example.note {
+                    text.push_str(&format!("\n    *   *Note:* {}", note));
+                }
+            }
+        }
+        format!(
+            "{}\n\n--------------------\n{}\n",
+            text,
+            instruction::TECHNIQUES
+        )
+    }
+}
+
+/// Front matter and body of a Markdown preset; without front matter the whole text is the body
+fn split_front_matter(text: &str) -> (&str, &str) {
+    let Some(rest) = text.strip_prefix(FRONT_MATTER) else {
+        return ("", text);
+    };
+    let rest = rest.trim_start_matches([' ', '\t', '\r']);
+    let Some(rest) = rest.strip_prefix('\n') else {
+        return ("", text);
+    };
+    let end = if rest.starts_with(FRONT_MATTER) {
+        Some(0)
+    } else {
+        rest.find(&format!("\n{}", FRONT_MATTER)).map(|end| end + 1)
+    };
+    match end {
+        Some(end) => (&rest[..end], &rest[end + FRONT_MATTER.len()..]),
+        None => ("", text),
+    }
+}
+
+/// The built-in presets, default first
+pub fn builtin() -> Vec<Preset> {
+    BUILTIN
+        .iter()
+        .map(|(name, text)| {
+            Preset::parse(name, text, false)
+                .unwrap_or_else(|e| panic!("built-in preset {} is invalid: {}", name, e))
+        })
+        .collect()
+}
+
+/// The default built-in preset
+pub fn default() -> Preset {
+    let (name, text) = BUILTIN[0];
+    Preset::parse(name, text, false).expect("the default preset is valid")
+}
+
+/// Built-in presets followed by the user's; a user preset replaces the built-in one of the same
+/// name
+pub fn load() -> Result<Vec<Preset>, PresetError> {
+    let mut presets = builtin();
+    if let Some(dir) = presets_dir() {
+        for preset in load_dir(&dir)? {
+            match presets
+                .iter()
+                .position(|p| p.name.eq_ignore_ascii_case(&preset.name))
+            {
+                Some(index) => presets[index] = preset,
+                None => presets.push(preset),
+            }
+        }
+    }
+    Ok(presets)
+}
+
+/// The `.toml` and `.md` presets in `dir`, sorted by name; a missing directory has none
+fn load_dir(dir: &Path) -> Result<Vec<Preset>, PresetError> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(PresetError::Io(dir.to_path_buf(), e)),
+    };
+    let mut presets = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|e| PresetError::Io(dir.to_path_buf(), e))?
+            .path();
+        let is_preset = path
+            .extension()
+            .is_some_and(|ext| ext == "toml" || ext == "md");
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if is_preset && !hidden && path.is_file() {
+            presets.push(Preset::read(&path)?);
+        }
+    }
+    presets.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(presets)
+}
+
+/// The preset called `name`, ignoring case
+pub fn find<'a>(presets: &'a [Preset], name: &str) -> Option<&'a Preset> {
+    presets
+        .iter()
+        .find(|preset| preset.name.eq_ignore_ascii_case(name.trim()))
+}
+
+/// Names of `presets`, for error messages
+pub fn names(presets: &[Preset]) -> String {
+    presets
+        .iter()
+        .map(|preset| preset.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Write a new user preset called `name` starting from `base`, as TOML or Markdown
+pub fn create(name: &str, base: &Preset, markdown: bool) -> Result<PathBuf, PresetError> {
+    let valid = !name.starts_with('.')
+        && !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(PresetError::InvalidName(name.to_string()));
+    }
+    let dir = presets_dir().ok_or(PresetError::NoDirectory)?;
+    // Either file would define the preset, whichever format is asked for
+    if let Some(existing) = ["toml", "md"]
+        .iter()
+        .map(|ext| dir.join(format!("{}.{}", name, ext)))
+        .find(|path| path.exists())
+    {
+        return Err(PresetError::Exists(existing));
+    }
+    let path = dir.join(format!("{}.{}", name, if markdown { "md" } else { "toml" }));
+    std::fs::create_dir_all(&dir).map_err(|e| PresetError::Io(dir.clone(), e))?;
+    let preset = Preset {
+        name: name.to_string(),
+        path: Some(path.clone()),
+        ..base.clone()
+    };
+    std::fs::write(&path, preset.to_file(markdown))
+        .map_err(|e| PresetError::Io(path.clone(), e))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_presets_are_complete() {
+        let presets = builtin();
+        assert_eq!(presets.len(), BUILTIN.len());
+        assert_eq!(presets[0].name, DEFAULT_PRESET);
+        for preset in &presets {
+            assert!(!preset.description.is_empty(), "{}", preset.name);
+            assert_eq!(preset.categories.len(), 8, "{}", preset.name);
+            assert!(!preset.examples.is_empty(), "{}", preset.name);
+            assert!(!preset.negative_prompt.is_empty(), "{}", preset.name);
+            assert!(preset.path.is_none());
+        }
+        assert!(
+            find(&presets, "photographic")
+                .unwrap()
+                .banned_terms
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn system_instruction_lists_categories_and_examples() {
+        let anime = default();
+        let instruction = anime.system_instruction();
+        assert!(instruction.starts_with(
+            "You are an assistant specialized in generating prompts **exclusively for anime-style**"
+        ));
+        assert!(instruction.contains(
+            "following categories:\n1.  **Subject:** (e.g., anime girl, shonen protagonist, mecha, fantasy creature in anime style)\n2.  **Medium:**"
+        ));
+        assert!(instruction.contains(
+            "\n\n--------------------\n**Example:**\n\n*   **Input Keyword:** 'anime girl with blue hair in a fantasy setting'\n*   **Generated Prompt:** 'HDR, 8K,"
+        ));
+        assert!(instruction.contains("'\n    *   *Note:* This example"));
+        assert!(instruction.ends_with(&format!(
+            "--------------------\n{}\n",
+            instruction::TECHNIQUES
+        )));
+    }
+
+    #[test]
+    fn parses_markdown_presets() {
+        let text =
+            "+++\ndescription = \"Ink\"\ncategories = [\"Subject: cat\"]\n+++\n\nDraw with ink.\n";
+        let preset = Preset::parse("ink", text, true).unwrap();
+        assert_eq!(preset.description, "Ink");
+        assert_eq!(preset.instruction, "Draw with ink.");
+        assert_eq!(preset.categories, vec!["Subject: cat"]);
+        // Fields left out fall back to the default preset's negative prompt
+        assert_eq!(preset.negative_prompt, default().negative_prompt);
+
+        let plain = Preset::parse("plain", "Just the instruction.", true).unwrap();
+        assert_eq!(plain.instruction, "Just the instruction.");
+        assert!(Preset::parse("x", "+++\ninstruction = \"a\"\n+++\nb", true).is_err());
+        assert!(Preset::parse("x", "+++\nnegative = \"a\"\n+++\nb", true).is_err());
+        assert!(Preset::parse("x", "+++\n+++\n  \n", true).is_err());
+    }
+
+    #[test]
+    fn preset_files_round_trip() {
+        let mut anime = default();
+        for markdown in [false, true] {
+            let text = anime.to_file(markdown);
+            assert_eq!(Preset::parse("anime", &text, markdown).unwrap(), anime);
+        }
+        anime.examples.clear();
+        assert!(!anime.to_file(false).contains("[[examples]]"));
+        assert!(Preset::parse("x", "description = \"no instruction\"\n", false).is_err());
+    }
+}
@@ -0,0 +1,533 @@
+//! `PromptFlow repl`: generate prompts and refine them without restarting.
+//!
+//! A line that doesn't start with `/` is a keyword and starts a new conversation with the model.
+//! `/more`, `/less` and `/regen` continue that conversation, so the model revises its own
+//! earlier reply instead of starting over. `/weight` and `/drop` edit the prompt locally, and
+//! the edited prompt replaces the model's last reply in the conversation. Nothing is added to
+//! the history until `/save`.
+
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::backend::{Message, PromptBackend};
+use crate::clip::ClipTokenizer;
+use crate::edit;
+use crate::generate::{self, Fitted, Generated, GenerationSettings};
+use crate::history::{History, Retention};
+use crate::{output, parser};
+
+/// Shown by `/help`
+const HELP: &str = "\
+KEYWORD               generate a prompt for KEYWORD, starting a new conversation
+/more ASPECT          ask the model to emphasize ASPECT, e.g. /more lighting
+/less ASPECT          ask the model to tone down ASPECT, e.g. /less detail
+/weight TERM WEIGHT   set the weight of the tokens matching TERM, e.g. /weight sparkles 0.6
+/drop TERM            remove the tokens matching TERM
+/regen                ask for a different answer to the last request
+/show                 print the current prompt again
+/copy                 copy the current prompt to the clipboard
+/save                 add the current prompt to the history
+/help                 print this help
+/quit                 leave (also Ctrl-D)";
+
+/// Clipboard tools tried by `/copy`, in order
+const CLIPBOARD_COMMANDS: &[&[&str]] = &[
+    &["wl-copy"],
+    &["xclip", "-selection", "clipboard"],
+    &["xsel", "--clipboard", "--input"],
+    &["pbcopy"],
+    &["clip.exe"],
+];
+
+/// A line typed at the REPL
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplCommand {
+    Keyword(String),
+    More(String),
+    Less(String),
+    Weight(String, f32),
+    Drop(String),
+    Regen,
+    Show,
+    Copy,
+    Save,
+    Help,
+    Quit,
+}
+
+impl ReplCommand {
+    /// Parse `line`, or `None` if it is blank
+    pub fn parse(line: &str) -> Result<Option<Self>, String> {
+        let line = line.trim();
+        let Some(command) = line.strip_prefix('/') else {
+            return Ok((!line.is_empty()).then(|| ReplCommand::Keyword(line.to_string())));
+        };
+        let (name, arg) = command
+            .split_once(char::is_whitespace)
+            .map_or((command, ""), |(name, arg)| (name, arg.trim()));
+        let required = |usage: &str| {
+            if arg.is_empty() {
+                Err(format!("usage: /{} {}", name, usage))
+            } else {
+                Ok(arg.to_string())
+            }
+        };
+        let command = match name.to_ascii_lowercase().as_str() {
+            "more" => ReplCommand::More(required("ASPECT")?),
+            "less" => ReplCommand::Less(required("ASPECT")?),
+            "weight" => {
+                let usage = || format!("usage: /{} TERM WEIGHT", name);
+                let (term, weight) = arg.rsplit_once(char::is_whitespace).ok_or_else(usage)?;
+                let weight = weight
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|weight| weight.is_finite())
+                    .ok_or_else(usage)?;
+                ReplCommand::Weight(term.trim().to_string(), weight)
+            }
+            "drop" => ReplCommand::Drop(required("TERM")?),
+            "regen" => ReplCommand::Regen,
+            "show" => ReplCommand::Show,
+            "copy" => ReplCommand::Copy,
+            "save" => ReplCommand::Save,
+            "help" | "?" => ReplCommand::Help,
+            "quit" | "exit" | "q" => ReplCommand::Quit,
+            _ => return Err(format!("unknown command /{} (see /help)", name)),
+        };
+        Ok(Some(command))
+    }
+}
+
+/// Message asking the model to revise its last prompt; `more` emphasizes `aspect`, otherwise it
+/// is toned down
+pub fn refine_request(aspect: &str, more: bool) -> String {
+    let change = if more {
+        format!(
+            "put more emphasis on {}: add keywords for it and raise their weights",
+            aspect
+        )
+    } else {
+        format!(
+            "tone down {}: lower the weights of its keywords or remove them",
+            aspect
+        )
+    };
+    format!(
+        "Revise your last prompt to {}. Keep the rest of the prompt as it is, and reply with \
+         the complete revised prompt in the same format.",
+        change
+    )
+}
+
+/// Failure of a REPL command, reported without leaving the REPL
+#[derive(Debug)]
+enum ReplError {
+    /// A command that refines a prompt was given before any keyword
+    NoPrompt,
+    Message(String),
+}
+
+impl fmt::Display for ReplError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplError::NoPrompt => f.write_str("no prompt yet, enter a keyword first"),
+            ReplError::Message(message) => f.write_str(message),
+        }
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for ReplError {
+    fn from(e: Box<dyn std::error::Error>) -> Self {
+        ReplError::Message(e.to_string())
+    }
+}
+
+/// The keyword being refined and the conversation about it
+struct Conversation {
+    keyword: String,
+    system_instruction: String,
+    /// Alternating user and model messages, ending with the model's current prompt
+    messages: Vec<Message>,
+    current: Generated,
+    /// Time the model took for the current prompt
+    latency: Duration,
+}
+
+/// Everything needed to run the REPL
+pub struct Repl {
+    pub backend: Box<dyn PromptBackend>,
+    pub history: History,
+    pub retention: Retention,
+    pub settings: GenerationSettings,
+    pub tokenizer: ClipTokenizer,
+    pub max_tokens: Option<usize>,
+}
+
+impl Repl {
+    /// Read commands from `input` until `/quit` or the end of the input, printing the prompts
+    /// to `out`; `prompt` is printed before each line is read
+    pub async fn run(
+        &mut self,
+        input: impl BufRead,
+        out: &mut impl Write,
+        prompt: Option<&str>,
+    ) -> io::Result<()> {
+        let mut conversation = None;
+        let mut lines = input.lines();
+        loop {
+            if let Some(prompt) = prompt {
+                write!(out, "{}", prompt)?;
+                out.flush()?;
+            }
+            let Some(line) = lines.next().transpose()? else {
+                break;
+            };
+            let command = match ReplCommand::parse(&line) {
+                Ok(Some(ReplCommand::Quit)) => break,
+                Ok(Some(command)) => command,
+                Ok(None) => continue,
+                Err(e) => {
+                    writeln!(out, "Error: {}", e)?;
+                    continue;
+                }
+            };
+            if let Err(e) = self.execute(command, &mut conversation, out).await {
+                writeln!(out, "Error: {}", e)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn execute(
+        &mut self,
+        command: ReplCommand,
+        conversation: &mut Option<Conversation>,
+        out: &mut impl Write,
+    ) -> Result<(), ReplError> {
+        let io_error = |e: io::Error| ReplError::Message(e.to_string());
+        match command {
+            ReplCommand::Keyword(keyword) => {
+                let system_instruction =
+                    generate::system_instruction(&self.history, &self.settings, &keyword);
+                let mut messages = vec![Message::user(keyword.as_str())];
+                let (current, latency) =
+                    self.reply(&system_instruction, &keyword, &messages).await?;
+                messages.push(Message::model(current.text.as_str()));
+                *conversation = Some(Conversation {
+                    keyword,
+                    system_instruction,
+                    messages,
+                    current,
+                    latency,
+                });
+            }
+            ReplCommand::More(aspect) => {
+                self.refine(conversation, refine_request(&aspect, true))
+                    .await?
+            }
+            ReplCommand::Less(aspect) => {
+                self.refine(conversation, refine_request(&aspect, false))
+                    .await?
+            }
+            ReplCommand::Regen => {
+                let conv = conversation.as_mut().ok_or(ReplError::NoPrompt)?;
+                // Ask the last request again, without the reply being replaced
+                let messages = &conv.messages[..conv.messages.len() - 1];
+                let (current, latency) = self
+                    .reply(&conv.system_instruction, &conv.keyword, messages)
+                    .await?;
+                conv.messages.pop();
+                conv.messages.push(Message::model(current.text.as_str()));
+                conv.current = current;
+                conv.latency = latency;
+            }
+            ReplCommand::Weight(term, weight) => {
+                let conv = conversation.as_mut().ok_or(ReplError::NoPrompt)?;
+                let (min, max) = self.settings.validator.rules.weight_range;
+                if !(min..=max).contains(&weight) {
+                    return Err(ReplError::Message(format!(
+                        "weight {} is outside the allowed range {}:{}",
+                        weight,
+                        parser::format_weight(min),
+                        parser::format_weight(max)
+                    )));
+                }
+                let text = edit::set_weight(&conv.current.text, &term, weight)
+                    .ok_or_else(|| no_match(&term))?;
+                conv.replace(text);
+            }
+            ReplCommand::Drop(term) => {
+                let conv = conversation.as_mut().ok_or(ReplError::NoPrompt)?;
+                let text =
+                    edit::drop_term(&conv.current.text, &term).ok_or_else(|| no_match(&term))?;
+                conv.replace(text);
+            }
+            ReplCommand::Show => {}
+            ReplCommand::Copy => {
+                let conv = conversation.as_ref().ok_or(ReplError::NoPrompt)?;
+                let fitted = self.fit(&conv.current);
+                let text = self.settings.dialect.format(&fitted.text);
+                let tool = copy_to_clipboard(&text).map_err(io_error)?;
+                writeln!(out, "Copied the prompt with {}", tool).map_err(io_error)?;
+                return Ok(());
+            }
+            ReplCommand::Save => {
+                let conv = conversation.as_ref().ok_or(ReplError::NoPrompt)?;
+                let fitted = self.fit(&conv.current);
+                let entry = generate::history_entry(
+                    self.backend.as_ref(),
+                    &self.settings,
+                    &conv.keyword,
+                    &fitted,
+                    &conv.current.negative_prompt,
+                    conv.latency,
+                );
+                self.history
+                    .record(entry, &self.retention)
+                    .map_err(io_error)?;
+                let id = self.history.entries().last().map_or(0, |entry| entry.id);
+                writeln!(out, "Saved as history entry {}", id).map_err(io_error)?;
+                return Ok(());
+            }
+            ReplCommand::Help => {
+                writeln!(out, "{}", HELP).map_err(io_error)?;
+                return Ok(());
+            }
+            ReplCommand::Quit => return Ok(()),
+        }
+        let conv = conversation.as_ref().ok_or(ReplError::NoPrompt)?;
+        self.show(&conv.current, out).map_err(io_error)
+    }
+
+    /// Send `request` in the conversation about the current prompt and make the reply current
+    async fn refine(
+        &self,
+        conversation: &mut Option<Conversation>,
+        request: String,
+    ) -> Result<(), ReplError> {
+        let conv = conversation.as_mut().ok_or(ReplError::NoPrompt)?;
+        let mut messages = conv.messages.clone();
+        messages.push(Message::user(request));
+        let (current, latency) = self
+            .reply(&conv.system_instruction, &conv.keyword, &messages)
+            .await?;
+        messages.push(Message::model(current.text.as_str()));
+        conv.messages = messages;
+        conv.current = current;
+        conv.latency = latency;
+        Ok(())
+    }
+
+    /// Ask the model to reply to `messages` and complete the reply into a prompt
+    async fn reply(
+        &self,
+        system_instruction: &str,
+        keyword: &str,
+        messages: &[Message],
+    ) -> Result<(Generated, Duration), ReplError> {
+        let started = Instant::now();
+        eprintln!(
+            "Generating... ({} / {})",
+            self.backend.name(),
+            self.backend.model()
+        );
+        let response = self
+            .backend
+            .chat(system_instruction, messages)
+            .await
+            .map_err(|e| ReplError::Message(e.to_string()))?;
+        let generated = generate::complete(
+            self.backend.as_ref(),
+            &self.settings,
+            system_instruction,
+            keyword,
+            &response,
+        )
+        .await?;
+        Ok((generated, started.elapsed()))
+    }
+
+    /// The prompt of `generated` fitted to the token budget, still in Stable Diffusion syntax
+    fn fit(&self, generated: &Generated) -> Fitted {
+        generate::fit_token_budget(&generated.text, &self.tokenizer, self.max_tokens)
+    }
+
+    fn show(&self, generated: &Generated, out: &mut impl Write) -> io::Result<()> {
+        let fitted = self.fit(generated);
+        let dialect = &self.settings.dialect;
+        output::write_result(
+            out,
+            &dialect.format(&fitted.text),
+            &dialect.format_negative(&generated.negative_prompt),
+        )?;
+        if self.settings.dialect.target.uses_clip() {
+            writeln!(out, "{}", output::token_summary(&fitted.tokens))?;
+        }
+        Ok(())
+    }
+}
+
+impl Conversation {
+    /// Make the locally edited `text` the current prompt, and the model's last reply
+    fn replace(&mut self, text: String) {
+        if let Some(last) = self.messages.last_mut() {
+            last.text = text.clone();
+        }
+        self.current.text = text;
+    }
+}
+
+fn no_match(term: &str) -> ReplError {
+    ReplError::Message(format!("no token matches {:?}", term))
+}
+
+/// Copy `text` with the first clipboard tool that is installed, returning its name
+fn copy_to_clipboard(text: &str) -> io::Result<&'static str> {
+    for command in CLIPBOARD_COMMANDS {
+        let mut child = match Command::new(command[0])
+            .args(&command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes())?;
+        }
+        if child.wait()?.success() {
+            return Ok(command[0]);
+        }
+        return Err(io::Error::other(format!("{} failed", command[0])));
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "no clipboard tool found (install wl-clipboard, xclip or xsel)",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{BackendError, MockBackend, Role};
+    use std::collections::HashMap;
+    use std::env;
+    use std::sync::Arc;
+
+    /// Lets the test inspect the calls of the mock the REPL owns
+    struct Shared(Arc<MockBackend>);
+
+    #[async_trait::async_trait]
+    impl PromptBackend for Shared {
+        fn name(&self) -> &'static str {
+            self.0.name()
+        }
+
+        fn model(&self) -> &str {
+            self.0.model()
+        }
+
+        async fn chat(
+            &self,
+            system_instruction: &str,
+            messages: &[Message],
+        ) -> Result<String, BackendError> {
+            self.0.chat(system_instruction, messages).await
+        }
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            ReplCommand::parse(" sea witch "),
+            Ok(Some(ReplCommand::Keyword("sea witch".to_string())))
+        );
+        assert_eq!(ReplCommand::parse("  "), Ok(None));
+        assert_eq!(
+            ReplCommand::parse("/weight night sky 0.8"),
+            Ok(Some(ReplCommand::Weight("night sky".to_string(), 0.8)))
+        );
+        assert_eq!(
+            ReplCommand::parse("/MORE rim lighting"),
+            Ok(Some(ReplCommand::More("rim lighting".to_string())))
+        );
+        assert_eq!(ReplCommand::parse("/exit"), Ok(Some(ReplCommand::Quit)));
+        assert_eq!(
+            ReplCommand::parse("/less"),
+            Err("usage: /less ASPECT".to_string())
+        );
+        assert!(ReplCommand::parse("/weight sparkles").is_err());
+        assert!(ReplCommand::parse("/zoom").is_err());
+    }
+
+    #[tokio::test]
+    async fn refinements_continue_the_conversation() {
+        let more = refine_request("lighting", true);
+        let backend = Arc::new(MockBackend::new(HashMap::from([
+            (
+                "knight".to_string(),
+                "1boy, (armor:1.2), castle, sparkles".to_string(),
+            ),
+            (
+                more.clone(),
+                "1boy, (armor:1.2), castle, sparkles, (rim lighting:1.3)".to_string(),
+            ),
+        ])));
+        let dir = env::temp_dir().join(format!("promptflow-repl-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        let mut repl = Repl {
+            backend: Box::new(Shared(Arc::clone(&backend))),
+            history: History::load(dir.join("history.jsonl")),
+            retention: Retention::default(),
+            settings: GenerationSettings::default(),
+            tokenizer: ClipTokenizer::estimating(),
+            max_tokens: None,
+        };
+        let input = "/drop castle\nknight\n/more lighting\n/weight sparkles 0.6\n\
+                     /weight sparkles 9\n/drop castle\n/save\n/regen\n/zoom\n/quit\nignored\n";
+        let mut out = Vec::new();
+        repl.run(input.as_bytes(), &mut out, None).await.unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.starts_with("Error: no prompt yet, enter a keyword first\n"));
+        for prompt in [
+            "1boy, (armor:1.2), castle, sparkles\n",
+            "1boy, (armor:1.2), castle, sparkles, (rim lighting:1.3)\n",
+            "1boy, (armor:1.2), castle, (sparkles:0.6), (rim lighting:1.3)\n",
+            "1boy, (armor:1.2), (sparkles:0.6), (rim lighting:1.3)\n",
+        ] {
+            assert!(
+                out.contains(&format!("=== GENERATED PROMPT ===\n{}", prompt)),
+                "{}",
+                out
+            );
+        }
+        assert!(out.contains("Error: weight 9 is outside the allowed range 0.1:2.0\n"));
+        assert!(out.contains("Saved as history entry 1\n"));
+        assert!(out.ends_with("Error: unknown command /zoom (see /help)\n"));
+        assert_eq!(
+            repl.history.entries()[0].prompt,
+            "1boy, (armor:1.2), (sparkles:0.6), (rim lighting:1.3)"
+        );
+
+        let calls = backend.calls();
+        assert_eq!(calls.len(), 3);
+        assert_eq!(calls[1].keyword, more);
+        assert_eq!(
+            calls[1].earlier,
+            [
+                Message::user("knight"),
+                Message::model("1boy, (armor:1.2), castle, sparkles")
+            ]
+        );
+        // /regen asks the last request again, dropping the reply and its local edits
+        assert_eq!(calls[2].keyword, more);
+        assert_eq!(calls[2].earlier, calls[1].earlier);
+        assert_eq!(calls[2].earlier[1].role, Role::Model);
+    }
+}
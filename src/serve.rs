@@ -0,0 +1,417 @@
+//! `PromptFlow serve`: prompt generation as a small JSON API over HTTP.
+//!
+//! - `POST /generate` with `{"keyword": "..."}` returns the prompt, the negative prompt and
+//!   the CLIP token count.
+//! - `GET /health` returns `{"status": "ok"}`.
+//!
+//! Connections are read concurrently, but generation runs one request at a time because every
+//! request shares the prompt history.
+//!
+//! Generation spends the user's API quota, so only requests a web page can't forge are served:
+//! the `Host` header must name the loopback address with the port listened on, which defeats
+//! DNS rebinding, and `POST /generate` must be sent as `application/json`, which browsers don't
+//! allow cross-origin without a preflight.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+
+use crate::backend::PromptBackend;
+use crate::clip::ClipTokenizer;
+use crate::generate::{self, GenerationSettings};
+use crate::history::{History, Retention};
+
+/// Largest request accepted, headers and body together
+const MAX_REQUEST_BYTES: usize = 64 * 1024;
+
+/// Longest wait for a client to send its whole request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Host names a request may address, followed by the port listened on
+const ALLOWED_HOSTS: [&str; 3] = ["localhost", "127.0.0.1", "[::1]"];
+
+/// Everything needed to answer a generation request
+pub struct Service {
+    pub backend: Box<dyn PromptBackend>,
+    pub history: History,
+    pub retention: Retention,
+    pub settings: GenerationSettings,
+    pub tokenizer: ClipTokenizer,
+    pub max_tokens: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GenerateRequest {
+    keyword: String,
+}
+
+/// An HTTP request, with the headers the service looks at
+#[derive(Debug, Default)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub host: Option<String>,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+impl Service {
+    /// Answer `request`, returning the status code and JSON response
+    pub async fn respond(&mut self, request: &Request) -> (u16, Value) {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/health") => (200, json!({ "status": "ok" })),
+            ("POST", "/generate") => {
+                if !is_json(request.content_type.as_deref()) {
+                    return (
+                        415,
+                        json!({ "error": "Content-Type must be application/json" }),
+                    );
+                }
+                let request: GenerateRequest = match serde_json::from_slice(&request.body) {
+                    Ok(request) => request,
+                    Err(e) => return (400, json!({ "error": format!("invalid request: {}", e) })),
+                };
+                let keyword = request.keyword.trim();
+                if keyword.is_empty() {
+                    return (400, json!({ "error": "keyword must not be empty" }));
+                }
+                let started = Instant::now();
+                match generate::generate(
+                    self.backend.as_ref(),
+                    &self.history,
+                    &self.settings,
+                    keyword,
+                )
+                .await
+                {
+                    Ok(generated) => {
+                        let fitted = generate::fit_token_budget(
+                            &generated.text,
+                            &self.tokenizer,
+                            self.max_tokens,
+                        );
+                        let entry = generate::history_entry(
+                            self.backend.as_ref(),
+                            &self.settings,
+                            keyword,
+                            &fitted,
+                            &generated.negative_prompt,
+                            started.elapsed(),
+                        );
+                        if let Err(e) = self.history.record(entry, &self.retention) {
+                            eprintln!("Warning: could not save the prompt history: {}", e);
+                        }
+                        let dialect = &self.settings.dialect;
+                        (
+                            200,
+                            json!({
+                                "keyword": keyword,
+                                "prompt": dialect.format(&fitted.text),
+                                "negative_prompt": dialect.format_negative(&generated.negative_prompt),
+                                "tokens": fitted.tokens.total(),
+                            }),
+                        )
+                    }
+                    Err(e) => (502, json!({ "error": e.to_string() })),
+                }
+            }
+            (_, "/health" | "/generate") => (405, json!({ "error": "method not allowed" })),
+            _ => (404, json!({ "error": "not found" })),
+        }
+    }
+}
+
+/// Accept connections on `listener` until it fails
+pub async fn run(listener: TcpListener, service: Service) -> std::io::Result<()> {
+    let port = listener.local_addr()?.port();
+    let service = Arc::new(Mutex::new(service));
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let service = Arc::clone(&service);
+        tokio::spawn(async move {
+            if let Err(e) = handle(socket, &service, port, READ_TIMEOUT).await {
+                eprintln!("Warning: connection failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle(
+    mut socket: TcpStream,
+    service: &Mutex<Service>,
+    port: u16,
+    read_timeout: Duration,
+) -> std::io::Result<()> {
+    let request = tokio::time::timeout(read_timeout, read_request(&mut socket)).await;
+    let (status, response) = match request {
+        Err(_) => (408, json!({ "error": "request timed out" })),
+        Ok(request) => match request? {
+            Some(request) if !is_allowed_host(request.host.as_deref(), port) => (
+                403,
+                json!({ "error": "Host must be localhost with the port listened on" }),
+            ),
+            Some(request) => service.lock().await.respond(&request).await,
+            None => (400, json!({ "error": "malformed HTTP request" })),
+        },
+    };
+    let body = response.to_string();
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        415 => "Unsupported Media Type",
+        _ => "Bad Gateway",
+    };
+    let message = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+    socket.write_all(message.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+/// Whether the `Host` header `host` names a loopback address with `port`
+fn is_allowed_host(host: Option<&str>, port: u16) -> bool {
+    let Some((name, host_port)) = host.and_then(|host| host.trim().rsplit_once(':')) else {
+        return false;
+    };
+    host_port.parse() == Ok(port)
+        && ALLOWED_HOSTS
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(name))
+}
+
+/// Whether `content_type` is JSON, parameters such as `charset` aside
+fn is_json(content_type: Option<&str>) -> bool {
+    content_type.is_some_and(|content_type| {
+        let media_type = content_type.split(';').next().unwrap_or_default();
+        media_type.trim().eq_ignore_ascii_case("application/json")
+    })
+}
+
+/// Read one request, or `None` if it isn't valid HTTP
+async fn read_request(socket: &mut TcpStream) -> std::io::Result<Option<Request>> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let end = loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 || buf.len() + n > MAX_REQUEST_BYTES {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..end]).to_string();
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let (Some(method), Some(path)) = (request_line.next(), request_line.next()) else {
+        return Ok(None);
+    };
+    let header = |wanted: &str| {
+        head.lines().skip(1).find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case(wanted)
+                .then(|| value.trim().to_string())
+        })
+    };
+    let length = match header("content-length") {
+        Some(length) => match length.parse::<usize>() {
+            Ok(length) => length,
+            Err(_) => return Ok(None),
+        },
+        None => 0,
+    };
+    if end + 4 + length > MAX_REQUEST_BYTES {
+        return Ok(None);
+    }
+    while buf.len() < end + 4 + length {
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Ok(Some(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        host: header("host"),
+        content_type: header("content-type"),
+        body: buf[end + 4..end + 4 + length].to_vec(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MockBackend;
+    use std::collections::HashMap;
+
+    fn service(name: &str) -> Service {
+        let dir =
+            std::env::temp_dir().join(format!("promptflow-serve-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(crate::history::HISTORY_FILE);
+        std::fs::remove_file(&path).ok();
+        Service {
+            backend: Box::new(MockBackend::new(HashMap::from([(
+                "knight".to_string(),
+                "1boy, (armor:1.2)".to_string(),
+            )]))),
+            history: History::load(path),
+            retention: Retention::default(),
+            settings: GenerationSettings {
+                negative_prompt: "lowres, lowres".to_string(),
+                ..Default::default()
+            },
+            tokenizer: ClipTokenizer::estimating(),
+            max_tokens: None,
+        }
+    }
+
+    fn request(method: &str, path: &str, body: &[u8]) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            content_type: Some("application/json".to_string()),
+            body: body.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_requests() {
+        let mut service = service("routes");
+        let (status, body) = service
+            .respond(&request("POST", "/generate", br#"{"keyword": " knight "}"#))
+            .await;
+        assert_eq!(status, 200);
+        assert_eq!(body["keyword"], "knight");
+        assert_eq!(body["prompt"], "1boy, (armor:1.2)");
+        assert_eq!(body["negative_prompt"], "lowres, multiple views, duplicate");
+        assert_eq!(service.history.recent(1)[0].keyword, "knight");
+        assert_eq!(service.history.recent(1)[0].prompt, "1boy, (armor:1.2)");
+
+        for (method, path, body, expected) in [
+            ("GET", "/health", &b""[..], 200),
+            ("POST", "/generate", b"{}", 400),
+            ("POST", "/generate", br#"{"keyword": ""}"#, 400),
+            ("GET", "/generate", b"", 405),
+            ("GET", "/", b"", 404),
+        ] {
+            let (status, _) = service.respond(&request(method, path, body)).await;
+            assert_eq!(status, expected, "{} {}", method, path);
+        }
+    }
+
+    #[tokio::test]
+    async fn generation_needs_a_json_content_type() {
+        let mut service = service("content-type");
+        for content_type in [
+            None,
+            Some("text/plain"),
+            Some("application/x-www-form-urlencoded"),
+        ] {
+            let request = Request {
+                content_type: content_type.map(str::to_string),
+                ..request("POST", "/generate", br#"{"keyword": "knight"}"#)
+            };
+            assert_eq!(service.respond(&request).await.0, 415);
+        }
+        assert!(service.history.entries().is_empty());
+        let request = Request {
+            content_type: Some("Application/JSON; charset=utf-8".to_string()),
+            ..request("POST", "/generate", br#"{"keyword": "knight"}"#)
+        };
+        assert_eq!(service.respond(&request).await.0, 200);
+    }
+
+    #[test]
+    fn only_loopback_hosts_with_the_port_are_allowed() {
+        for host in [
+            "localhost:8787",
+            "127.0.0.1:8787",
+            "[::1]:8787",
+            "LOCALHOST:8787",
+        ] {
+            assert!(is_allowed_host(Some(host), 8787), "{}", host);
+        }
+        for host in [
+            None,
+            Some("localhost"),
+            Some("localhost:8080"),
+            Some("evil.example:8787"),
+            Some("127.0.0.1.evil.example:8787"),
+            Some("::1:8787"),
+        ] {
+            assert!(!is_allowed_host(host, 8787), "{:?}", host);
+        }
+    }
+
+    /// Send `request` to a connection handled with `read_timeout` and return the response
+    async fn exchange(service: Service, request: &[u8], read_timeout: Duration) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = Mutex::new(service);
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        client.write_all(request).await.unwrap();
+        handle(socket, &service, addr.port(), read_timeout)
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn rejects_foreign_hosts_and_slow_clients() {
+        let rebound = b"POST /generate HTTP/1.1\r\nHost: evil.example:8787\r\n\
+            Content-Type: application/json\r\nContent-Length: 21\r\n\r\n{\"keyword\": \"knight\"}";
+        let response = exchange(service("host"), rebound, READ_TIMEOUT).await;
+        assert!(
+            response.starts_with("HTTP/1.1 403 Forbidden\r\n"),
+            "{}",
+            response
+        );
+
+        let partial = b"POST /generate HTTP/1.1\r\nHost: localhost";
+        let response = exchange(service("timeout"), partial, Duration::from_millis(50)).await;
+        assert!(
+            response.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run(listener, service("http")));
+
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/generate", addr))
+            .json(&json!({ "keyword": "knight" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["prompt"], "1boy, (armor:1.2)");
+        assert!(body["tokens"].as_u64().unwrap() > 0);
+    }
+}
@@ -0,0 +1,241 @@
+//! Named sessions, each with its own history and generation defaults.
+//!
+//! A session lives in `$XDG_DATA_HOME/promptflow/sessions/<name>/`: `session.toml` holds its
+//! preset, negative prompt and character descriptors, and `history.jsonl` its prompt history.
+//! The session switched to with `session switch` is remembered in `active_session`.
+
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config, history};
+
+/// File holding a session's settings, inside its directory
+pub const SESSION_FILE: &str = "session.toml";
+
+/// Directory all sessions are kept in
+pub fn sessions_dir() -> Result<PathBuf, SessionError> {
+    Ok(data_dir()?.join("sessions"))
+}
+
+fn data_dir() -> Result<PathBuf, SessionError> {
+    config::data_dir().ok_or(SessionError::NoDataDir)
+}
+
+fn active_path() -> Result<PathBuf, SessionError> {
+    Ok(data_dir()?.join("active_session"))
+}
+
+/// History file of `session`, or the global one without a session
+pub fn history_path(session: Option<&str>) -> Result<PathBuf, SessionError> {
+    match session {
+        Some(name) => Ok(sessions_dir()?.join(name).join(history::HISTORY_FILE)),
+        None => history::history_path().ok_or(SessionError::NoDataDir),
+    }
+}
+
+/// Name of the session chosen with `session switch`, if any
+pub fn active() -> Option<String> {
+    let name = std::fs::read_to_string(active_path().ok()?).ok()?;
+    let name = name.trim();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Remember `name` as the active session, or go back to the global history with `None`
+pub fn set_active(name: Option<&str>) -> Result<(), SessionError> {
+    let path = active_path()?;
+    let result = match name {
+        Some(name) => std::fs::create_dir_all(data_dir()?)
+            .and_then(|()| std::fs::write(&path, format!("{}\n", name))),
+        None => match std::fs::remove_file(&path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            other => other,
+        },
+    };
+    result.map_err(|e| SessionError::Io(path, e))
+}
+
+/// Failure to find, read or write a session
+#[derive(Debug)]
+pub enum SessionError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    InvalidName(String),
+    NotFound(String),
+    Exists(String),
+    Archived(String),
+    /// Neither `XDG_DATA_HOME` nor `HOME` is set
+    NoDataDir,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Io(path, e) => write!(f, "could not access {}: {}", path.display(), e),
+            SessionError::Parse(path, e) => write!(f, "invalid session {}: {}", path.display(), e),
+            SessionError::InvalidName(name) => write!(
+                f,
+                "invalid session name {:?}: use letters, digits, '-', '_' and '.', not starting with '.'",
+                name
+            ),
+            SessionError::NotFound(name) => write!(
+                f,
+                "no session named {:?} (create it with `PromptFlow session new {}`)",
+                name, name
+            ),
+            SessionError::Exists(name) => write!(f, "session {:?} already exists", name),
+            SessionError::Archived(name) => write!(f, "session {:?} is archived", name),
+            SessionError::NoDataDir => f.write_str(
+                "cannot determine the data directory for the history and sessions; set XDG_DATA_HOME or HOME",
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+/// Check that `name` can be used as a directory name
+fn check_name(name: &str) -> Result<(), SessionError> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if valid {
+        Ok(())
+    } else {
+        Err(SessionError::InvalidName(name.to_string()))
+    }
+}
+
+/// Settings a session applies on top of the config files
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Session {
+    #[serde(skip)]
+    pub name: String,
+    /// When the session was created, in seconds since the Unix epoch
+    pub created: u64,
+    /// Archived sessions are hidden from `session list` and can't be switched to
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub archived: bool,
+    /// Built-in preset the system instruction is taken from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub negative_prompt: Option<String>,
+    /// Character descriptions every prompt of the session should stay consistent with
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub descriptors: Vec<String>,
+}
+
+impl Session {
+    /// Directory of the session called `name`
+    fn dir(name: &str) -> Result<PathBuf, SessionError> {
+        Ok(sessions_dir()?.join(name))
+    }
+
+    /// Create the session called `self.name`, failing if it already exists
+    pub fn create(&mut self) -> Result<(), SessionError> {
+        check_name(&self.name)?;
+        let dir = Self::dir(&self.name)?;
+        if dir.join(SESSION_FILE).exists() {
+            return Err(SessionError::Exists(self.name.clone()));
+        }
+        self.created = history::now();
+        self.save()
+    }
+
+    /// Open the session called `name`
+    pub fn open(name: &str) -> Result<Self, SessionError> {
+        check_name(name)?;
+        let path = Self::dir(name)?.join(SESSION_FILE);
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(SessionError::NotFound(name.to_string()));
+            }
+            Err(e) => return Err(SessionError::Io(path, e)),
+        };
+        let mut session: Session =
+            toml::from_str(&text).map_err(|e| SessionError::Parse(path, e))?;
+        session.name = name.to_string();
+        Ok(session)
+    }
+
+    /// Every session, sorted by name
+    pub fn list() -> Result<Vec<Session>, SessionError> {
+        let dir = sessions_dir()?;
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(SessionError::Io(dir, e)),
+        };
+        let mut sessions = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|e| SessionError::Io(dir.clone(), e))?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if check_name(&name).is_ok() && entry.path().join(SESSION_FILE).is_file() {
+                sessions.push(Self::open(&name)?);
+            }
+        }
+        sessions.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(sessions)
+    }
+
+    /// Write `session.toml`
+    pub fn save(&self) -> Result<(), SessionError> {
+        let dir = Self::dir(&self.name)?;
+        std::fs::create_dir_all(&dir).map_err(|e| SessionError::Io(dir.clone(), e))?;
+        let path = dir.join(SESSION_FILE);
+        let text = toml::to_string(self).expect("sessions serialize to TOML");
+        std::fs::write(&path, text).map_err(|e| SessionError::Io(path, e))
+    }
+
+    /// The session's history file
+    pub fn history_path(&self) -> Result<PathBuf, SessionError> {
+        history_path(Some(&self.name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_unsafe_names() {
+        for name in ["azure-knight", "series_2", "v1.5", "魔法少女"] {
+            assert!(check_name(name).is_ok(), "{}", name);
+        }
+        for name in ["", ".hidden", "../escape", "a/b", "with space"] {
+            assert!(check_name(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn session_file_round_trip() {
+        let session = Session {
+            name: "azure-knight".to_string(),
+            created: 1_792_195_200,
+            archived: true,
+            preset: Some("anime".to_string()),
+            negative_prompt: Some("lowres".to_string()),
+            descriptors: vec!["Azure: long blue hair, silver armor".to_string()],
+        };
+        let text = toml::to_string(&session).unwrap();
+        let mut parsed: Session = toml::from_str(&text).unwrap();
+        parsed.name = session.name.clone();
+        assert_eq!(parsed, session);
+
+        let minimal: Session = toml::from_str("created = 1\n").unwrap();
+        assert!(!minimal.archived && minimal.descriptors.is_empty());
+        assert!(toml::from_str::<Session>("created = 1\nnegative = \"x\"\n").is_err());
+        assert!(
+            session
+                .history_path()
+                .unwrap()
+                .ends_with("sessions/azure-knight/history.jsonl")
+        );
+    }
+}
@@ -0,0 +1,401 @@
+//! Image models a prompt is written for.
+//!
+//! The model always answers in the Stable Diffusion syntax the parser understands, so
+//! validation, coverage and the CLIP budget work the same for every target. Each target adds
+//! its own notes to the system instruction, and [`Dialect::format`] rewrites the finished prompt
+//! into the syntax the target expects:
+//! - `sd15`, `sdxl`, `illustrious`: unchanged
+//! - `pony`: `score_9, score_8_up, score_7_up` quality tags in front
+//! - `flux`, `dalle`: weights and brackets dropped, `BREAK` segments become sentences
+//! - `midjourney`: weights become `::` multi-prompt weights, followed by `--ar` and `--stylize`
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::parser::{self, Prompt, Token};
+
+/// Quality tags Pony Diffusion prompts start with
+const PONY_SCORE_TAGS: &str = "score_9, score_8_up, score_7_up";
+
+/// Low quality tags Pony Diffusion negative prompts start with
+const PONY_NEGATIVE_SCORE_TAGS: &str = "score_6, score_5, score_4";
+
+/// Highest `--stylize` value Midjourney accepts
+pub const MAX_STYLIZE: u32 = 1000;
+
+/// Image model family a prompt is written for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    /// Stable Diffusion 1.5 and its anime fine-tunes
+    #[default]
+    Sd15,
+    Sdxl,
+    /// Pony Diffusion XL
+    Pony,
+    /// Illustrious XL and NoobAI
+    Illustrious,
+    Flux,
+    Midjourney,
+    /// DALL·E 3
+    DallE,
+}
+
+impl Target {
+    pub const ALL: [Target; 7] = [
+        Target::Sd15,
+        Target::Sdxl,
+        Target::Pony,
+        Target::Illustrious,
+        Target::Flux,
+        Target::Midjourney,
+        Target::DallE,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Target::Sd15 => "sd15",
+            Target::Sdxl => "sdxl",
+            Target::Pony => "pony",
+            Target::Illustrious => "illustrious",
+            Target::Flux => "flux",
+            Target::Midjourney => "midjourney",
+            Target::DallE => "dalle",
+        }
+    }
+
+    /// Whether the target encodes prompts with CLIP, so CLIP token counts apply
+    pub fn uses_clip(self) -> bool {
+        !matches!(self, Target::Midjourney | Target::DallE)
+    }
+
+    /// Notes appended to the system instruction, if the target needs any beyond the preset's
+    pub fn instruction(self) -> Option<&'static str> {
+        match self {
+            Target::Sd15 => None,
+            Target::Sdxl => Some(
+                "**Target Model: Stable Diffusion XL**\n\
+                 *   SDXL understands short descriptive phrases as well as single tags; prefer \
+                 phrases such as `girl reading under a cherry tree` over long tag lists.\n\
+                 *   Keep weights gentle (0.8 to 1.3); SDXL overreacts to strong weights.",
+            ),
+            Target::Pony => Some(
+                "**Target Model: Pony Diffusion XL**\n\
+                 *   Write Danbooru-style tags (`1girl`, `solo`, `long hair`, `looking at viewer`) \
+                 instead of sentences.\n\
+                 *   Add a source tag such as `source_anime` and a rating tag such as `rating_safe`.\n\
+                 *   Do not write `score_*` quality tags; they are added automatically.",
+            ),
+            Target::Illustrious => Some(
+                "**Target Model: Illustrious XL**\n\
+                 *   Write Danbooru-style tags (`1girl`, `solo`, `long hair`, `looking at viewer`) \
+                 instead of sentences.\n\
+                 *   Start with `masterpiece, best quality, very aesthetic, absurdres`.\n\
+                 *   Artist and character names work best written exactly as Danbooru tags.",
+            ),
+            Target::Flux => Some(
+                "**Target Model: Flux**\n\
+                 *   Flux reads natural language: make every comma-separated entry a descriptive \
+                 phrase or short sentence rather than a single tag.\n\
+                 *   Weights and brackets are removed afterwards, so put the most important \
+                 details first instead of weighting them.\n\
+                 *   Each `BREAK` segment becomes its own sentence.\n\
+                 *   Leave out quality tags such as `masterpiece` or `best quality`.",
+            ),
+            Target::Midjourney => Some(
+                "**Target Model: Midjourney**\n\
+                 *   Use short, evocative phrases; Midjourney ignores filler words and long tag lists.\n\
+                 *   Weights are converted to Midjourney `::` weights, so use them only for the \
+                 few elements that really need balancing.\n\
+                 *   Do not write parameters such as `--ar` or `--stylize`; they are added \
+                 automatically.\n\
+                 *   Leave out quality tags such as `masterpiece` or `best quality`.",
+            ),
+            Target::DallE => Some(
+                "**Target Model: DALL·E 3**\n\
+                 *   DALL·E reads plain English: make every comma-separated entry a descriptive \
+                 phrase or short sentence.\n\
+                 *   Weights and brackets are removed afterwards, so put the most important \
+                 details first instead of weighting them.\n\
+                 *   Each `BREAK` segment becomes its own sentence.\n\
+                 *   Describe styles instead of naming living artists, and leave out quality tags.",
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|target| target.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| {
+                let names: Vec<&str> = Self::ALL.iter().map(|target| target.name()).collect();
+                format!("unknown target {:?} (available: {})", s, names.join(", "))
+            })
+    }
+}
+
+/// Parse an aspect ratio written as `W:H`, e.g. `16:9`
+pub fn parse_aspect_ratio(s: &str) -> Result<(u32, u32), String> {
+    let parsed = s.split_once(':').and_then(|(width, height)| {
+        Some((width.trim().parse().ok()?, height.trim().parse().ok()?))
+    });
+    match parsed {
+        Some((width, height)) if width > 0 && height > 0 => Ok((width, height)),
+        _ => Err(format!(
+            "Invalid aspect ratio {:?}, expected W:H such as 16:9",
+            s
+        )),
+    }
+}
+
+/// Parse a Midjourney `--stylize` value, 0 to [`MAX_STYLIZE`]
+pub fn parse_stylize(s: &str) -> Result<u32, String> {
+    match s.trim().parse() {
+        Ok(value) if value <= MAX_STYLIZE => Ok(value),
+        _ => Err(format!(
+            "Invalid stylize value {:?}, expected 0 to {}",
+            s, MAX_STYLIZE
+        )),
+    }
+}
+
+/// A target together with the parameters its formatter appends
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Dialect {
+    pub target: Target,
+    /// `--ar W:H` (Midjourney only)
+    pub aspect_ratio: Option<(u32, u32)>,
+    /// `--stylize N` (Midjourney only)
+    pub stylize: Option<u32>,
+}
+
+impl Dialect {
+    /// Rewrite `text`, written in Stable Diffusion syntax, into the target's dialect
+    pub fn format(&self, text: &str) -> String {
+        match self.target {
+            Target::Sd15 | Target::Sdxl | Target::Illustrious => text.to_string(),
+            Target::Pony => with_score_tags(text, PONY_SCORE_TAGS),
+            Target::Flux | Target::DallE => natural_language(&parser::parse(text).0),
+            Target::Midjourney => {
+                let mut prompt = multi_prompt(&parser::parse(text).0);
+                if let Some((width, height)) = self.aspect_ratio {
+                    prompt.push_str(&format!(" --ar {}:{}", width, height));
+                }
+                if let Some(stylize) = self.stylize {
+                    prompt.push_str(&format!(" --stylize {}", stylize));
+                }
+                prompt
+            }
+        }
+    }
+
+    /// Rewrite the negative prompt `text` into the target's dialect
+    pub fn format_negative(&self, text: &str) -> String {
+        match self.target {
+            Target::Sd15 | Target::Sdxl | Target::Illustrious => text.to_string(),
+            Target::Pony => with_score_tags(text, PONY_NEGATIVE_SCORE_TAGS),
+            Target::Flux | Target::Midjourney | Target::DallE => plain_tags(text),
+        }
+    }
+}
+
+/// `text` with `tags` in front, unless it already has `score_*` tags
+fn with_score_tags(text: &str, tags: &str) -> String {
+    let (prompt, _) = parser::parse(text);
+    let scored = prompt
+        .segments
+        .iter()
+        .flat_map(|segment| &segment.tokens)
+        .any(|token| token.plain_text().trim().starts_with("score_"));
+    match (scored, text.trim().is_empty()) {
+        (true, _) => text.to_string(),
+        (false, true) => tags.to_string(),
+        (false, false) => format!("{}, {}", tags, text),
+    }
+}
+
+/// The text of every token without grouping or weights
+fn plain_tokens(tokens: &[Token]) -> Vec<String> {
+    tokens
+        .iter()
+        .map(Token::plain_text)
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty())
+        .collect()
+}
+
+/// Comma-separated tags without grouping, weights or `BREAK`s
+fn plain_tags(text: &str) -> String {
+    let (prompt, _) = parser::parse(text);
+    let tokens: Vec<Token> = prompt
+        .segments
+        .into_iter()
+        .flat_map(|segment| segment.tokens)
+        .collect();
+    plain_tokens(&tokens).join(", ")
+}
+
+/// Segments as sentences of comma-separated phrases, without grouping or weights
+fn natural_language(prompt: &Prompt) -> String {
+    prompt
+        .segments
+        .iter()
+        .map(|segment| plain_tokens(&segment.tokens).join(", "))
+        .filter(|sentence| !sentence.is_empty())
+        .map(|sentence| {
+            let sentence = sentence.trim_end_matches(['.', ',']);
+            let mut chars = sentence.chars();
+            match chars.next() {
+                Some(first) => format!("{}{}.", first.to_uppercase(), chars.as_str()),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Midjourney multi-prompt: runs of unweighted tokens form one part, every weighted token its
+/// own part with its weight after `::`, and `BREAK` ends a part
+fn multi_prompt(prompt: &Prompt) -> String {
+    let mut parts: Vec<(Vec<String>, f32)> = Vec::new();
+    for segment in &prompt.segments {
+        let mut run = Vec::new();
+        for token in &segment.tokens {
+            let text = token.plain_text().trim().to_string();
+            if text.is_empty() {
+                continue;
+            }
+            let weight = token.weight();
+            if (weight - 1.0).abs() < f32::EPSILON {
+                run.push(text);
+                continue;
+            }
+            if !run.is_empty() {
+                parts.push((std::mem::take(&mut run), 1.0));
+            }
+            parts.push((vec![text], weight));
+        }
+        if !run.is_empty() {
+            parts.push((run, 1.0));
+        }
+    }
+    // A single unweighted part needs no separators at all
+    if let [(tokens, weight)] = parts.as_slice()
+        && (weight - 1.0).abs() < f32::EPSILON
+    {
+        return tokens.join(", ");
+    }
+    let last = parts.len().saturating_sub(1);
+    parts
+        .iter()
+        .enumerate()
+        .map(|(i, (tokens, weight))| {
+            let text = tokens.join(", ");
+            if (weight - 1.0).abs() >= f32::EPSILON {
+                format!("{}::{}", text, parser::format_weight(*weight))
+            } else if i < last {
+                format!("{}::", text)
+            } else {
+                text
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dialect(target: Target) -> Dialect {
+        Dialect {
+            target,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_target_names() {
+        for target in Target::ALL {
+            assert_eq!(target.name().parse::<Target>(), Ok(target));
+        }
+        assert_eq!("DallE".parse::<Target>(), Ok(Target::DallE));
+        assert!("sd3".parse::<Target>().unwrap_err().contains("midjourney"));
+    }
+
+    #[test]
+    fn stable_diffusion_targets_keep_the_prompt() {
+        let text = "1girl, (azure armor:1.3), BREAK, castle";
+        for target in [Target::Sd15, Target::Sdxl, Target::Illustrious] {
+            assert_eq!(dialect(target).format(text), text);
+        }
+    }
+
+    #[test]
+    fn pony_adds_score_tags_once() {
+        let pony = dialect(Target::Pony);
+        assert_eq!(
+            pony.format("1girl, solo"),
+            "score_9, score_8_up, score_7_up, 1girl, solo"
+        );
+        assert_eq!(pony.format("score_9, 1girl"), "score_9, 1girl");
+        assert_eq!(
+            pony.format_negative("blurry"),
+            "score_6, score_5, score_4, blurry"
+        );
+    }
+
+    #[test]
+    fn natural_language_targets_drop_weights() {
+        let text = "a knight in (azure armor:1.3), [crowd], BREAK, ((stormy sky)), castle";
+        let expected = "A knight in azure armor, crowd. Stormy sky, castle.";
+        assert_eq!(dialect(Target::Flux).format(text), expected);
+        assert_eq!(dialect(Target::DallE).format(text), expected);
+        assert_eq!(
+            dialect(Target::DallE).format_negative("(worst quality:1.4), blurry"),
+            "worst quality, blurry"
+        );
+    }
+
+    #[test]
+    fn midjourney_converts_weights_and_appends_parameters() {
+        let midjourney = Dialect {
+            target: Target::Midjourney,
+            aspect_ratio: Some((2, 3)),
+            stylize: Some(250),
+        };
+        assert_eq!(
+            midjourney.format("1girl, solo, (azure armor:1.3), castle, [crowd]"),
+            "1girl, solo:: azure armor::1.3 castle:: crowd::0.91 --ar 2:3 --stylize 250"
+        );
+        assert_eq!(
+            dialect(Target::Midjourney).format("knight, BREAK, castle"),
+            "knight:: castle"
+        );
+        assert_eq!(
+            dialect(Target::Midjourney).format("knight, castle"),
+            "knight, castle"
+        );
+    }
+
+    #[test]
+    fn parses_midjourney_parameters() {
+        assert_eq!(parse_aspect_ratio(" 16 : 9 "), Ok((16, 9)));
+        assert!(parse_aspect_ratio("16x9").is_err());
+        assert!(parse_aspect_ratio("0:1").is_err());
+        assert_eq!(parse_stylize("1000"), Ok(1000));
+        assert!(parse_stylize("1001").is_err());
+    }
+}
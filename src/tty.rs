@@ -0,0 +1,78 @@
+//! Reading secrets from the terminal.
+
+use std::cell::RefCell;
+use std::io::{self, BufRead, IsTerminal};
+
+use crate::key::{KeyError, PASSPHRASE_ENV_VAR};
+
+/// Read a secret after showing `prompt`: without echo on a terminal, otherwise as one line of
+/// standard input so it can be piped in
+pub fn read_secret(prompt: &str) -> io::Result<String> {
+    let secret = if io::stdin().is_terminal() {
+        rpassword::prompt_password(prompt)?
+    } else {
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line)?;
+        line
+    };
+    let secret = secret.trim().to_string();
+    if secret.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no input given",
+        ));
+    }
+    Ok(secret)
+}
+
+/// Passphrase of the key store, taken from [`PASSPHRASE_ENV_VAR`] or asked for on the terminal
+/// at most once
+#[derive(Default)]
+pub struct Passphrase {
+    cached: RefCell<Option<String>>,
+}
+
+impl Passphrase {
+    pub fn get(&self) -> Result<String, KeyError> {
+        if let Some(passphrase) = self.cached.borrow().as_ref() {
+            return Ok(passphrase.clone());
+        }
+        let passphrase = match std::env::var(PASSPHRASE_ENV_VAR) {
+            Ok(passphrase) => passphrase,
+            Err(_) if io::stdin().is_terminal() => {
+                rpassword::prompt_password("Key store passphrase: ").map_err(KeyError::Terminal)?
+            }
+            Err(_) => return Err(KeyError::PassphraseRequired),
+        };
+        self.remember(passphrase.clone());
+        Ok(passphrase)
+    }
+
+    /// Use `passphrase` from now on instead of asking
+    pub fn remember(&self, passphrase: String) {
+        *self.cached.borrow_mut() = Some(passphrase);
+    }
+
+    /// Choose a passphrase for a store being encrypted, asking twice on a terminal
+    pub fn choose(&self) -> Result<String, KeyError> {
+        if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV_VAR) {
+            self.remember(passphrase.clone());
+            return Ok(passphrase);
+        }
+        if !io::stdin().is_terminal() {
+            return Err(KeyError::PassphraseRequired);
+        }
+        let passphrase =
+            rpassword::prompt_password("New key store passphrase: ").map_err(KeyError::Terminal)?;
+        let confirmation =
+            rpassword::prompt_password("Repeat the passphrase: ").map_err(KeyError::Terminal)?;
+        if passphrase != confirmation {
+            return Err(KeyError::PassphraseMismatch);
+        }
+        if passphrase.is_empty() {
+            return Err(KeyError::PassphraseRequired);
+        }
+        self.remember(passphrase.clone());
+        Ok(passphrase)
+    }
+}
@@ -0,0 +1,623 @@
+//! `PromptFlow tui`: a full-screen workspace around the history and generation.
+//!
+//! The history is listed on the left, newest first. The selected prompt is shown on the right,
+//! one block per `BREAK` segment with its CLIP token count, and each token colored by its
+//! weight. In the prompt pane the arrow keys pick a token and change its weight, with the token
+//! counts following every edit; edited prompts are saved as new history entries. Marking an
+//! entry and pressing `d` compares it side by side with the selected one.
+
+use std::collections::HashMap;
+use std::io;
+use std::time::Instant;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::backend::PromptBackend;
+use crate::clip::{self, ClipTokenizer};
+use crate::edit;
+use crate::generate::{self, GenerationSettings};
+use crate::history::{self, Entry, History, Retention};
+use crate::output;
+use crate::parser::{self, Token};
+
+/// Weight change of one Up or Down key press
+const WEIGHT_STEP: f32 = 0.05;
+
+/// Key help shown in the status bar for each pane
+const LIST_KEYS: &str = "↑↓ select  → edit  g generate  m mark  d compare  q quit";
+const PROMPT_KEYS: &str = "←→ token  ↑↓ weight  s save  u undo  ← back  q quit";
+
+/// Pane the keys act on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Focus {
+    List,
+    Prompt,
+}
+
+/// What the event loop has to do after a key press
+#[derive(Debug, Clone, PartialEq)]
+enum Action {
+    None,
+    Quit,
+    /// Generate a new prompt for a keyword
+    Generate(String),
+    /// Save an edited prompt as a new entry based on the entry with this id
+    Save(u64, String),
+}
+
+/// Everything shown on screen, apart from what is computed while drawing
+struct App {
+    /// History entries, newest first
+    entries: Vec<Entry>,
+    selected: usize,
+    focus: Focus,
+    /// Token the weight keys act on, counted across segments
+    cursor: usize,
+    /// Unsaved edits by entry id
+    edits: HashMap<u64, String>,
+    /// Entry the selected one is compared with
+    marked: Option<u64>,
+    diff: bool,
+    status: String,
+}
+
+impl App {
+    fn new(entries: &[Entry]) -> Self {
+        let mut app = Self {
+            entries: Vec::new(),
+            selected: 0,
+            focus: Focus::List,
+            cursor: 0,
+            edits: HashMap::new(),
+            marked: None,
+            diff: false,
+            status: String::new(),
+        };
+        app.reload(entries, None);
+        app
+    }
+
+    /// Show `entries` again, selecting the entry with `select` if given
+    fn reload(&mut self, entries: &[Entry], select: Option<u64>) {
+        self.entries = entries.iter().rev().cloned().collect();
+        if let Some(index) = select.and_then(|id| self.entries.iter().position(|e| e.id == id)) {
+            self.selected = index;
+        }
+        self.selected = self.selected.min(self.entries.len().saturating_sub(1));
+        self.cursor = 0;
+    }
+
+    fn current(&self) -> Option<&Entry> {
+        self.entries.get(self.selected)
+    }
+
+    /// The selected prompt, with its unsaved edits
+    fn text(&self) -> Option<&str> {
+        let entry = self.current()?;
+        Some(self.edits.get(&entry.id).unwrap_or(&entry.prompt))
+    }
+
+    fn handle(&mut self, key: KeyCode, weight_range: (f32, f32)) -> Action {
+        if matches!(key, KeyCode::Char('q')) {
+            return Action::Quit;
+        }
+        let Some(entry) = self.current() else {
+            return match key {
+                KeyCode::Esc => Action::Quit,
+                _ => Action::None,
+            };
+        };
+        let id = entry.id;
+        match (self.focus, key) {
+            (Focus::List, KeyCode::Esc) => return Action::Quit,
+            (Focus::List, KeyCode::Up | KeyCode::Char('k')) => {
+                self.selected = self.selected.saturating_sub(1);
+                self.cursor = 0;
+            }
+            (Focus::List, KeyCode::Down | KeyCode::Char('j')) => {
+                self.selected = (self.selected + 1).min(self.entries.len() - 1);
+                self.cursor = 0;
+            }
+            (Focus::List, KeyCode::Right | KeyCode::Enter | KeyCode::Tab) => {
+                self.focus = Focus::Prompt;
+                self.diff = false;
+            }
+            (Focus::List, KeyCode::Char('g')) => {
+                return Action::Generate(entry.keyword.clone());
+            }
+            (Focus::List, KeyCode::Char('m')) => {
+                self.marked = Some(id).filter(|_| self.marked != Some(id));
+                self.status = match self.marked {
+                    Some(id) => format!("Marked entry {} for comparison", id),
+                    None => "Unmarked".to_string(),
+                };
+            }
+            (Focus::List, KeyCode::Char('d')) => match self.marked {
+                Some(_) => self.diff = !self.diff,
+                None => self.status = "Mark an entry with m first".to_string(),
+            },
+            (Focus::Prompt, KeyCode::Esc | KeyCode::Tab) => self.focus = Focus::List,
+            (Focus::Prompt, KeyCode::Left | KeyCode::Char('h')) if self.cursor == 0 => {
+                self.focus = Focus::List;
+            }
+            (Focus::Prompt, KeyCode::Left | KeyCode::Char('h')) => self.cursor -= 1,
+            (Focus::Prompt, KeyCode::Right | KeyCode::Char('l')) => {
+                let count = self.text().map_or(0, edit::token_count);
+                self.cursor = (self.cursor + 1).min(count.saturating_sub(1));
+            }
+            (Focus::Prompt, KeyCode::Up | KeyCode::Down | KeyCode::Char('k' | 'j')) => {
+                let delta = match key {
+                    KeyCode::Up | KeyCode::Char('k') => WEIGHT_STEP,
+                    _ => -WEIGHT_STEP,
+                };
+                let text = self.text().unwrap_or_default();
+                if let Some(edited) = edit::adjust_weight(text, self.cursor, delta, weight_range) {
+                    self.edits.insert(id, edited);
+                }
+            }
+            (Focus::Prompt, KeyCode::Char('u')) => {
+                self.edits.remove(&id);
+                self.status = "Edits undone".to_string();
+            }
+            (Focus::Prompt, KeyCode::Char('s')) => match self.edits.get(&id) {
+                Some(text) => return Action::Save(id, text.clone()),
+                None => self.status = "Nothing to save".to_string(),
+            },
+            _ => {}
+        }
+        Action::None
+    }
+}
+
+/// Style of a token of weight `weight`: brighter and bolder the stronger it is
+fn weight_style(weight: f32) -> Style {
+    if weight >= 1.3 {
+        Style::new()
+            .fg(Color::LightRed)
+            .add_modifier(Modifier::BOLD)
+    } else if weight > 1.0 + f32::EPSILON {
+        Style::new().fg(Color::Yellow)
+    } else if weight < 1.0 - f32::EPSILON {
+        Style::new().fg(Color::DarkGray)
+    } else {
+        Style::new()
+    }
+}
+
+/// Lines of `text`: a header with the token count before each segment, then its tokens colored
+/// by weight, with the token at `cursor` highlighted
+fn prompt_lines(
+    text: &str,
+    cursor: Option<usize>,
+    tokenizer: &ClipTokenizer,
+) -> Vec<Line<'static>> {
+    let (prompt, _) = parser::parse(text);
+    let report = clip::analyze(&prompt, tokenizer);
+    let mut lines = Vec::new();
+    let mut index = 0;
+    for (i, segment) in prompt.segments.iter().enumerate() {
+        let header = if i == 0 {
+            format!("── segment 1 · {} tokens ──", report.segments[0])
+        } else {
+            format!(
+                "── {} · segment {} · {} tokens ──",
+                parser::BREAK,
+                i + 1,
+                report.segments[i]
+            )
+        };
+        lines.push(Line::styled(header, Style::new().fg(Color::Magenta)));
+        let mut spans = Vec::new();
+        for (j, token) in segment.tokens.iter().enumerate() {
+            if j > 0 {
+                spans.push(Span::raw(", "));
+            }
+            let mut style = weight_style(token.weight());
+            if cursor == Some(index) {
+                style = style.add_modifier(Modifier::REVERSED);
+            }
+            spans.push(Span::styled(token.to_string(), style));
+            index += 1;
+        }
+        lines.push(Line::from(spans));
+        lines.push(Line::default());
+    }
+    lines
+}
+
+/// How a token compares with the other side of a diff
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    Same,
+    /// Present on both sides with different weights
+    Reweighted,
+    /// Only on this side
+    Only,
+}
+
+/// Each token of `text` with how it compares with the tokens of `other`, by plain text
+fn compare(text: &str, other: &str) -> Vec<(String, Change)> {
+    let tokens = |text: &str| -> Vec<Token> {
+        let (prompt, _) = parser::parse(text);
+        prompt.segments.into_iter().flat_map(|s| s.tokens).collect()
+    };
+    let key = |token: &Token| token.plain_text().trim().to_lowercase();
+    let other: HashMap<String, f32> = tokens(other)
+        .iter()
+        .map(|token| (key(token), token.weight()))
+        .collect();
+    tokens(text)
+        .iter()
+        .map(|token| {
+            let change = match other.get(&key(token)) {
+                None => Change::Only,
+                Some(weight) if (weight - token.weight()).abs() > 0.005 => Change::Reweighted,
+                Some(_) => Change::Same,
+            };
+            (token.to_string(), change)
+        })
+        .collect()
+}
+
+/// Line of `text` compared with `other`; tokens only on this side get `only`
+fn diff_line(text: &str, other: &str, only: Color) -> Line<'static> {
+    let mut spans = Vec::new();
+    for (i, (token, change)) in compare(text, other).into_iter().enumerate() {
+        if i > 0 {
+            spans.push(Span::raw(", "));
+        }
+        let style = match change {
+            Change::Same => Style::new(),
+            Change::Reweighted => Style::new().fg(Color::Yellow),
+            Change::Only => Style::new().fg(only).add_modifier(Modifier::BOLD),
+        };
+        spans.push(Span::styled(token, style));
+    }
+    Line::from(spans)
+}
+
+fn title(entry: &Entry) -> String {
+    format!(" #{} {} ", entry.id, entry.keyword)
+}
+
+/// Everything needed to run the TUI
+pub struct Tui {
+    pub backend: Box<dyn PromptBackend>,
+    pub history: History,
+    pub retention: Retention,
+    pub settings: GenerationSettings,
+    pub tokenizer: ClipTokenizer,
+    pub max_tokens: Option<usize>,
+}
+
+impl Tui {
+    /// Take over the terminal until the user quits
+    pub async fn run(&mut self) -> io::Result<()> {
+        let mut terminal = ratatui::init();
+        let result = self.event_loop(&mut terminal).await;
+        ratatui::restore();
+        result
+    }
+
+    async fn event_loop(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        let mut app = App::new(self.history.entries());
+        if app.entries.is_empty() {
+            app.status = "The history is empty: generate a prompt first".to_string();
+        }
+        loop {
+            terminal.draw(|frame| self.draw(frame, &app))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match app.handle(key.code, self.settings.validator.rules.weight_range) {
+                Action::None => {}
+                Action::Quit => return Ok(()),
+                Action::Generate(keyword) => {
+                    app.status = format!("Generating a prompt for {:?}...", keyword);
+                    terminal.draw(|frame| self.draw(frame, &app))?;
+                    app.status = match self.generate(&keyword).await {
+                        Ok(id) => {
+                            app.reload(self.history.entries(), Some(id));
+                            format!("Generated entry {}", id)
+                        }
+                        Err(e) => format!("Error: {}", e),
+                    };
+                    // Warnings printed while generating leave marks on the screen
+                    terminal.clear()?;
+                }
+                Action::Save(id, text) => {
+                    app.status = match self.save(id, &text) {
+                        Ok(new_id) => {
+                            app.edits.remove(&id);
+                            app.reload(self.history.entries(), Some(new_id));
+                            format!("Saved as entry {}", new_id)
+                        }
+                        Err(e) => format!("Error: could not save the prompt history: {}", e),
+                    };
+                }
+            }
+        }
+    }
+
+    /// Generate a prompt for `keyword` and record it, returning its entry id
+    async fn generate(&mut self, keyword: &str) -> Result<u64, Box<dyn std::error::Error>> {
+        let started = Instant::now();
+        let generated = generate::generate(
+            self.backend.as_ref(),
+            &self.history,
+            &self.settings,
+            keyword,
+        )
+        .await?;
+        let fitted = generate::fit_token_budget(&generated.text, &self.tokenizer, self.max_tokens);
+        let entry = generate::history_entry(
+            self.backend.as_ref(),
+            &self.settings,
+            keyword,
+            &fitted,
+            &generated.negative_prompt,
+            started.elapsed(),
+        );
+        self.history.record(entry, &self.retention)?;
+        Ok(self.history.entries().last().map_or(0, |entry| entry.id))
+    }
+
+    /// Record `text`, an edit of the entry with `id`, as a new entry and return its id
+    fn save(&mut self, id: u64, text: &str) -> io::Result<u64> {
+        let Some(original) = self.history.get(id) else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "entry not found"));
+        };
+        let fitted = generate::fit_token_budget(text, &self.tokenizer, None);
+        let entry = Entry {
+            id: 0,
+            timestamp: history::now(),
+            prompt: fitted.text,
+            tokens: fitted.tokens.segments,
+            latency_ms: 0,
+            pinned: false,
+            ..original.clone()
+        };
+        self.history.record(entry, &self.retention)?;
+        Ok(self.history.entries().last().map_or(0, |entry| entry.id))
+    }
+
+    fn draw(&self, frame: &mut Frame, app: &App) {
+        let [main, status] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let [list, detail] =
+            Layout::horizontal([Constraint::Percentage(30), Constraint::Min(0)]).areas(main);
+
+        let items: Vec<ListItem> = app
+            .entries
+            .iter()
+            .map(|entry| {
+                let mark = match (
+                    app.marked == Some(entry.id),
+                    app.edits.contains_key(&entry.id),
+                ) {
+                    (true, _) => "◆ ",
+                    (false, true) => "* ",
+                    (false, false) => "  ",
+                };
+                ListItem::new(format!("{}{:>4} {}", mark, entry.id, entry.keyword))
+            })
+            .collect();
+        let list_style = match app.focus {
+            Focus::List => Style::new().fg(Color::Cyan),
+            Focus::Prompt => Style::new(),
+        };
+        frame.render_stateful_widget(
+            List::new(items)
+                .block(
+                    Block::bordered()
+                        .title(" History ")
+                        .border_style(list_style),
+                )
+                .highlight_style(Style::new().add_modifier(Modifier::REVERSED)),
+            list,
+            &mut ListState::default().with_selected(app.current().map(|_| app.selected)),
+        );
+
+        let marked = app
+            .marked
+            .and_then(|id| app.entries.iter().find(|entry| entry.id == id));
+        match (app.current(), marked) {
+            (Some(entry), Some(marked)) if app.diff => self.draw_diff(frame, detail, marked, entry),
+            (Some(entry), _) => self.draw_prompt(frame, detail, app, entry),
+            (None, _) => frame.render_widget(Block::bordered().title(" Prompt "), detail),
+        }
+
+        let keys = match app.focus {
+            Focus::List => LIST_KEYS,
+            Focus::Prompt => PROMPT_KEYS,
+        };
+        let line = if app.status.is_empty() {
+            Line::styled(keys, Style::new().fg(Color::DarkGray))
+        } else {
+            Line::from(app.status.as_str())
+        };
+        frame.render_widget(Paragraph::new(line), status);
+    }
+
+    fn draw_prompt(&self, frame: &mut Frame, area: Rect, app: &App, entry: &Entry) {
+        let text = app.text().unwrap_or_default();
+        let [prompt_area, negative_area] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(5)]).areas(area);
+        let cursor = (app.focus == Focus::Prompt).then_some(app.cursor);
+        let (prompt, _) = parser::parse(text);
+        let report = clip::analyze(&prompt, &self.tokenizer);
+        let edited = if app.edits.contains_key(&entry.id) {
+            " (edited)"
+        } else {
+            ""
+        };
+        let style = match app.focus {
+            Focus::Prompt => Style::new().fg(Color::Cyan),
+            Focus::List => Style::new(),
+        };
+        frame.render_widget(
+            Paragraph::new(prompt_lines(text, cursor, &self.tokenizer))
+                .wrap(Wrap { trim: false })
+                .block(
+                    Block::bordered()
+                        .title(format!("{}{}", title(entry), edited))
+                        .title_bottom(format!(" {} ", output::token_summary(&report)))
+                        .border_style(style),
+                ),
+            prompt_area,
+        );
+        frame.render_widget(
+            Paragraph::new(entry.negative_prompt.as_str())
+                .style(Style::new().fg(Color::DarkGray))
+                .wrap(Wrap { trim: false })
+                .block(Block::bordered().title(" Negative prompt ")),
+            negative_area,
+        );
+    }
+
+    fn draw_diff(&self, frame: &mut Frame, area: Rect, left: &Entry, right: &Entry) {
+        let [left_area, right_area] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .areas(area);
+        for (entry, other, only, area) in [
+            (left, right, Color::Red, left_area),
+            (right, left, Color::Green, right_area),
+        ] {
+            frame.render_widget(
+                Paragraph::new(diff_line(&entry.prompt, &other.prompt, only))
+                    .wrap(Wrap { trim: false })
+                    .block(Block::bordered().title(title(entry))),
+                area,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target::Target;
+    use ratatui::Terminal;
+    use ratatui::backend::TestBackend;
+
+    fn entry(id: u64, keyword: &str, prompt: &str) -> Entry {
+        Entry {
+            id,
+            timestamp: 0,
+            keyword: keyword.to_string(),
+            prompt: prompt.to_string(),
+            negative_prompt: "lowres".to_string(),
+            backend: "mock".to_string(),
+            model: "replay".to_string(),
+            preset: None,
+            target: Target::Sd15,
+            aspect_ratio: None,
+            stylize: None,
+            tokens: Vec::new(),
+            latency_ms: 0,
+            pinned: false,
+        }
+    }
+
+    fn app() -> App {
+        App::new(&[
+            entry(1, "knight", "1boy, (armor:1.2), BREAK, castle"),
+            entry(2, "witch", "1girl, [hat], moon"),
+        ])
+    }
+
+    #[test]
+    fn arrow_keys_edit_weights() {
+        let mut app = app();
+        let range = (0.1, 2.0);
+        assert_eq!(app.current().unwrap().id, 2);
+        assert_eq!(app.handle(KeyCode::Down, range), Action::None);
+        assert_eq!(app.handle(KeyCode::Right, range), Action::None);
+        assert_eq!(app.focus, Focus::Prompt);
+        app.handle(KeyCode::Right, range);
+        app.handle(KeyCode::Up, range);
+        app.handle(KeyCode::Up, range);
+        assert_eq!(app.text(), Some("1boy, (armor:1.3), BREAK, castle"));
+        for _ in 0..5 {
+            app.handle(KeyCode::Right, range);
+        }
+        app.handle(KeyCode::Down, range);
+        assert_eq!(app.text(), Some("1boy, (armor:1.3), BREAK, (castle:0.95)"));
+        assert_eq!(
+            app.handle(KeyCode::Char('s'), range),
+            Action::Save(1, "1boy, (armor:1.3), BREAK, (castle:0.95)".to_string())
+        );
+        app.handle(KeyCode::Char('u'), range);
+        assert_eq!(app.text(), Some("1boy, (armor:1.2), BREAK, castle"));
+        app.handle(KeyCode::Esc, range);
+        assert_eq!(
+            app.handle(KeyCode::Char('g'), range),
+            Action::Generate("knight".to_string())
+        );
+        assert_eq!(app.handle(KeyCode::Char('q'), range), Action::Quit);
+    }
+
+    #[test]
+    fn compares_tokens_by_plain_text() {
+        assert_eq!(
+            compare("1girl, (moon:1.2), stars", "1girl, moon, sun"),
+            vec![
+                ("1girl".to_string(), Change::Same),
+                ("(moon:1.2)".to_string(), Change::Reweighted),
+                ("stars".to_string(), Change::Only),
+            ]
+        );
+        assert_eq!(weight_style(1.0), Style::new());
+        assert_eq!(weight_style(1.5).fg, Some(Color::LightRed));
+        assert_eq!(weight_style(0.9).fg, Some(Color::DarkGray));
+    }
+
+    #[test]
+    fn draws_segments_and_comparisons() {
+        let tui = Tui {
+            backend: Box::new(crate::backend::MockBackend::default()),
+            history: History::load(std::env::temp_dir().join("promptflow-tui-unused.jsonl")),
+            retention: Retention::default(),
+            settings: GenerationSettings::default(),
+            tokenizer: ClipTokenizer::estimating(),
+            max_tokens: None,
+        };
+        let mut app = app();
+        app.handle(KeyCode::Down, (0.1, 2.0));
+        let mut terminal = Terminal::new(TestBackend::new(100, 20)).unwrap();
+        let screen = |terminal: &mut Terminal<TestBackend>, app: &App| {
+            terminal.draw(|frame| tui.draw(frame, app)).unwrap();
+            let buffer = terminal.backend().buffer();
+            (0..buffer.area.height)
+                .map(|y| {
+                    (0..buffer.area.width)
+                        .map(|x| buffer[(x, y)].symbol())
+                        .collect::<String>()
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+
+        let text = screen(&mut terminal, &app);
+        assert!(text.contains("#1 knight"), "{}", text);
+        assert!(text.contains("── BREAK · segment 2 · "), "{}", text);
+        assert!(text.contains("1boy, (armor:1.2)"), "{}", text);
+        assert!(text.contains("CLIP tokens"), "{}", text);
+
+        app.handle(KeyCode::Char('m'), (0.1, 2.0));
+        app.handle(KeyCode::Up, (0.1, 2.0));
+        app.handle(KeyCode::Char('d'), (0.1, 2.0));
+        let text = screen(&mut terminal, &app);
+        assert!(text.contains("#1 knight"), "{}", text);
+        assert!(text.contains("#2 witch"), "{}", text);
+        assert!(text.contains("1girl, [hat], moon"), "{}", text);
+    }
+}
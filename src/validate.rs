@@ -0,0 +1,394 @@
+//! Checks generated prompts against the rules in the system instruction and repairs them.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::parser::{self, GroupKind, Node, ParseIssue, Prompt, Token};
+
+/// Realism terms the anime presets forbid
+pub const DEFAULT_BANNED_TERMS: &[&str] = &["photorealistic", "hyperrealistic", "photorealism"];
+
+/// Explicit weights outside this range are reported, as `(min, max)`
+pub const DEFAULT_WEIGHT_RANGE: (f32, f32) = (0.1, 2.0);
+
+/// Number of times the model is asked to correct its own output in [`ValidationMode::Retry`]
+pub const DEFAULT_MAX_RETRIES: u32 = 2;
+
+/// What to do with a prompt that breaks the rules
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValidationMode {
+    /// Print the problems and leave the prompt untouched
+    Off,
+    /// Repair the prompt locally
+    #[default]
+    Fix,
+    /// Send the problems back to the model, then repair whatever is left locally
+    Retry,
+}
+
+impl fmt::Display for ValidationMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ValidationMode::Off => "off",
+            ValidationMode::Fix => "fix",
+            ValidationMode::Retry => "retry",
+        })
+    }
+}
+
+impl FromStr for ValidationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" => Ok(ValidationMode::Off),
+            "fix" => Ok(ValidationMode::Fix),
+            "retry" => Ok(ValidationMode::Retry),
+            _ => Err(format!(
+                "Unknown validation mode {:?} (available: off, fix, retry)",
+                s
+            )),
+        }
+    }
+}
+
+/// Limits a generated prompt has to stay within
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationRules {
+    /// Inclusive `(min, max)` range for explicit `(keyword:factor)` weights
+    pub weight_range: (f32, f32),
+    /// Terms that must not appear in any token, matched case-insensitively as whole words
+    pub banned_terms: Vec<String>,
+}
+
+impl Default for ValidationRules {
+    fn default() -> Self {
+        Self {
+            weight_range: DEFAULT_WEIGHT_RANGE,
+            banned_terms: DEFAULT_BANNED_TERMS.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+}
+
+/// Parse a weight range written as `MIN:MAX`, e.g. `0.5:1.5`
+pub fn parse_weight_range(s: &str) -> Result<(f32, f32), String> {
+    let parsed = s
+        .split_once(':')
+        .and_then(|(min, max)| Some((min.trim().parse().ok()?, max.trim().parse().ok()?)));
+    match parsed {
+        Some((min, max)) if min <= max => Ok((min, max)),
+        _ => Err(format!(
+            "Invalid weight range {:?}, expected MIN:MAX such as 0.5:1.5",
+            s
+        )),
+    }
+}
+
+/// Validation settings for a run
+#[derive(Debug, Clone, Default)]
+pub struct Validator {
+    pub mode: ValidationMode,
+    /// Follow-up requests allowed in [`ValidationMode::Retry`]
+    pub max_retries: u32,
+    pub rules: ValidationRules,
+}
+
+/// A rule broken by a generated prompt
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// Parentheses or brackets don't pair up
+    Unbalanced(ParseIssue),
+    /// `(text: factor)` where the factor isn't a number
+    MalformedWeight(String),
+    /// An explicit weight outside [`ValidationRules::weight_range`]
+    WeightOutOfRange { text: String, weight: f32 },
+    /// The prompt spans several lines
+    Newlines,
+    /// A token contains one of [`ValidationRules::banned_terms`]
+    BannedTerm { term: String, token: String },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Unbalanced(issue) => write!(f, "unbalanced brackets: {}", issue),
+            Problem::MalformedWeight(text) => {
+                write!(f, "malformed weight ({}), expected (keyword:number)", text)
+            }
+            Problem::WeightOutOfRange { text, weight } => write!(
+                f,
+                "weight {} on ({}) is outside the allowed range",
+                parser::format_weight(*weight),
+                text
+            ),
+            Problem::Newlines => write!(f, "prompt must be a single line without line breaks"),
+            Problem::BannedTerm { term, token } => {
+                write!(f, "forbidden realism term {:?} in {:?}", term, token)
+            }
+        }
+    }
+}
+
+/// List every rule `text` breaks
+pub fn validate(text: &str, rules: &ValidationRules) -> Vec<Problem> {
+    let (prompt, issues) = parser::parse(text);
+    let mut problems: Vec<Problem> = issues
+        .into_iter()
+        .map(|issue| match issue {
+            ParseIssue::MalformedWeight { text, .. } => Problem::MalformedWeight(text),
+            issue => Problem::Unbalanced(issue),
+        })
+        .collect();
+
+    if text.trim().contains(['\n', '\r']) {
+        problems.push(Problem::Newlines);
+    }
+
+    for segment in &prompt.segments {
+        for token in &segment.tokens {
+            check_weights(token, rules, &mut problems);
+            let plain = token.plain_text();
+            for term in &rules.banned_terms {
+                if contains_term(&plain, term) {
+                    problems.push(Problem::BannedTerm {
+                        term: term.clone(),
+                        token: plain.clone(),
+                    });
+                }
+            }
+        }
+    }
+    problems
+}
+
+fn check_weights(token: &Token, rules: &ValidationRules, problems: &mut Vec<Problem>) {
+    let (min, max) = rules.weight_range;
+    for node in &token.nodes {
+        if let Node::Group(group) = node {
+            if let Some(weight) = group.weight
+                && !(min..=max).contains(&weight)
+            {
+                let text = group
+                    .tokens
+                    .iter()
+                    .map(Token::plain_text)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                problems.push(Problem::WeightOutOfRange { text, weight });
+            }
+            for inner in &group.tokens {
+                check_weights(inner, rules, problems);
+            }
+        }
+    }
+}
+
+/// Whether `text` contains `term` as a whole word (or word sequence), ignoring case
+pub fn contains_term(text: &str, term: &str) -> bool {
+    let text = text.to_lowercase();
+    let term = term.to_lowercase();
+    if term.is_empty() {
+        return false;
+    }
+    text.match_indices(&term).any(|(at, _)| {
+        let before = text[..at].chars().last();
+        let after = text[at + term.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+/// Repair `text` so it passes [`validate`]: stray closing brackets are dropped, unclosed groups
+/// closed, malformed factors removed, weights clamped into range, tokens with banned terms
+/// removed and everything joined onto a single line.
+///
+/// Text that already passes is returned unchanged.
+pub fn repair(text: &str, rules: &ValidationRules) -> String {
+    if validate(text, rules).is_empty() {
+        return text.to_string();
+    }
+
+    // Drop closing brackets that have no opening partner before re-parsing
+    let (_, issues) = parser::parse(text);
+    let stray: Vec<usize> = issues
+        .iter()
+        .filter_map(|issue| match issue {
+            ParseIssue::UnmatchedClose { offset, .. } => Some(*offset),
+            _ => None,
+        })
+        .collect();
+    let cleaned: String = text
+        .char_indices()
+        .filter(|(offset, _)| !stray.contains(offset))
+        .map(|(_, c)| c)
+        .collect();
+
+    let (mut prompt, _) = parser::parse(&cleaned);
+    repair_prompt(&mut prompt, rules);
+    prompt.to_string()
+}
+
+fn repair_prompt(prompt: &mut Prompt, rules: &ValidationRules) {
+    for segment in &mut prompt.segments {
+        repair_tokens(&mut segment.tokens, rules);
+    }
+    prompt.segments.retain(|segment| !segment.tokens.is_empty());
+}
+
+fn repair_tokens(tokens: &mut Vec<Token>, rules: &ValidationRules) {
+    let (min, max) = rules.weight_range;
+    for token in tokens.iter_mut() {
+        for node in &mut token.nodes {
+            let Node::Group(group) = node else { continue };
+            repair_tokens(&mut group.tokens, rules);
+            match group.weight {
+                Some(weight) => group.weight = Some(weight.clamp(min, max)),
+                None if group.kind == GroupKind::Paren => strip_malformed_factor(&mut group.tokens),
+                None => {}
+            }
+        }
+        token
+            .nodes
+            .retain(|node| !matches!(node, Node::Group(group) if group.tokens.is_empty()));
+    }
+    // Inner tokens are cleaned first, so only tokens that still carry a banned term are dropped
+    tokens.retain(|token| {
+        let plain = token.plain_text();
+        !token.nodes.is_empty()
+            && !rules
+                .banned_terms
+                .iter()
+                .any(|term| contains_term(&plain, term))
+    });
+}
+
+/// Remove a non-numeric `: factor` suffix from the last token of a group
+fn strip_malformed_factor(tokens: &mut Vec<Token>) {
+    if let Some(token) = tokens.last_mut()
+        && let Some(Node::Text(last)) = token.nodes.last_mut()
+        && let Some((head, _)) = last.rsplit_once(':')
+    {
+        *last = head.trim_end().to_string();
+        if last.is_empty() {
+            token.nodes.pop();
+        }
+        if token.nodes.is_empty() {
+            tokens.pop();
+        }
+    }
+}
+
+/// Follow-up message asking the model to correct `text`
+pub fn retry_message(keyword: &str, text: &str, problems: &[Problem]) -> String {
+    let list: Vec<String> = problems.iter().map(|p| format!("- {}", p)).collect();
+    format!(
+        "{}\n\nYour previous prompt for this keyword was:\n{}\n\nIt has these problems:\n{}\n\nReturn a corrected prompt that fixes all of them, following every rule above.",
+        keyword,
+        text,
+        list.join("\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preset;
+
+    fn rules() -> ValidationRules {
+        ValidationRules::default()
+    }
+
+    #[test]
+    fn clean_prompt_passes_unchanged() {
+        let text = "1girl, (cel shading:1.3), [background], BREAK, night sky";
+        assert!(validate(text, &rules()).is_empty());
+        assert_eq!(repair(text, &rules()), text);
+    }
+
+    #[test]
+    fn preset_examples_follow_the_rules() {
+        for preset in preset::builtin() {
+            let rules = ValidationRules {
+                banned_terms: preset.banned_terms.clone(),
+                ..rules()
+            };
+            for example in &preset.examples {
+                assert_eq!(validate(&example.prompt, &rules), vec![], "{}", preset.name);
+            }
+        }
+    }
+
+    #[test]
+    fn detects_every_kind_of_problem() {
+        let text = "1girl, smile), (x: abc), (glow:3.5), photorealistic skin\nBREAK\n(sky";
+        let problems = validate(text, &rules());
+        assert!(
+            problems.contains(&Problem::Unbalanced(ParseIssue::UnmatchedClose {
+                close: ')',
+                offset: text.find(')').unwrap()
+            }))
+        );
+        assert!(
+            problems.contains(&Problem::Unbalanced(ParseIssue::UnclosedGroup {
+                open: '(',
+                offset: text.rfind('(').unwrap()
+            }))
+        );
+        assert!(problems.contains(&Problem::MalformedWeight("x: abc".to_string())));
+        assert!(problems.contains(&Problem::WeightOutOfRange {
+            text: "glow".to_string(),
+            weight: 3.5
+        }));
+        assert!(problems.contains(&Problem::Newlines));
+        assert!(problems.contains(&Problem::BannedTerm {
+            term: "photorealistic".to_string(),
+            token: "photorealistic skin".to_string()
+        }));
+    }
+
+    #[test]
+    fn banned_terms_match_whole_words() {
+        assert!(contains_term("Photorealistic skin", "photorealistic"));
+        assert!(!contains_term("non-photorealistic-ish", "realistic"));
+        assert!(contains_term("semi realistic", "realistic"));
+    }
+
+    #[test]
+    fn repairs_into_a_valid_single_line() {
+        let text = "1girl, smile), (x: abc), (glow:3.5), photorealistic skin\nBREAK\n(sky, hyperrealistic clouds";
+        let repaired = repair(text, &rules());
+        assert_eq!(repaired, "1girl, smile, (x), (glow:2.0), BREAK, (sky)");
+        assert!(validate(&repaired, &rules()).is_empty());
+    }
+
+    #[test]
+    fn respects_custom_weight_range() {
+        let rules = ValidationRules {
+            weight_range: (0.5, 1.5),
+            ..rules()
+        };
+        assert_eq!(repair("(a:0.2), (b:1.4)", &rules), "(a:0.5), (b:1.4)");
+    }
+
+    #[test]
+    fn parses_weight_ranges_and_modes() {
+        assert_eq!(parse_weight_range("0.5:1.5"), Ok((0.5, 1.5)));
+        assert!(parse_weight_range("1.5:0.5").is_err());
+        assert!(parse_weight_range("wide").is_err());
+        assert_eq!("RETRY".parse(), Ok(ValidationMode::Retry));
+        assert!("maybe".parse::<ValidationMode>().is_err());
+    }
+
+    #[test]
+    fn retry_message_lists_problems() {
+        let message = retry_message(
+            "knight",
+            "(a:9)",
+            &[Problem::WeightOutOfRange {
+                text: "a".to_string(),
+                weight: 9.0,
+            }],
+        );
+        assert!(message.starts_with("knight\n"));
+        assert!(message.contains("- weight 9.0 on (a) is outside the allowed range"));
+    }
+}
@@ -0,0 +1,153 @@
+//! Several distinct prompts for one keyword.
+//!
+//! Variants are generated with parallel calls. Each call is told which variant it is and which
+//! aspect to vary most, and `--diversity` (0 to 1) scales both the temperature and how far the
+//! model is asked to stray from the obvious prompt. Variants whose token sets overlap too much
+//! with an earlier one are dropped.
+
+use std::collections::HashSet;
+
+use crate::parser;
+
+/// Most variants generated for one keyword
+pub const MAX_COUNT: u32 = 20;
+
+/// Diversity used when only `--count` is given
+pub const DEFAULT_DIVERSITY: f32 = 0.5;
+
+/// Temperature scaled by the diversity when the backend's default is unknown
+const BASE_TEMPERATURE: f32 = 1.0;
+
+/// Highest temperature the diversity can push to
+const MAX_TEMPERATURE: f32 = 2.0;
+
+/// Token-set overlap (Jaccard index) from which a variant counts as a near duplicate
+pub const SIMILARITY_THRESHOLD: f64 = 0.8;
+
+/// Aspects the variants take turns emphasizing, so parallel calls don't all vary the same thing
+const FOCUS: &[&str] = &[
+    "composition and camera angle",
+    "lighting and time of day",
+    "color palette",
+    "art style and medium",
+    "setting and background",
+    "pose, expression and action",
+];
+
+/// Parse a diversity between 0 and 1
+pub fn parse_diversity(s: &str) -> Result<f32, String> {
+    match s.trim().parse::<f32>() {
+        Ok(value) if (0.0..=1.0).contains(&value) => Ok(value),
+        _ => Err(format!(
+            "Invalid diversity {:?}, expected a number from 0 to 1",
+            s
+        )),
+    }
+}
+
+/// `temperature` scaled by `diversity`: 0.7x at 0, unchanged at 0.5 and 1.3x at 1
+pub fn temperature(temperature: Option<f32>, diversity: f32) -> f32 {
+    let base = temperature.unwrap_or(BASE_TEMPERATURE);
+    (base * (0.7 + 0.6 * diversity)).min(MAX_TEMPERATURE)
+}
+
+/// Instruction note for variant `index` (from 0) of `count`
+pub fn note(index: usize, count: usize, diversity: f32) -> String {
+    let focus = FOCUS[index % FOCUS.len()];
+    let how = if diversity < 1.0 / 3.0 {
+        format!(
+            "Keep the overall concept; vary small details, mostly the {}.",
+            focus
+        )
+    } else if diversity < 2.0 / 3.0 {
+        format!(
+            "Vary the composition, lighting and style from the most obvious prompt, \
+             especially the {}.",
+            focus
+        )
+    } else {
+        format!(
+            "Make this variant clearly different from the most obvious prompt: change the \
+             composition, lighting, color palette and style, and above all the {}.",
+            focus
+        )
+    };
+    format!(
+        "**Variation {} of {}:**\nThis is one of {} distinct prompts for the same keyword. {}",
+        index + 1,
+        count,
+        count,
+        how
+    )
+}
+
+/// Lowercased plain text of every token in `text`
+fn token_set(text: &str) -> HashSet<String> {
+    let (prompt, _) = parser::parse(text);
+    prompt
+        .segments
+        .iter()
+        .flat_map(|segment| &segment.tokens)
+        .map(|token| token.plain_text().trim().to_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+/// Jaccard index of the token sets of `a` and `b`
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let (a, b) = (token_set(a), token_set(b));
+    let union = a.union(&b).count();
+    if union == 0 {
+        return 1.0;
+    }
+    a.intersection(&b).count() as f64 / union as f64
+}
+
+/// Indices of the prompts in `texts` that aren't near duplicates of an earlier kept one
+pub fn distinct<S: AsRef<str>>(texts: &[S]) -> Vec<usize> {
+    let mut kept: Vec<usize> = Vec::new();
+    for (i, text) in texts.iter().enumerate() {
+        if kept
+            .iter()
+            .all(|&k| similarity(texts[k].as_ref(), text.as_ref()) < SIMILARITY_THRESHOLD)
+        {
+            kept.push(i);
+        }
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_token_overlap() {
+        assert_eq!(similarity("1girl, (red hair:1.2)", "red hair, 1girl"), 1.0);
+        assert_eq!(similarity("a, b, c", "a, b, d"), 0.5);
+        assert_eq!(similarity("a", "b"), 0.0);
+    }
+
+    #[test]
+    fn drops_near_duplicates() {
+        let texts = [
+            "1girl, red hair, castle, night, moon",
+            "1girl, red hair, castle, night, (moon:1.2)",
+            "1girl, red hair, beach, sunset, waves",
+            "1girl, red hair, castle, night, moon, stars",
+        ];
+        assert_eq!(distinct(&texts), vec![0, 2]);
+    }
+
+    #[test]
+    fn diversity_scales_temperature_and_wording() {
+        assert_eq!(temperature(None, 0.5), 1.0);
+        assert!((temperature(Some(0.8), 1.0) - 1.04).abs() < 1e-6);
+        assert_eq!(temperature(Some(1.8), 1.0), MAX_TEMPERATURE);
+        assert!(note(0, 3, 0.1).contains("Keep the overall concept"));
+        assert!(note(1, 3, 0.9).starts_with("**Variation 2 of 3:**"));
+        assert!(note(1, 3, 0.9).ends_with("above all the lighting and time of day."));
+        assert!(parse_diversity("1.5").is_err());
+        assert_eq!(parse_diversity("0.25"), Ok(0.25));
+    }
+}
@@ -0,0 +1,945 @@
+//! End-to-end runs of the binary against the offline mock backend and local stub servers.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::{Arc, Mutex};
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("promptflow-cli-{}-{}", name, std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// PromptFlow with its temp, config and data dirs redirected into `dir`, and `dir` as the
+/// working directory, so no real state or configuration is touched
+fn promptflow(dir: &PathBuf, args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_PromptFlow"));
+    command
+        .args(args)
+        .current_dir(dir)
+        .env("TMPDIR", dir)
+        .env("XDG_CONFIG_HOME", dir.join("config"))
+        .env("XDG_DATA_HOME", dir.join("data"))
+        .env_remove("GENAI_API_KEY");
+    command
+}
+
+/// History file of a [`promptflow`] run in `dir`
+fn history_file(dir: &Path) -> PathBuf {
+    dir.join("data/promptflow/history.jsonl")
+}
+
+fn run(dir: &PathBuf, args: &[&str]) -> Output {
+    promptflow(dir, args).output().unwrap()
+}
+
+#[test]
+fn mock_backend_generates_without_a_key() {
+    let dir = scratch_dir("mock");
+    let responses = dir.join("responses.json");
+    std::fs::write(
+        &responses,
+        r#"{"azure knight": "1girl, (azure armor:1.3), BREAK, castle"}"#,
+    )
+    .unwrap();
+
+    let output = promptflow(&dir, &["--backend", "mock", "azure knight"])
+        .env("PROMPTFLOW_MOCK_RESPONSES", &responses)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{:?}", output);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Generating prompt for: \"azure knight\" (mock / replay)"));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.starts_with("\n=== GENERATED PROMPT ===\n1girl, (azure armor:1.3), BREAK, castle\n")
+    );
+    assert!(stdout.contains("=== NEGATIVE PROMPT ===\nugly, tiling"));
+    let history = std::fs::read_to_string(history_file(&dir)).unwrap();
+    assert_eq!(history.lines().count(), 1);
+    let entry: serde_json::Value = serde_json::from_str(&history).unwrap();
+    assert_eq!(entry["keyword"], "azure knight");
+    assert_eq!(entry["prompt"], "1girl, (azure armor:1.3), BREAK, castle");
+    assert_eq!(entry["backend"], "mock");
+    assert_eq!(entry["model"], "replay");
+    assert_eq!(entry["preset"], "anime");
+    assert_eq!(entry["tokens"].as_array().unwrap().len(), 2);
+    assert!(
+        entry["negative_prompt"]
+            .as_str()
+            .unwrap()
+            .starts_with("ugly, tiling")
+    );
+    assert!(entry["timestamp"].as_u64().unwrap() > 0);
+    assert!(!dir.join("key").exists());
+}
+
+#[test]
+fn history_is_never_kept_in_the_temp_dir() {
+    let dir = scratch_dir("no-data-dir");
+    let output = promptflow(&dir, &["--backend", "mock", "knight"])
+        .env_remove("XDG_DATA_HOME")
+        .env_remove("HOME")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("Error: cannot determine the data directory"),
+        "{}",
+        stderr
+    );
+    assert!(!dir.join("promptflow").exists());
+}
+
+#[test]
+fn unreadable_key_store_is_reported_for_keyless_backends() {
+    let dir = scratch_dir("corrupt-store");
+    std::fs::create_dir_all(dir.join("config/promptflow")).unwrap();
+    std::fs::write(dir.join("config/promptflow/keys.toml"), "profiles = [").unwrap();
+
+    let output = run(&dir, &["--backend", "mock", "knight"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Error: invalid key store "), "{}", stderr);
+    assert!(!history_file(&dir).exists());
+
+    // A key on the command line doesn't need the store
+    let output = run(&dir, &["--backend", "mock", "--key", "sk-test", "knight"]);
+    assert!(output.status.success(), "{:?}", output);
+}
+
+#[test]
+fn replies_stream_to_stderr_in_text_mode() {
+    let dir = scratch_dir("stream");
+    let reply = "masterpiece, best quality, anime screenshot, (moon:1.2), cel shading";
+    let stderr = |args: &[&str]| {
+        let output = run(
+            &dir,
+            &[&["-b", "mock", "--validate", "off"][..], args].concat(),
+        );
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stderr).unwrap()
+    };
+
+    assert!(stderr(&["moon"]).contains(&format!("(mock / replay)\n{}", reply)));
+    assert!(!stderr(&["--no-stream", "moon"]).contains(reply));
+    assert!(!stderr(&["--format", "json", "moon"]).contains(reply));
+    assert!(!stderr(&["-n", "2", "moon"]).contains(reply));
+}
+
+#[test]
+fn machine_readable_formats_keep_stdout_clean() {
+    let dir = scratch_dir("formats");
+    let responses = dir.join("responses.json");
+    std::fs::write(
+        &responses,
+        r#"{"azure knight": "1girl, (azure armor:1.3), BREAK, castle"}"#,
+    )
+    .unwrap();
+    let generate = |format: &str| {
+        let output = promptflow(&dir, &["-b", "mock", "--explain", "--format", format])
+            .arg("azure knight")
+            .env("PROMPTFLOW_MOCK_RESPONSES", &responses)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let json: serde_json::Value = serde_json::from_str(&generate("json")).unwrap();
+    assert_eq!(json["prompt"], "1girl, (azure armor:1.3), BREAK, castle");
+    assert_eq!(json["backend"], "mock");
+    assert_eq!(json["model"], "replay");
+    assert_eq!(json["target"], "sd15");
+    assert_eq!(json["segments"][0]["text"], "1girl, (azure armor:1.3)");
+    assert_eq!(json["segments"][0]["weights"][1]["token"], "azure armor");
+    assert_eq!(json["segments"][0]["weights"][1]["weight"], 1.3);
+    assert_eq!(json["segments"][1]["text"], "castle");
+    assert!(json["tokens"]["total"].as_u64().unwrap() > 0);
+    assert!(
+        json["negative_prompt"]
+            .as_str()
+            .unwrap()
+            .starts_with("ugly, tiling")
+    );
+
+    let yaml = generate("yaml");
+    assert!(yaml.starts_with(
+        "keyword: \"azure knight\"\nprompt: \"1girl, (azure armor:1.3), BREAK, castle\"\n"
+    ));
+    assert!(yaml.contains("\nsegments:\n  -\n    text: \"1girl, (azure armor:1.3)\"\n"));
+    let env = generate("env");
+    assert!(env.starts_with(
+        "PROMPT='1girl, (azure armor:1.3), BREAK, castle'\nNEGATIVE_PROMPT='ugly, tiling"
+    ));
+    assert!(env.contains("\nMODEL='replay'\n"));
+    assert_eq!(generate("raw"), "1girl, (azure armor:1.3), BREAK, castle\n");
+}
+
+#[test]
+fn missing_arguments_fail_with_usage() {
+    let dir = scratch_dir("usage");
+    let output = run(&dir, &[]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Usage: PromptFlow"));
+    assert!(stderr.contains("Commands:"));
+}
+
+#[test]
+fn help_and_version() {
+    let dir = scratch_dir("help");
+    let output = run(&dir, &["--help"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    for command in ["generate", "history", "config", "key", "presets", "serve"] {
+        assert!(stdout.contains(&format!("  {} ", command)), "{}", stdout);
+    }
+
+    let output = run(&dir, &["--version"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("PromptFlow {}\n", env!("CARGO_PKG_VERSION"))
+    );
+}
+
+#[test]
+fn unknown_flags_are_errors() {
+    let dir = scratch_dir("unknown");
+    let output = run(&dir, &["--backend", "mock", "--modle", "qwen", "knight"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("unexpected argument '--modle'"),
+        "{}",
+        stderr
+    );
+    assert!(!history_file(&dir).exists());
+}
+
+#[test]
+fn generate_subcommand_and_history() {
+    let dir = scratch_dir("subcommands");
+    let output = run(
+        &dir,
+        &[
+            "generate",
+            "--backend",
+            "mock",
+            "--preset",
+            "chibi",
+            "--target",
+            "midjourney",
+            "--ar",
+            "2:3",
+            "anime",
+            "knight",
+        ],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    let output = run(&dir, &["-b", "mock", "sea", "witch"]);
+    assert!(output.status.success(), "{:?}", output);
+
+    let stdout = |output: Output| String::from_utf8(output.stdout).unwrap();
+    let list = stdout(run(&dir, &["history", "list"]));
+    let lines: Vec<&str> = list.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", list);
+    assert!(lines[0].starts_with("   1  "), "{}", list);
+    assert!(lines[0].contains("  mock/replay "), "{}", list);
+    assert!(lines[0].ends_with(" anime knight"), "{}", list);
+    assert!(lines[1].starts_with("   2  ") && lines[1].ends_with(" sea witch"));
+    let list = stdout(run(&dir, &["history", "list", "-n", "1"]));
+    assert!(list.starts_with("   2  ") && list.lines().count() == 1);
+    let found = stdout(run(&dir, &["history", "search", "KNIGHT"]));
+    assert!(found.starts_with("   1  ") && found.lines().count() == 1);
+
+    let shown = stdout(run(&dir, &["history", "show", "2"]));
+    assert!(shown.contains("keyword  sea witch\n"), "{}", shown);
+    assert!(shown.contains("=== GENERATED PROMPT ===\n"));
+    let output = run(&dir, &["history", "show", "9"]);
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("no history entry with id 9")
+    );
+
+    let output = run(&dir, &["history", "rerun", "1"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("Generating prompt for: \"anime knight\" (mock / replay)")
+    );
+    // The rerun keeps the preset and target of the entry
+    let shown = stdout(run(&dir, &["history", "show", "3"]));
+    assert!(shown.contains("preset   chibi\n"), "{}", shown);
+    assert!(shown.contains("target   midjourney\n"), "{}", shown);
+    assert!(shown.contains(" --ar 2:3\n"), "{}", shown);
+    let output = run(&dir, &["history", "rerun", "1", "--target", "sdxl"]);
+    assert!(output.status.success(), "{:?}", output);
+    let shown = stdout(run(&dir, &["history", "show", "4"]));
+    assert!(shown.contains("preset   chibi\n") && shown.contains("target   sdxl\n"));
+
+    let csv = stdout(run(&dir, &["history", "export", "--format", "csv"]));
+    let rows: Vec<&str> = csv.lines().collect();
+    assert_eq!(rows.len(), 5, "{}", csv);
+    assert!(rows[0].starts_with("id,timestamp,keyword,prompt,"));
+    assert!(rows[3].starts_with("3,") && rows[3].contains(",anime knight,"));
+    let json: serde_json::Value =
+        serde_json::from_str(&stdout(run(&dir, &["history", "export"]))).unwrap();
+    assert_eq!(json.as_array().unwrap().len(), 4);
+
+    assert!(run(&dir, &["history", "pin", "2"]).status.success());
+    assert!(!run(&dir, &["history", "pin", "8"]).status.success());
+    let list = stdout(run(&dir, &["history", "list"]));
+    assert!(
+        list.lines().nth(1).unwrap().starts_with("   2* "),
+        "{}",
+        list
+    );
+    let output = run(
+        &dir,
+        &["--backend", "mock", "--context", "pinned", "knight"],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    let output = run(
+        &dir,
+        &["--backend", "mock", "--context", "oldest", "knight"],
+    );
+    assert_eq!(output.status.code(), Some(2));
+    assert!(run(&dir, &["history", "unpin", "2"]).status.success());
+    assert!(!stdout(run(&dir, &["history", "list"])).contains('*'));
+
+    assert!(
+        run(&dir, &["history", "rm", "1", "3", "4", "5"])
+            .status
+            .success()
+    );
+    assert!(!run(&dir, &["history", "rm", "1"]).status.success());
+    let markdown = stdout(run(&dir, &["history", "export", "-f", "md"]));
+    assert_eq!(markdown.lines().count(), 3, "{}", markdown);
+    assert!(markdown.lines().nth(2).unwrap().starts_with("| 2 | "));
+
+    assert!(run(&dir, &["history", "clear"]).status.success());
+    let output = run(&dir, &["history", "list"]);
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn sessions_keep_their_own_history() {
+    let dir = scratch_dir("sessions");
+    let stdout = |output: Output| String::from_utf8(output.stdout).unwrap();
+    let stderr = |output: Output| String::from_utf8(output.stderr).unwrap();
+
+    let output = run(
+        &dir,
+        &[
+            "session",
+            "new",
+            "azure",
+            "--preset",
+            "anime",
+            "--negative-prompt",
+            "lowres, extra fingers",
+            "-d",
+            "Azure: long blue hair, silver armor",
+            "--switch",
+        ],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert!(!run(&dir, &["session", "new", "azure"]).status.success());
+    assert!(!run(&dir, &["session", "new", "../up"]).status.success());
+    assert!(run(&dir, &["session", "new", "noir"]).status.success());
+
+    // The active session's negative prompt and history are used
+    let generated = stdout(run(&dir, &["-b", "mock", "knight"]));
+    assert!(generated.contains("=== NEGATIVE PROMPT ===\nlowres, extra fingers"));
+    let shown = stdout(run(&dir, &["config", "show"]));
+    assert!(shown.contains("session \"azure\""), "{}", shown);
+    assert!(
+        run(&dir, &["--session", "noir", "-b", "mock", "rain"])
+            .status
+            .success()
+    );
+    assert!(!history_file(&dir).exists());
+    let list = stdout(run(&dir, &["history", "list"]));
+    assert!(
+        list.ends_with(" knight\n") && list.lines().count() == 1,
+        "{}",
+        list
+    );
+    let list = stdout(run(&dir, &["history", "--session", "noir", "list"]));
+    assert!(
+        list.ends_with(" rain\n") && list.lines().count() == 1,
+        "{}",
+        list
+    );
+
+    let sessions = stdout(run(&dir, &["session", "list"]));
+    let lines: Vec<&str> = sessions.lines().collect();
+    assert!(lines[0].starts_with("* azure ") && lines[0].contains(" 1 prompt "));
+    assert!(lines[1].starts_with("  noir ") && lines[1].ends_with(" -"));
+
+    // Archiving the active session goes back to the global history
+    assert!(run(&dir, &["session", "archive", "azure"]).status.success());
+    let sessions = stdout(run(&dir, &["session", "list"]));
+    assert!(!sessions.contains("azure"), "{}", sessions);
+    assert!(stdout(run(&dir, &["session", "list", "--all"])).contains(" (archived)"));
+    assert!(stderr(run(&dir, &["session", "switch", "azure"])).contains("is archived"));
+    assert!(run(&dir, &["-b", "mock", "dragon"]).status.success());
+    assert!(history_file(&dir).exists());
+
+    assert!(run(&dir, &["session", "switch", "noir"]).status.success());
+    assert!(stdout(run(&dir, &["history", "list"])).ends_with(" rain\n"));
+    assert!(run(&dir, &["session", "switch"]).status.success());
+    assert!(stdout(run(&dir, &["history", "list"])).ends_with(" dragon\n"));
+    let output = run(&dir, &["--session", "missing", "-b", "mock", "x"]);
+    assert!(!output.status.success());
+    assert!(stderr(output).contains("session new missing"));
+}
+
+#[test]
+fn presets_pick_the_instruction_and_negative_prompt() {
+    let dir = scratch_dir("presets");
+    let stdout = |output: Output| String::from_utf8(output.stdout).unwrap();
+
+    let list = stdout(run(&dir, &["presets", "list"]));
+    assert!(list.starts_with("anime          built-in  "), "{}", list);
+    assert!(list.contains("\nphotographic   built-in  "), "{}", list);
+
+    let generated = stdout(run(
+        &dir,
+        &["-b", "mock", "--preset", "Photographic", "fisherman"],
+    ));
+    assert!(generated.contains("=== NEGATIVE PROMPT ===\nanime, cartoon"));
+    let history = std::fs::read_to_string(history_file(&dir)).unwrap();
+    let entry: serde_json::Value = serde_json::from_str(&history).unwrap();
+    assert_eq!(entry["preset"], "photographic");
+
+    // A user preset copied from a built-in one, then edited
+    let output = run(
+        &dir,
+        &[
+            "presets",
+            "new",
+            "ink",
+            "--from",
+            "manga-lineart",
+            "--markdown",
+        ],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    let path = PathBuf::from(stdout(output).trim());
+    assert_eq!(path, dir.join("config/promptflow/presets/ink.md"));
+    let text = std::fs::read_to_string(&path).unwrap();
+    assert!(text.starts_with("+++\ndescription = \"Black and white manga"));
+    std::fs::write(
+        &path,
+        "+++\ndescription = \"Sumi-e\"\nnegative_prompt = \"color\"\n+++\nPaint with ink.\n",
+    )
+    .unwrap();
+    assert!(!run(&dir, &["presets", "new", "ink"]).status.success());
+    assert!(stdout(run(&dir, &["presets", "list"])).ends_with("ink            user      Sumi-e\n"));
+    let shown = stdout(run(&dir, &["presets", "show", "ink"]));
+    assert!(shown.starts_with("Paint with ink.\n\n--------------------\n**Advanced Techniques"));
+    assert!(shown.ends_with("=== NEGATIVE PROMPT ===\ncolor\n"));
+
+    let output = run(&dir, &["-b", "mock", "--preset", "oil", "x"]);
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("unknown preset \"oil\" (available: anime, 90s-anime,")
+    );
+}
+
+#[test]
+fn targets_rewrite_the_prompt() {
+    let dir = scratch_dir("targets");
+    let responses = dir.join("responses.json");
+    std::fs::write(
+        &responses,
+        r#"{"azure knight": "1girl, (azure armor:1.3), BREAK, castle"}"#,
+    )
+    .unwrap();
+    let generate = |args: &[&str]| {
+        let output = promptflow(&dir, args)
+            .args(["--backend", "mock", "azure knight"])
+            .env("PROMPTFLOW_MOCK_RESPONSES", &responses)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        (
+            String::from_utf8(output.stdout).unwrap(),
+            String::from_utf8(output.stderr).unwrap(),
+        )
+    };
+
+    let (stdout, stderr) = generate(&["--target", "midjourney", "--ar", "2:3"]);
+    assert!(
+        stdout.contains("=== GENERATED PROMPT ===\n1girl:: azure armor::1.3 castle --ar 2:3\n")
+    );
+    assert!(!stderr.contains("CLIP tokens"), "{}", stderr);
+    let (stdout, _) = generate(&["--target", "dalle"]);
+    assert!(stdout.contains("=== GENERATED PROMPT ===\n1girl, azure armor. Castle.\n"));
+    let (stdout, stderr) = generate(&["--target", "pony"]);
+    assert!(stdout.contains(
+        "=== GENERATED PROMPT ===\nscore_9, score_8_up, score_7_up, 1girl, (azure armor:1.3), BREAK, castle\n"
+    ));
+    assert!(stdout.contains("=== NEGATIVE PROMPT ===\nscore_6, score_5, score_4, ugly"));
+    assert!(stderr.contains("CLIP tokens"), "{}", stderr);
+
+    // The history keeps the Stable Diffusion syntax and formats it on output
+    let history = std::fs::read_to_string(history_file(&dir)).unwrap();
+    let entries: Vec<serde_json::Value> = history
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(
+        entries[1]["prompt"],
+        "1girl, (azure armor:1.3), BREAK, castle"
+    );
+    assert_eq!(entries[1]["target"], "dalle");
+    assert_eq!(entries[0]["aspect_ratio"], serde_json::json!([2, 3]));
+    let show = String::from_utf8(run(&dir, &["history", "show", "2"]).stdout).unwrap();
+    assert!(show.contains("target   dalle\n"), "{}", show);
+    assert!(show.contains("=== GENERATED PROMPT ===\n1girl, azure armor. Castle.\n"));
+}
+
+#[test]
+fn variants_drop_near_duplicates() {
+    let dir = scratch_dir("variants");
+    let output = promptflow(
+        &dir,
+        &["--backend", "mock", "--count", "3", "-f", "json", "knight"],
+    )
+    .output()
+    .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Generating 3 prompt variants for: \"knight\""));
+    // The mock answers every variant alike
+    assert!(
+        stderr.contains("Dropped 2 near-duplicate variant(s)"),
+        "{}",
+        stderr
+    );
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["keyword"], "knight");
+    let history = std::fs::read_to_string(history_file(&dir)).unwrap();
+    assert_eq!(history.lines().count(), 1);
+}
+
+#[test]
+fn batch_writes_results_and_resumes() {
+    let dir = scratch_dir("batch");
+    std::fs::write(
+        dir.join("ideas.csv"),
+        "keyword,notes\nknight,\"tall, armored\"\n\"sea witch\",\n",
+    )
+    .unwrap();
+    let batch = |args: &[&str]| {
+        let output = promptflow(&dir, &["batch", "ideas.csv", "-o", "out.csv"])
+            .args(["--backend", "mock"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stderr).unwrap()
+    };
+
+    let stderr = batch(&["--concurrency", "2"]);
+    assert!(stderr.contains("Generated 2, failed 0"), "{}", stderr);
+    let results = std::fs::read_to_string(dir.join("out.csv")).unwrap();
+    let mut lines: Vec<&str> = results.lines().collect();
+    assert_eq!(
+        lines.remove(0),
+        "row,keyword,prompt,negative_prompt,tokens,error"
+    );
+    lines.sort();
+    assert!(
+        lines[0].starts_with("2,knight,\"masterpiece, "),
+        "{}",
+        results
+    );
+    assert!(lines[1].starts_with("3,sea witch,"), "{}", results);
+
+    let stderr = batch(&[]);
+    assert!(stderr.contains("Resuming: 2 keyword(s) already done"));
+    assert!(stderr.contains("Nothing to generate"));
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out.csv")).unwrap(),
+        results
+    );
+    assert!(batch(&["--restart"]).contains("Generated 2, failed 0"));
+    assert_eq!(
+        std::fs::read_to_string(history_file(&dir))
+            .unwrap()
+            .lines()
+            .count(),
+        4
+    );
+}
+
+#[test]
+fn repl_refines_and_saves() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let dir = scratch_dir("repl");
+    let mut child = promptflow(&dir, &["repl", "--backend", "mock"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"knight\n/weight knight 0.8\n/drop vibrant colors\n/save\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let refined =
+        "masterpiece, best quality, anime screenshot, (knight:0.8), cel shading, soft lighting";
+    assert!(
+        stdout.contains(&format!("=== GENERATED PROMPT ===\n{}\n", refined)),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("Saved as history entry 1"));
+    let history = std::fs::read_to_string(history_file(&dir)).unwrap();
+    assert!(history.contains(refined));
+}
+
+#[test]
+fn prints_completions() {
+    let dir = scratch_dir("completions");
+    let output = run(&dir, &["completions", "zsh"]);
+    assert!(output.status.success());
+    assert!(
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .contains("#compdef PromptFlow")
+    );
+}
+
+#[test]
+fn gemini_without_key_fails_before_generating() {
+    let dir = scratch_dir("nokey");
+    let output = run(&dir, &["anime knight"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("API key not found"), "{}", stderr);
+    assert!(!history_file(&dir).exists());
+}
+
+#[test]
+fn config_layers_apply_in_order() {
+    let dir = scratch_dir("config");
+    std::fs::create_dir_all(dir.join("config/promptflow")).unwrap();
+    std::fs::write(
+        dir.join("config/promptflow/config.toml"),
+        "backend = \"mock\"\nnegative_prompt = \"lowres, blurry\"\nnegative_mode = \"fixed\"\n\
+         validate = \"off\"\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join(".promptflow.toml"), "validate = \"retry\"\n").unwrap();
+
+    let output = promptflow(&dir, &["config", "show", "--max-tokens", "150"])
+        .env("PROMPTFLOW_HISTORY_DEPTH", "2")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let line = |key: &str| {
+        stdout
+            .lines()
+            .find(|line| line.starts_with(&format!("{} ", key)))
+            .unwrap()
+            .to_string()
+    };
+    assert!(line("backend").contains("= mock"));
+    assert!(line("backend").ends_with("config/promptflow/config.toml"));
+    assert!(line("validate").contains("= retry"));
+    assert!(line("validate").ends_with(".promptflow.toml"));
+    assert!(line("history_depth").ends_with("# environment PROMPTFLOW_HISTORY_DEPTH"));
+    assert!(line("max_tokens").ends_with("# command line --max-tokens"));
+    assert!(line("model").ends_with("# default"));
+
+    // The configured backend and negative prompt are used for generation
+    let output = run(&dir, &["azure knight"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("(mock / replay)")
+    );
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("=== NEGATIVE PROMPT ===\nlowres, blurry\n"));
+}
+
+#[test]
+fn invalid_config_is_reported() {
+    let dir = scratch_dir("badconfig");
+    std::fs::write(dir.join(".promptflow.toml"), "modle = \"qwen\"\n").unwrap();
+    let output = run(&dir, &["config", "show"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("unknown field `modle`"), "{}", stderr);
+}
+
+/// Run PromptFlow like [`run`], feeding `input` to standard input
+fn run_with_input(dir: &PathBuf, args: &[&str], input: &str, envs: &[(&str, &str)]) -> Output {
+    let mut child = promptflow(dir, args)
+        .envs(envs.iter().copied())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn legacy_key_file_is_migrated() {
+    let dir = scratch_dir("legacy");
+    std::fs::write(dir.join("key"), "AIzaLegacyKey1234\n").unwrap();
+
+    let output = run(&dir, &["key", "show", "gemini"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "*************1234\n"
+    );
+    assert!(!dir.join("key").exists());
+
+    let store = dir.join("config/promptflow/keys.toml");
+    assert!(
+        std::fs::read_to_string(&store)
+            .unwrap()
+            .contains("gemini = \"AIzaLegacyKey1234\"")
+    );
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&store).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
+
+#[test]
+fn key_commands_manage_profiles() {
+    let dir = scratch_dir("keys");
+    let output = run_with_input(
+        &dir,
+        &["key", "set", "openai", "--profile", "work"],
+        "sk-work-5678\n",
+        &[],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    let output = run_with_input(&dir, &["key", "set"], "AIzaDefault9999\n", &[]);
+    assert!(output.status.success(), "{:?}", output);
+
+    let output = run(&dir, &["key", "show"]);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "default      gemini   ***********9999\nwork         openai   ********5678\n"
+    );
+    let output = run(
+        &dir,
+        &["key", "show", "openai", "--profile", "work", "--reveal"],
+    );
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "sk-work-5678\n");
+
+    let output = run_with_input(
+        &dir,
+        &["key", "rotate", "openai", "--profile", "work"],
+        "sk-new-0000\n",
+        &[],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    let output = run(
+        &dir,
+        &["key", "show", "openai", "--profile", "work", "--reveal"],
+    );
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "sk-new-0000\n");
+
+    assert!(
+        run(&dir, &["key", "rm", "openai", "--profile", "work"])
+            .status
+            .success()
+    );
+    let output = run(&dir, &["key", "rm", "openai", "--profile", "work"]);
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("no openai API key stored in profile \"work\"")
+    );
+    let output = run_with_input(&dir, &["key", "rotate", "mock"], "x\n", &[]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn encrypted_key_store() {
+    let dir = scratch_dir("encrypted");
+    let passphrase = [("PROMPTFLOW_KEY_PASSPHRASE", "correct horse")];
+    let output = run_with_input(
+        &dir,
+        &["key", "set", "--encrypt"],
+        "AIzaSecretKey4321\n",
+        &passphrase,
+    );
+    assert!(output.status.success(), "{:?}", output);
+    let store = std::fs::read_to_string(dir.join("config/promptflow/keys.toml")).unwrap();
+    assert!(!store.contains("AIzaSecretKey4321"));
+
+    let output = run_with_input(
+        &dir,
+        &["key", "show", "gemini", "--reveal"],
+        "",
+        &passphrase,
+    );
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "AIzaSecretKey4321\n"
+    );
+
+    let output = run_with_input(&dir, &["key", "show", "gemini"], "", &[]);
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("PROMPTFLOW_KEY_PASSPHRASE")
+    );
+
+    let output = run_with_input(
+        &dir,
+        &["key", "show", "gemini"],
+        "",
+        &[("PROMPTFLOW_KEY_PASSPHRASE", "wrong")],
+    );
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("wrong passphrase")
+    );
+}
+
+/// OpenAI-compatible server on a local port that rejects every bearer token but `valid`,
+/// recording the tokens it was sent
+fn auth_server(valid: &'static str) -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&seen);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let (mut token, mut length) = (String::new(), 0);
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                let (name, value) = line.split_once(':').unwrap_or_default();
+                match name.to_ascii_lowercase().as_str() {
+                    "authorization" => {
+                        token = value.trim().trim_start_matches("Bearer ").to_string()
+                    }
+                    "content-length" => length = value.trim().parse().unwrap(),
+                    _ => {}
+                }
+            }
+            reader.read_exact(&mut vec![0; length]).unwrap();
+            let (status, body) = if token == valid {
+                (
+                    "200 OK",
+                    r#"{"choices":[{"message":{"content":"1girl, knight"}}]}"#,
+                )
+            } else {
+                (
+                    "401 Unauthorized",
+                    r#"{"error":{"code":"invalid_api_key"}}"#,
+                )
+            };
+            recorded.lock().unwrap().push(token);
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .unwrap();
+        }
+    });
+    (url, seen)
+}
+
+#[test]
+fn rejected_keys() {
+    let dir = scratch_dir("rejected");
+    let (url, seen) = auth_server("sk-new");
+    let args = [
+        "--backend",
+        "openai",
+        "--base-url",
+        &url,
+        "--validate",
+        "off",
+        "knight",
+    ];
+    let output = run_with_input(&dir, &["key", "set", "openai"], "sk-old\n", &[]);
+    assert!(output.status.success(), "{:?}", output);
+
+    // The stored key is rejected: a new one is asked for, stored and used
+    let output = run_with_input(&dir, &args, "sk-new\n", &[]);
+    assert!(output.status.success(), "{:?}", output);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("The stored API key was rejected"),
+        "{}",
+        stderr
+    );
+    assert!(
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .contains("1girl, knight")
+    );
+    let output = run(&dir, &["key", "show", "openai", "--reveal"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "sk-new\n");
+
+    // --key wins over the stored key and fails without touching the store
+    let output = run(&dir, &[&["--key", "sk-typo"][..], &args].concat());
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("The API key from --key was rejected"),
+        "{}",
+        stderr
+    );
+    let output = run(&dir, &["key", "show", "openai", "--reveal"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "sk-new\n");
+
+    assert_eq!(*seen.lock().unwrap(), vec!["sk-old", "sk-new", "sk-typo"]);
+}